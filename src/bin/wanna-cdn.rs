@@ -1,5 +1,3 @@
-use std::collections::HashMap;
-
 use clap::Parser;
 use log::{info, warn};
 use wanna_cdn::{AppOpts, AppServiceImpl};
@@ -51,8 +49,7 @@ fn check_license_agreement() {
   }
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
   match dotenvy::dotenv() {
     Err(e) => warn!("dotenv(): failed to load .env file: {}", e),
     _ => {}
@@ -67,6 +64,40 @@ async fn main() {
 
   let opts = AppOpts::parse();
 
+  // 0 means whatever Tokio picks by default.
+  let mut runtime = tokio::runtime::Builder::new_multi_thread();
+  runtime.enable_all();
+  if opts.worker_threads > 0 {
+    runtime.worker_threads(opts.worker_threads);
+  }
+  if opts.blocking_threads > 0 {
+    runtime.max_blocking_threads(opts.blocking_threads);
+  }
+  let runtime = runtime.build().expect("Failed to build the Tokio runtime");
+  runtime.block_on(serve(opts));
+  // Don't wait for whatever is still running in the blocking pool.
+  runtime.shutdown_background();
+}
+
+/// How long in-flight requests may take to finish after Ctrl-C.
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Ctrl-C, or SIGTERM as sent by `docker stop`.
+async fn shutdown_signal() {
+  #[cfg(unix)]
+  {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+      .expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+  }
+  #[cfg(not(unix))]
+  let _ = tokio::signal::ctrl_c().await;
+}
+
+async fn serve(opts: AppOpts) {
   info!(
     "WannaDance: starting daemon, version {}",
     wanna_cdn::my_git_hash()
@@ -77,7 +108,13 @@ async fn main() {
     .await
     .expect("Failed to initialize app service");
 
-  let http = tokio::spawn(wanna_cdn::http::serve_video_http(app.clone()));
+  let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel();
+  let mut http = tokio::spawn(wanna_cdn::http::serve_video_http(app.clone(), shutdown_rx));
+  tokio::spawn(wanna_cdn::cdn::serve_cache_evictor(app.clone()));
+  tokio::spawn(wanna_cdn::cdn::integrity::serve_cache_scanner(app.clone()));
+  tokio::spawn(wanna_cdn::index::watch::serve_index_watcher(
+    app.index.clone(),
+  ));
   let rtsp = match opts.rtsp_listen.is_some() {
     true => tokio::spawn(wanna_cdn::rtsp::serve_rtsp_typewriter(app.clone())),
     false => {
@@ -86,36 +123,35 @@ async fn main() {
     }
   };
   let (l4, l4_enabled) = match (&opts.builtin_sni_listen, &opts.builtin_sni_proxy) {
-    (Some(listen), Some(proxy)) if !proxy.is_empty() && !listen.is_empty() => {
-      let mut proxy_targets = HashMap::new();
-      for target_def in proxy {
-        // api.udon.dance=ud-orig.kiva.moe:443
-        let mut parts = target_def.splitn(2, '=');
-        if let (Some(host), Some(forward_target)) = (parts.next(), parts.next()) {
-          proxy_targets.insert(host.to_string(), forward_target.to_string());
-        }
-      }
-      (
-        tokio::spawn(wanna_cdn::forward::serve_sni_proxy(
-          listen.clone(),
-          proxy_targets,
-        )),
-        true,
-      )
-    }
+    (Some(listen), Some(proxy)) if !proxy.is_empty() && !listen.is_empty() => (
+      tokio::spawn(wanna_cdn::forward::serve_sni_proxy(
+        listen.clone(),
+        app.sni.clone(),
+        wanna_cdn::forward::SniProxyOpts {
+          health_check_interval: std::time::Duration::from_secs(
+            opts.sni_health_check_interval_secs,
+          ),
+          max_connections: opts.sni_max_connections,
+          idle_timeout: std::time::Duration::from_secs(opts.sni_idle_timeout_seconds),
+          rejected_connections: app.metrics.sni_rejected_connections.clone(),
+        },
+      )),
+      true,
+    ),
     _ => {
       info!("No SNI proxy configured");
       (tokio::task::spawn(async { Ok(()) }), false)
     }
   };
 
-  tokio::select! {
+  let interrupted = tokio::select! {
       e = l4, if l4_enabled => {
           match e {
               Ok(Ok(_)) => info!("SNI proxy exited successfully"),
               Ok(Err(e)) => warn!("SNI proxy exited with error: {}", e),
               Err(e) => warn!("SNI proxy exited with error: {}", e),
           }
+          false
       },
       e = rtsp, if opts.rtsp_listen.is_some() => {
           match e {
@@ -123,17 +159,31 @@ async fn main() {
               Ok(Err(e)) => warn!("RTSP exited with error: {}", e),
               Err(e) => warn!("RTSP exited with error: {}", e),
           }
+          false
       },
-      e = http => {
+      e = &mut http => {
           match e {
               Ok(Ok(_)) => info!("Server exited successfully"),
               Ok(Err(e)) => warn!("Server exited with error: {}", e),
               Err(e) => warn!("Server exited with error: {}", e),
           }
+          false
       },
-      _ = tokio::signal::ctrl_c() => {
+      _ = shutdown_signal() => {
           info!("Received Ctrl-C, shutting down...");
+          true
       }
+  };
+
+  if interrupted {
+    let _ = shutdown.send(());
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, http).await {
+      Ok(_) => info!("Server shut down gracefully"),
+      Err(_) => warn!(
+        "Server still busy after {}s, exiting anyway",
+        SHUTDOWN_TIMEOUT.as_secs()
+      ),
+    }
   }
 
   info!("Goodbye!");
@@ -0,0 +1,176 @@
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::{Instant, SystemTime},
+};
+
+use itertools::Either;
+use serde_derive::Serialize;
+use tokio::sync::{watch, Semaphore, SemaphorePermit};
+
+use crate::types::SongId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CompensatorTask {
+  pub song_id: SongId,
+  pub audio_offset: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RunningCompensation {
+  pub song_id: SongId,
+  pub audio_offset: f64,
+  pub started_at_unix: u64,
+  pub elapsed_secs: f64,
+}
+
+/// Running tasks, with an id to remove them by and when they started.
+type RunningTasks = Arc<Mutex<Vec<(u64, CompensatorTask, SystemTime, Instant)>>>;
+
+/// Limits how many audio compensations run at once, and keeps track of them.
+#[derive(Debug)]
+pub struct CompensatorServiceImpl {
+  workers: Semaphore,
+  next_id: AtomicU64,
+  running_tasks: RunningTasks,
+  /// Compensations in flight by output file, so concurrent requests for the
+  /// same one wait for the first instead of writing the same files.
+  compensating: Mutex<HashMap<String, watch::Receiver<()>>>,
+}
+
+pub type CompensatorService = Arc<CompensatorServiceImpl>;
+
+impl CompensatorServiceImpl {
+  pub fn new(max_workers: usize) -> CompensatorService {
+    Arc::new(CompensatorServiceImpl {
+      workers: Semaphore::new(max_workers),
+      next_id: Default::default(),
+      running_tasks: Default::default(),
+      compensating: Default::default(),
+    })
+  }
+
+  /// Waits for a free worker, which is taken until the permit is dropped.
+  pub async fn acquire(&self) -> SemaphorePermit<'_> {
+    // The semaphore is never closed.
+    self.workers.acquire().await.unwrap()
+  }
+
+  /// Registers a compensation writing `output`. Returns `Left(sender)` if the
+  /// caller should run it, or `Right(receiver)` which is notified when the
+  /// one already in flight is done or abandoned.
+  pub fn begin(&self, output: &str) -> Either<watch::Sender<()>, watch::Receiver<()>> {
+    let mut compensating = self.compensating.lock().unwrap();
+    // Forget compensations whose sender is gone, they were either finished
+    // or abandoned.
+    compensating.retain(|_, rx| rx.has_changed().is_ok());
+    if let Some(rx) = compensating.get(output) {
+      return Either::Right(rx.clone());
+    }
+    let (tx, rx) = watch::channel(());
+    compensating.insert(output.to_string(), rx);
+    Either::Left(tx)
+  }
+
+  /// Lists `task` as running until the returned guard is dropped.
+  pub fn track(&self, task: CompensatorTask) -> CompensatorTaskGuard {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    self
+      .running_tasks
+      .lock()
+      .unwrap()
+      .push((id, task, SystemTime::now(), Instant::now()));
+    CompensatorTaskGuard {
+      id,
+      running_tasks: self.running_tasks.clone(),
+    }
+  }
+
+  pub fn running(&self) -> Vec<RunningCompensation> {
+    self
+      .running_tasks
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(_, task, started_at, start)| RunningCompensation {
+        song_id: task.song_id,
+        audio_offset: task.audio_offset,
+        started_at_unix: started_at
+          .duration_since(SystemTime::UNIX_EPOCH)
+          .unwrap_or_default()
+          .as_secs(),
+        elapsed_secs: start.elapsed().as_secs_f64(),
+      })
+      .collect()
+  }
+}
+
+pub struct CompensatorTaskGuard {
+  id: u64,
+  running_tasks: RunningTasks,
+}
+
+impl Drop for CompensatorTaskGuard {
+  fn drop(&mut self) {
+    self
+      .running_tasks
+      .lock()
+      .unwrap()
+      .retain(|(id, ..)| *id != self.id);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn tasks_are_listed_while_running() {
+    let compensator = CompensatorServiceImpl::new(2);
+    let task = |song_id| CompensatorTask {
+      song_id,
+      audio_offset: 0.5,
+    };
+    let first = compensator.track(task(1));
+    let second = compensator.track(task(2));
+    drop(first);
+    let running = compensator.running();
+    assert_eq!(running.len(), 1);
+    assert_eq!(running[0].song_id, 2);
+    drop(second);
+    assert!(compensator.running().is_empty());
+  }
+
+  #[tokio::test]
+  async fn workers_are_limited() {
+    let compensator = CompensatorServiceImpl::new(2);
+    let _first = compensator.acquire().await;
+    let second = compensator.acquire().await;
+    let third = compensator.acquire();
+    tokio::pin!(third);
+    assert!(futures::poll!(&mut third).is_pending());
+    drop(second);
+    assert!(futures::poll!(&mut third).is_ready());
+  }
+
+  #[tokio::test]
+  async fn same_output_is_compensated_once() {
+    let compensator = CompensatorServiceImpl::new(2);
+    let Either::Left(first) = compensator.begin("1-abc-audio-offset-0.5.mp4") else {
+      panic!("nothing should be running yet");
+    };
+    let Either::Right(mut waiting) = compensator.begin("1-abc-audio-offset-0.5.mp4") else {
+      panic!("the first compensation is still running");
+    };
+    assert!(compensator.begin("1-abc-audio-offset-1.mp4").is_left());
+    let done = waiting.changed();
+    tokio::pin!(done);
+    assert!(futures::poll!(&mut done).is_pending());
+    drop(first);
+    assert!(futures::poll!(&mut done).is_ready());
+    assert!(compensator.begin("1-abc-audio-offset-0.5.mp4").is_left());
+  }
+}
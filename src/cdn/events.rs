@@ -0,0 +1,49 @@
+use serde_derive::Serialize;
+use tokio::sync::broadcast;
+
+use crate::types::SongId;
+
+/// Events kept for subscribers that fall behind, older ones are dropped.
+const CACHE_EVENT_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CacheEvent {
+  Hit {
+    id: SongId,
+  },
+  /// `redirected_to` is `None` when the song is proxied from the upstream.
+  Miss {
+    id: SongId,
+    redirected_to: Option<String>,
+  },
+}
+
+/// Cache hits and misses as they are served, for dashboards.
+#[derive(Debug, Clone)]
+pub struct CacheEventBus {
+  sender: broadcast::Sender<CacheEvent>,
+}
+
+impl CacheEventBus {
+  pub fn new() -> Self {
+    Self {
+      sender: broadcast::channel(CACHE_EVENT_CAPACITY).0,
+    }
+  }
+
+  pub fn emit(&self, event: CacheEvent) {
+    // Nobody is watching most of the time.
+    let _ = self.sender.send(event);
+  }
+
+  pub fn subscribe(&self) -> broadcast::Receiver<CacheEvent> {
+    self.sender.subscribe()
+  }
+}
+
+impl Default for CacheEventBus {
+  fn default() -> Self {
+    Self::new()
+  }
+}
@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+use log::{info, warn};
+use serde_derive::Serialize;
+
+use crate::{
+  cdn::{md5_file, CdnServiceImpl},
+  types::SongId,
+  AppService, Result,
+};
+
+/// Pause between two songs, so a scan doesn't saturate the disk while we are
+/// serving videos.
+const SCAN_THROTTLE: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SongIntegrity {
+  Ok,
+  /// `metadata.json` is missing or broken, or the checksum doesn't match.
+  Corrupt,
+  /// `video.mp4` is missing.
+  Missing,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IntegrityReport {
+  pub scanned_at: Option<i64>,
+  pub ok: usize,
+  pub corrupt: usize,
+  pub missing: usize,
+  pub bad: Vec<BadSong>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BadSong {
+  pub id: SongId,
+  pub state: SongIntegrity,
+}
+
+impl IntegrityReport {
+  fn count(&mut self, state: SongIntegrity, n: isize) {
+    let counter = match state {
+      SongIntegrity::Ok => &mut self.ok,
+      SongIntegrity::Corrupt => &mut self.corrupt,
+      SongIntegrity::Missing => &mut self.missing,
+    };
+    *counter = counter.saturating_add_signed(n);
+  }
+}
+
+impl CdnServiceImpl {
+  pub async fn check_song(&self, id: SongId) -> SongIntegrity {
+    let (video, metadata_json, _) = self.get_video_file_path(id).await;
+    let metadata = match std::fs::File::open(&metadata_json)
+      .map_err(anyhow::Error::from)
+      .and_then(|f| Ok(serde_json::from_reader::<_, aya_dance_types::Song>(f)?))
+    {
+      Ok(metadata) => metadata,
+      Err(e) => {
+        warn!("Cache {} has bad metadata {}: {}", id, metadata_json, e);
+        return SongIntegrity::Corrupt;
+      }
+    };
+    if !std::path::Path::new(&video).exists() {
+      warn!("Cache {} has no video {}", id, video);
+      return SongIntegrity::Missing;
+    }
+    // Songs put here by hand may have no checksum, nothing to check then.
+    let Some(expected) = metadata.checksum else {
+      return SongIntegrity::Ok;
+    };
+    match md5_file(&video).await {
+      Ok(md5) if md5 == expected => SongIntegrity::Ok,
+      Ok(md5) => {
+        warn!(
+          "Cache {} is corrupt: expected checksum {}, got {}",
+          id, expected, md5
+        );
+        SongIntegrity::Corrupt
+      }
+      Err(e) => {
+        warn!("Failed to checksum cache file {}: {}", video, e);
+        SongIntegrity::Corrupt
+      }
+    }
+  }
+
+  /// Checks every song in `video_path` and keeps the result as the latest
+  /// integrity report.
+  pub async fn scan_integrity(&self) -> Result<IntegrityReport> {
+    let mut report = IntegrityReport {
+      scanned_at: Some(chrono::Utc::now().timestamp()),
+      ..Default::default()
+    };
+    let mut cursor = tokio::fs::read_dir(&self.video_path).await?;
+    while let Some(entry) = cursor.next_entry().await? {
+      let Some(id) = entry
+        .file_name()
+        .to_str()
+        .and_then(|x| x.parse::<SongId>().ok())
+      else {
+        continue;
+      };
+      let state = self.check_song(id).await;
+      report.count(state, 1);
+      if state != SongIntegrity::Ok {
+        report.bad.push(BadSong { id, state });
+      }
+      tokio::time::sleep(SCAN_THROTTLE).await;
+    }
+    report.bad.sort_by_key(|x| x.id);
+    info!(
+      "Cache integrity: {} ok, {} corrupt, {} missing",
+      report.ok, report.corrupt, report.missing
+    );
+    *self.integrity.write().await = report.clone();
+    Ok(report)
+  }
+
+  pub async fn integrity_report(&self) -> IntegrityReport {
+    self.integrity.read().await.clone()
+  }
+
+  /// Deletes the bad songs of the latest report that are still bad, so the
+  /// next request downloads them again.
+  pub async fn fix_integrity(&self) -> IntegrityReport {
+    let mut report = self.integrity.write().await;
+    for bad in std::mem::take(&mut report.bad) {
+      // It may have been downloaded again since the scan.
+      if self.check_song(bad.id).await == SongIntegrity::Ok {
+        report.count(bad.state, -1);
+        report.count(SongIntegrity::Ok, 1);
+        continue;
+      }
+      match self.remove_song(bad.id).await {
+        Ok(_) => {
+          info!("Removed bad cache {}", bad.id);
+          report.count(bad.state, -1);
+        }
+        Err(e) => {
+          warn!("Failed to remove bad cache {}: {}", bad.id, e);
+          report.bad.push(bad);
+        }
+      }
+    }
+    report.clone()
+  }
+}
+
+pub async fn serve_cache_scanner(app: AppService) -> Result<()> {
+  let interval = Duration::from_secs(app.opts.cache_scan_interval_seconds);
+  loop {
+    if let Err(e) = app.cdn.scan_integrity().await {
+      warn!("Failed to scan cache integrity: {:?}", e);
+    }
+    tokio::time::sleep(interval).await;
+  }
+}
@@ -1,32 +1,124 @@
-use std::{net::IpAddr, sync::Arc};
+use std::{
+  collections::HashMap,
+  net::IpAddr,
+  sync::Arc,
+  time::{Duration, Instant, SystemTime},
+};
 
 use anyhow::anyhow;
-use log::trace;
+use itertools::{Either, Itertools};
+use log::{debug, info, trace, warn};
+use serde_derive::Serialize;
+use tokio::sync::{watch, RwLock};
 use uuid::Uuid;
+use warp::http::StatusCode;
 
 use crate::{
-  types::{SongId, UuidString},
-  Result,
+  cdn::integrity::IntegrityReport,
+  types::{timedmap, timedmap::TimedMap, SongId, UuidString},
+  AppService, Result,
 };
 
+pub mod compensator;
+pub mod events;
+pub mod integrity;
+pub mod prewarm;
 pub mod proxy;
 pub mod range;
 pub mod receipt;
+pub mod receipt_store;
+pub mod songs_list;
 
 #[derive(Debug)]
 pub struct CdnServiceImpl {
   pub video_path: String,
   pub cache_path: String,
+  /// Tokens revoked by the admin, they are rejected by `serve_file_auth`
+  /// until the entry expires.
+  revoked_tokens: Arc<TimedMap<CdnFetchToken, ()>>,
+  revoke_expire: Duration,
+  /// Songs being downloaded from upstream, so concurrent cache misses for
+  /// the same song wait for the first download instead of fetching again.
+  downloading: RwLock<HashMap<SongId, watch::Receiver<()>>>,
+  /// Last access time of cached songs, used to evict the least recently
+  /// used songs when the cache grows over `cache_max_bytes`.
+  accessed: Arc<TimedMap<SongId, Instant>>,
+  cache_max_bytes: Option<u64>,
+  /// Checksums of cached videos verified by `serve_local_cache`, keyed by
+  /// song and invalidated when the file's size or mtime changes.
+  verified: Arc<TimedMap<SongId, VerifiedChecksum>>,
+  verify_on_serve: bool,
+  /// Result of the last `scan_integrity`.
+  integrity: RwLock<IntegrityReport>,
+  /// Songs the upstream answered 404 or 410 for, they are not redirected to
+  /// the upstream again until the entry expires.
+  missing: Arc<TimedMap<SongId, StatusCode>>,
+  missing_expire: Duration,
+  /// Checksums from the `metadata.json` of cached songs, so
+  /// `get_video_checksum` doesn't parse it on every request. Dropped with the
+  /// song, and on `serve_local_cache` misses as the song may be downloaded
+  /// again.
+  checksums: RwLock<HashMap<SongId, String>>,
+  /// Songs ready to be served, see `cached_song_ids`.
+  cached_ids: Arc<RwLock<CachedSongIds>>,
+}
+
+#[derive(Debug, Default)]
+struct CachedSongIds {
+  ids: Vec<SongId>,
+  scanned_at: Option<Instant>,
+  scanning: bool,
 }
 
+/// How long `cached_song_ids` answers from its last scan before rescanning.
+const CACHED_SONG_IDS_EXPIRE: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+struct VerifiedChecksum {
+  size: u64,
+  modified: SystemTime,
+  md5: String,
+}
+
+/// Songs not accessed for this long are forgotten by the access tracker and
+/// are evicted first, ordered by their modification time.
+const ACCESS_RECORD_EXPIRE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
 pub type CdnService = Arc<CdnServiceImpl>;
 pub type CdnFetchToken = UuidString;
 
 impl CdnServiceImpl {
-  pub fn new(video_path: String, cache_path: String) -> CdnService {
+  pub fn new(
+    video_path: String,
+    cache_path: String,
+    revoke_expire: Duration,
+    cache_max_bytes: Option<u64>,
+    verify_on_serve: bool,
+    missing_expire: Duration,
+  ) -> CdnService {
+    let revoked_tokens = Arc::new(TimedMap::new());
+    let _canceller = timedmap::tokio_cleaner(revoked_tokens.clone(), Duration::from_secs(60));
+    let accessed = Arc::new(TimedMap::new());
+    let _canceller = timedmap::tokio_cleaner(accessed.clone(), Duration::from_secs(60 * 60));
+    let verified = Arc::new(TimedMap::new());
+    let _canceller = timedmap::tokio_cleaner(verified.clone(), Duration::from_secs(60 * 60));
+    let missing = Arc::new(TimedMap::new());
+    let _canceller = timedmap::tokio_cleaner(missing.clone(), Duration::from_secs(60));
     Arc::new(CdnServiceImpl {
       video_path,
       cache_path,
+      revoked_tokens,
+      revoke_expire,
+      downloading: Default::default(),
+      accessed,
+      cache_max_bytes,
+      verified,
+      verify_on_serve,
+      integrity: Default::default(),
+      missing,
+      missing_expire,
+      checksums: Default::default(),
+      cached_ids: Default::default(),
     })
   }
 }
@@ -35,6 +127,8 @@ impl CdnServiceImpl {
 pub enum CdnFetchResult {
   Hit(CdnFetchToken),
   Miss,
+  /// The upstream doesn't have it either, with what it answered.
+  Missing(StatusCode),
 }
 
 impl CdnServiceImpl {
@@ -46,21 +140,53 @@ impl CdnServiceImpl {
     (video_mp4, metadata_json, available)
   }
 
+  pub async fn get_video_metadata(&self, id: SongId) -> Option<aya_dance_types::Song> {
+    let (_, metadata_json, available) = self.get_video_file_path(id).await;
+    if !available {
+      return None;
+    }
+    let reader = std::fs::File::open(metadata_json).ok()?;
+    serde_json::from_reader(reader).ok()
+  }
+
+  /// Whether each of `ids` is cached, in the same order.
+  pub async fn batch_exists(&self, ids: &[SongId]) -> Vec<(SongId, bool)> {
+    futures::future::join_all(ids.iter().map(|&id| async move {
+      let (_, _, available) = self.get_video_file_path(id).await;
+      (id, available)
+    }))
+    .await
+  }
+
+  /// The checksum recorded in the metadata of a cached song, if any.
+  pub async fn get_video_checksum(&self, id: SongId) -> Option<String> {
+    if let Some(checksum) = self.checksums.read().await.get(&id) {
+      return Some(checksum.clone());
+    }
+    let checksum = self.get_video_metadata(id).await?.checksum?;
+    self.checksums.write().await.insert(id, checksum.clone());
+    Some(checksum)
+  }
+
   pub async fn serve_file(
     &self,
     id: Option<SongId>,
     token: Option<String>,
     remote: IpAddr,
   ) -> Result<Option<String>> {
-    match token {
-      Some(token) => self.serve_file_auth(id, token, remote).await,
+    let video = match token {
+      Some(token) => self.serve_file_auth(id, token, remote).await?,
       None => {
         let (video, _, avail) = self
           .serve_file_no_auth(id.ok_or_else(|| anyhow!("missing song id"))?)
           .await;
-        Ok(avail.then(|| video))
+        avail.then(|| video)
       }
+    };
+    if let (Some(_), Some(id)) = (&video, id) {
+      self.record_access(id).await;
     }
+    Ok(video)
   }
 
   async fn serve_file_no_auth(&self, id: SongId) -> (String, String, bool) {
@@ -82,6 +208,10 @@ impl CdnServiceImpl {
       None => return Err(anyhow!("wrong token format")),
     };
 
+    if self.revoked_tokens.contains(&token).await {
+      return Err(anyhow!("token revoked"));
+    }
+
     // If provided, the song id must match the one in the token
     match id {
       Some(id) if id != id_in_token => {
@@ -103,12 +233,89 @@ impl CdnServiceImpl {
     let token = token_for_song_id(id);
 
     let (_, _, avail) = self.get_video_file_path(id).await;
-    match avail {
-      true => Ok(CdnFetchResult::Hit(token)),
-      false => Ok(CdnFetchResult::Miss),
+    if avail {
+      return Ok(CdnFetchResult::Hit(token));
+    }
+    match self.missing.get(&id).await {
+      Some(status) => Ok(CdnFetchResult::Missing(status)),
+      None => Ok(CdnFetchResult::Miss),
     }
   }
 
+  /// Remembers what the upstream answered for song `id`: songs it doesn't
+  /// have are not redirected to it for a while, the others are forgotten.
+  pub async fn record_upstream_status(&self, id: SongId, status: StatusCode) {
+    match status {
+      StatusCode::NOT_FOUND | StatusCode::GONE => {
+        if !self.missing.contains(&id).await {
+          info!("Song {} is missing upstream ({})", id, status);
+        }
+        self.missing.insert(id, status, self.missing_expire).await;
+      }
+      x if x.is_success() => {
+        self.missing.remove(&id).await;
+      }
+      _ => {}
+    }
+  }
+
+  /// Songs the upstream recently said it doesn't have, with the status it
+  /// answered.
+  pub async fn missing_upstream(&self) -> HashMap<SongId, u16> {
+    self
+      .missing
+      .snapshot::<Vec<_>>()
+      .await
+      .into_iter()
+      .map(|(id, status)| (id, status.as_u16()))
+      .collect()
+  }
+
+  /// Revokes a token handed out by `serve_token`. Returns `false` if the
+  /// token is malformed.
+  pub async fn revoke_token(&self, token: CdnFetchToken) -> bool {
+    if song_id_for_token(&token).is_none() {
+      return false;
+    }
+    self
+      .revoked_tokens
+      .insert(token, (), self.revoke_expire)
+      .await;
+    true
+  }
+
+  /// Registers an upstream download of `id`. Returns `Left(sender)` if the
+  /// caller should perform the download, or `Right(receiver)` which is
+  /// notified when the download already in flight is published or abandoned.
+  pub async fn begin_download(&self, id: SongId) -> Either<watch::Sender<()>, watch::Receiver<()>> {
+    let mut downloading = self.downloading.write().await;
+    // Forget downloads whose sender is gone, they were either finished or
+    // abandoned.
+    downloading.retain(|_, rx| rx.has_changed().is_ok());
+    if let Some(rx) = downloading.get(&id) {
+      return Either::Right(rx.clone());
+    }
+    let (tx, rx) = watch::channel(());
+    downloading.insert(id, rx);
+    Either::Left(tx)
+  }
+
+  /// Number of upstream downloads still in flight.
+  pub async fn active_downloads(&self) -> usize {
+    self
+      .downloading
+      .read()
+      .await
+      .values()
+      .filter(|rx| rx.has_changed().is_ok())
+      .count()
+  }
+
+  /// Where an interrupted download of upstream `file` is kept for resuming.
+  pub fn partial_download_path(&self, file: &str) -> String {
+    format!("{}/{}.partial", self.cache_path, file)
+  }
+
   pub async fn serve_local_cache(
     &self,
     id: SongId,
@@ -117,7 +324,29 @@ impl CdnServiceImpl {
     size: u64,
     remote: std::net::SocketAddr,
   ) -> (String, String, String, bool) {
-    let download_tmp_file = format!("{}/{}_{}", self.cache_path, remote.port(), file);
+    let result = self.check_local_cache(id, file, md5, size, remote).await;
+    if !result.3 {
+      self.checksums.write().await.remove(&id);
+    }
+    result
+  }
+
+  async fn check_local_cache(
+    &self,
+    id: SongId,
+    file: String,
+    md5: String,
+    size: u64,
+    remote: std::net::SocketAddr,
+  ) -> (String, String, String, bool) {
+    // Unique per attempt, so a failed download can't clobber a concurrent one.
+    let download_tmp_file = format!(
+      "{}/{}_{}_{}",
+      self.cache_path,
+      remote.port(),
+      Uuid::new_v4().simple(),
+      file
+    );
     let (video, metadata_json, avail) = self.get_video_file_path(id).await;
     if !avail {
       return (download_tmp_file, video, metadata_json, false);
@@ -140,10 +369,497 @@ impl CdnServiceImpl {
       }
     };
     match x.checksum {
-      Some(x) if x == md5 => (download_tmp_file, video, metadata_json, true),
+      Some(x) if x == md5 => {
+        if self.verify_on_serve
+          && !self
+            .verify_cached_video(id, &video, &metadata_json, &x)
+            .await
+        {
+          return (download_tmp_file, video, metadata_json, false);
+        }
+        self.record_access(id).await;
+        (download_tmp_file, video, metadata_json, true)
+      }
       _ => (download_tmp_file, video, metadata_json, false),
     }
   }
+
+  /// Checks that `video_path` is readable and `cache_path` is writable.
+  pub async fn check_health(&self) -> Result<()> {
+    let readable = async {
+      let mut dir = tokio::fs::read_dir(&self.video_path).await?;
+      dir.next_entry().await
+    };
+    readable
+      .await
+      .map_err(|e| anyhow!("video path {} is not readable: {}", self.video_path, e))?;
+    let probe = format!("{}/.healthz-{}", self.cache_path, Uuid::new_v4().simple());
+    let writable = async {
+      tokio::fs::create_dir_all(&self.cache_path).await?;
+      tokio::fs::write(&probe, b"").await?;
+      tokio::fs::remove_file(&probe).await
+    };
+    writable
+      .await
+      .map_err(|e| anyhow!("cache path {} is not writable: {}", self.cache_path, e))?;
+    Ok(())
+  }
+
+  /// Checks the MD5 of a cached video against its metadata, remembering the
+  /// result until the file changes. Corrupt videos are deleted together with
+  /// their metadata, so they get downloaded again.
+  async fn verify_cached_video(
+    &self,
+    id: SongId,
+    video: &str,
+    metadata_json: &str,
+    expected: &str,
+  ) -> bool {
+    let (size, modified) = match tokio::fs::metadata(video).await {
+      Ok(m) => (m.len(), m.modified().unwrap_or(SystemTime::UNIX_EPOCH)),
+      Err(e) => {
+        warn!("Failed to stat cache file {}: {}", video, e);
+        return false;
+      }
+    };
+    let md5 = match self.verified.get(&id).await {
+      Some(v) if v.size == size && v.modified == modified => v.md5,
+      _ => match md5_file(video).await {
+        Ok(md5) => {
+          let verified = VerifiedChecksum {
+            size,
+            modified,
+            md5: md5.clone(),
+          };
+          self
+            .verified
+            .insert(id, verified, ACCESS_RECORD_EXPIRE)
+            .await;
+          md5
+        }
+        Err(e) => {
+          warn!("Failed to checksum cache file {}: {}", video, e);
+          return false;
+        }
+      },
+    };
+    if md5 == expected {
+      return true;
+    }
+    warn!(
+      "Cache {} is corrupt: expected checksum {}, got {}, deleting it",
+      id, expected, md5
+    );
+    self.verified.remove(&id).await;
+    self.checksums.write().await.remove(&id);
+    for file in [video, metadata_json] {
+      if let Err(e) = tokio::fs::remove_file(file).await {
+        warn!("Failed to remove corrupt cache file {}: {}", file, e);
+      }
+    }
+    false
+  }
+
+  pub async fn record_access(&self, id: SongId) {
+    self
+      .accessed
+      .insert(id, Instant::now(), ACCESS_RECORD_EXPIRE)
+      .await;
+  }
+
+  /// Sums up the size of cached songs in `video_path` and of everything in
+  /// `cache_path` (download tmp files and compensated videos).
+  pub async fn cache_usage(&self) -> Result<CacheUsage> {
+    let (used_bytes, songs) = self.scan_cache().await?;
+    Ok(CacheUsage {
+      used_bytes,
+      max_bytes: self.cache_max_bytes,
+      songs: songs.len(),
+    })
+  }
+
+  /// Every song directory under `video_path`, ordered by song id.
+  pub async fn list_cached_videos(&self) -> Result<Vec<CachedVideoInfo>> {
+    let mut videos = vec![];
+    let mut cursor = tokio::fs::read_dir(&self.video_path).await?;
+    while let Some(entry) = cursor.next_entry().await? {
+      let Some(id) = entry
+        .file_name()
+        .to_str()
+        .and_then(|x| x.parse::<SongId>().ok())
+      else {
+        continue;
+      };
+      let (video, metadata_json, _) = self.get_video_file_path(id).await;
+      let (size_bytes, modified) = match tokio::fs::metadata(&video).await {
+        Ok(m) => (m.len(), m.modified().unwrap_or(SystemTime::UNIX_EPOCH)),
+        Err(_) => (0, SystemTime::UNIX_EPOCH),
+      };
+      let metadata = std::fs::File::open(&metadata_json)
+        .ok()
+        .and_then(|x| serde_json::from_reader::<_, aya_dance_types::Song>(x).ok());
+      videos.push(CachedVideoInfo {
+        id,
+        checksum: metadata.as_ref().and_then(|x| x.checksum.clone()),
+        size_bytes,
+        video_file: video,
+        has_metadata: metadata.is_some(),
+        last_modified_unix: modified
+          .duration_since(SystemTime::UNIX_EPOCH)
+          .map(|x| x.as_secs())
+          .unwrap_or(0),
+      });
+    }
+    videos.sort_by_key(|x| x.id);
+    Ok(videos)
+  }
+
+  /// The songs with both a video and a checksum in their metadata, so
+  /// `serve_token` hits. Scanning takes a while, so only the first call waits
+  /// for it: later ones answer from the last scan, refreshing it in the
+  /// background once it is older than `CACHED_SONG_IDS_EXPIRE`.
+  pub async fn cached_song_ids(self: &Arc<Self>) -> Result<Vec<SongId>> {
+    let mut cached = self.cached_ids.write().await;
+    match cached.scanned_at {
+      None => {
+        cached.ids = self.scan_cached_song_ids().await?;
+        cached.scanned_at = Some(Instant::now());
+      }
+      Some(x) if x.elapsed() >= CACHED_SONG_IDS_EXPIRE && !cached.scanning => {
+        cached.scanning = true;
+        let cdn = self.clone();
+        tokio::spawn(async move {
+          let ids = cdn.scan_cached_song_ids().await;
+          let mut cached = cdn.cached_ids.write().await;
+          cached.scanning = false;
+          match ids {
+            Ok(ids) => {
+              cached.ids = ids;
+              cached.scanned_at = Some(Instant::now());
+            }
+            Err(e) => warn!("Failed to scan cached songs: {:?}", e),
+          }
+        });
+      }
+      Some(_) => {}
+    }
+    Ok(cached.ids.clone())
+  }
+
+  async fn scan_cached_song_ids(&self) -> Result<Vec<SongId>> {
+    Ok(
+      self
+        .list_cached_videos()
+        .await?
+        .into_iter()
+        .filter(|x| x.size_bytes > 0 && x.checksum.as_ref().is_some_and(|x| !x.is_empty()))
+        .map(|x| x.id)
+        .collect(),
+    )
+  }
+
+  async fn scan_cache(&self) -> Result<(u64, HashMap<SongId, CachedSong>)> {
+    let mut total_bytes = 0u64;
+    let mut songs = HashMap::new();
+    let mut cursor = tokio::fs::read_dir(&self.video_path).await?;
+    while let Some(entry) = cursor.next_entry().await? {
+      let Some(id) = entry
+        .file_name()
+        .to_str()
+        .and_then(|x| x.parse::<SongId>().ok())
+      else {
+        continue;
+      };
+      let (video, metadata_json, _) = self.get_video_file_path(id).await;
+      let (video_size, modified) = match tokio::fs::metadata(&video).await {
+        Ok(m) => (m.len(), m.modified().unwrap_or(SystemTime::UNIX_EPOCH)),
+        Err(_) => (0, SystemTime::UNIX_EPOCH),
+      };
+      let metadata_size = tokio::fs::metadata(&metadata_json)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0);
+      let size = video_size + metadata_size;
+      total_bytes += size;
+      songs.insert(
+        id,
+        CachedSong {
+          accessed: self.accessed.get(&id).await,
+          modified,
+          size,
+          compensated: vec![],
+        },
+      );
+    }
+
+    // Compensated videos are named `{id}-{md5}-audio-offset-{offset}.mp4`,
+    // ending with `-loudnorm.mp4` when normalized, they belong to the song
+    // and go away with it.
+    if let Ok(mut cursor) = tokio::fs::read_dir(&self.cache_path).await {
+      while let Some(entry) = cursor.next_entry().await? {
+        let size = entry.metadata().await.map(|m| m.len()).unwrap_or(0);
+        total_bytes += size;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let id = name
+          .split('-')
+          .next()
+          .and_then(|x| x.parse::<SongId>().ok());
+        if let Some(song) = id.and_then(|id| songs.get_mut(&id)) {
+          song.size += size;
+          song
+            .compensated
+            .push(entry.path().to_string_lossy().to_string());
+        }
+      }
+    }
+    Ok((total_bytes, songs))
+  }
+
+  /// Evicts the least recently used songs until the total size of the cache
+  /// fits in `cache_max_bytes`. Returns the evicted song ids.
+  pub async fn evict_until_fits(&self) -> Result<Vec<SongId>> {
+    let Some(max_bytes) = self.cache_max_bytes else {
+      return Ok(vec![]);
+    };
+
+    let (mut total_bytes, songs) = self.scan_cache().await?;
+    let used_bytes = total_bytes;
+    let mut evicted = vec![];
+    // Songs we have never seen accessed come first (`None` < `Some`)
+    let songs = songs
+      .into_iter()
+      .sorted_by_key(|(id, song)| (song.accessed, song.modified, *id));
+    for (id, song) in songs {
+      if total_bytes <= max_bytes {
+        break;
+      }
+      if let Err(e) = self.evict_one(id, &song).await {
+        warn!("Failed to evict cache {}: {}", id, e);
+        continue;
+      }
+      debug!("Evicted cache {} ({} bytes)", id, song.size);
+      total_bytes = total_bytes.saturating_sub(song.size);
+      evicted.push(id);
+    }
+    if !evicted.is_empty() {
+      info!(
+        "Evicted {} songs ({} bytes), cache usage {} -> {} bytes (max {} bytes): {:?}",
+        evicted.len(),
+        used_bytes - total_bytes,
+        used_bytes,
+        total_bytes,
+        max_bytes,
+        evicted
+      );
+    }
+    Ok(evicted)
+  }
+
+  /// Removes the audio compensated versions of song `id`, returning how many.
+  pub async fn remove_compensated(&self, id: SongId) -> usize {
+    let prefix = format!("{}-", id);
+    let mut removed = 0;
+    if let Ok(mut cursor) = tokio::fs::read_dir(&self.cache_path).await {
+      while let Ok(Some(entry)) = cursor.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with(&prefix) || !name.contains("-audio-offset-") {
+          continue;
+        }
+        match tokio::fs::remove_file(entry.path()).await {
+          Ok(_) => removed += 1,
+          Err(e) => warn!("Failed to remove compensated file {}: {}", name, e),
+        }
+      }
+    }
+    removed
+  }
+
+  /// Removes song `id` and every file of it in `cache_path`, so it gets
+  /// downloaded again. Returns whether there was anything to remove.
+  pub async fn evict_song(&self, id: SongId) -> Result<bool> {
+    let purged = self.purge_song(id).await;
+    if let Some(file) = purged.iter().find(|x| x.error.is_some()) {
+      return Err(anyhow!(
+        "failed to remove {}: {}",
+        file.path,
+        file.error.as_deref().unwrap_or_default()
+      ));
+    }
+    Ok(purged.iter().any(|x| x.removed))
+  }
+
+  /// Removes song `id`, the files derived from it in `cache_path` and its
+  /// leftover downloads, reporting on each file. Keeps going when some can't
+  /// be removed, they may be served or written right now. Overrides stay.
+  pub async fn purge_song(&self, id: SongId) -> Vec<PurgedFile> {
+    let mut files = vec![];
+    // Moved away as a whole first, like `remove_song` does.
+    let song_dir = format!("{}/{}", self.video_path, id);
+    let removing_dir = format!("{}/.{}.removing", self.video_path, id);
+    match tokio::fs::rename(&song_dir, &removing_dir).await {
+      Ok(_) => {
+        self.accessed.remove(&id).await;
+        self.verified.remove(&id).await;
+        self.checksums.write().await.remove(&id);
+        match tokio::fs::read_dir(&removing_dir).await {
+          Ok(mut cursor) => {
+            while let Ok(Some(entry)) = cursor.next_entry().await {
+              let path = format!("{}/{}", song_dir, entry.file_name().to_string_lossy());
+              files.push(PurgedFile::remove(&entry.path().to_string_lossy(), path).await);
+            }
+          }
+          Err(e) => warn!("Failed to list directory {}: {}", removing_dir, e),
+        }
+        if let Err(e) = tokio::fs::remove_dir(&removing_dir).await {
+          warn!("Failed to remove directory {}: {}", removing_dir, e);
+        }
+      }
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+      Err(e) => files.push(PurgedFile {
+        path: song_dir,
+        removed: false,
+        error: Some(e.to_string()),
+      }),
+    }
+
+    // Compensated videos, waveforms and partial downloads start with the id,
+    // in-flight downloads have a `{port}_{uuid}_` prefix before it.
+    let prefix = format!("{}-", id);
+    let download = format!("_{}-", id);
+    if let Ok(mut cursor) = tokio::fs::read_dir(&self.cache_path).await {
+      while let Ok(Some(entry)) = cursor.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(&prefix) || (name.contains(&download) && name.ends_with(".mp4")) {
+          let path = entry.path().to_string_lossy().to_string();
+          files.push(PurgedFile::remove(&path, path.clone()).await);
+        }
+      }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    files
+  }
+
+  async fn evict_one(&self, id: SongId, song: &CachedSong) -> Result<()> {
+    self.remove_song(id).await?;
+    for file in &song.compensated {
+      if let Err(e) = tokio::fs::remove_file(file).await {
+        warn!("Failed to remove compensated file {}: {}", file, e);
+      }
+    }
+    Ok(())
+  }
+}
+
+impl CdnServiceImpl {
+  async fn remove_song(&self, id: SongId) -> Result<()> {
+    // Move the whole song directory away first, so `video.mp4` and
+    // `metadata.json` disappear together and nobody sees a half-deleted song.
+    let song_dir = format!("{}/{}", self.video_path, id);
+    let removing_dir = format!("{}/.{}.removing", self.video_path, id);
+    tokio::fs::rename(&song_dir, &removing_dir).await?;
+    self.accessed.remove(&id).await;
+    self.verified.remove(&id).await;
+    self.checksums.write().await.remove(&id);
+    if let Err(e) = tokio::fs::remove_dir_all(&removing_dir).await {
+      warn!("Failed to remove directory {}: {}", removing_dir, e);
+    }
+    Ok(())
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PurgedFile {
+  pub path: String,
+  /// `false` if it was gone already.
+  pub removed: bool,
+  pub error: Option<String>,
+}
+
+impl PurgedFile {
+  /// Removes `file`, reported as `path`.
+  async fn remove(file: &str, path: String) -> Self {
+    match tokio::fs::remove_file(file).await {
+      Ok(_) => Self {
+        path,
+        removed: true,
+        error: None,
+      },
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self {
+        path,
+        removed: false,
+        error: None,
+      },
+      Err(e) => {
+        warn!("Failed to remove {}: {}", file, e);
+        Self {
+          path,
+          removed: false,
+          error: Some(e.to_string()),
+        }
+      }
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheUsage {
+  pub used_bytes: u64,
+  pub max_bytes: Option<u64>,
+  pub songs: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CachedVideoInfo {
+  pub id: SongId,
+  /// From `metadata.json`, missing for songs put there by hand.
+  pub checksum: Option<String>,
+  pub size_bytes: u64,
+  pub video_file: String,
+  /// Whether `metadata.json` could be read.
+  pub has_metadata: bool,
+  pub last_modified_unix: u64,
+}
+
+#[derive(Debug)]
+struct CachedSong {
+  accessed: Option<Instant>,
+  modified: SystemTime,
+  size: u64,
+  compensated: Vec<String>,
+}
+
+/// Computes the hex MD5 of a file without reading it into memory at once.
+pub async fn md5_file(path: &str) -> Result<String> {
+  let path = path.to_string();
+  tokio::task::spawn_blocking(move || {
+    use std::io::Read;
+    let mut file = std::fs::File::open(path)?;
+    let mut md5 = md5::Context::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+      match file.read(&mut buf)? {
+        0 => break,
+        n => md5.consume(&buf[..n]),
+      }
+    }
+    Ok(hex::encode(md5.compute().as_slice()))
+  })
+  .await?
+}
+
+/// Periodically evicts the cache when `cache_max_bytes` is set.
+pub async fn serve_cache_evictor(app: AppService) -> Result<()> {
+  if app.opts.cache_max_bytes.is_none() {
+    info!("Cache eviction disabled");
+    return Ok(());
+  }
+  let interval = Duration::from_secs(app.opts.cache_evict_interval_seconds);
+  loop {
+    tokio::time::sleep(interval).await;
+    if let Err(e) = app.cdn.evict_until_fits().await {
+      warn!("Failed to evict cache: {:?}", e);
+    }
+  }
 }
 
 fn token_for_song_id(song_id: SongId) -> String {
@@ -0,0 +1,204 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail};
+use futures::StreamExt;
+use itertools::Either;
+use log::{info, warn};
+use serde_derive::Serialize;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+  cdn::proxy::{proxy_and_inspecting, InspectingOpts},
+  types::{timedmap, timedmap::TimedMap, SongId, UuidString},
+  AppService, Result,
+};
+
+/// How long the progress of a job is kept after its last update.
+const PREWARM_JOB_EXPIRE: Duration = Duration::from_secs(6 * 60 * 60);
+
+pub type PrewarmJobId = UuidString;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PrewarmProgress {
+  pub done: usize,
+  pub failed: usize,
+  pub pending: usize,
+  /// Why the failed songs failed.
+  pub errors: BTreeMap<SongId, String>,
+}
+
+/// Fills the cache with songs in the background, see `start`.
+#[derive(Debug)]
+pub struct PrewarmServiceImpl {
+  jobs: Arc<TimedMap<PrewarmJobId, Arc<Mutex<PrewarmProgress>>>>,
+  concurrency: usize,
+}
+
+pub type PrewarmService = Arc<PrewarmServiceImpl>;
+
+impl PrewarmServiceImpl {
+  pub fn new(concurrency: usize) -> PrewarmService {
+    let jobs = Arc::new(TimedMap::new());
+    let _canceller = timedmap::tokio_cleaner(jobs.clone(), Duration::from_secs(60));
+    Arc::new(PrewarmServiceImpl {
+      jobs,
+      concurrency: concurrency.max(1),
+    })
+  }
+
+  /// Downloads songs `ids` from the upstream, `concurrency` at a time.
+  /// Returns right away with the job to ask the progress of.
+  pub async fn start(&self, app: AppService, mut ids: Vec<SongId>) -> PrewarmJobId {
+    ids.sort();
+    ids.dedup();
+    let job = Uuid::new_v4().to_string();
+    let progress = Arc::new(Mutex::new(PrewarmProgress {
+      pending: ids.len(),
+      ..Default::default()
+    }));
+    self
+      .jobs
+      .insert(job.clone(), progress.clone(), PREWARM_JOB_EXPIRE)
+      .await;
+    info!("Prewarm job {}: fetching {} songs", job, ids.len());
+
+    let jobs = self.jobs.clone();
+    let concurrency = self.concurrency;
+    let running = job.clone();
+    tokio::spawn(async move {
+      futures::stream::iter(ids)
+        .for_each_concurrent(concurrency, |id| {
+          let (app, progress) = (app.clone(), progress.clone());
+          let (jobs, job) = (jobs.clone(), running.clone());
+          async move {
+            let result = prewarm_song(&app, id).await;
+            let mut progress = progress.lock().await;
+            progress.pending -= 1;
+            match result {
+              Ok(_) => progress.done += 1,
+              Err(e) => {
+                warn!("Prewarm job {}: failed to fetch song {}: {:?}", job, id, e);
+                progress.failed += 1;
+                progress.errors.insert(id, e.to_string());
+              }
+            }
+            jobs.refresh(&job, PREWARM_JOB_EXPIRE).await;
+          }
+        })
+        .await;
+      info!("Prewarm job {}: finished", running);
+    });
+    job
+  }
+
+  pub async fn progress(&self, job: &PrewarmJobId) -> Option<PrewarmProgress> {
+    let progress = self.jobs.get(job).await?;
+    let progress = progress.lock().await;
+    Some(progress.clone())
+  }
+}
+
+/// Fetches song `id` into the cache like a client missing it would, unless
+/// it's cached already.
+async fn prewarm_song(app: &AppService, id: SongId) -> Result<()> {
+  if app.cdn.get_video_file_path(id).await.2 {
+    return Ok(());
+  }
+  let path = upstream_file_of(app, id).await?;
+  let file = path
+    .split('?')
+    .next()
+    .and_then(|x| x.rsplit('/').next())
+    .unwrap_or_default()
+    .to_string();
+  let query = reqwest::Url::parse(&format!("http://localhost{}", path))?;
+  let query = query.query_pairs().collect::<BTreeMap<_, _>>();
+  let (Some(etag), Some(expected_size)) = (
+    query.get("e").map(|x| x.to_string()),
+    query.get("s").and_then(|x| x.parse::<u64>().ok()),
+  ) else {
+    bail!("the upstream sent song {} to {}", id, path);
+  };
+
+  let downloading = match app.cdn.begin_download(id).await {
+    Either::Left(downloading) => downloading,
+    Either::Right(mut rx) => {
+      let _ = rx.changed().await;
+      return match app.cdn.get_video_file_path(id).await.2 {
+        true => Ok(()),
+        false => Err(anyhow!("a concurrent download of song {} failed", id)),
+      };
+    }
+  };
+  let (cache_file, metadata_json, _) = app.cdn.get_video_file_path(id).await;
+  let response = proxy_and_inspecting(
+    reqwest::Method::GET,
+    Default::default(),
+    Default::default(),
+    app.proxy_opts(id, app.file_upstreams(None, &path)),
+    Some(InspectingOpts {
+      id,
+      download_tmp: format!(
+        "{}/prewarm_{}_{}",
+        app.cdn.cache_path,
+        Uuid::new_v4().simple(),
+        file
+      ),
+      cache_file,
+      metadata_json,
+      etag,
+      expected_size,
+      downloading,
+      partial_file: app.cdn.partial_download_path(&file),
+      cdn: app.cdn.clone(),
+    }),
+  )
+  .await
+  .map_err(|e| anyhow!("failed to fetch {}: {:?}", path, e))?;
+  app.cdn.record_upstream_status(id, response.status()).await;
+  if !response.status().is_success() {
+    bail!("the upstream answered {} for {}", response.status(), path);
+  }
+  // Cached as it's read, see `InspectingOpts`.
+  let mut body = response.into_body();
+  while let Some(chunk) = body.next().await {
+    chunk?;
+  }
+  match app.cdn.get_video_file_path(id).await.2 {
+    true => Ok(()),
+    false => Err(anyhow!("{} was fetched but not cached", path)),
+  }
+}
+
+/// Asks the upstream API where song `id` is, like players do. Returns the
+/// path and query of its file on the upstream CDN.
+async fn upstream_file_of(app: &AppService, id: SongId) -> Result<String> {
+  let url = format!(
+    "{}/Api/Songs/play?id={}",
+    app.opts.cache_upstream_ud_api.trim_end_matches('/'),
+    id
+  );
+  // Redirects are not followed, see `default_reqwest_client`.
+  let response = app.proxy_client.get(&url).send().await?;
+  let location = response
+    .headers()
+    .get(reqwest::header::LOCATION)
+    .and_then(|x| x.to_str().ok())
+    .filter(|_| response.status().is_redirection());
+  let Some(location) = location else {
+    // The API and the CDN come from different `http` versions.
+    if let Ok(status) = warp::http::StatusCode::from_u16(response.status().as_u16()) {
+      app.cdn.record_upstream_status(id, status).await;
+    }
+    bail!("the upstream answered {} for {}", response.status(), url);
+  };
+  let location = reqwest::Url::parse(location)?;
+  if !location.path().starts_with("/files/") {
+    bail!("the upstream sent song {} to {}", id, location);
+  }
+  Ok(match location.query() {
+    Some(query) => format!("{}?{}", location.path(), query),
+    None => location.path().to_string(),
+  })
+}
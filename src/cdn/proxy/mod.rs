@@ -1,20 +1,26 @@
 pub mod errors;
 
-use std::str::FromStr;
+use std::{str::FromStr, sync::Arc, time::Duration};
 
 use aya_dance_types::SongId;
 use futures::{Stream, StreamExt};
 use log::trace;
-use once_cell::sync::OnceCell;
 use reqwest::redirect::Policy;
-use tokio::{fs::File, io::AsyncWriteExt};
+use tokio::{
+  fs::File,
+  io::{AsyncReadExt, AsyncWriteExt},
+  sync::watch,
+};
 use warp::{
   filters::path::FullPath,
   hyper::{body::Bytes, Body},
   Rejection,
 };
 
-pub static CLIENT: OnceCell<reqwest::Client> = OnceCell::new();
+use crate::{
+  cdn::CdnService,
+  types::timedmap::{self, TimedMap},
+};
 
 pub type Uri = FullPath;
 pub type QueryParameters = Option<String>;
@@ -27,31 +33,185 @@ pub struct InspectingOpts {
   pub metadata_json: String,
   pub etag: String,
   pub expected_size: u64,
+  /// Notifies clients waiting for the same song once the download is
+  /// published (or abandoned, when dropped).
+  pub downloading: watch::Sender<()>,
+  /// Where an interrupted download is kept, so the next attempt can resume it
+  /// with a Range request instead of starting over.
+  pub partial_file: String,
+  pub cdn: CdnService,
 }
 
 pub struct ProxyOpts {
-  pub host_override: Option<String>,
+  pub client: reqwest::Client,
+  /// Logged with failovers.
+  pub id: SongId,
+  /// Tried in turn while one can't be reached or answers with a 5xx, those
+  /// that failed recently last.
+  pub upstreams: Vec<Upstream>,
+  pub failures: Arc<UpstreamFailures>,
   pub user_agent_override: Option<String>,
   pub allow_304: bool,
+  /// How many more times a GET or HEAD is sent when it fails before any
+  /// response, waiting twice as long before each attempt.
+  pub retries: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Upstream {
+  pub uri: String,
+  pub host_override: Option<String>,
+}
+
+impl Upstream {
+  /// Its scheme, host and port, as remembered by `UpstreamFailures`.
+  pub fn name(&self) -> String {
+    reqwest::Url::parse(&self.uri)
+      .map(|x| x.origin().ascii_serialization())
+      .unwrap_or_else(|_| self.uri.clone())
+  }
+}
+
+/// Upstreams that failed recently, by `Upstream::name`.
+#[derive(Debug)]
+pub struct UpstreamFailures {
+  failed: Arc<TimedMap<String, ()>>,
+  cooldown: Duration,
 }
 
+impl UpstreamFailures {
+  pub fn new(cooldown: Duration) -> Self {
+    let failed = Arc::new(TimedMap::new());
+    let _canceller = timedmap::tokio_cleaner(failed.clone(), Duration::from_secs(60));
+    Self { failed, cooldown }
+  }
+
+  async fn mark(&self, upstream: &Upstream) {
+    self.failed.insert(upstream.name(), (), self.cooldown).await;
+  }
+
+  async fn has_failed(&self, upstream: &Upstream) -> bool {
+    self.failed.contains(&upstream.name()).await
+  }
+}
+
+/// Wait before the first retry, doubled for each one after.
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
 pub async fn proxy_and_inspecting(
-  proxy_uri: String,
   method: reqwest::Method,
   headers: warp::http::HeaderMap,
   body: Bytes,
   proxy_opts: ProxyOpts,
   dump_opts: Option<InspectingOpts>,
 ) -> Result<warp::http::Response<Body>, Rejection> {
+  // Only resume when the client wants the whole file, we can't serve both
+  // its range and ours.
+  let resume_from = match &dump_opts {
+    Some(opts) if !headers.contains_key(warp::http::header::RANGE) => prepare_resume(opts).await,
+    _ => 0,
+  };
+  let retries = match method {
+    reqwest::Method::GET | reqwest::Method::HEAD => proxy_opts.retries,
+    _ => 0,
+  };
+  let mut upstreams = vec![];
+  let mut failed = vec![];
+  for upstream in &proxy_opts.upstreams {
+    match proxy_opts.failures.has_failed(upstream).await {
+      true => failed.push(upstream),
+      false => upstreams.push(upstream),
+    }
+  }
+  if let (Some(first), Some(preferred)) = (failed.first(), upstreams.first()) {
+    log::info!(
+      "Song {}: upstream {} failed recently, trying {} first",
+      proxy_opts.id,
+      first.name(),
+      preferred.name()
+    );
+  }
+  upstreams.extend(failed);
+
+  let mut error = None;
+  for (i, upstream) in upstreams.iter().enumerate() {
+    let last = i + 1 == upstreams.len();
+    let hdr = upstream_headers(&headers, upstream, &proxy_opts, resume_from);
+    let request = proxy_opts
+      .client
+      .request(method.clone(), upstream.uri.as_str())
+      .headers(hdr)
+      .body(body.clone())
+      .build()
+      .map_err(errors::Error::Request)
+      .map_err(warp::reject::custom)?;
+    trace!(">>>>> Request: {:#?}", request);
+    match execute_with_retries(&proxy_opts.client, request, retries).await {
+      Ok(response) if response.status().is_server_error() => {
+        proxy_opts.failures.mark(upstream).await;
+        if !last {
+          log::info!(
+            "Song {}: upstream {} answered {}, failing over",
+            proxy_opts.id,
+            upstream.name(),
+            response.status()
+          );
+          continue;
+        }
+        return reply(response, dump_opts, resume_from).await;
+      }
+      Ok(response) => return reply(response, dump_opts, resume_from).await,
+      Err(e) => {
+        proxy_opts.failures.mark(upstream).await;
+        if !last {
+          log::info!(
+            "Song {}: upstream {} failed, failing over: {}",
+            proxy_opts.id,
+            upstream.name(),
+            e
+          );
+        }
+        error = Some(e);
+      }
+    }
+  }
+
+  if let Some(opts) = dump_opts.as_ref().filter(|_| resume_from > 0) {
+    // Keep what we had for the next attempt.
+    let _ = tokio::fs::rename(&opts.download_tmp, &opts.partial_file).await;
+  }
+  Err(warp::reject::custom(match error {
+    Some(e) => errors::Error::Request(e),
+    None => errors::Error::String("No upstream to proxy to".to_string()),
+  }))
+}
+
+async fn reply(
+  response: reqwest::Response,
+  dump_opts: Option<InspectingOpts>,
+  resume_from: u64,
+) -> Result<warp::http::Response<Body>, Rejection> {
+  trace!("<<<<< Response: {:#?}", response);
+  response_to_reply(response, dump_opts, resume_from)
+    .await
+    .map_err(warp::reject::custom)
+}
+
+/// The client's `headers` as they are sent to `upstream`.
+fn upstream_headers(
+  headers: &warp::http::HeaderMap,
+  upstream: &Upstream,
+  proxy_opts: &ProxyOpts,
+  resume_from: u64,
+) -> reqwest::header::HeaderMap {
   let mut hdr = reqwest::header::HeaderMap::new();
   for (k, v) in headers.iter() {
     let ks = k.as_str();
     match ks.to_lowercase().as_str() {
-      "host" if proxy_opts.host_override.is_some() => {
+      "host" if upstream.host_override.is_some() => {
         hdr.insert(
           reqwest::header::HOST,
-          reqwest::header::HeaderValue::from_str(proxy_opts.host_override.as_ref().unwrap())
-            .unwrap(),
+          reqwest::header::HeaderValue::from_str(upstream.host_override.as_ref().unwrap()).unwrap(),
         );
       }
       "user-agent" if proxy_opts.user_agent_override.is_some() => {
@@ -78,34 +238,147 @@ pub async fn proxy_and_inspecting(
       }
     }
   }
-  let request = CLIENT
-    .get_or_init(default_reqwest_client)
-    .request(method, proxy_uri)
-    .headers(hdr)
-    .body(body)
-    .build()
-    .map_err(errors::Error::Request)
-    .map_err(warp::reject::custom)?;
-  trace!(">>>>> Request: {:#?}", request);
-  let response = CLIENT
-    .get_or_init(default_reqwest_client)
-    .execute(request)
-    .await
-    .map_err(errors::Error::Request)
-    .map_err(warp::reject::custom)?;
-  trace!("<<<<< Response: {:#?}", response);
-  response_to_reply(response, dump_opts)
-    .await
-    .map_err(warp::reject::custom)
+  if resume_from > 0 {
+    hdr.insert(
+      reqwest::header::RANGE,
+      reqwest::header::HeaderValue::from_str(&format!("bytes={}-", resume_from)).unwrap(),
+    );
+  }
+  hdr
+}
+
+/// Sends `request`, again up to `retries` times if it fails before there is
+/// any response. Nothing was streamed to the client by then, so it is safe.
+async fn execute_with_retries(
+  client: &reqwest::Client,
+  mut request: reqwest::Request,
+  retries: u32,
+) -> Result<reqwest::Response, reqwest::Error> {
+  let start = std::time::Instant::now();
+  let mut attempt = 0;
+  loop {
+    // Bodies we send are all in memory, so it can always be cloned.
+    let retry = match attempt < retries {
+      true => request.try_clone(),
+      false => None,
+    };
+    match client.execute(request).await {
+      Ok(response) => return Ok(response),
+      Err(e) => match retry {
+        Some(retry) => {
+          attempt += 1;
+          log::warn!(
+            "Upstream request to {} failed, retry {}/{} after {:.2}s: {}",
+            retry.url(),
+            attempt,
+            retries,
+            start.elapsed().as_secs_f64(),
+            e
+          );
+          tokio::time::sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+          request = retry;
+        }
+        None => return Err(e),
+      },
+    }
+  }
+}
+
+const UPSTREAM_PROXY_SCHEMES: [&str; 4] = ["http", "https", "socks5", "socks5h"];
+
+/// The proxy at `url` for every upstream request, but those to `bypass`
+/// hosts. Fails for URLs reqwest can't use, so they're caught at startup.
+pub fn upstream_proxy(url: &str, bypass: &[String]) -> anyhow::Result<reqwest::Proxy> {
+  // reqwest takes any URL here and only fails on first use.
+  let parsed =
+    reqwest::Url::parse(url).map_err(|e| anyhow::anyhow!("Bad upstream proxy {}: {}", url, e))?;
+  if !UPSTREAM_PROXY_SCHEMES.contains(&parsed.scheme()) || parsed.host_str().is_none() {
+    return Err(anyhow::anyhow!(
+      "Bad upstream proxy {}: expected {}://host:port",
+      url,
+      UPSTREAM_PROXY_SCHEMES.join("|")
+    ));
+  }
+  let proxy =
+    reqwest::Proxy::all(url).map_err(|e| anyhow::anyhow!("Bad upstream proxy {}: {}", url, e))?;
+  Ok(proxy.no_proxy(reqwest::NoProxy::from_string(&bypass.join(","))))
+}
+
+/// Moves a previously interrupted download into `download_tmp` and returns
+/// how many bytes of it we already have.
+async fn prepare_resume(opts: &InspectingOpts) -> u64 {
+  let len = match tokio::fs::metadata(&opts.partial_file).await {
+    Ok(m) => m.len(),
+    Err(_) => return 0,
+  };
+  if len == 0 || len >= opts.expected_size {
+    // Nothing to resume, or it is somehow larger than the whole file.
+    let _ = tokio::fs::remove_file(&opts.partial_file).await;
+    return 0;
+  }
+  match tokio::fs::rename(&opts.partial_file, &opts.download_tmp).await {
+    Ok(_) => {
+      log::info!(
+        "Resuming download of {} from {}/{} bytes",
+        opts.cache_file,
+        len,
+        opts.expected_size
+      );
+      len
+    }
+    Err(e) => {
+      log::warn!(
+        "Failed to resume partial download {}: {}",
+        opts.partial_file,
+        e
+      );
+      0
+    }
+  }
 }
 
 /// Converts a reqwest response into a http::Response
 async fn response_to_reply(
   response: reqwest::Response,
   dump_opts: Option<InspectingOpts>,
+  resume_from: u64,
 ) -> Result<warp::http::Response<Body>, errors::Error> {
+  let mut status = response.status();
+  // The client asked for the whole file, so a resumed download is answered
+  // with our partial file followed by the rest from the upstream.
+  let resumed = resume_from > 0 && status == reqwest::StatusCode::PARTIAL_CONTENT;
+  // Anything else is not the file we expect, error pages included.
+  let expected_length = dump_opts.as_ref().map(|opts| match resumed {
+    true => opts.expected_size - resume_from,
+    false => opts.expected_size,
+  });
+  let dump_opts = match dump_opts {
+    Some(opts) if response.content_length() != expected_length => {
+      log::warn!(
+        "Upstream sent {:?} bytes of {} instead of {:?}, not caching it",
+        response.content_length(),
+        opts.cache_file,
+        expected_length
+      );
+      if resume_from > 0 {
+        let _ = tokio::fs::rename(&opts.download_tmp, &opts.partial_file).await;
+      }
+      if resumed {
+        // It's not the rest of what we have, nor what the client asked for.
+        return Err(errors::Error::String(format!(
+          "Upstream sent a bad range of {}",
+          opts.cache_file
+        )));
+      }
+      None
+    }
+    x => x,
+  };
   let mut builder = warp::http::Response::builder();
   for (k, v) in response.headers().iter() {
+    if resumed && (k == reqwest::header::CONTENT_RANGE || k == reqwest::header::CONTENT_LENGTH) {
+      continue;
+    }
     let kk = k.to_string();
     let vv = v
       .to_str()
@@ -113,39 +386,58 @@ async fn response_to_reply(
       .to_string();
     builder = builder.header(kk, vv);
   }
-  let status = response.status();
+  if let Some(opts) = &dump_opts {
+    // create parent directories if not exist
+    for file in [&opts.cache_file, &opts.download_tmp, &opts.metadata_json] {
+      if let Some(parent) = std::path::Path::new(file).parent() {
+        if let Err(e) = tokio::fs::create_dir_all(parent).await {
+          log::warn!(
+            "Failed to create parent directories for file {}: {}",
+            file,
+            e
+          );
+        }
+      }
+    }
+  }
   let byte_stream = response.bytes_stream();
   let body = match dump_opts {
-    Some(opts) => {
-      // create parent directories if not exist
-      for file in [&opts.cache_file, &opts.download_tmp, &opts.metadata_json] {
-        if let Some(parent) = std::path::Path::new(file).parent() {
-          if let Err(e) = tokio::fs::create_dir_all(parent).await {
-            log::warn!(
-              "Failed to create parent directories for file {}: {}",
-              file,
-              e
-            );
-          }
+    Some(opts) if resumed => {
+      status = reqwest::StatusCode::OK;
+      let expected_size = opts.expected_size;
+      builder = builder.header(warp::http::header::CONTENT_LENGTH, expected_size);
+      match tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(opts.download_tmp.clone())
+        .await
+      {
+        Ok(file) => inspecting(opts, byte_stream, file, resume_from),
+        Err(e) => {
+          // We already told the upstream to skip what we had, nothing we can
+          // serve the client now.
+          return Err(errors::Error::String(format!(
+            "Failed to reopen partial download {}: {}",
+            opts.download_tmp, e
+          )));
         }
       }
+    }
+    Some(opts) => {
+      if resume_from > 0 {
+        log::info!(
+          "Upstream ignored our Range request, restarting download of {}",
+          opts.cache_file
+        );
+      }
       // open file for dumping
       match tokio::fs::OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(true)
         .open(opts.download_tmp.clone())
         .await
       {
-        Ok(file) => inspecting(
-          opts.id,
-          opts.expected_size,
-          opts.download_tmp,
-          opts.cache_file,
-          opts.metadata_json,
-          byte_stream,
-          file,
-          opts.etag,
-        ),
+        Ok(file) => inspecting(opts, byte_stream, file, 0),
         Err(e) => {
           log::warn!(
             "Failed to open file {} for caching: {}",
@@ -164,70 +456,192 @@ async fn response_to_reply(
     .map_err(errors::Error::Http)
 }
 
-fn inspecting(
-  id: SongId,
-  expected_size: u64,
+/// Keeps an interrupted download around for resuming. Dropped together with
+/// the response stream, which happens when the client goes away too.
+struct PartialDownload {
   download_tmp: String,
-  cache_file: String,
-  metadata_json: String,
+  partial_file: String,
+  finished: bool,
+  /// Held here so waiters are only woken after the partial file is in place.
+  downloading: watch::Sender<()>,
+}
+
+impl Drop for PartialDownload {
+  fn drop(&mut self) {
+    if self.finished {
+      return;
+    }
+    match std::fs::rename(&self.download_tmp, &self.partial_file) {
+      Ok(_) => log::info!(
+        "Download interrupted, keeping {} for resuming",
+        self.partial_file
+      ),
+      Err(e) => log::warn!(
+        "Failed to keep partial download {}: {}",
+        self.download_tmp,
+        e
+      ),
+    }
+  }
+}
+
+fn inspecting(
+  opts: InspectingOpts,
   mut byte_stream: impl Stream<Item = Result<Bytes, reqwest::Error>> + Unpin + Send + 'static,
   mut file: File,
-  etag: String,
+  resume_from: u64,
 ) -> Body {
+  let InspectingOpts {
+    id,
+    download_tmp,
+    cache_file,
+    metadata_json,
+    etag,
+    expected_size,
+    downloading,
+    partial_file,
+    cdn,
+  } = opts;
   Body::wrap_stream(async_stream::stream! {
-    let mut total_written = 0u64;
+    let mut partial = PartialDownload {
+      download_tmp: download_tmp.clone(),
+      partial_file,
+      finished: false,
+      downloading,
+    };
+    let mut md5 = md5::Context::new();
+    if resume_from > 0 {
+      match File::open(&download_tmp).await {
+        Ok(prefix) => {
+          let mut prefix = prefix.take(resume_from);
+          let mut buf = vec![0u8; 64 * 1024];
+          loop {
+            match prefix.read(&mut buf).await {
+              Ok(0) => break,
+              Ok(n) => {
+                md5.consume(&buf[..n]);
+                yield Ok(Bytes::copy_from_slice(&buf[..n]));
+              }
+              Err(e) => {
+                log::warn!("Failed to read partial download {}: {}", download_tmp, e);
+                yield Err(e);
+                return;
+              }
+            }
+          }
+        }
+        Err(e) => {
+          log::warn!("Failed to read partial download {}: {}", download_tmp, e);
+          yield Err(e);
+          return;
+        }
+      }
+    }
+    let mut total_written = resume_from;
     let mut last_show_percentage = 0;
+    let mut caching = true;
+    let mut failed = false;
     let start_time = std::time::Instant::now();
-    loop {
-      tokio::select! {
-        Some(bytes) = byte_stream.next() => {
-          match &bytes {
-            Err(e) => {
-              log::warn!("Failed to read from response stream: {}", e);
-              break;
-            }
-            Ok(bytes) => match file.write_all(&bytes).await {
-              Ok(_) => {
-                let len = bytes.len();
-                total_written += len as u64;
-                let percentage = total_written as f64 / expected_size as f64 * 100.0;
-                trace!("Wrote {}/{} ({:.2}%) bytes to cache file {}",
-                  total_written, expected_size,
-                  percentage,
-                  download_tmp
-                );
-                let percentage_level = percentage as u64 / 20;
-                if percentage_level > last_show_percentage {
-                  last_show_percentage = percentage_level;
-                  log::debug!("Wrote {}/{} ({:.2}%) bytes to cache file {}",
-                    total_written, expected_size,
-                    percentage,
-                    download_tmp
-                  );
-                }
-                if total_written >= expected_size {
-                  let elapsed = start_time.elapsed().as_secs_f64();
-                  let speed = total_written as f64 / elapsed;
-                  log::info!("Finished fetching {} ({}) to cache file {}",
-                    to_human_readable_size(expected_size),
-                    to_human_readable_speed(speed),
-                    download_tmp
-                  );
-                  match file.sync_all().await {
-                    Ok(_) => match publish_to_local_videos(id, &metadata_json, &cache_file, &download_tmp, &etag).await {
-                      Ok(_) => log::info!("Successfully generated metadata for cache file {}", cache_file),
-                      Err(e) => log::warn!("Failed to activate cache file {}: {}", download_tmp, e),
-                    }
-                    Err(e) => log::warn!("Failed to sync cache file {}: {}", download_tmp, e),
-                  }
-                }
-              }
-              Err(e) => log::warn!("Failed to write to cache file {}: {}", download_tmp, e),
+    while let Some(bytes) = byte_stream.next().await {
+      let bytes = match bytes {
+        Ok(bytes) => bytes,
+        Err(e) => {
+          // Kept for resuming, see `PartialDownload`.
+          log::warn!("Failed to read from response stream after {}/{} bytes of {}: {}",
+            total_written, expected_size, cache_file, e
+          );
+          failed = true;
+          // The client is likely gone after this, let the pending write
+          // land before the file is kept.
+          if let Err(e) = file.flush().await {
+            log::warn!("Failed to flush cache file {}: {}", download_tmp, e);
+          }
+          yield Err(std::io::Error::other(e));
+          break;
+        }
+      };
+      if caching && total_written + bytes.len() as u64 > expected_size {
+        log::warn!("Upstream sent more than the {} bytes of {}, not caching it",
+          expected_size, cache_file
+        );
+        caching = false;
+      } else if caching {
+        match file.write_all(&bytes).await {
+          Ok(_) => {
+            md5.consume(&bytes);
+            total_written += bytes.len() as u64;
+            let percentage = total_written as f64 / expected_size as f64 * 100.0;
+            trace!("Wrote {}/{} ({:.2}%) bytes to cache file {}",
+              total_written, expected_size,
+              percentage,
+              download_tmp
+            );
+            let percentage_level = percentage as u64 / 20;
+            if percentage_level > last_show_percentage {
+              last_show_percentage = percentage_level;
+              log::debug!("Wrote {}/{} ({:.2}%) bytes to cache file {}",
+                total_written, expected_size,
+                percentage,
+                download_tmp
+              );
             }
           }
-          yield bytes;
+          Err(e) => {
+            log::warn!("Failed to write to cache file {}, not caching it: {}", download_tmp, e);
+            caching = false;
+          }
         }
       }
+      yield Ok(bytes);
+    }
+
+    if failed {
+      return;
+    }
+    if caching && total_written < expected_size {
+      log::warn!("Upstream ended after {}/{} bytes of {}, not caching it",
+        total_written, expected_size, cache_file
+      );
+      caching = false;
+    }
+    // It ended cleanly, there's nothing to resume.
+    partial.finished = true;
+    if !caching {
+      if let Err(e) = tokio::fs::remove_file(&download_tmp).await {
+        log::warn!("Failed to remove cache file {}: {}", download_tmp, e);
+      }
+      return;
+    }
+
+    let elapsed = start_time.elapsed().as_secs_f64();
+    let speed = (total_written - resume_from) as f64 / elapsed;
+    log::info!("Finished fetching {} ({}) to cache file {}",
+      to_human_readable_size(expected_size),
+      to_human_readable_speed(speed),
+      download_tmp
+    );
+    let md5 = hex::encode(md5.compute().as_slice());
+    match file.sync_all().await {
+      Ok(_) => match publish_to_local_videos(id, &metadata_json, &cache_file, &download_tmp, &etag, &md5).await {
+        Ok(_) => {
+          log::info!("Successfully generated metadata for cache file {}", cache_file);
+          cdn.record_access(id).await;
+        }
+        Err(e) => {
+          log::warn!("Failed to activate cache file {}: {}", download_tmp, e);
+          // Most likely a checksum mismatch, don't resume from
+          // this one again and start over next time.
+          if let Err(e) = tokio::fs::remove_file(&download_tmp).await {
+            log::warn!("Failed to remove cache file {}: {}", download_tmp, e);
+          }
+        }
+      }
+      Err(e) => log::warn!("Failed to sync cache file {}: {}", download_tmp, e),
+    }
+    // Wake up clients waiting for this song, whether we published it or not.
+    let _ = partial.downloading.send(());
+    if let Err(e) = cdn.evict_until_fits().await {
+      log::warn!("Failed to evict cache: {}", e);
     }
   })
 }
@@ -238,10 +652,9 @@ async fn publish_to_local_videos(
   cache_file: &String,
   download_tmp: &String,
   etag: &String,
+  md5: &String,
 ) -> anyhow::Result<()> {
-  let md5 = md5::compute(tokio::fs::read(download_tmp).await?);
-  let md5 = hex::encode(md5.as_slice());
-  if &md5 != etag {
+  if md5 != etag {
     return Err(anyhow::anyhow!(
       "Checksum mismatch for file {}: expected {}, got {}",
       download_tmp,
@@ -284,13 +697,25 @@ async fn publish_to_local_videos(
   Ok(())
 }
 
-fn default_reqwest_client() -> reqwest::Client {
-  reqwest::Client::builder()
+/// The client upstream requests are sent with, see `ProxyOpts::client`.
+/// `read_timeout` applies to every read, so slow but steady downloads are fine.
+pub fn default_reqwest_client(
+  connect_timeout: Duration,
+  read_timeout: Duration,
+  proxy: Option<reqwest::Proxy>,
+) -> reqwest::Client {
+  let builder = reqwest::Client::builder()
     .redirect(Policy::none())
-    .build()
-    // we should panic here, it is enforce that the client is needed, and there is no error
-    // handling possible on function call, better to stop execution.
-    .expect("Default reqwest client couldn't build")
+    .connect_timeout(connect_timeout)
+    .read_timeout(read_timeout);
+  match proxy {
+    Some(proxy) => builder.proxy(proxy),
+    None => builder,
+  }
+  .build()
+  // we should panic here, it is enforce that the client is needed, and there is no error
+  // handling possible on function call, better to stop execution.
+  .expect("Default reqwest client couldn't build")
 }
 
 fn to_human_readable_size(size: u64) -> String {
@@ -314,3 +739,219 @@ fn to_human_readable(x: f64, units: &[&str]) -> String {
   }
   format!("{:.2} {}", x, units[i])
 }
+
+#[cfg(test)]
+mod test {
+  use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+  };
+
+  use super::*;
+  use crate::cdn::CdnServiceImpl;
+
+  async fn inspect(chunks: Vec<&'static [u8]>, etag: String) -> (CdnService, SongId) {
+    let expected_size = chunks.iter().map(|x| x.len() as u64).sum();
+    let chunks = chunks.into_iter().map(|x| Ok(Bytes::from(x))).collect();
+    let (cdn, id, body) = inspect_stream(chunks, etag, expected_size).await;
+    body.unwrap();
+    (cdn, id)
+  }
+
+  /// Streams `chunks` through `inspecting` as the upstream response of a
+  /// `expected_size` bytes song, returning what the client got.
+  async fn inspect_stream(
+    chunks: Vec<Result<Bytes, reqwest::Error>>,
+    etag: String,
+    expected_size: u64,
+  ) -> (CdnService, SongId, Result<Bytes, warp::hyper::Error>) {
+    let root = std::env::temp_dir().join(format!("wanna-cdn-test-{}", uuid::Uuid::new_v4()));
+    let cdn = CdnServiceImpl::new(
+      root.join("song").to_string_lossy().to_string(),
+      root.join("cache").to_string_lossy().to_string(),
+      Duration::from_secs(60),
+      None,
+      false,
+      Duration::from_secs(600),
+    );
+    let id = 1;
+    let (cache_file, metadata_json, _) = cdn.get_video_file_path(id).await;
+    std::fs::create_dir_all(format!("{}/{}", cdn.video_path, id)).unwrap();
+    std::fs::create_dir_all(&cdn.cache_path).unwrap();
+    let download_tmp = format!("{}/tmp", cdn.cache_path);
+    let file = File::create(&download_tmp).await.unwrap();
+    let opts = InspectingOpts {
+      id,
+      download_tmp,
+      cache_file,
+      metadata_json,
+      etag,
+      expected_size,
+      downloading: watch::channel(()).0,
+      partial_file: cdn.partial_download_path("1-test.mp4"),
+      cdn: cdn.clone(),
+    };
+    let byte_stream = futures::stream::iter(chunks);
+    let body = warp::hyper::body::to_bytes(inspecting(opts, byte_stream, file, 0)).await;
+    (cdn, id, body)
+  }
+
+  #[tokio::test]
+  async fn md5_is_computed_while_streaming() {
+    let chunks: Vec<&'static [u8]> = vec![b"definitely ", b"a ", b"video"];
+    let etag = hex::encode(md5::compute(chunks.concat()).as_slice());
+    let (cdn, id) = inspect(chunks, etag).await;
+    assert!(cdn.get_video_file_path(id).await.2);
+
+    let (cdn, id) = inspect(vec![b"not ", b"that one"], "0".repeat(32)).await;
+    assert!(!cdn.get_video_file_path(id).await.2);
+  }
+
+  #[tokio::test]
+  async fn short_or_long_transfers_are_not_cached() {
+    let chunks: Vec<&'static [u8]> = vec![b"definitely ", b"a ", b"video"];
+    let etag = hex::encode(md5::compute(chunks.concat()).as_slice());
+    let body = || chunks.iter().map(|x| Ok(Bytes::from(*x))).collect();
+
+    let (cdn, id, served) = inspect_stream(body(), etag.clone(), 19).await;
+    assert_eq!(served.unwrap().as_ref(), b"definitely a video");
+    assert!(!cdn.get_video_file_path(id).await.2);
+    assert!(!std::path::Path::new(&format!("{}/tmp", cdn.cache_path)).exists());
+    assert!(!std::path::Path::new(&cdn.partial_download_path("1-test.mp4")).exists());
+
+    // The client still gets all of it.
+    let (cdn, id, served) = inspect_stream(body(), etag.clone(), 13).await;
+    assert_eq!(served.unwrap().as_ref(), b"definitely a video");
+    assert!(!cdn.get_video_file_path(id).await.2);
+    assert!(!std::path::Path::new(&format!("{}/tmp", cdn.cache_path)).exists());
+  }
+
+  #[tokio::test]
+  async fn interrupted_transfers_are_kept_for_resuming() {
+    // Any request error will do, nothing listens on port 0.
+    let error = reqwest::get("http://127.0.0.1:0/").await.unwrap_err();
+    let chunks = vec![Ok(Bytes::from_static(b"definitely ")), Err(error)];
+
+    let (cdn, id, served) = inspect_stream(chunks, "0".repeat(32), 18).await;
+    assert!(served.is_err());
+    assert!(!cdn.get_video_file_path(id).await.2);
+    let partial = std::fs::read(cdn.partial_download_path("1-test.mp4")).unwrap();
+    assert_eq!(partial, b"definitely ");
+  }
+
+  #[tokio::test]
+  async fn failed_requests_are_retried() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      // Hang up on the first one.
+      drop(listener.accept().await.unwrap());
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let _ = stream.read(&mut [0; 1024]).await;
+      let _ = stream
+        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+        .await;
+    });
+    let client = default_reqwest_client(Duration::from_secs(5), Duration::from_secs(5), None);
+    let request = || client.get(format!("http://{}/", addr)).build().unwrap();
+
+    let response = execute_with_retries(&client, request(), 1).await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "ok");
+    // Nobody listens anymore.
+    assert!(execute_with_retries(&client, request(), 0).await.is_err());
+  }
+
+  /// An HTTP server answering every request with `status`, counting them.
+  async fn stub_upstream(status: &'static str) -> (String, Arc<AtomicUsize>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    tokio::spawn(async move {
+      while let Ok((mut stream, _)) = listener.accept().await {
+        counter.fetch_add(1, Ordering::SeqCst);
+        let _ = stream.read(&mut [0; 1024]).await;
+        let response = format!(
+          "HTTP/1.1 {}\r\nconnection: close\r\ncontent-length: {}\r\n\r\n{}",
+          status,
+          status.len(),
+          status
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+      }
+    });
+    (format!("http://{}/files/1.mp4", addr), hits)
+  }
+
+  #[tokio::test]
+  async fn upstream_requests_go_through_the_proxy() {
+    let (proxy_url, hits) = stub_upstream("200 OK").await;
+    let proxy_url = proxy_url.trim_end_matches("/files/1.mp4").to_string();
+    let client = |bypass: &[String]| {
+      default_reqwest_client(
+        Duration::from_secs(5),
+        Duration::from_secs(5),
+        Some(upstream_proxy(&proxy_url, bypass).unwrap()),
+      )
+    };
+
+    // Nothing listens at upstream.test, only the proxy can answer.
+    let response = client(&[])
+      .get("http://upstream.test/files/1.mp4")
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(response.text().await.unwrap(), "200 OK");
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    let bypassed = client(&["upstream.test".to_string()])
+      .get("http://upstream.test/files/1.mp4")
+      .send()
+      .await;
+    assert!(bypassed.is_err());
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+    assert!(upstream_proxy("ftp://127.0.0.1:7890", &[]).is_err());
+  }
+
+  #[tokio::test]
+  async fn failed_upstreams_are_failed_over() {
+    let (bad, bad_hits) = stub_upstream("502 Bad Gateway").await;
+    let (good, good_hits) = stub_upstream("200 OK").await;
+    let failures = Arc::new(UpstreamFailures::new(Duration::from_secs(60)));
+    let proxy = || {
+      let opts = ProxyOpts {
+        client: default_reqwest_client(Duration::from_secs(5), Duration::from_secs(5), None),
+        id: 1,
+        upstreams: [&bad, &good]
+          .map(|uri| Upstream {
+            uri: uri.clone(),
+            host_override: None,
+          })
+          .to_vec(),
+        failures: failures.clone(),
+        user_agent_override: None,
+        allow_304: false,
+        retries: 0,
+      };
+      proxy_and_inspecting(
+        reqwest::Method::GET,
+        Default::default(),
+        Bytes::new(),
+        opts,
+        None,
+      )
+    };
+
+    for _ in 0..2 {
+      let response = proxy().await.unwrap();
+      assert_eq!(response.status(), warp::http::StatusCode::OK);
+      let body = warp::hyper::body::to_bytes(response.into_body())
+        .await
+        .unwrap();
+      assert_eq!(body.as_ref(), b"200 OK");
+    }
+    // The second one went straight to the healthy upstream.
+    assert_eq!(bad_hits.load(Ordering::SeqCst), 1);
+    assert_eq!(good_hits.load(Ordering::SeqCst), 2);
+  }
+}
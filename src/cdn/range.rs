@@ -1,6 +1,7 @@
-use std::{cmp::min, io::SeekFrom, num::ParseIntError};
+use std::{cmp::min, io::SeekFrom, time::SystemTime};
 
 use async_stream::stream;
+use chrono::{DateTime, Utc};
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use warp::{
   http::HeaderValue,
@@ -13,64 +14,153 @@ pub fn filter_range() -> impl Filter<Extract = (Option<String>,), Error = Reject
   warp::header::optional::<String>("Range")
 }
 
-/// This function retrives the range of bytes requested by the web client
+/// Conditional request headers, checked against the ETag and Last-Modified
+/// of the served file.
+#[derive(Debug, Clone, Default)]
+pub struct Conditionals {
+  pub if_none_match: Option<String>,
+  pub if_modified_since: Option<String>,
+  pub if_range: Option<String>,
+}
+
+impl Conditionals {
+  pub fn from_headers(headers: &HeaderMap) -> Self {
+    let get = |name| {
+      headers
+        .get(name)
+        .and_then(|x: &HeaderValue| x.to_str().ok())
+        .map(|x| x.to_string())
+    };
+    Conditionals {
+      if_none_match: get("If-None-Match"),
+      if_modified_since: get("If-Modified-Since"),
+      if_range: get("If-Range"),
+    }
+  }
+}
+
+/// This function filters and extracts the conditional request headers
+pub fn filter_conditionals() -> impl Filter<Extract = (Conditionals,), Error = Rejection> + Copy {
+  warp::header::optional::<String>("If-None-Match")
+    .and(warp::header::optional::<String>("If-Modified-Since"))
+    .and(warp::header::optional::<String>("If-Range"))
+    .map(|if_none_match, if_modified_since, if_range| Conditionals {
+      if_none_match,
+      if_modified_since,
+      if_range,
+    })
+}
+
+/// This function retrives the range of bytes requested by the web client.
+/// `etag` (unquoted, usually the checksum of the file) is used as validator
+/// for the conditional request headers, besides the file mtime. With `head`
+/// set, only the headers are produced and the file is not read.
 pub async fn get_range(
   range_header: Option<String>,
+  conditionals: Conditionals,
+  etag: Option<String>,
   file: &str,
   content_type: &str,
+  head: bool,
 ) -> Result<warp::http::Response<Body>, Rejection> {
-  internal_get_range(range_header, file, content_type, None)
-    .await
-    .map_err(|e| {
-      println!("Error in get_range: {}", e.message);
-      warp::reject()
-    })
+  internal_get_range(
+    range_header,
+    conditionals,
+    etag,
+    file,
+    content_type,
+    head,
+    None,
+  )
+  .await
+  .map_err(|e| {
+    println!("Error in get_range: {}", e.message);
+    warp::reject()
+  })
 }
 
 /// This function retrives the range of bytes requested by the web client. You
 /// can define a callback function for logging purpose or media access control
 pub async fn get_range_with_cb(
   range_header: Option<String>,
+  conditionals: Conditionals,
+  etag: Option<String>,
   file: &str,
   content_type: &str,
   progress: fn(size: u64),
 ) -> Result<warp::http::Response<Body>, Rejection> {
-  internal_get_range(range_header, file, content_type, Some(progress))
-    .await
-    .map_err(|e| {
-      println!("Error in get_range: {}", e.message);
-      warp::reject()
-    })
+  internal_get_range(
+    range_header,
+    conditionals,
+    etag,
+    file,
+    content_type,
+    false,
+    Some(progress),
+  )
+  .await
+  .map_err(|e| {
+    println!("Error in get_range: {}", e.message);
+    warp::reject()
+  })
 }
 
-fn get_range_params(range: &Option<String>, size: u64) -> Result<(u64, u64), Error> {
-  match range {
-    Some(range) => {
-      let range: Vec<String> = range
-        .replace("bytes=", "")
-        .split("-")
-        .filter_map(|n| {
-          if n.len() > 0 {
-            Some(n.to_string())
-          } else {
-            None
-          }
-        })
-        .collect();
-      let start = if range.len() > 0 {
-        range[0].parse::<u64>()?
-      } else {
-        0
-      };
-      let end = if range.len() > 1 {
-        range[1].parse::<u64>()?
-      } else {
-        size - 1
-      };
-      Ok((start, end))
+#[derive(Debug, PartialEq, Eq)]
+enum RangeParams {
+  /// No usable range, serve the whole file.
+  Full,
+  /// Serve the bytes from `start` to `end` of each range, both inclusive.
+  Partial(Vec<(u64, u64)>),
+  /// Some requested range has nothing of the file in it.
+  Unsatisfiable,
+}
+
+/// Parses byte ranges, see RFC 7233 2.1. Malformed headers are ignored.
+fn get_range_params(range: &Option<String>, size: u64) -> RangeParams {
+  let Some(range) = range
+    .as_deref()
+    .and_then(|x| x.trim().strip_prefix("bytes="))
+  else {
+    return RangeParams::Full;
+  };
+  let mut ranges = vec![];
+  for spec in range.split(',') {
+    match get_range_spec(spec, size) {
+      RangeParams::Partial(range) => ranges.extend(range),
+      params => return params,
     }
-    None => Ok((0, size - 1)),
   }
+  RangeParams::Partial(ranges)
+}
+
+/// Parses a single `start-end` range of `get_range_params`.
+fn get_range_spec(spec: &str, size: u64) -> RangeParams {
+  let Some((start, end)) = spec.split_once('-') else {
+    return RangeParams::Full;
+  };
+  let parse = |x: &str| x.trim().parse::<u64>().ok();
+  let range = match (start.trim(), end.trim()) {
+    ("", "") => return RangeParams::Full,
+    ("", suffix) => match parse(suffix) {
+      None => return RangeParams::Full,
+      Some(0) => return RangeParams::Unsatisfiable,
+      Some(_) if size == 0 => return RangeParams::Unsatisfiable,
+      Some(suffix) => (size.saturating_sub(suffix), size - 1),
+    },
+    (start, "") => match parse(start) {
+      None => return RangeParams::Full,
+      Some(start) if start >= size => return RangeParams::Unsatisfiable,
+      Some(start) => (start, size - 1),
+    },
+    (start, end) => match (parse(start), parse(end)) {
+      (Some(start), Some(end)) if start > end || start >= size => {
+        return RangeParams::Unsatisfiable
+      }
+      (Some(start), Some(end)) => (start, min(end, size - 1)),
+      _ => return RangeParams::Full,
+    },
+  };
+  RangeParams::Partial(vec![range])
 }
 
 #[derive(Debug)]
@@ -85,57 +175,434 @@ impl From<std::io::Error> for Error {
     }
   }
 }
-impl From<ParseIntError> for Error {
-  fn from(err: ParseIntError) -> Self {
-    Error {
-      message: err.to_string(),
-    }
-  }
+
+fn http_date(time: SystemTime) -> String {
+  DateTime::<Utc>::from(time)
+    .format("%a, %d %b %Y %H:%M:%S GMT")
+    .to_string()
 }
 
-async fn internal_get_range(
-  range_header: Option<String>,
+fn parse_http_date(date: &str) -> Option<DateTime<Utc>> {
+  DateTime::parse_from_rfc2822(date)
+    .ok()
+    .map(|x| x.with_timezone(&Utc))
+}
+
+/// Weak comparison of an If-None-Match header against our ETag.
+pub fn etag_matches(header: &str, etag: &str) -> bool {
+  header
+    .split(',')
+    .map(|x| x.trim().trim_start_matches("W/"))
+    .any(|x| x == etag || x == "*")
+}
+
+/// A piece of a response body: `prefix`, then `byte_count` bytes of the file
+/// from `start`.
+struct BodyPart {
+  prefix: Vec<u8>,
+  start: u64,
+  byte_count: u64,
+}
+
+/// Streams the `parts` of `file`, then `suffix`.
+async fn range_body(
   file: &str,
-  content_type: &str,
+  parts: Vec<BodyPart>,
+  suffix: Vec<u8>,
   cb: Option<fn(u64)>,
-) -> Result<warp::http::Response<Body>, Error> {
+) -> Result<Body, Error> {
   let mut file = tokio::fs::File::open(file).await?;
-  let metadata = file.metadata().await?;
-  let size = metadata.len();
-  let (start_range, end_range) = get_range_params(&range_header, size)?;
-  let byte_count = end_range - start_range + 1;
-  file.seek(SeekFrom::Start(start_range)).await?;
 
   let stream = stream! {
       let bufsize = 16384;
-      let cycles = byte_count / bufsize as u64 + 1;
       let mut sent_bytes: u64 = 0;
-      for _ in 0..cycles {
-          let mut buffer: Vec<u8> = vec![0; min(byte_count - sent_bytes, bufsize) as usize];
-          let bytes_read = file.read_exact(&mut buffer).await.unwrap();
-          sent_bytes += bytes_read as u64;
-          if let Some(cb) = cb {
-              cb(sent_bytes);
+      'parts: for part in parts {
+          if !part.prefix.is_empty() {
+              yield Ok(part.prefix);
           }
-          yield Ok(buffer) as Result<Vec<u8>, warp::hyper::Error>;
+          if let Err(e) = file.seek(SeekFrom::Start(part.start)).await {
+              yield Err(e);
+              break 'parts;
+          }
+          let mut part_bytes: u64 = 0;
+          while part_bytes < part.byte_count {
+              let mut buffer: Vec<u8> = vec![0; min(part.byte_count - part_bytes, bufsize) as usize];
+              // The file may have been truncated under us, just cut the response.
+              if let Err(e) = file.read_exact(&mut buffer).await {
+                  yield Err(e);
+                  break 'parts;
+              }
+              part_bytes += buffer.len() as u64;
+              sent_bytes += buffer.len() as u64;
+              if let Some(cb) = cb {
+                  cb(sent_bytes);
+              }
+              yield Ok(buffer);
+          }
+      }
+      if !suffix.is_empty() {
+          yield Ok(suffix);
       }
   };
-  let body = Body::wrap_stream(stream);
+  Ok(Body::wrap_stream(stream))
+}
+
+async fn internal_get_range(
+  range_header: Option<String>,
+  conditionals: Conditionals,
+  etag: Option<String>,
+  file: &str,
+  content_type: &str,
+  head: bool,
+  cb: Option<fn(u64)>,
+) -> Result<warp::http::Response<Body>, Error> {
+  let metadata = tokio::fs::metadata(file).await?;
+  let size = metadata.len();
+  let etag = etag.map(|x| format!("\"{}\"", x));
+  let modified = metadata.modified().ok();
+  let last_modified = modified.map(http_date);
+
+  let mut validators = HeaderMap::new();
+  if let Some(etag) = &etag {
+    validators.insert("ETag", HeaderValue::from_str(etag).unwrap());
+  }
+  if let Some(last_modified) = &last_modified {
+    validators.insert(
+      "Last-Modified",
+      HeaderValue::from_str(last_modified).unwrap(),
+    );
+  }
+
+  // If-None-Match takes precedence over If-Modified-Since, see RFC 7232 6.
+  let not_modified = match (&conditionals.if_none_match, &conditionals.if_modified_since) {
+    (Some(if_none_match), _) => etag
+      .as_ref()
+      .is_some_and(|etag| etag_matches(if_none_match, etag)),
+    (None, Some(if_modified_since)) => match (modified, parse_http_date(if_modified_since)) {
+      (Some(modified), Some(since)) => {
+        DateTime::<Utc>::from(modified).timestamp() <= since.timestamp()
+      }
+      _ => false,
+    },
+    (None, None) => false,
+  };
+  if not_modified {
+    let mut response = warp::reply::Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NOT_MODIFIED;
+    response.headers_mut().extend(validators);
+    return Ok(response);
+  }
+
+  // A range is only served if If-Range still matches, the client wants the
+  // whole file otherwise, see RFC 7233 3.2.
+  let range_header = match &conditionals.if_range {
+    Some(if_range) if range_header.is_some() => {
+      let matches = match if_range.trim() {
+        x if x.starts_with('"') || x.starts_with("W/") => etag.as_deref() == Some(x),
+        x => last_modified.as_deref() == Some(x),
+      };
+      range_header.filter(|_| matches)
+    }
+    _ => range_header,
+  };
+
+  let ranges = match get_range_params(&range_header, size) {
+    RangeParams::Unsatisfiable => {
+      let mut response = warp::reply::Response::new(Body::empty());
+      *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+      response.headers_mut().insert(
+        "Content-Range",
+        HeaderValue::from_str(&format!("bytes */{}", size)).unwrap(),
+      );
+      return Ok(response);
+    }
+    RangeParams::Partial(ranges) => ranges,
+    RangeParams::Full => vec![],
+  };
+  if ranges.len() > 1 {
+    let mut response = multipart_response(file, &ranges, size, content_type, head, cb).await?;
+    response.headers_mut().extend(validators);
+    return Ok(response);
+  }
+  let (start_range, end_range, partial) = match ranges.first() {
+    Some(&(start, end)) => (start, Some(end), true),
+    None if size == 0 => (0, None, false),
+    None => (0, Some(size - 1), false),
+  };
+  let byte_count = end_range.map_or(0, |end| end - start_range + 1);
+
+  let body = match head {
+    true => Body::empty(),
+    false => {
+      let part = BodyPart {
+        prefix: vec![],
+        start: start_range,
+        byte_count,
+      };
+      range_body(file, vec![part], vec![], cb).await?
+    }
+  };
   let mut response = warp::reply::Response::new(body);
 
   let headers = response.headers_mut();
   let mut header_map = HeaderMap::new();
   header_map.insert("Content-Type", HeaderValue::from_str(content_type).unwrap());
   header_map.insert("Accept-Ranges", HeaderValue::from_str("bytes").unwrap());
-  header_map.insert(
-    "Content-Range",
-    HeaderValue::from_str(&format!("bytes {}-{}/{}", start_range, end_range, size)).unwrap(),
-  );
+  if let Some(end_range) = end_range {
+    header_map.insert(
+      "Content-Range",
+      HeaderValue::from_str(&format!("bytes {}-{}/{}", start_range, end_range, size)).unwrap(),
+    );
+  }
   header_map.insert("Content-Length", HeaderValue::from(byte_count));
   headers.extend(header_map);
+  headers.extend(validators);
 
-  if range_header.is_some() {
+  if partial {
     *response.status_mut() = StatusCode::PARTIAL_CONTENT;
   }
   Ok(response)
 }
+
+/// Answers a request for several `ranges` with a `multipart/byteranges` body,
+/// see RFC 7233 4.1.
+async fn multipart_response(
+  file: &str,
+  ranges: &[(u64, u64)],
+  size: u64,
+  content_type: &str,
+  head: bool,
+  cb: Option<fn(u64)>,
+) -> Result<warp::http::Response<Body>, Error> {
+  let boundary = uuid::Uuid::new_v4().simple().to_string();
+  let parts = ranges
+    .iter()
+    .map(|&(start, end)| BodyPart {
+      prefix: format!(
+        "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+        boundary, content_type, start, end, size
+      )
+      .into_bytes(),
+      start,
+      byte_count: end - start + 1,
+    })
+    .collect::<Vec<_>>();
+  let suffix = format!("\r\n--{}--\r\n", boundary).into_bytes();
+  let content_length = parts
+    .iter()
+    .map(|x| x.prefix.len() as u64 + x.byte_count)
+    .sum::<u64>()
+    + suffix.len() as u64;
+
+  let body = match head {
+    true => Body::empty(),
+    false => range_body(file, parts, suffix, cb).await?,
+  };
+  let mut response = warp::reply::Response::new(body);
+  *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+  let headers = response.headers_mut();
+  headers.insert(
+    "Content-Type",
+    HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary)).unwrap(),
+  );
+  headers.insert("Accept-Ranges", HeaderValue::from_str("bytes").unwrap());
+  headers.insert("Content-Length", HeaderValue::from(content_length));
+  Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  async fn serve(range: Option<&str>, conditionals: Conditionals) -> warp::http::Response<Body> {
+    let file = std::env::temp_dir().join(format!("range-{}.mp4", uuid::Uuid::new_v4()));
+    std::fs::write(&file, b"0123456789").unwrap();
+    let response = internal_get_range(
+      range.map(|x| x.to_string()),
+      conditionals,
+      Some("abcdef".to_string()),
+      file.to_str().unwrap(),
+      "video/mp4",
+      false,
+      None,
+    )
+    .await
+    .unwrap();
+    std::fs::remove_file(&file).unwrap();
+    response
+  }
+
+  #[test]
+  fn range_params() {
+    let params = |x: &str, size| get_range_params(&Some(x.to_string()), size);
+    assert_eq!(get_range_params(&None, 10), RangeParams::Full);
+    assert_eq!(params("bytes=2-5", 10), RangeParams::Partial(vec![(2, 5)]));
+    assert_eq!(params("bytes=2-", 10), RangeParams::Partial(vec![(2, 9)]));
+    assert_eq!(
+      params("bytes=2-100", 10),
+      RangeParams::Partial(vec![(2, 9)])
+    );
+    assert_eq!(params("bytes=-3", 10), RangeParams::Partial(vec![(7, 9)]));
+    assert_eq!(params("bytes=-100", 10), RangeParams::Partial(vec![(0, 9)]));
+    assert_eq!(params("bytes=10-", 10), RangeParams::Unsatisfiable);
+    assert_eq!(params("bytes=999999999-", 10), RangeParams::Unsatisfiable);
+    assert_eq!(params("bytes=5-2", 10), RangeParams::Unsatisfiable);
+    assert_eq!(params("bytes=-0", 10), RangeParams::Unsatisfiable);
+    assert_eq!(params("bytes=0-", 0), RangeParams::Unsatisfiable);
+    assert_eq!(params("bytes=-5", 0), RangeParams::Unsatisfiable);
+    assert_eq!(
+      params("bytes=0-1, 5-6,-2", 10),
+      RangeParams::Partial(vec![(0, 1), (5, 6), (8, 9)])
+    );
+    assert_eq!(params("bytes=0-1,10-", 10), RangeParams::Unsatisfiable);
+    assert_eq!(params("bytes=0-1,a-b", 10), RangeParams::Full);
+    assert_eq!(params("bytes=-", 10), RangeParams::Full);
+    assert_eq!(params("bytes=a-b", 10), RangeParams::Full);
+    assert_eq!(params("items=0-5", 10), RangeParams::Full);
+  }
+
+  #[tokio::test]
+  async fn unsatisfiable_range_is_rejected() {
+    let response = serve(Some("bytes=999999999-"), Default::default()).await;
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers()["Content-Range"], "bytes */10");
+
+    let response = serve(Some("bytes=0-1,20-30"), Default::default()).await;
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+  }
+
+  #[tokio::test]
+  async fn suffix_and_open_ended_ranges_are_served() {
+    for (range, content_range, body) in [
+      ("bytes=-3", "bytes 7-9/10", "789"),
+      ("bytes=-1048576", "bytes 0-9/10", "0123456789"),
+      ("bytes=7-", "bytes 7-9/10", "789"),
+      ("bytes= -1", "bytes 9-9/10", "9"),
+    ] {
+      let response = serve(Some(range), Default::default()).await;
+      assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT, "{}", range);
+      assert_eq!(response.headers()["Content-Range"], content_range);
+      assert_eq!(
+        response.headers()["Content-Length"],
+        body.len().to_string().as_str()
+      );
+      let bytes = warp::hyper::body::to_bytes(response.into_body())
+        .await
+        .unwrap();
+      assert_eq!(bytes, body.as_bytes(), "{}", range);
+    }
+
+    let response = serve(Some("bytes=0-0,-2"), Default::default()).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    let body = warp::hyper::body::to_bytes(response.into_body())
+      .await
+      .unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("Content-Range: bytes 0-0/10\r\n\r\n0\r\n"));
+    assert!(body.contains("Content-Range: bytes 8-9/10\r\n\r\n89\r\n"));
+  }
+
+  #[tokio::test]
+  async fn multiple_ranges_are_served_as_multipart() {
+    let response = serve(Some("bytes=0-1,5-6"), Default::default()).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    let content_type = response.headers()["Content-Type"].to_str().unwrap();
+    let boundary = content_type
+      .strip_prefix("multipart/byteranges; boundary=")
+      .unwrap()
+      .to_string();
+    let content_length = response.headers()["Content-Length"].clone();
+    let body = warp::hyper::body::to_bytes(response.into_body())
+      .await
+      .unwrap();
+    assert_eq!(content_length, body.len().to_string().as_str());
+    assert_eq!(
+      String::from_utf8(body.to_vec()).unwrap(),
+      format!(
+        "\r\n--{b}\r\nContent-Type: video/mp4\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+         \r\n--{b}\r\nContent-Type: video/mp4\r\nContent-Range: bytes 5-6/10\r\n\r\n56\
+         \r\n--{b}--\r\n",
+        b = boundary
+      )
+    );
+  }
+
+  #[tokio::test]
+  async fn empty_file_is_served() {
+    let file = std::env::temp_dir().join(format!("range-{}.mp4", uuid::Uuid::new_v4()));
+    std::fs::write(&file, b"").unwrap();
+    let response = get_range(
+      None,
+      Default::default(),
+      None,
+      file.to_str().unwrap(),
+      "video/mp4",
+      false,
+    )
+    .await
+    .unwrap();
+    std::fs::remove_file(&file).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["Content-Length"], "0");
+    let body = warp::hyper::body::to_bytes(response.into_body())
+      .await
+      .unwrap();
+    assert!(body.is_empty());
+  }
+
+  #[tokio::test]
+  async fn if_none_match_returns_not_modified() {
+    let response = serve(None, Default::default()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["ETag"], "\"abcdef\"");
+    assert!(response.headers().contains_key("Last-Modified"));
+
+    let response = serve(
+      None,
+      Conditionals {
+        if_none_match: Some("\"other\", \"abcdef\"".to_string()),
+        ..Default::default()
+      },
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()["ETag"], "\"abcdef\"");
+
+    let response = serve(
+      None,
+      Conditionals {
+        if_modified_since: Some(http_date(
+          SystemTime::now() + std::time::Duration::from_secs(60),
+        )),
+        ..Default::default()
+      },
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+  }
+
+  #[tokio::test]
+  async fn if_range_falls_back_to_full_content() {
+    let response = serve(
+      Some("bytes=2-5"),
+      Conditionals {
+        if_range: Some("\"abcdef\"".to_string()),
+        ..Default::default()
+      },
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()["Content-Range"], "bytes 2-5/10");
+
+    let response = serve(
+      Some("bytes=2-5"),
+      Conditionals {
+        if_range: Some("\"stale\"".to_string()),
+        ..Default::default()
+      },
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["Content-Length"], "10");
+  }
+}
@@ -1,10 +1,16 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
 
 use anyhow::anyhow;
 use itertools::{Either, Itertools};
 use serde_derive::{Deserialize, Serialize};
+use tokio::{sync::broadcast, task::AbortHandle};
 
 use crate::{
+  cdn::receipt_store::{ReceiptStore, StoreRecord},
   types::{timedmap, timedmap::TimedMap, SongId, UuidString},
   Result,
 };
@@ -24,6 +30,18 @@ pub struct Receipt {
   pub song_url: Option<String>,
   pub sender: Option<UserId>,
   pub message: Option<String>,
+  /// Set once the target has seen it, it stays until it expires.
+  #[serde(default)]
+  pub acknowledged_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum ReceiptEvent {
+  Created(Receipt),
+  Acknowledged(Receipt),
+  Expired(ReceiptId),
+  Deleted(ReceiptId),
 }
 
 #[derive(Debug)]
@@ -31,7 +49,15 @@ pub struct ReceiptServiceImpl {
   /// TimedMap is thread-safe, since it uses a RwLock internally.
   receipts: Arc<TimedMap<ReceiptId, Receipt>>,
   max_receipts_per_user_per_sender: usize,
+  max_receipts_per_target: usize,
+  max_receipts_per_room: usize,
   default_expire: Duration,
+  /// Event channels of the rooms someone is listening to.
+  rooms: Arc<Mutex<HashMap<RoomId, broadcast::Sender<ReceiptEvent>>>>,
+  /// Pending expired events, aborted when the receipt is deleted first.
+  expirations: Arc<Mutex<HashMap<ReceiptId, AbortHandle>>>,
+  /// Where receipts are journaled to survive restarts, if anywhere.
+  store: Option<ReceiptStore>,
 }
 
 pub type ReceiptService = Arc<ReceiptServiceImpl>;
@@ -39,19 +65,88 @@ pub type ReceiptService = Arc<ReceiptServiceImpl>;
 impl ReceiptServiceImpl {
   pub async fn new(
     max_receipts_per_user_per_sender: usize,
+    max_receipts_per_target: usize,
+    max_receipts_per_room: usize,
     default_expire: Duration,
+    store_path: Option<String>,
   ) -> Result<ReceiptService> {
+    let (store, loaded) = match &store_path {
+      Some(path) => {
+        let (store, loaded) = ReceiptStore::open(path).await?;
+        (Some(store), loaded)
+      }
+      None => (None, vec![]),
+    };
     let receipts = Arc::new(TimedMap::new());
     let _canceller = timedmap::tokio_cleaner(receipts.clone(), Duration::from_secs(60));
-    Ok(Arc::new(ReceiptServiceImpl {
+    let service = Arc::new(ReceiptServiceImpl {
       receipts,
       max_receipts_per_user_per_sender,
+      max_receipts_per_target,
+      max_receipts_per_room,
       default_expire,
-    }))
+      rooms: Default::default(),
+      expirations: Default::default(),
+      store,
+    });
+    let now = chrono::Utc::now().timestamp();
+    for receipt in loaded {
+      let remaining = Duration::from_secs((receipt.expires_at - now).max(0) as u64);
+      let (id, room_id) = (receipt.receipt_id.clone(), receipt.room_id.clone());
+      service
+        .receipts
+        .insert(id.clone(), receipt, remaining)
+        .await;
+      service.expire_later(id, room_id, remaining);
+    }
+    Ok(service)
   }
 }
 
 impl ReceiptServiceImpl {
+  fn journal(&self, record: StoreRecord) {
+    if let Some(store) = &self.store {
+      store.append(record);
+    }
+  }
+
+  /// Waits until every change so far is written to the store, if any.
+  pub async fn sync(&self) {
+    if let Some(store) = &self.store {
+      store.sync().await;
+    }
+  }
+
+  /// Sends the expired event of `receipt_id` once `after` has elapsed, unless
+  /// it is deleted before.
+  fn expire_later(&self, receipt_id: ReceiptId, room_id: RoomId, after: Duration) {
+    let rooms = self.rooms.clone();
+    let expirations = self.expirations.clone();
+    let mut pending = self.expirations.lock().unwrap();
+    let id = receipt_id.clone();
+    let task = tokio::spawn(async move {
+      tokio::time::sleep(after).await;
+      expirations.lock().unwrap().remove(&receipt_id);
+      publish(&rooms, &room_id, ReceiptEvent::Expired(receipt_id));
+    });
+    pending.insert(id, task.abort_handle());
+  }
+
+  fn publish(&self, room_id: &RoomId, event: ReceiptEvent) {
+    publish(&self.rooms, room_id, event)
+  }
+
+  /// Subscribes to receipts of `room_id` being created, acknowledged, expiring
+  /// or deleted.
+  pub fn subscribe(&self, room_id: RoomId) -> broadcast::Receiver<ReceiptEvent> {
+    let mut rooms = self.rooms.lock().unwrap();
+    rooms.retain(|_, events| events.receiver_count() > 0);
+    rooms
+      .entry(room_id)
+      .or_insert_with(|| broadcast::channel(64).0)
+      .subscribe()
+  }
+
   pub async fn receipts(&self, room_id: RoomId) -> Vec<Receipt> {
     self
       .receipts
@@ -68,6 +163,69 @@ impl ReceiptServiceImpl {
       .collect()
   }
 
+  pub async fn get_receipt(&self, receipt_id: &ReceiptId) -> Option<Receipt> {
+    self.receipts.get(receipt_id).await
+  }
+
+  /// Removes a receipt before it expires. Returns `false` if there is no such
+  /// receipt in `room_id`.
+  pub async fn delete_receipt(&self, room_id: RoomId, receipt_id: ReceiptId) -> Result<bool> {
+    match self.receipts.get(&receipt_id).await {
+      Some(receipt) if receipt.room_id == room_id => {}
+      _ => return Ok(false),
+    }
+    if self.receipts.remove(&receipt_id).await.is_none() {
+      return Ok(false);
+    }
+    if let Some(expiration) = self.expirations.lock().unwrap().remove(&receipt_id) {
+      expiration.abort();
+    }
+    self.journal(StoreRecord::Delete {
+      receipt_id: receipt_id.clone(),
+    });
+    self.publish(&room_id, ReceiptEvent::Deleted(receipt_id));
+    Ok(true)
+  }
+
+  /// Marks a receipt of `room_id` as acknowledged, keeping its expiry.
+  pub async fn ack_receipt(&self, room_id: RoomId, receipt_id: ReceiptId) -> Result<Receipt> {
+    let (Some(mut receipt), Some(remaining)) = (
+      self.receipts.get(&receipt_id).await,
+      self.receipts.time_remaining(&receipt_id).await,
+    ) else {
+      return Err(anyhow!("Receipt {} not found or expired", receipt_id));
+    };
+    if receipt.room_id != room_id {
+      return Err(anyhow!(
+        "Receipt {} not found in room {}",
+        receipt_id,
+        room_id
+      ));
+    }
+    if receipt.acknowledged_at.is_none() {
+      receipt.acknowledged_at = Some(chrono::Utc::now().timestamp());
+      self
+        .receipts
+        .insert(receipt_id, receipt.clone(), remaining)
+        .await;
+      self.publish(&room_id, ReceiptEvent::Acknowledged(receipt.clone()));
+      self.journal(StoreRecord::Put {
+        receipt: receipt.clone(),
+      });
+    }
+    Ok(receipt)
+  }
+
+  pub async fn count_per_room(&self) -> HashMap<RoomId, usize> {
+    self
+      .receipts
+      .snapshot::<HashMap<_, _>>()
+      .await
+      .into_values()
+      .map(|receipt| receipt.room_id)
+      .counts()
+  }
+
   pub async fn create_receipt(
     &self,
     room_id: RoomId,
@@ -77,11 +235,28 @@ impl ReceiptServiceImpl {
     message: Option<String>,
   ) -> Result<Receipt> {
     let snapshots = self.receipts(room_id.clone()).await;
+    if snapshots.len() >= self.max_receipts_per_room {
+      let next_expire = snapshots
+        .iter()
+        .map(|r| r.expires_at)
+        .min()
+        .unwrap_or_default();
+      return Err(anyhow!(
+        "Room has reached maximum receipt count, the next one expires in {}s",
+        (next_expire - chrono::Utc::now().timestamp()).max(0)
+      ));
+    }
     let user_receipts = snapshots
       .iter()
       .filter(|r| r.target == target)
       .cloned()
       .collect::<Vec<_>>();
+    if user_receipts.len() >= self.max_receipts_per_target {
+      return Err(anyhow!(
+        "Target {} already reached the maximum number of receipts",
+        target
+      ));
+    }
 
     let per_sender = user_receipts
       .into_iter()
@@ -149,11 +324,99 @@ impl ReceiptServiceImpl {
       sender,
       target,
       message,
+      acknowledged_at: None,
     };
     self
       .receipts
-      .insert(uuid, receipt.clone(), valid_duration)
+      .insert(uuid.clone(), receipt.clone(), valid_duration)
       .await;
+    self.publish(&receipt.room_id, ReceiptEvent::Created(receipt.clone()));
+    self.expire_later(uuid, receipt.room_id.clone(), valid_duration);
+    self.journal(StoreRecord::Put {
+      receipt: receipt.clone(),
+    });
     Ok(receipt)
   }
 }
+
+fn publish(
+  rooms: &Mutex<HashMap<RoomId, broadcast::Sender<ReceiptEvent>>>,
+  room_id: &RoomId,
+  event: ReceiptEvent,
+) {
+  let mut rooms = rooms.lock().unwrap();
+  if let Some(events) = rooms.get(room_id) {
+    // Sending only fails when the last subscriber went away.
+    if events.send(event).is_err() {
+      rooms.remove(room_id);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::io::Write;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn receipts_are_capped_per_target_and_room() {
+    let receipt = ReceiptServiceImpl::new(5, 2, 3, Duration::from_secs(60), None)
+      .await
+      .unwrap();
+    let room = "room1".to_string();
+    let create = |target: &str, sender: &str| {
+      receipt.create_receipt(
+        room.clone(),
+        target.to_string(),
+        Either::Left(1),
+        Some(sender.to_string()),
+        None,
+      )
+    };
+    create("alice", "bob").await.unwrap();
+    create("alice", "carol").await.unwrap();
+    // Another sender, but alice already has two.
+    assert!(create("alice", "dave").await.is_err());
+    create("erin", "bob").await.unwrap();
+    // The room is full.
+    assert!(create("frank", "bob").await.is_err());
+  }
+
+  #[tokio::test]
+  async fn receipts_survive_restarts() {
+    let path = std::env::temp_dir().join(format!("receipts-{}.jsonl", uuid::Uuid::new_v4()));
+    let path = path.to_str().unwrap().to_string();
+    let new = || ReceiptServiceImpl::new(5, 10, 100, Duration::from_secs(60), Some(path.clone()));
+    let receipt = new().await.unwrap();
+    let create = |target: &str| {
+      receipt.create_receipt(
+        "room1".to_string(),
+        target.to_string(),
+        Either::Left(1),
+        None,
+        None,
+      )
+    };
+    let kept = create("alice").await.unwrap();
+    let deleted = create("bob").await.unwrap();
+    receipt
+      .delete_receipt("room1".to_string(), deleted.receipt_id)
+      .await
+      .unwrap();
+    receipt.sync().await;
+    // A half-written line, as if we crashed while appending.
+    std::fs::OpenOptions::new()
+      .append(true)
+      .open(&path)
+      .unwrap()
+      .write_all(b"{\"op\":\"put\",\"rec")
+      .unwrap();
+
+    let receipt = new().await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let receipts = receipt.receipts("room1".to_string()).await;
+    assert_eq!(receipts.len(), 1);
+    assert_eq!(receipts[0].receipt_id, kept.receipt_id);
+  }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::{
+  io::AsyncWriteExt,
+  sync::{mpsc, oneshot},
+};
+
+use crate::{
+  cdn::receipt::{Receipt, ReceiptId},
+  Result,
+};
+
+/// A line of the store, applied in order when loading.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum StoreRecord {
+  /// A receipt was created or updated.
+  Put {
+    receipt: Receipt,
+  },
+  Delete {
+    receipt_id: ReceiptId,
+  },
+}
+
+enum Message {
+  Record(StoreRecord),
+  Sync(oneshot::Sender<()>),
+}
+
+/// Appends receipt changes to a JSON-lines file. Writes happen in a background
+/// task, in batches, so callers never wait for the disk.
+#[derive(Debug, Clone)]
+pub struct ReceiptStore {
+  tx: mpsc::UnboundedSender<Message>,
+}
+
+impl ReceiptStore {
+  /// Loads the receipts of `path` that have not expired yet, and rewrites the
+  /// file with only those before appending to it.
+  pub async fn open(path: &str) -> Result<(ReceiptStore, Vec<Receipt>)> {
+    let receipts = match tokio::fs::read_to_string(path).await {
+      Ok(content) => replay(path, &content),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+      Err(e) => return Err(e.into()),
+    };
+    info!("Loaded {} receipts from {}", receipts.len(), path);
+
+    let mut compacted = String::new();
+    for receipt in &receipts {
+      compacted += &serde_json::to_string(&StoreRecord::Put {
+        receipt: receipt.clone(),
+      })?;
+      compacted += "\n";
+    }
+    let tmp = format!("{}.{}", path, uuid::Uuid::new_v4().simple());
+    tokio::fs::write(&tmp, compacted).await?;
+    tokio::fs::rename(&tmp, path).await?;
+
+    let file = tokio::fs::OpenOptions::new()
+      .append(true)
+      .open(path)
+      .await?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(serve_receipt_store(path.to_string(), file, rx));
+    Ok((ReceiptStore { tx }, receipts))
+  }
+
+  pub fn append(&self, record: StoreRecord) {
+    // The writer only goes away with the runtime.
+    let _ = self.tx.send(Message::Record(record));
+  }
+
+  /// Waits until everything appended so far is written.
+  pub async fn sync(&self) {
+    let (tx, rx) = oneshot::channel();
+    if self.tx.send(Message::Sync(tx)).is_ok() {
+      let _ = rx.await;
+    }
+  }
+}
+
+fn replay(path: &str, content: &str) -> Vec<Receipt> {
+  let mut receipts = HashMap::new();
+  for (n, line) in content.lines().enumerate() {
+    if line.trim().is_empty() {
+      continue;
+    }
+    match serde_json::from_str::<StoreRecord>(line) {
+      Ok(StoreRecord::Put { receipt }) => {
+        receipts.insert(receipt.receipt_id.clone(), receipt);
+      }
+      Ok(StoreRecord::Delete { receipt_id }) => {
+        receipts.remove(&receipt_id);
+      }
+      Err(e) => warn!("Skipping corrupt line {} of {}: {}", n + 1, path, e),
+    }
+  }
+  let now = chrono::Utc::now().timestamp();
+  let mut receipts = receipts
+    .into_values()
+    .filter(|r| r.expires_at > now)
+    .collect::<Vec<_>>();
+  receipts.sort_by_key(|r| r.created_at);
+  receipts
+}
+
+async fn serve_receipt_store(
+  path: String,
+  mut file: tokio::fs::File,
+  mut rx: mpsc::UnboundedReceiver<Message>,
+) {
+  while let Some(message) = rx.recv().await {
+    // Take whatever else is already queued, and write it all at once.
+    let mut batch = String::new();
+    let mut syncs = vec![];
+    let mut next = Some(message);
+    while let Some(message) = next {
+      match message {
+        Message::Record(record) => match serde_json::to_string(&record) {
+          Ok(line) => {
+            batch += &line;
+            batch += "\n";
+          }
+          Err(e) => warn!("Failed to serialize receipt record: {:?}", e),
+        },
+        Message::Sync(tx) => syncs.push(tx),
+      }
+      next = rx.try_recv().ok();
+    }
+    if !batch.is_empty() {
+      if let Err(e) = file.write_all(batch.as_bytes()).await {
+        warn!("Failed to write receipts to {}: {:?}", path, e);
+      }
+    }
+    if !syncs.is_empty() {
+      if let Err(e) = file.sync_data().await {
+        warn!("Failed to sync receipts to {}: {:?}", path, e);
+      }
+    }
+    for tx in syncs {
+      let _ = tx.send(());
+    }
+  }
+}
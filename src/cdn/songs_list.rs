@@ -0,0 +1,154 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::bail;
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{AppService, Result};
+
+/// Set on lists that didn't just come from the upstream.
+pub const STALE_FIELD: &str = "WDSelfHostedCDNStale";
+/// The songs served from the local cache, see
+/// `CdnServiceImpl::cached_song_ids`.
+pub const CACHED_FIELD: &str = "WDSelfHostedCDNCached";
+
+/// What the upstream last answered to `/Api/Songs/list`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedSongsList {
+  fetched_at: i64,
+  list: serde_json::Value,
+}
+
+/// Serves `/Api/Songs/list` from the upstream API, keeping its last answer
+/// on disk for when the upstream is unreachable.
+#[derive(Debug)]
+pub struct SongsListServiceImpl {
+  /// `songs-list.json` in the cache directory.
+  file: PathBuf,
+  ttl: Duration,
+  /// Loaded from `file` on first use. Locked while refreshing, so only one
+  /// request at a time asks the upstream.
+  cached: Mutex<Option<CachedSongsList>>,
+}
+
+pub type SongsListService = Arc<SongsListServiceImpl>;
+
+impl SongsListServiceImpl {
+  pub fn new(cache_path: &str, ttl: Duration) -> SongsListService {
+    Arc::new(SongsListServiceImpl {
+      file: PathBuf::from(cache_path).join("songs-list.json"),
+      ttl,
+      cached: Default::default(),
+    })
+  }
+
+  /// The songs list, telling which songs are cached locally in
+  /// `CACHED_FIELD`.
+  pub async fn get(&self, app: &AppService) -> Result<serde_json::Value> {
+    let mut list = self.list(app).await?;
+    if let Some(list) = list.as_object_mut() {
+      let cached = match app.cdn.cached_song_ids().await {
+        Ok(ids) => ids,
+        Err(e) => {
+          warn!("Failed to list cached songs: {:?}", e);
+          vec![]
+        }
+      };
+      list.insert(CACHED_FIELD.to_string(), cached.into());
+    }
+    Ok(list)
+  }
+
+  /// The songs list, asking the upstream at most once per `ttl`. When the
+  /// upstream fails, the last list it answered is served instead, or one
+  /// made from the local index if there is none, both marked `STALE_FIELD`.
+  async fn list(&self, app: &AppService) -> Result<serde_json::Value> {
+    let mut cached = self.cached.lock().await;
+    if cached.is_none() {
+      *cached = self.read_cached().await;
+    }
+    let now = chrono::Utc::now().timestamp();
+    if let Some(cached) = &*cached {
+      if now - cached.fetched_at < self.ttl.as_secs() as i64 {
+        return Ok(cached.list.clone());
+      }
+    }
+
+    let error = match fetch_upstream(app).await {
+      Ok(list) => {
+        let fresh = CachedSongsList {
+          fetched_at: now,
+          list,
+        };
+        if let Err(e) = self.write_cached(&fresh).await {
+          warn!("Failed to save the songs list to {:?}: {:?}", self.file, e);
+        }
+        let list = fresh.list.clone();
+        *cached = Some(fresh);
+        return Ok(list);
+      }
+      Err(e) => e,
+    };
+    let mut list = match &*cached {
+      Some(cached) => {
+        warn!(
+          "Failed to fetch the songs list, serving the one from {}: {:?}",
+          cached.fetched_at, error
+        );
+        cached.list.clone()
+      }
+      None => {
+        warn!(
+          "Failed to fetch the songs list, serving the local index: {:?}",
+          error
+        );
+        serde_json::to_value(app.index.get_index(false).await?)?
+      }
+    };
+    if let Some(list) = list.as_object_mut() {
+      list.insert(STALE_FIELD.to_string(), true.into());
+    }
+    Ok(list)
+  }
+
+  async fn read_cached(&self) -> Option<CachedSongsList> {
+    let content = match tokio::fs::read(&self.file).await {
+      Ok(content) => content,
+      Err(e) => {
+        debug!("No songs list in {:?}: {:?}", self.file, e);
+        return None;
+      }
+    };
+    match serde_json::from_slice(&content) {
+      Ok(cached) => Some(cached),
+      Err(e) => {
+        warn!("Ignoring bad songs list in {:?}: {:?}", self.file, e);
+        None
+      }
+    }
+  }
+
+  async fn write_cached(&self, cached: &CachedSongsList) -> Result<()> {
+    if let Some(dir) = self.file.parent() {
+      tokio::fs::create_dir_all(dir).await?;
+    }
+    // Renamed into place, so a crash can't leave half a list behind.
+    let tmp = self.file.with_extension("json.tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec(cached)?).await?;
+    tokio::fs::rename(&tmp, &self.file).await?;
+    Ok(())
+  }
+}
+
+async fn fetch_upstream(app: &AppService) -> Result<serde_json::Value> {
+  let url = format!(
+    "{}/Api/Songs/list",
+    app.opts.cache_upstream_ud_api.trim_end_matches('/')
+  );
+  let response = app.proxy_client.get(&url).send().await?;
+  if !response.status().is_success() {
+    bail!("the upstream answered {} for {}", response.status(), url);
+  }
+  Ok(serde_json::from_slice(&response.bytes().await?)?)
+}
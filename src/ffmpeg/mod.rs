@@ -1,10 +1,14 @@
-use std::{ffi::CString, ptr};
+use std::{
+  ffi::{CStr, CString},
+  ptr,
+};
 
 use anyhow::anyhow;
 use rsmpeg::{
-  avcodec::{AVCodec, AVCodecContext, AVCodecParameters, AVPacket},
+  avcodec::{AVCodec, AVCodecContext, AVCodecParameters, AVCodecRef, AVPacket},
+  avfilter::{AVFilter, AVFilterGraph, AVFilterInOut},
   avformat::{AVFormatContextInput, AVFormatContextOutput, AVStreamMut, AVStreamRef},
-  avutil::{AVDictionary, AVFrame, AVRational},
+  avutil::{av_rescale_q, get_sample_fmt_name, AVChannelLayout, AVDictionary, AVFrame, AVRational},
   error::RsmpegError,
   ffi,
   swresample::SwrContext,
@@ -19,12 +23,51 @@ pub struct AudioCompensationStatistics {
   pub audio_resample_secs: f64,
 }
 
+/// Hardware to encode with. Video is only ever copied for now, so it picks the
+/// AAC encoder, falling back to the software one when ffmpeg wasn't built with
+/// the hardware one.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HwAccelBackend {
+  Nvenc,
+  Vaapi,
+  VideoToolbox,
+  #[default]
+  None,
+}
+
+impl HwAccelBackend {
+  /// AAC encoders to try before the software one.
+  fn aac_encoders(self) -> &'static [&'static str] {
+    match self {
+      HwAccelBackend::Nvenc => &["nvenc_aac"],
+      HwAccelBackend::VideoToolbox => &["aac_at"],
+      // VAAPI has no audio encoders.
+      HwAccelBackend::Vaapi | HwAccelBackend::None => &[],
+    }
+  }
+}
+
+fn find_aac_encoder(hw_accel: HwAccelBackend) -> anyhow::Result<AVCodecRef<'static>> {
+  for name in hw_accel.aac_encoders() {
+    if let Some(encoder) = AVCodec::find_encoder_by_name(&CString::new(*name)?) {
+      log::debug!("Using {} AAC encoder", name);
+      return Ok(encoder);
+    }
+    log::warn!(
+      "{} AAC encoder not available, falling back to software",
+      name
+    );
+  }
+  AVCodec::find_encoder(ffi::AV_CODEC_ID_AAC).ok_or_else(|| anyhow!("Could not find AAC encoder"))
+}
+
 // ffmpeg -i %input_file% -ss %audio_offset% -i %input_file% -map 0:v -map 1:a
 // -c:v copy -c:a aac -async 1 %output_file%
 pub fn ffmpeg_audio_compensation(
   input_file: &str,
   output_file: &str,
   audio_offset: f64,
+  hw_accel: HwAccelBackend,
 ) -> anyhow::Result<AudioCompensationStatistics> {
   let mut stats = AudioCompensationStatistics {
     video_copy_secs: 0.0,
@@ -90,8 +133,7 @@ pub fn ffmpeg_audio_compensation(
       return Err(anyhow!("Input audio stream is not in AAC format"));
     }
 
-    let aac_encoder = AVCodec::find_encoder(ffi::AV_CODEC_ID_AAC)
-      .ok_or_else(|| anyhow!("Could not find AAC encoder"))?;
+    let aac_encoder = find_aac_encoder(hw_accel)?;
     let mut aac_ctx = AVCodecContext::new(&aac_encoder);
 
     aac_ctx.set_ch_layout(audio_in_codecpar.ch_layout);
@@ -521,3 +563,659 @@ pub fn ffmpeg_copy(input_file: &str, output_file: &str) -> anyhow::Result<()> {
 
   Ok(())
 }
+
+/// Loudness of the input of `ffmpeg_normalize_audio`.
+#[derive(Debug, Copy, Clone)]
+pub struct AudioLoudnessStats {
+  /// Integrated loudness, in LUFS.
+  pub input_i: f64,
+  /// True peak, in dBTP.
+  pub input_tp: f64,
+  /// Loudness range, in LU.
+  pub input_lra: f64,
+  /// Relative gating threshold, in LUFS.
+  pub input_thresh: f64,
+}
+
+// EBU R128
+const LOUDNORM_TARGET_I: f64 = -23.0;
+const LOUDNORM_TARGET_TP: f64 = -1.0;
+const LOUDNORM_TARGET_LRA: f64 = 7.0;
+
+// ffmpeg -i %input_file% -af ebur128=peak=true -f null -
+// ffmpeg -i %input_file% -c:v copy -c:a aac
+// -af loudnorm=I=-23:measured_I=...:linear=true %output_file%
+pub fn ffmpeg_normalize_audio(
+  input_file: &str,
+  output_file: &str,
+) -> anyhow::Result<AudioLoudnessStats> {
+  let input_file = CString::new(input_file)?;
+  let output_file = CString::new(output_file)?;
+
+  // First pass, measure the loudness
+  let stats = measure_loudness(&input_file)?;
+
+  // Second pass, normalize with the measurements
+  let mut input_ctx = AVFormatContextInput::open(&input_file, None, &mut None)
+    .map_err(|e| anyhow!("Could not open input file: {}", e))?;
+  let mut output_ctx = AVFormatContextOutput::create(&output_file, None)?;
+
+  let (video_in_stream_index, video_in_timebase, audio_in_stream_index, audio_in_timebase) = {
+    let ((video_in_stream, video_in_stream_index), (audio_in_stream, audio_in_stream_index)) =
+      find_video_audio(&input_ctx, &input_ctx)
+        .map_err(|e| anyhow!("Could not find video and audio streams: {}", e))?;
+    new_stream(video_in_stream, &mut output_ctx, None);
+    (
+      video_in_stream_index,
+      video_in_stream.time_base,
+      audio_in_stream_index,
+      audio_in_stream.time_base,
+    )
+  };
+
+  let mut dec_audio_ctx = open_audio_decoder(&input_ctx.streams()[audio_in_stream_index])?;
+
+  // Create AAC encoder for output audio stream
+  let mut enc_audio_ctx = {
+    let audio_in_codecpar = input_ctx.streams()[audio_in_stream_index].codecpar();
+    let aac_encoder = find_aac_encoder(HwAccelBackend::None)?;
+    let mut aac_ctx = AVCodecContext::new(&aac_encoder);
+    aac_ctx.set_ch_layout(audio_in_codecpar.ch_layout);
+    aac_ctx.set_sample_rate(audio_in_codecpar.sample_rate);
+    aac_ctx.set_sample_fmt(
+      aac_encoder
+        .sample_fmts()
+        .unwrap_or(&[ffi::AV_SAMPLE_FMT_FLTP])[0],
+    );
+    aac_ctx.set_bit_rate(audio_in_codecpar.bit_rate);
+    aac_ctx.set_time_base(AVRational {
+      num: 1,
+      den: audio_in_codecpar.sample_rate,
+    });
+    if (output_ctx.oformat().flags & ffi::AVFMT_GLOBALHEADER as i32) != 0 {
+      aac_ctx.set_flags(ffi::AV_CODEC_FLAG_GLOBAL_HEADER as i32);
+    }
+    aac_ctx
+      .open(None)
+      .map_err(|e| anyhow!("Could not open AAC encoder: {}", e))?;
+    aac_ctx
+  };
+
+  new_stream(
+    &input_ctx.streams()[audio_in_stream_index],
+    &mut output_ctx,
+    Some(enc_audio_ctx.extract_codecpar()),
+  );
+
+  // loudnorm works at 192kHz, so convert back to what the encoder takes, in
+  // frames of the size it takes.
+  let filter_spec = format!(
+    "loudnorm=I={}:TP={}:LRA={}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:linear=true,\
+     aformat=sample_fmts={}:sample_rates={}:channel_layouts={},asetnsamples=n={}:p=0",
+    LOUDNORM_TARGET_I,
+    LOUDNORM_TARGET_TP,
+    LOUDNORM_TARGET_LRA,
+    // Silence measures -inf, which loudnorm doesn't take
+    stats.input_i.max(-99.0),
+    stats.input_tp.clamp(-99.0, 99.0),
+    stats.input_lra.clamp(0.0, 99.0),
+    stats.input_thresh.max(-99.0),
+    get_sample_fmt_name(enc_audio_ctx.sample_fmt)
+      .ok_or_else(|| anyhow!("Unknown AAC encoder sample format"))?
+      .to_str()?,
+    enc_audio_ctx.sample_rate,
+    enc_audio_ctx.ch_layout().describe()?.to_str()?,
+    enc_audio_ctx.frame_size,
+  );
+  let mut filter = AudioFilter::new(&dec_audio_ctx, audio_in_timebase, &filter_spec)?;
+
+  // Set faststart flag for HTTP progressive download
+  let muxer_opts = AVDictionary::new(&CString::new("movflags")?, &CString::new("+faststart")?, 0);
+  output_ctx
+    .write_header(&mut Some(muxer_opts))
+    .map_err(|e| anyhow!("Could not write output file header: {}", e))?;
+
+  // The muxer may have changed the time bases
+  let out_video_stream_time_base = output_ctx.streams()[0].time_base;
+  let out_audio_stream_time_base = output_ctx.streams()[1].time_base;
+
+  while let Some(mut pkt) = input_ctx.read_packet()? {
+    let stream_index = pkt.stream_index as usize;
+    if stream_index == video_in_stream_index {
+      pkt.set_stream_index(0);
+      pkt.rescale_ts(video_in_timebase, out_video_stream_time_base);
+      pkt.set_pos(-1);
+      output_ctx.interleaved_write_frame(&mut pkt)?;
+    } else if stream_index == audio_in_stream_index {
+      dec_audio_ctx
+        .send_packet(Some(&pkt))
+        .map_err(|e| anyhow!("Error sending audio packet to decoder: {}", e))?;
+      while let Ok(dec_frame) = dec_audio_ctx.receive_frame() {
+        filter.push(Some(dec_frame))?;
+        encode_filtered_frames(
+          &mut filter,
+          &mut output_ctx,
+          &mut enc_audio_ctx,
+          out_audio_stream_time_base,
+        )?;
+      }
+    }
+  }
+
+  // Flush audio decoder, filter and encoder
+  dec_audio_ctx
+    .send_packet(None)
+    .map_err(|e| anyhow!("Error flushing audio decoder: {}", e))?;
+  while let Ok(dec_frame) = dec_audio_ctx.receive_frame() {
+    filter.push(Some(dec_frame))?;
+  }
+  filter.push(None)?;
+  encode_filtered_frames(
+    &mut filter,
+    &mut output_ctx,
+    &mut enc_audio_ctx,
+    out_audio_stream_time_base,
+  )?;
+  write_encoded_packets(
+    None,
+    &mut output_ctx,
+    &mut enc_audio_ctx,
+    out_audio_stream_time_base,
+  )
+  .map_err(|e| anyhow!("Error flushing audio encoder: {}", e))?;
+
+  output_ctx.write_trailer()?;
+
+  Ok(stats)
+}
+
+fn measure_loudness(input_file: &CStr) -> anyhow::Result<AudioLoudnessStats> {
+  let mut input_ctx = AVFormatContextInput::open(input_file, None, &mut None)
+    .map_err(|e| anyhow!("Could not open input file: {}", e))?;
+  let audio_in_stream_index = input_ctx
+    .streams()
+    .iter()
+    .position(|stream| stream.codecpar().codec_type == ffi::AVMEDIA_TYPE_AUDIO)
+    .ok_or_else(|| anyhow!("No audio stream found"))?;
+  let audio_in_stream = &input_ctx.streams()[audio_in_stream_index];
+  let mut dec_audio_ctx = open_audio_decoder(audio_in_stream)?;
+  // The measurements so far are attached to every frame, the last ones are
+  // of the whole input.
+  let mut filter = AudioFilter::new(
+    &dec_audio_ctx,
+    audio_in_stream.time_base,
+    "ebur128=peak=true:metadata=1",
+  )?;
+
+  let mut stats = None;
+  while let Some(pkt) = input_ctx.read_packet()? {
+    if pkt.stream_index as usize != audio_in_stream_index {
+      continue;
+    }
+    dec_audio_ctx
+      .send_packet(Some(&pkt))
+      .map_err(|e| anyhow!("Error sending audio packet to decoder: {}", e))?;
+    while let Ok(dec_frame) = dec_audio_ctx.receive_frame() {
+      filter.push(Some(dec_frame))?;
+      while let Some(frame) = filter.pull()? {
+        stats = loudness_metadata(&frame).or(stats);
+      }
+    }
+  }
+
+  // Flush audio decoder and filter
+  dec_audio_ctx
+    .send_packet(None)
+    .map_err(|e| anyhow!("Error flushing audio decoder: {}", e))?;
+  while let Ok(dec_frame) = dec_audio_ctx.receive_frame() {
+    filter.push(Some(dec_frame))?;
+  }
+  filter.push(None)?;
+  while let Some(frame) = filter.pull()? {
+    stats = loudness_metadata(&frame).or(stats);
+  }
+
+  stats.ok_or_else(|| anyhow!("Could not measure loudness"))
+}
+
+/// The measurements the ebur128 filter attached to `frame`.
+fn loudness_metadata(frame: &AVFrame) -> Option<AudioLoudnessStats> {
+  let get = |key: &str| -> Option<f64> {
+    let key = CString::new(format!("lavfi.r128.{}", key)).ok()?;
+    let entry = unsafe { ffi::av_dict_get(frame.metadata, key.as_ptr(), ptr::null(), 0) };
+    if entry.is_null() {
+      return None;
+    }
+    unsafe { CStr::from_ptr((*entry).value) }
+      .to_str()
+      .ok()?
+      .parse()
+      .ok()
+  };
+  let input_i = get("I")?;
+  Some(AudioLoudnessStats {
+    input_i,
+    input_tp: get("true_peak")?,
+    input_lra: get("LRA")?,
+    // The relative gate is 10 LU below the absolute-gated loudness, which is
+    // close enough to the integrated loudness for loudnorm.
+    input_thresh: input_i - 10.0,
+  })
+}
+
+fn open_audio_decoder(audio_in_stream: &AVStreamRef) -> anyhow::Result<AVCodecContext> {
+  let audio_in_codecpar = audio_in_stream.codecpar();
+  let audio_decoder = AVCodec::find_decoder(audio_in_codecpar.codec_id)
+    .ok_or_else(|| anyhow!("Could not find audio decoder"))?;
+  let mut decoder_ctx = AVCodecContext::new(&audio_decoder);
+  decoder_ctx
+    .apply_codecpar(&audio_in_codecpar)
+    .map_err(|e| {
+      anyhow!(
+        "Could not apply codec parameters to audio decoder context: {}",
+        e
+      )
+    })?;
+  decoder_ctx
+    .open(None)
+    .map_err(|e| anyhow!("Could not open audio decoder: {}", e))?;
+  Ok(decoder_ctx)
+}
+
+fn encode_filtered_frames(
+  filter: &mut AudioFilter,
+  output_ctx: &mut AVFormatContextOutput,
+  enc_audio_ctx: &mut AVCodecContext,
+  out_audio_stream_time_base: AVRational,
+) -> anyhow::Result<()> {
+  while let Some(mut frame) = filter.pull()? {
+    frame.set_pts(av_rescale_q(
+      frame.pts,
+      filter.time_base,
+      enc_audio_ctx.time_base,
+    ));
+    write_encoded_packets(
+      Some(&frame),
+      output_ctx,
+      enc_audio_ctx,
+      out_audio_stream_time_base,
+    )
+    .map_err(|e| anyhow!("Error encoding and writing audio frame: {}", e))?;
+  }
+  Ok(())
+}
+
+fn write_encoded_packets(
+  frame: Option<&AVFrame>,
+  output_ctx: &mut AVFormatContextOutput,
+  enc_audio_ctx: &mut AVCodecContext,
+  out_audio_stream_time_base: AVRational,
+) -> anyhow::Result<()> {
+  enc_audio_ctx
+    .send_frame(frame)
+    .map_err(|e| anyhow!("Error sending frame to encoder: {}", e))?;
+  while let Ok(mut enc_pkt) = enc_audio_ctx.receive_packet() {
+    // The audio stream is added after the video one
+    enc_pkt.set_stream_index(1);
+    enc_pkt.rescale_ts(enc_audio_ctx.time_base, out_audio_stream_time_base);
+    enc_pkt.set_pos(-1);
+    output_ctx.interleaved_write_frame(&mut enc_pkt)?;
+  }
+  Ok(())
+}
+
+/// Decoded audio frames in, `filter_spec` applied frames out.
+struct AudioFilter {
+  graph: AVFilterGraph,
+  /// Of the frames out.
+  time_base: AVRational,
+}
+
+impl AudioFilter {
+  fn new(
+    dec_audio_ctx: &AVCodecContext,
+    in_time_base: AVRational,
+    filter_spec: &str,
+  ) -> anyhow::Result<Self> {
+    let mut graph = AVFilterGraph::new();
+    let args = format!(
+      "time_base={}/{}:sample_rate={}:sample_fmt={}:channel_layout={}",
+      in_time_base.num,
+      in_time_base.den,
+      dec_audio_ctx.sample_rate,
+      get_sample_fmt_name(dec_audio_ctx.sample_fmt)
+        .ok_or_else(|| anyhow!("Unknown audio decoder sample format"))?
+        .to_str()?,
+      dec_audio_ctx.ch_layout().describe()?.to_str()?,
+    );
+    {
+      let abuffer =
+        AVFilter::get_by_name(&CString::new("abuffer")?).ok_or_else(|| anyhow!("No abuffer"))?;
+      let abuffersink = AVFilter::get_by_name(&CString::new("abuffersink")?)
+        .ok_or_else(|| anyhow!("No abuffersink"))?;
+      let mut src_ctx =
+        graph.create_filter_context(&abuffer, &CString::new("in")?, Some(&CString::new(args)?))?;
+      let mut sink_ctx = graph.create_filter_context(&abuffersink, &CString::new("out")?, None)?;
+      // The graph's input is linked to our source, its output to our sink.
+      let outputs = AVFilterInOut::new(&CString::new("in")?, &mut src_ctx, 0);
+      let inputs = AVFilterInOut::new(&CString::new("out")?, &mut sink_ctx, 0);
+      graph
+        .parse_ptr(&CString::new(filter_spec)?, Some(inputs), Some(outputs))
+        .map_err(|e| anyhow!("Could not parse audio filter {}: {}", filter_spec, e))?;
+    }
+    graph
+      .config()
+      .map_err(|e| anyhow!("Could not configure audio filter {}: {}", filter_spec, e))?;
+    let time_base = graph
+      .get_filter(&CString::new("out")?)
+      .ok_or_else(|| anyhow!("No audio filter output"))?
+      .get_time_base();
+    Ok(AudioFilter { graph, time_base })
+  }
+
+  /// Sends a frame in, `None` to flush.
+  fn push(&mut self, frame: Option<AVFrame>) -> anyhow::Result<()> {
+    self
+      .graph
+      .get_filter(&CString::new("in")?)
+      .ok_or_else(|| anyhow!("No audio filter input"))?
+      .buffersrc_add_frame(frame, None)
+      .map_err(|e| anyhow!("Error sending frame to audio filter: {}", e))
+  }
+
+  /// The next frame out, `None` until more are pushed in.
+  fn pull(&mut self) -> anyhow::Result<Option<AVFrame>> {
+    let mut sink_ctx = self
+      .graph
+      .get_filter(&CString::new("out")?)
+      .ok_or_else(|| anyhow!("No audio filter output"))?;
+    match sink_ctx.buffersink_get_frame(None) {
+      Ok(frame) => Ok(Some(frame)),
+      Err(RsmpegError::BufferSinkDrainError) | Err(RsmpegError::BufferSinkEofError) => Ok(None),
+      Err(e) => Err(anyhow!("Error receiving frame from audio filter: {}", e)),
+    }
+  }
+}
+
+// ffmpeg -i %input_file% -ac 1 -f f32le - | rms every 1/%samples_per_second%s
+pub fn ffmpeg_extract_waveform(
+  input_file: &str,
+  samples_per_second: u32,
+) -> anyhow::Result<Vec<f32>> {
+  if samples_per_second == 0 {
+    return Err(anyhow!("Waveform needs at least one sample per second"));
+  }
+  let input_file = CString::new(input_file)?;
+
+  let mut input_ctx = AVFormatContextInput::open(&input_file, None, &mut None)
+    .map_err(|e| anyhow!("Could not open input file: {}", e))?;
+  let audio_in_stream_index = input_ctx
+    .streams()
+    .iter()
+    .position(|stream| stream.codecpar().codec_type == ffi::AVMEDIA_TYPE_AUDIO)
+    .ok_or_else(|| anyhow!("No audio stream found"))?;
+  let mut dec_audio_ctx = open_audio_decoder(&input_ctx.streams()[audio_in_stream_index])?;
+
+  // Mix down to mono floats, so the samples can be read as they are
+  let mono = AVChannelLayout::from_nb_channels(1);
+  let mut swr_ctx = SwrContext::new(
+    &mono,
+    ffi::AV_SAMPLE_FMT_FLT,
+    dec_audio_ctx.sample_rate,
+    &dec_audio_ctx.ch_layout(),
+    dec_audio_ctx.sample_fmt,
+    dec_audio_ctx.sample_rate,
+  )
+  .map_err(|e| anyhow!("Could not create SwrContext: {}", e))?;
+  swr_ctx
+    .init()
+    .map_err(|e| anyhow!("Could not initialize SwrContext: {}", e))?;
+
+  let sample_rate = dec_audio_ctx.sample_rate;
+  let mut waveform = Waveform::new((sample_rate as u32 / samples_per_second).max(1));
+  let mut convert = |dec_frame: Option<&AVFrame>| -> anyhow::Result<()> {
+    let mut mono_frame = AVFrame::new();
+    mono_frame.set_ch_layout(AVChannelLayout::from_nb_channels(1).into_inner());
+    mono_frame.set_format(ffi::AV_SAMPLE_FMT_FLT);
+    mono_frame.set_sample_rate(sample_rate);
+    swr_ctx
+      .convert_frame(dec_frame, &mut mono_frame)
+      .map_err(|e| anyhow!("Error resampling audio frame: {}", e))?;
+    if mono_frame.nb_samples > 0 {
+      let samples = unsafe {
+        std::slice::from_raw_parts(
+          mono_frame.data[0] as *const f32,
+          mono_frame.nb_samples as usize,
+        )
+      };
+      waveform.extend(samples);
+    }
+    Ok(())
+  };
+
+  while let Some(pkt) = input_ctx.read_packet()? {
+    if pkt.stream_index as usize != audio_in_stream_index {
+      continue;
+    }
+    dec_audio_ctx
+      .send_packet(Some(&pkt))
+      .map_err(|e| anyhow!("Error sending audio packet to decoder: {}", e))?;
+    while let Ok(dec_frame) = dec_audio_ctx.receive_frame() {
+      convert(Some(&dec_frame))?;
+    }
+  }
+
+  // Flush audio decoder and resampler
+  dec_audio_ctx
+    .send_packet(None)
+    .map_err(|e| anyhow!("Error flushing audio decoder: {}", e))?;
+  while let Ok(dec_frame) = dec_audio_ctx.receive_frame() {
+    convert(Some(&dec_frame))?;
+  }
+  convert(None)?;
+
+  Ok(waveform.finish())
+}
+
+/// RMS amplitude of every block of samples.
+struct Waveform {
+  block_size: u32,
+  block_len: u32,
+  block_sum_squares: f64,
+  rms: Vec<f32>,
+}
+
+impl Waveform {
+  fn new(block_size: u32) -> Self {
+    Waveform {
+      block_size,
+      block_len: 0,
+      block_sum_squares: 0.0,
+      rms: vec![],
+    }
+  }
+
+  fn extend(&mut self, samples: &[f32]) {
+    for sample in samples {
+      self.block_sum_squares += (*sample as f64).powi(2);
+      self.block_len += 1;
+      if self.block_len == self.block_size {
+        self.end_block();
+      }
+    }
+  }
+
+  fn end_block(&mut self) {
+    self
+      .rms
+      .push((self.block_sum_squares / self.block_len as f64).sqrt() as f32);
+    self.block_len = 0;
+    self.block_sum_squares = 0.0;
+  }
+
+  /// Ends the last block, which may be shorter.
+  fn finish(mut self) -> Vec<f32> {
+    if self.block_len > 0 {
+      self.end_block();
+    }
+    self.rms
+  }
+}
+
+// ffmpeg -ss %seek_secs% -i %input_file% -frames:v 1 -c:v libwebp %output_file%
+pub fn ffmpeg_extract_thumbnail(
+  input_file: &str,
+  output_file: &str,
+  seek_secs: f64,
+) -> anyhow::Result<()> {
+  let input_file = CString::new(input_file)?;
+
+  // Open input video file
+  let mut input_ctx = AVFormatContextInput::open(&input_file, None, &mut None)
+    .map_err(|e| anyhow!("Could not open input video file: {}", e))?;
+
+  // Create video decoder based on input video stream
+  let (video_in_stream_index, video_decoder) = input_ctx
+    .find_best_stream(ffi::AVMEDIA_TYPE_VIDEO)?
+    .ok_or_else(|| anyhow!("No video stream found"))?;
+  let video_in_timebase = input_ctx.streams()[video_in_stream_index].time_base;
+  let mut decoder_ctx = AVCodecContext::new(&video_decoder);
+  decoder_ctx
+    .apply_codecpar(&input_ctx.streams()[video_in_stream_index].codecpar())
+    .map_err(|e| {
+      anyhow!(
+        "Could not apply codec parameters to video decoder context: {}",
+        e
+      )
+    })?;
+  decoder_ctx
+    .open(None)
+    .map_err(|e| anyhow!("Could not open video decoder: {}", e))?;
+
+  // Seek to the keyframe before seek_secs, then decode up to seek_secs
+  let ts = unsafe { seek_secs / ffi::av_q2d(video_in_timebase) } as i64;
+  unsafe {
+    ffi::av_seek_frame(
+      input_ctx.as_mut_ptr(),
+      video_in_stream_index as i32,
+      ts,
+      ffi::AVSEEK_FLAG_BACKWARD as i32,
+    );
+  }
+  let frame = decode_video_frame_at(&mut input_ctx, &mut decoder_ctx, video_in_stream_index, ts)?;
+
+  // Create WebP encoder for the frame, we don't scale so the decoded pixel
+  // format must be supported by the encoder (yuv420p usually is).
+  let webp_encoder = AVCodec::find_encoder(ffi::AV_CODEC_ID_WEBP)
+    .ok_or_else(|| anyhow!("Could not find WebP encoder"))?;
+  if !webp_encoder
+    .pix_fmts()
+    .unwrap_or(&[])
+    .contains(&frame.format)
+  {
+    return Err(anyhow!(
+      "WebP encoder does not support pixel format {}",
+      frame.format
+    ));
+  }
+  let mut encoder_ctx = AVCodecContext::new(&webp_encoder);
+  encoder_ctx.set_width(frame.width);
+  encoder_ctx.set_height(frame.height);
+  encoder_ctx.set_pix_fmt(frame.format);
+  encoder_ctx.set_time_base(video_in_timebase);
+  encoder_ctx
+    .open(None)
+    .map_err(|e| anyhow!("Could not open WebP encoder: {}", e))?;
+
+  // Encode the frame and flush the encoder
+  encoder_ctx
+    .send_frame(Some(&frame))
+    .map_err(|e| anyhow!("Error sending frame to encoder: {}", e))?;
+  encoder_ctx
+    .send_frame(None)
+    .map_err(|e| anyhow!("Error flushing WebP encoder: {}", e))?;
+  let pkt = encoder_ctx
+    .receive_packet()
+    .map_err(|e| anyhow!("Error receiving packet from encoder: {}", e))?;
+
+  // libwebp produces a complete WebP file per packet, no muxer needed
+  let data = unsafe { std::slice::from_raw_parts(pkt.data, pkt.size as usize) };
+  std::fs::write(output_file, data).map_err(|e| anyhow!("Could not write output file: {}", e))?;
+
+  Ok(())
+}
+
+fn decode_video_frame_at(
+  input_ctx: &mut AVFormatContextInput,
+  decoder_ctx: &mut AVCodecContext,
+  video_in_stream_index: usize,
+  ts: i64,
+) -> anyhow::Result<AVFrame> {
+  let mut last_frame = None;
+  while let Some(pkt) = input_ctx.read_packet()? {
+    if pkt.stream_index as usize != video_in_stream_index {
+      continue;
+    }
+    decoder_ctx
+      .send_packet(Some(&pkt))
+      .map_err(|e| anyhow!("Error sending video packet to decoder: {}", e))?;
+    while let Ok(frame) = decoder_ctx.receive_frame() {
+      if frame.best_effort_timestamp >= ts {
+        return Ok(frame);
+      }
+      last_frame = Some(frame);
+    }
+  }
+
+  // Flush video decoder, the video may end before seek_secs
+  decoder_ctx
+    .send_packet(None)
+    .map_err(|e| anyhow!("Error flushing video decoder: {}", e))?;
+  while let Ok(frame) = decoder_ctx.receive_frame() {
+    last_frame = Some(frame);
+  }
+  last_frame.ok_or_else(|| anyhow!("No video frame decoded"))
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaProbe {
+  /// Short names of the demuxer, `mov,mp4,m4a,3gp,3g2,mj2` for MP4.
+  pub format_name: String,
+  pub duration_secs: f64,
+  pub video_streams: usize,
+  pub audio_streams: usize,
+}
+
+impl MediaProbe {
+  /// Whether it's an MP4 we can serve as a song video.
+  pub fn is_mp4_video(&self) -> bool {
+    self.format_name.split(',').any(|x| x == "mp4") && self.video_streams > 0
+  }
+}
+
+// ffprobe %input_file%
+pub fn ffmpeg_probe(input_file: &str) -> anyhow::Result<MediaProbe> {
+  let input_file = CString::new(input_file)?;
+  let input_ctx = AVFormatContextInput::open(&input_file, None, &mut None)
+    .map_err(|e| anyhow!("Could not open input file: {}", e))?;
+  let format_name = unsafe { CStr::from_ptr(input_ctx.iformat().name) }
+    .to_string_lossy()
+    .to_string();
+  let count = |codec_type| {
+    input_ctx
+      .streams()
+      .iter()
+      .filter(|stream| stream.codecpar().codec_type == codec_type)
+      .count()
+  };
+  let duration_secs = match input_ctx.duration {
+    ffi::AV_NOPTS_VALUE => 0.0,
+    x => x as f64 / ffi::AV_TIME_BASE as f64,
+  };
+  Ok(MediaProbe {
+    format_name,
+    duration_secs,
+    video_streams: count(ffi::AVMEDIA_TYPE_VIDEO),
+    audio_streams: count(ffi::AVMEDIA_TYPE_AUDIO),
+  })
+}
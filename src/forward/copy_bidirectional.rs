@@ -5,21 +5,32 @@
 // - Don't bother initializing buffer
 // - Read and write whenever there's a space
 // - Circular buffer
+// - Idle timeout
+// - Byte counters updated as it goes
 
 use std::{
   future::Future,
-  io,
+  io, mem,
   pin::Pin,
+  sync::atomic::{AtomicU64, Ordering},
   task::{Context, Poll},
+  time::Duration,
 };
 
 use futures::ready;
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::{
+  io::{AsyncRead, AsyncWrite, ReadBuf},
+  time::{sleep, Instant, Sleep},
+};
 
 #[derive(Debug)]
 struct CopyBuffer {
   read_done: bool,
   need_flush: bool,
+  /// Whether any byte was read or written since it was last reset.
+  progressed: bool,
+  /// Bytes written so far.
+  amt: u64,
   start_index: usize,
   cache_length: usize,
   size: usize,
@@ -35,6 +46,8 @@ impl CopyBuffer {
     Self {
       read_done: false,
       need_flush: false,
+      progressed: false,
+      amt: 0,
       start_index: 0,
       cache_length: 0,
       size,
@@ -75,6 +88,7 @@ impl CopyBuffer {
               self.read_done = true;
             } else {
               self.cache_length += n;
+              self.progressed = true;
             }
           }
           Poll::Pending => {
@@ -109,12 +123,14 @@ impl CopyBuffer {
               )));
             } else {
               self.cache_length -= written;
+              self.amt += written as u64;
               if self.cache_length == 0 {
                 self.start_index = 0;
               } else {
                 self.start_index = (self.start_index + written) % self.size;
               }
               self.need_flush = true;
+              self.progressed = true;
             }
           }
           Poll::Pending => {
@@ -161,6 +177,10 @@ struct CopyBidirectional<'a, A: ?Sized, B: ?Sized> {
   b_buf: CopyBuffer,
   a_to_b: TransferState,
   b_to_a: TransferState,
+  idle_timeout: Duration,
+  idle: Pin<Box<Sleep>>,
+  a_to_b_bytes: &'a AtomicU64,
+  b_to_a_bytes: &'a AtomicU64,
 }
 
 fn transfer_one_direction<A, B>(
@@ -197,7 +217,7 @@ where
   A: AsyncRead + AsyncWrite + Unpin + ?Sized,
   B: AsyncRead + AsyncWrite + Unpin + ?Sized,
 {
-  type Output = io::Result<()>;
+  type Output = io::Result<(u64, u64)>;
 
   fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
     // Unpack self into mut refs to each field to avoid borrow check issues.
@@ -208,16 +228,35 @@ where
       b_buf,
       a_to_b,
       b_to_a,
+      idle_timeout,
+      idle,
+      a_to_b_bytes,
+      b_to_a_bytes,
     } = &mut *self;
 
+    let a_to_b_amt = a_buf.amt;
+    let b_to_a_amt = b_buf.amt;
     let a_to_b = transfer_one_direction(cx, a_to_b, &mut *a_buf, &mut *a, &mut *b);
     let b_to_a = transfer_one_direction(cx, b_to_a, &mut *b_buf, &mut *b, &mut *a);
+    a_to_b_bytes.fetch_add(a_buf.amt - a_to_b_amt, Ordering::Relaxed);
+    b_to_a_bytes.fetch_add(b_buf.amt - b_to_a_amt, Ordering::Relaxed);
 
+    let amt = (a_buf.amt, b_buf.amt);
     if a_to_b.is_ready() {
-      a_to_b
-    } else {
-      b_to_a
+      return a_to_b.map_ok(|_| amt);
+    }
+    if b_to_a.is_ready() {
+      return b_to_a.map_ok(|_| amt);
     }
+
+    if mem::take(&mut a_buf.progressed) | mem::take(&mut b_buf.progressed) {
+      idle.as_mut().reset(Instant::now() + *idle_timeout);
+    }
+    ready!(idle.as_mut().poll(cx));
+    Poll::Ready(Err(io::Error::new(
+      io::ErrorKind::TimedOut,
+      format!("no bytes in either direction for {:?}", idle_timeout),
+    )))
   }
 }
 
@@ -241,6 +280,10 @@ where
 ///
 /// # Errors
 ///
+/// The future returns a [`io::ErrorKind::TimedOut`] error once no bytes were
+/// copied in either direction for `idle_timeout`, the caller is to shut down
+/// both streams.
+///
 /// The future will immediately return an error if any IO operation on `a`
 /// or `b` returns an error. Some data read from either stream may be lost (not
 /// written to the other stream) in this case.
@@ -248,11 +291,16 @@ where
 /// # Return value
 ///
 /// Returns a tuple of bytes copied `a` to `b` and bytes copied `b` to `a`.
+/// They are also added to `a_to_b_bytes` and `b_to_a_bytes` as they are
+/// copied, so long copies can be followed.
 pub async fn copy_bidirectional<A, B>(
   a: &mut A,
   b: &mut B,
   buffer_size: usize,
-) -> Result<(), std::io::Error>
+  idle_timeout: Duration,
+  a_to_b_bytes: &AtomicU64,
+  b_to_a_bytes: &AtomicU64,
+) -> Result<(u64, u64), std::io::Error>
 where
   A: AsyncRead + AsyncWrite + Unpin + ?Sized,
   B: AsyncRead + AsyncWrite + Unpin + ?Sized,
@@ -264,6 +312,10 @@ where
     b_buf: CopyBuffer::new(buffer_size),
     a_to_b: TransferState::Running,
     b_to_a: TransferState::Running,
+    idle_timeout,
+    idle: Box::pin(sleep(idle_timeout)),
+    a_to_b_bytes,
+    b_to_a_bytes,
   }
   .await
 }
@@ -0,0 +1,206 @@
+use std::{
+  collections::HashMap,
+  net::SocketAddr,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use tokio::net::lookup_host;
+
+#[async_trait]
+pub trait Resolver: Send + Sync + std::fmt::Debug {
+  async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves with the system's DNS settings.
+#[derive(Debug, Default)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+  async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    Ok(lookup_host((host, port)).await?.collect())
+  }
+}
+
+/// How long an address that failed to connect is skipped.
+const DEAD_ADDRESS_DURATION: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct CachedAddresses {
+  addresses: Vec<SocketAddr>,
+  resolved_at: Instant,
+  refreshing: bool,
+}
+
+type AddressCache = Arc<Mutex<HashMap<(String, u16), CachedAddresses>>>;
+
+/// All the addresses of each upstream host, re-resolved in the background once
+/// older than `ttl` so connections never wait for it, and the addresses that
+/// failed to connect recently.
+#[derive(Debug)]
+pub struct DnsCache {
+  resolver: Arc<dyn Resolver>,
+  ttl: Duration,
+  cache: AddressCache,
+  dead: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl Default for DnsCache {
+  fn default() -> Self {
+    DnsCache::new(Arc::new(SystemResolver), Duration::from_secs(60))
+  }
+}
+
+impl DnsCache {
+  pub fn new(resolver: Arc<dyn Resolver>, ttl: Duration) -> Self {
+    DnsCache {
+      resolver,
+      ttl,
+      cache: Default::default(),
+      dead: Default::default(),
+    }
+  }
+
+  /// The addresses of `host`, only waiting for the resolver the first time.
+  pub async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    let key = (host.to_string(), port);
+    if let Some(cached) = self.cache.lock().unwrap().get_mut(&key) {
+      if cached.resolved_at.elapsed() >= self.ttl && !cached.refreshing {
+        cached.refreshing = true;
+        self.refresh_later(key);
+      }
+      return Ok(cached.addresses.clone());
+    }
+    let addresses = self.resolver.resolve(host, port).await?;
+    self.cache.lock().unwrap().insert(
+      key,
+      CachedAddresses {
+        addresses: addresses.clone(),
+        resolved_at: Instant::now(),
+        refreshing: false,
+      },
+    );
+    Ok(addresses)
+  }
+
+  fn refresh_later(&self, key: (String, u16)) {
+    let resolver = self.resolver.clone();
+    let cache = self.cache.clone();
+    tokio::spawn(async move {
+      let resolved = resolver.resolve(&key.0, key.1).await;
+      let mut cache = cache.lock().unwrap();
+      let Some(cached) = cache.get_mut(&key) else {
+        return;
+      };
+      cached.refreshing = false;
+      // Keep the old addresses rather than none, and try again next time.
+      match resolved {
+        Ok(addresses) if !addresses.is_empty() => {
+          debug!("Re-resolved {}:{}: {:?}", key.0, key.1, addresses);
+          cached.addresses = addresses;
+          cached.resolved_at = Instant::now();
+        }
+        Ok(_) => warn!("Re-resolved {}:{} to no address", key.0, key.1),
+        Err(e) => warn!("Failed to re-resolve {}:{}: {:?}", key.0, key.1, e),
+      }
+    });
+  }
+
+  /// Skips `addr` for a while.
+  pub fn mark_dead(&self, addr: SocketAddr) {
+    self
+      .dead
+      .lock()
+      .unwrap()
+      .insert(addr, Instant::now() + DEAD_ADDRESS_DURATION);
+  }
+
+  pub fn is_dead(&self, addr: &SocketAddr) -> bool {
+    let mut dead = self.dead.lock().unwrap();
+    match dead.get(addr) {
+      Some(until) if *until > Instant::now() => true,
+      Some(_) => {
+        dead.remove(addr);
+        false
+      }
+      None => false,
+    }
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  use super::*;
+
+  /// Resolves every host to `addresses`, counting the lookups.
+  #[derive(Debug, Default)]
+  pub(crate) struct MockResolver {
+    pub addresses: Mutex<Vec<SocketAddr>>,
+    pub lookups: AtomicUsize,
+  }
+
+  #[async_trait]
+  impl Resolver for MockResolver {
+    async fn resolve(&self, _host: &str, _port: u16) -> std::io::Result<Vec<SocketAddr>> {
+      self.lookups.fetch_add(1, Ordering::Relaxed);
+      Ok(self.addresses.lock().unwrap().clone())
+    }
+  }
+
+  fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+  }
+
+  #[tokio::test]
+  async fn addresses_are_cached_for_the_ttl() {
+    let resolver = Arc::new(MockResolver::default());
+    *resolver.addresses.lock().unwrap() = vec![addr(1), addr(2)];
+    let dns = DnsCache::new(resolver.clone(), Duration::from_secs(60));
+    for _ in 0..3 {
+      assert_eq!(
+        dns.resolve("upstream", 443).await.unwrap(),
+        vec![addr(1), addr(2)]
+      );
+    }
+    assert_eq!(resolver.lookups.load(Ordering::Relaxed), 1);
+  }
+
+  #[tokio::test]
+  async fn expired_addresses_are_refreshed_in_background() {
+    let resolver = Arc::new(MockResolver::default());
+    *resolver.addresses.lock().unwrap() = vec![addr(1)];
+    let dns = DnsCache::new(resolver.clone(), Duration::ZERO);
+    assert_eq!(dns.resolve("upstream", 443).await.unwrap(), vec![addr(1)]);
+
+    *resolver.addresses.lock().unwrap() = vec![addr(2)];
+    // The stale ones are served while refreshing.
+    assert_eq!(dns.resolve("upstream", 443).await.unwrap(), vec![addr(1)]);
+    for _ in 0..100 {
+      if resolver.lookups.load(Ordering::Relaxed) == 2 {
+        break;
+      }
+      tokio::task::yield_now().await;
+    }
+    tokio::task::yield_now().await;
+    assert_eq!(dns.resolve("upstream", 443).await.unwrap(), vec![addr(2)]);
+  }
+
+  #[test]
+  fn dead_addresses_come_back() {
+    let dns = DnsCache::default();
+    dns.mark_dead(addr(1));
+    assert!(dns.is_dead(&addr(1)));
+    assert!(!dns.is_dead(&addr(2)));
+    dns
+      .dead
+      .lock()
+      .unwrap()
+      .insert(addr(1), Instant::now() - Duration::from_secs(1));
+    assert!(!dns.is_dead(&addr(1)));
+  }
+}
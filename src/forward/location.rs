@@ -18,7 +18,9 @@ impl TryFrom<&str> for NetLocation {
       ));
     }
     let address = tokens[0].to_string();
-    let port = tokens[1].parse::<u16>().unwrap();
+    let port = tokens[1]
+      .parse::<u16>()
+      .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
     Ok(Self { address, port })
   }
 }
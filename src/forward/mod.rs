@@ -1,43 +1,148 @@
 mod async_stream;
 mod copy_bidirectional;
+pub mod dns;
 mod location;
 mod sni;
 mod tcp;
 pub mod tokio_util;
+pub mod tunnel;
 
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+  collections::{HashMap, HashSet},
+  net::SocketAddr,
+  sync::Arc,
+  time::Duration,
+};
 
-use log::{debug, error, info};
+use anyhow::{anyhow, bail};
+use log::{debug, error, info, warn};
+use prometheus::IntCounter;
+pub use sni::{SniMap, TrafficStats};
 use tcp::TargetData;
-use tokio::net::TcpListener;
+use tokio::{
+  net::{TcpListener, TcpStream},
+  sync::{RwLock, Semaphore},
+};
 
 use crate::forward::{
+  dns::DnsCache,
   location::{Location, NetLocation},
   tcp::TargetLocationData,
+  tokio_util::{resolve_host, IpVersion},
+  tunnel::HttpTunnel,
 };
 
+/// Upstreams that failed their last health check, by their `Location`.
+pub type UnhealthyUpstreams = Arc<RwLock<HashSet<String>>>;
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tries to connect to every upstream periodically, and keeps the ones it
+/// couldn't reach in `SniMap::unhealthy` so connections avoid them.
+pub struct HealthChecker {
+  sni_map: Arc<SniMap>,
+  interval: Duration,
+}
+
+impl HealthChecker {
+  pub fn new(sni_map: Arc<SniMap>, interval: Duration) -> Self {
+    Self { sni_map, interval }
+  }
+
+  pub async fn check(&self) {
+    // The mappings may have been reloaded since the last check.
+    let upstreams = self.sni_map.upstreams().await;
+    self
+      .sni_map
+      .unhealthy
+      .write()
+      .await
+      .retain(|x| upstreams.iter().any(|u| u.location.to_string() == *x));
+    for TargetLocationData {
+      location: upstream,
+      ip_version,
+    } in &upstreams
+    {
+      let healthy = match tokio::time::timeout(
+        HEALTH_CHECK_TIMEOUT,
+        connect(upstream, *ip_version, self.sni_map.tunnel.as_deref()),
+      )
+      .await
+      {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+          warn!("SNI upstream {} is unhealthy: {:?}", upstream, e);
+          false
+        }
+        Err(_) => {
+          warn!("SNI upstream {} is unhealthy: connect timed out", upstream);
+          false
+        }
+      };
+      let mut unhealthy = self.sni_map.unhealthy.write().await;
+      if healthy {
+        if unhealthy.remove(&upstream.to_string()) {
+          info!("SNI upstream {} is healthy again", upstream);
+        }
+      } else {
+        unhealthy.insert(upstream.to_string());
+      }
+    }
+  }
+
+  pub async fn run(self) {
+    loop {
+      self.check().await;
+      tokio::time::sleep(self.interval).await;
+    }
+  }
+}
+
+async fn connect(
+  location: &Location,
+  ip_version: IpVersion,
+  tunnel: Option<&HttpTunnel>,
+) -> std::io::Result<TcpStream> {
+  match (location, tunnel) {
+    (Location::Address(location), Some(tunnel)) if !tunnel.bypasses(&location.address) => {
+      tunnel.connect(location).await
+    }
+    (Location::Address(NetLocation { address, port }), _) => {
+      TcpStream::connect(resolve_host((address.as_str(), *port), ip_version).await?).await
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct SniProxyOpts {
+  pub health_check_interval: Duration,
+  /// Connections accepted beyond it are closed right away.
+  pub max_connections: usize,
+  /// Connections with no bytes in either direction for that long are closed.
+  pub idle_timeout: Duration,
+  /// Counts connections closed for `max_connections`.
+  pub rejected_connections: IntCounter,
+}
+
 pub async fn serve_sni_proxy(
   listen: String,
-  proxy_targets: HashMap<String, String>,
+  sni_map: Arc<SniMap>,
+  opts: SniProxyOpts,
 ) -> anyhow::Result<()> {
   let socket = listen
     .parse::<SocketAddr>()
     .expect("Failed to parse listen address");
 
-  let mut host_mappings = HashMap::new();
-  for (host, forward_target) in proxy_targets {
-    let (_, target_location) = to_location(&forward_target);
-    host_mappings.insert(host, (forward_target, target_location));
-  }
-  let sni_map = Arc::new(sni::SniMap { host_mappings });
+  tokio::spawn(HealthChecker::new(sni_map.clone(), opts.health_check_interval).run());
+  let connections = Arc::new(Semaphore::new(opts.max_connections));
 
-  for (host, (forward, _)) in &sni_map.host_mappings {
-    info!("SNI proxy {} {} -> {}", socket, host, forward);
+  for (host, forward) in sni_map.mappings().await {
+    info!("SNI proxy {} {} -> {}", socket, host, forward.join(","));
   }
 
   loop {
     // Currently no QUIC support, we only support TCP
-    if let Err(e) = listen_tcp(socket, sni_map.clone()).await {
+    if let Err(e) = listen_tcp(socket, sni_map.clone(), connections.clone(), &opts).await {
       error!("SNI proxy exited with error, restarting\n{:?}", e);
     } else {
       debug!("SNI proxy exited unexpectedly, restarting...");
@@ -45,21 +150,96 @@ pub async fn serve_sni_proxy(
   }
 }
 
-fn to_location(forward_target: &String) -> (Location, Arc<TargetData>) {
-  let location_jd = Location::Address(
-    NetLocation::try_from(forward_target.as_str()).expect("Failed to parse forward address"),
-  );
-  let target_jd = Arc::new(TargetData {
-    location_data: vec![TargetLocationData {
-      location: location_jd.clone(),
-    }],
+/// Groups `host=target` definitions by host. Since they come comma-separated, a
+/// definition without `=` is another target of the host before it, connections
+/// are spread across them round-robin.
+///
+/// `api.udon.dance=host1:443,host2:443,nya.xin.moe=host3:443`
+///
+/// Hosts may also be `*.` wildcards, or `default` for clients without SNI or
+/// matching nothing else, see `sni::match_rule`.
+///
+/// Targets may end with options: `:proxy_proto` to send a PROXY protocol
+/// header, see `TargetData::proxy_protocol`, and `:ipv4` or `:ipv6` to only
+/// connect to addresses of that family.
+pub fn parse_proxy_targets(target_defs: &[String]) -> HashMap<String, Vec<String>> {
+  let mut proxy_targets = HashMap::<String, Vec<String>>::new();
+  let mut last_host = None;
+  for target_def in target_defs {
+    let (host, forward_target) = match target_def.split_once('=') {
+      Some((host, forward_target)) => (host.to_string(), forward_target),
+      None => match &last_host {
+        Some(host) => (String::clone(host), target_def.as_str()),
+        None => {
+          error!("SNI proxy target {} has no host, ignored", target_def);
+          continue;
+        }
+      },
+    };
+    proxy_targets
+      .entry(host.clone())
+      .or_default()
+      .push(forward_target.to_string());
+    last_host = Some(host);
+  }
+  proxy_targets
+}
+
+/// Splits `host:port:option...` into `host:port` and the options.
+fn split_target_options(forward_target: &str) -> (String, Vec<&str>) {
+  let mut tokens = forward_target.split(':');
+  let address = tokens.by_ref().take(2).collect::<Vec<_>>().join(":");
+  (address, tokens.collect())
+}
+
+fn to_location(
+  forward_targets: &[String],
+  unhealthy: UnhealthyUpstreams,
+  dns: Arc<DnsCache>,
+  tunnel: Option<Arc<HttpTunnel>>,
+) -> anyhow::Result<Arc<TargetData>> {
+  let mut proxy_protocol = false;
+  let mut location_data = vec![];
+  for forward_target in forward_targets {
+    let (address, options) = split_target_options(forward_target);
+    let mut ip_version = IpVersion::Any;
+    for option in options {
+      match option {
+        // It is per host, so any of its targets asking for it enables it.
+        "proxy_proto" => proxy_protocol = true,
+        "ipv4" => ip_version = IpVersion::V4,
+        "ipv6" => ip_version = IpVersion::V6,
+        _ => bail!(
+          "Unknown option {} of SNI proxy target {}",
+          option,
+          forward_target
+        ),
+      }
+    }
+    let address = NetLocation::try_from(address.as_str())
+      .map_err(|e| anyhow!("Bad SNI proxy target {}: {}", forward_target, e))?;
+    location_data.push(TargetLocationData {
+      location: Location::Address(address),
+      ip_version,
+    });
+  }
+  Ok(Arc::new(TargetData {
+    location_data,
     next_address_index: Default::default(),
     tcp_nodelay: false,
-  });
-  (location_jd, target_jd)
+    proxy_protocol,
+    unhealthy,
+    dns,
+    tunnel,
+  }))
 }
 
-async fn listen_tcp(socket: SocketAddr, sni_map: Arc<sni::SniMap>) -> anyhow::Result<()> {
+async fn listen_tcp(
+  socket: SocketAddr,
+  sni_map: Arc<SniMap>,
+  connections: Arc<Semaphore>,
+  opts: &SniProxyOpts,
+) -> anyhow::Result<()> {
   let listener = TcpListener::bind(socket).await?;
 
   loop {
@@ -71,11 +251,108 @@ async fn listen_tcp(socket: SocketAddr, sni_map: Arc<sni::SniMap>) -> anyhow::Re
       }
     };
 
+    // Dropping the stream closes it.
+    let Ok(permit) = connections.clone().try_acquire_owned() else {
+      debug!(
+        "SNI proxy rejected {:?}: over {} connections",
+        client, opts.max_connections
+      );
+      opts.rejected_connections.inc();
+      continue;
+    };
+
     let sni_map = sni_map.clone();
+    let idle_timeout = opts.idle_timeout;
     tokio::spawn(async move {
-      if let Err(e) = sni::sni_proxy(sni_map, stream, client).await {
+      if let Err(e) = sni::sni_proxy(sni_map, stream, client, idle_timeout).await {
         debug!("SNI proxy forward for {:?} exited: {:?}", &client, e);
       }
+      drop(permit);
     });
   }
 }
+
+#[cfg(test)]
+mod test {
+  use tokio::io::AsyncReadExt;
+
+  use super::*;
+
+  #[test]
+  fn proxy_targets_are_grouped_by_host() {
+    let defs = ["a.com=h1:443", "h2:443", "b.com=h3:443", "a.com=h4:443"]
+      .map(String::from)
+      .to_vec();
+    let targets = parse_proxy_targets(&defs);
+    assert_eq!(targets["a.com"], vec!["h1:443", "h2:443", "h4:443"]);
+    assert_eq!(targets["b.com"], vec!["h3:443"]);
+  }
+
+  #[test]
+  fn proxy_protocol_is_enabled_per_host() {
+    let targets = ["h1:443:proxy_proto".to_string(), "h2:443".to_string()];
+    let target_data = to_location(&targets, Default::default(), Default::default(), None).unwrap();
+    assert!(target_data.proxy_protocol);
+    assert_eq!(target_data.location_data[0].location.to_string(), "h1:443");
+    assert!(
+      !to_location(&targets[1..], Default::default(), Default::default(), None)
+        .unwrap()
+        .proxy_protocol
+    );
+    assert!(to_location(
+      &["h1:443:ipv5".to_string()],
+      Default::default(),
+      Default::default(),
+      None
+    )
+    .is_err());
+    assert!(to_location(
+      &["h1:https".to_string()],
+      Default::default(),
+      Default::default(),
+      None
+    )
+    .is_err());
+  }
+
+  #[tokio::test]
+  async fn connections_over_the_limit_are_closed() {
+    // Nothing listens there once the listener is dropped.
+    let socket = TcpListener::bind("127.0.0.1:0")
+      .await
+      .unwrap()
+      .local_addr()
+      .unwrap();
+    let opts = SniProxyOpts {
+      health_check_interval: Duration::from_secs(30),
+      max_connections: 1,
+      idle_timeout: Duration::from_secs(300),
+      rejected_connections: IntCounter::new("rejected", "rejected").unwrap(),
+    };
+    let rejected = opts.rejected_connections.clone();
+    let connections = Arc::new(Semaphore::new(opts.max_connections));
+    tokio::spawn(async move { listen_tcp(socket, Default::default(), connections, &opts).await });
+
+    let _first = loop {
+      match TcpStream::connect(socket).await {
+        Ok(stream) => break stream,
+        Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+      }
+    };
+    let mut second = TcpStream::connect(socket).await.unwrap();
+    let read = tokio::time::timeout(Duration::from_secs(3), second.read(&mut [0; 1]))
+      .await
+      .unwrap();
+    assert_eq!(read.unwrap_or(0), 0);
+    assert_eq!(rejected.get(), 1);
+  }
+
+  #[test]
+  fn ip_version_is_set_per_target() {
+    let targets = ["h1:443:ipv6:proxy_proto".to_string(), "h2:443".to_string()];
+    let target_data = to_location(&targets, Default::default(), Default::default(), None).unwrap();
+    assert_eq!(target_data.location_data[0].ip_version, IpVersion::V6);
+    assert_eq!(target_data.location_data[1].ip_version, IpVersion::Any);
+    assert!(target_data.proxy_protocol);
+  }
+}
@@ -7,35 +7,205 @@ use std::{
   task::{Context, Poll},
   time::Duration,
 };
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+  collections::{HashMap, HashSet},
+  net::SocketAddr,
+  sync::{atomic::Ordering, Arc, Mutex},
+};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use async_trait::async_trait;
 use byteorder::{ByteOrder, NetworkEndian};
 use log::debug;
 use pin_project::pin_project;
+use serde_derive::Serialize;
 use tokio::{
   io::{self, AsyncRead, AsyncReadExt, AsyncWrite, Error, ReadBuf},
   net::TcpStream,
-  pin, time,
+  pin,
+  sync::RwLock,
+  time,
+};
+
+use crate::forward::{
+  async_stream::AsyncStream,
+  dns::DnsCache,
+  tcp,
+  tcp::{TargetData, TargetLocationData, Traffic},
+  to_location,
+  tunnel::HttpTunnel,
+  UnhealthyUpstreams,
 };
 
-use crate::forward::{async_stream::AsyncStream, tcp, tcp::TargetData};
+/// Hostname to its targets as configured, and how to reach them.
+type HostMappings = HashMap<String, (Vec<String>, Arc<TargetData>)>;
 
+/// Where to forward each SNI hostname. It can be reloaded while running,
+/// connections already forwarded keep their target.
+#[derive(Debug, Default)]
 pub struct SniMap {
-  pub host_mappings: std::collections::HashMap<String, (String, Arc<TargetData>)>,
+  host_mappings: RwLock<HostMappings>,
+  pub unhealthy: UnhealthyUpstreams,
+  dns: Arc<DnsCache>,
+  pub(super) tunnel: Option<Arc<HttpTunnel>>,
+  /// By the rule connections matched, kept across reloads.
+  traffic: Mutex<HashMap<String, Arc<Traffic>>>,
+}
+
+/// What `SniMap::traffic` reports for a rule.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TrafficStats {
+  pub up_bytes: u64,
+  pub down_bytes: u64,
+  pub active_connections: u64,
+}
+
+impl SniMap {
+  pub fn new(
+    proxy_targets: HashMap<String, Vec<String>>,
+    dns: Arc<DnsCache>,
+    tunnel: Option<Arc<HttpTunnel>>,
+  ) -> anyhow::Result<SniMap> {
+    let sni_map = SniMap {
+      dns,
+      tunnel,
+      ..Default::default()
+    };
+    let host_mappings = sni_map.build(proxy_targets)?;
+    *sni_map.host_mappings.try_write()? = host_mappings;
+    Ok(sni_map)
+  }
+
+  fn build(&self, proxy_targets: HashMap<String, Vec<String>>) -> anyhow::Result<HostMappings> {
+    proxy_targets
+      .into_iter()
+      .map(|(host, forward_targets)| {
+        if let Some(suffix) = host.strip_prefix("*.") {
+          if suffix.is_empty() || suffix.contains('*') {
+            bail!("Bad SNI proxy wildcard {}", host);
+          }
+        } else if host.contains('*') {
+          bail!("Bad SNI proxy wildcard {}", host);
+        }
+        let target_data = to_location(
+          &forward_targets,
+          self.unhealthy.clone(),
+          self.dns.clone(),
+          self.tunnel.clone(),
+        )?;
+        Ok((host, (forward_targets, target_data)))
+      })
+      .collect()
+  }
+
+  /// Replaces all the mappings at once, unless any target is invalid.
+  pub async fn reload(&self, proxy_targets: HashMap<String, Vec<String>>) -> anyhow::Result<()> {
+    let host_mappings = self.build(proxy_targets)?;
+    *self.host_mappings.write().await = host_mappings;
+    Ok(())
+  }
+
+  /// Hostnames and their targets, as configured.
+  pub async fn mappings(&self) -> HashMap<String, Vec<String>> {
+    self
+      .host_mappings
+      .read()
+      .await
+      .iter()
+      .map(|(host, (forward_targets, _))| (host.clone(), forward_targets.clone()))
+      .collect()
+  }
+
+  pub async fn upstreams(&self) -> Vec<TargetLocationData> {
+    self
+      .host_mappings
+      .read()
+      .await
+      .values()
+      .flat_map(|(_, target_data)| target_data.location_data.iter().cloned())
+      .collect::<HashSet<_>>()
+      .into_iter()
+      .collect()
+  }
+
+  /// Bytes forwarded so far and connections open, by rule.
+  pub fn traffic(&self) -> HashMap<String, TrafficStats> {
+    self
+      .traffic
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(rule, traffic)| {
+        let stats = TrafficStats {
+          up_bytes: traffic.up_bytes.load(Ordering::Relaxed),
+          down_bytes: traffic.down_bytes.load(Ordering::Relaxed),
+          active_connections: traffic.active_connections.load(Ordering::Relaxed),
+        };
+        (rule.clone(), stats)
+      })
+      .collect()
+  }
+
+  fn traffic_for(&self, rule: &str) -> Arc<Traffic> {
+    self
+      .traffic
+      .lock()
+      .unwrap()
+      .entry(rule.to_string())
+      .or_default()
+      .clone()
+  }
+
+  /// The rule `host` matches, and where it forwards to.
+  async fn get(&self, host: Option<&str>) -> Option<(String, Vec<String>, Arc<TargetData>)> {
+    let host_mappings = self.host_mappings.read().await;
+    let (rule, (forward_targets, target_data)) = match_rule(&host_mappings, host)?;
+    Some((
+      rule.to_string(),
+      forward_targets.clone(),
+      target_data.clone(),
+    ))
+  }
+}
+
+/// The rule for no SNI, or an SNI no other rule matches.
+const DEFAULT_RULE: &str = "default";
+
+/// Finds the rule for `host`: the exact hostname, then `*.` wildcards from the
+/// longest suffix to the shortest, then `default`. A wildcard doesn't match
+/// the bare suffix, `*.udon.dance` doesn't match `udon.dance`.
+fn match_rule<'a, T>(
+  mappings: &'a HashMap<String, T>,
+  host: Option<&str>,
+) -> Option<(&'a str, &'a T)> {
+  let host = host.map(|x| x.trim_end_matches('.').to_lowercase());
+  let mut rules = vec![];
+  if let Some(host) = &host {
+    rules.push(host.clone());
+    rules.extend(
+      host
+        .match_indices('.')
+        .map(|(i, _)| format!("*{}", &host[i..])),
+    );
+  }
+  rules.push(DEFAULT_RULE.to_string());
+  rules
+    .into_iter()
+    .find_map(|rule| mappings.get_key_value(&rule))
+    .map(|(rule, x)| (rule.as_str(), x))
 }
 
 pub async fn sni_proxy(
   sni_map: Arc<SniMap>,
   mut client_stream: TcpStream,
   client_socket: SocketAddr,
+  idle_timeout: Duration,
 ) -> crate::Result<()> {
   // Read SNI hostname.
   let mut recording_reader = RecordingBufReader::new(&mut client_stream);
   let reader = HandshakeRecordReader::new(&mut recording_reader);
   pin!(reader);
-  let sni_hostname = time::timeout(
+  let sni_hostname: Option<String> = time::timeout(
     Duration::from_secs(5),
     read_sni_host_name_from_client_hello(reader),
   )
@@ -43,23 +213,54 @@ pub async fn sni_proxy(
   let read_buf = recording_reader.buf();
 
   // Determine server hostname from SNI hostname.
-  let (server_host, forward) = sni_map.host_mappings.get(&sni_hostname).ok_or_else(|| {
-    Error::new(
-      ErrorKind::InvalidData,
-      format!("unknown SNI hostname: {}", sni_hostname),
-    )
-  })?;
+  let sni_hostname_display = sni_hostname.as_deref().unwrap_or("<none>");
+  let (rule, server_hosts, forward) =
+    sni_map.get(sni_hostname.as_deref()).await.ok_or_else(|| {
+      Error::new(
+        ErrorKind::InvalidData,
+        format!("unknown SNI hostname: {}", sni_hostname_display),
+      )
+    })?;
 
   debug!(
-    "SNI proxy for {:?} ({}) -> {}",
-    &client_socket, &sni_hostname, server_host
+    "SNI proxy for {:?} ({}) matched {} -> {}",
+    &client_socket,
+    sni_hostname_display,
+    rule,
+    server_hosts.join(",")
   );
 
+  let traffic = sni_map.traffic_for(&rule);
+  let _connection = ActiveConnection::new(&traffic);
+  let local_socket = client_stream.local_addr()?;
   // remember to send TLS handshake bytes to the server as well
   let client_stream = PrefixedReaderWriter::new(client_stream, read_buf);
-  tcp::process_generic_stream(Box::new(client_stream), &client_socket, forward.clone())
-    .await
-    .map_err(|e| anyhow!("(SNI {}) {:?}", &sni_hostname, e))
+  tcp::process_generic_stream(
+    Box::new(client_stream),
+    &client_socket,
+    &local_socket,
+    forward,
+    idle_timeout,
+    &traffic,
+  )
+  .await
+  .map_err(|e| anyhow!("(SNI {}) {:?}", sni_hostname_display, e))
+}
+
+/// Counts a connection in `Traffic::active_connections` until dropped.
+struct ActiveConnection<'a>(&'a Traffic);
+
+impl<'a> ActiveConnection<'a> {
+  fn new(traffic: &'a Traffic) -> Self {
+    traffic.active_connections.fetch_add(1, Ordering::Relaxed);
+    Self(traffic)
+  }
+}
+
+impl Drop for ActiveConnection<'_> {
+  fn drop(&mut self) {
+    self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+  }
 }
 
 #[pin_project]
@@ -308,9 +509,10 @@ impl<R: AsyncRead> AsyncRead for HandshakeRecordReader<R> {
   }
 }
 
+/// The SNI hostname, `None` if the ClientHello has none.
 async fn read_sni_host_name_from_client_hello<R: AsyncRead>(
   mut reader: Pin<&mut R>,
-) -> io::Result<String> {
+) -> io::Result<Option<String>> {
   // Handshake message type.
   const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
   let typ = reader.read_u8().await?;
@@ -338,11 +540,17 @@ async fn read_sni_host_name_from_client_hello<R: AsyncRead>(
   skip_vec_u16(reader.as_mut()).await?;
   skip_vec_u8(reader.as_mut()).await?;
 
-  // Extensions.
+  // Extensions, which may be absent altogether.
+  if reader.limit() == 0 {
+    return Ok(None);
+  }
   let ext_len = reader.read_u16().await?;
   let new_limit = min(reader.limit(), ext_len.into());
   reader.set_limit(new_limit);
   loop {
+    if reader.limit() == 0 {
+      return Ok(None);
+    }
     // Extension type & length.
     let ext_typ = reader.read_u16().await?;
     let ext_len = reader.read_u16().await?;
@@ -377,6 +585,7 @@ async fn read_sni_host_name_from_client_hello<R: AsyncRead>(
       let mut name_buf = vec![0; name_len.into()];
       reader.read_exact(&mut name_buf).await?;
       return String::from_utf8(name_buf)
+        .map(Some)
         .map_err(|err| io::Error::new(ErrorKind::InvalidData, err));
     }
   }
@@ -411,3 +620,100 @@ async fn read_u24<R: AsyncRead>(mut reader: Pin<&mut R>) -> io::Result<u32> {
     .await
     .map(|_| NetworkEndian::read_u24(&buf))
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn rules(rules: &[&str]) -> HashMap<String, ()> {
+    rules.iter().map(|x| (x.to_string(), ())).collect()
+  }
+
+  fn matched<'a>(mappings: &'a HashMap<String, ()>, host: Option<&str>) -> Option<&'a str> {
+    match_rule(mappings, host).map(|(rule, _)| rule)
+  }
+
+  #[test]
+  fn exact_hostnames_match_first() {
+    let mappings = rules(&["play.udon.dance", "*.udon.dance", "default"]);
+    assert_eq!(
+      matched(&mappings, Some("play.udon.dance")),
+      Some("play.udon.dance")
+    );
+    assert_eq!(
+      matched(&mappings, Some("Play.Udon.Dance.")),
+      Some("play.udon.dance")
+    );
+  }
+
+  #[test]
+  fn longest_wildcard_wins() {
+    let mappings = rules(&["*.udon.dance", "*.play.udon.dance"]);
+    assert_eq!(
+      matched(&mappings, Some("cdn1.play.udon.dance")),
+      Some("*.play.udon.dance")
+    );
+    assert_eq!(
+      matched(&mappings, Some("api.udon.dance")),
+      Some("*.udon.dance")
+    );
+    assert_eq!(matched(&mappings, Some("udon.dance")), None);
+    assert_eq!(matched(&mappings, Some("kiva.moe")), None);
+  }
+
+  #[test]
+  fn default_matches_the_rest() {
+    let mappings = rules(&["*.udon.dance", "default"]);
+    assert_eq!(matched(&mappings, Some("kiva.moe")), Some("default"));
+    assert_eq!(matched(&mappings, None), Some("default"));
+    assert_eq!(matched(&rules(&["*.udon.dance"]), None), None);
+  }
+
+  #[test]
+  fn bad_wildcards_are_rejected() {
+    for host in ["*udon.dance", "*.", "play.*.dance", "*.*.dance"] {
+      let proxy_targets = HashMap::from([(host.to_string(), vec!["h1:443".to_string()])]);
+      assert!(
+        SniMap::new(proxy_targets, Default::default(), None).is_err(),
+        "{}",
+        host
+      );
+    }
+  }
+
+  /// A ClientHello with no extensions, or only the SNI one for `host`.
+  fn client_hello(host: Option<&str>) -> Vec<u8> {
+    let mut body = vec![3, 3];
+    body.extend([0; 32]);
+    // Session ID, one cipher suite, null compression.
+    body.extend([0, 0, 2, 0x13, 0x01, 1, 0]);
+    if let Some(host) = host {
+      let name_len = host.len() as u16;
+      let mut ext = vec![0, 0];
+      ext.extend((name_len + 5).to_be_bytes());
+      ext.extend((name_len + 3).to_be_bytes());
+      ext.push(0);
+      ext.extend(name_len.to_be_bytes());
+      ext.extend(host.as_bytes());
+      body.extend((ext.len() as u16).to_be_bytes());
+      body.extend(ext);
+    }
+    let mut msg = vec![1];
+    msg.extend(&(body.len() as u32).to_be_bytes()[1..]);
+    msg.extend(body);
+    msg
+  }
+
+  #[tokio::test]
+  async fn client_hello_without_sni_is_read() {
+    for host in [Some("play.udon.dance"), None] {
+      let msg = client_hello(host);
+      let reader = msg.as_slice();
+      pin!(reader);
+      assert_eq!(
+        read_sni_host_name_from_client_hello(reader).await.unwrap(),
+        host.map(String::from)
+      );
+    }
+  }
+}
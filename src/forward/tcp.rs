@@ -1,27 +1,49 @@
 use std::sync::{
-  atomic::{AtomicUsize, Ordering},
+  atomic::{AtomicU64, AtomicUsize, Ordering},
   Arc,
 };
 
 use futures::join;
-use log::{debug, error};
-use tokio::net::TcpStream;
+use log::{debug, error, warn};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
 
 use crate::forward::{
   async_stream::AsyncStream,
   copy_bidirectional::copy_bidirectional,
+  dns::DnsCache,
   location::{Location, NetLocation},
-  tokio_util::resolve_host,
+  tokio_util::IpVersion,
+  tunnel::HttpTunnel,
+  UnhealthyUpstreams,
 };
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct TargetLocationData {
   pub location: Location,
+  pub ip_version: IpVersion,
 }
 
+#[derive(Debug)]
 pub struct TargetData {
   pub location_data: Vec<TargetLocationData>,
   pub next_address_index: AtomicUsize,
   pub tcp_nodelay: bool,
+  /// Sends a PROXY protocol v1 header first, so the upstream knows the client.
+  pub proxy_protocol: bool,
+  pub unhealthy: UnhealthyUpstreams,
+  pub dns: Arc<DnsCache>,
+  /// Upstreams it doesn't bypass are connected to through it.
+  pub tunnel: Option<Arc<HttpTunnel>>,
+}
+
+/// Bytes forwarded and connections open, added to while copying.
+#[derive(Debug, Default)]
+pub struct Traffic {
+  /// From the client to the upstream.
+  pub up_bytes: AtomicU64,
+  /// From the upstream to the client.
+  pub down_bytes: AtomicU64,
+  pub active_connections: AtomicU64,
 }
 
 const BUFFER_SIZE: usize = 8192;
@@ -29,26 +51,26 @@ const BUFFER_SIZE: usize = 8192;
 pub async fn process_generic_stream<T: AsyncStream>(
   mut source_stream: Box<T>,
   addr: &std::net::SocketAddr,
+  local_addr: &std::net::SocketAddr,
   target_data: Arc<TargetData>,
+  idle_timeout: std::time::Duration,
+  traffic: &Traffic,
 ) -> std::io::Result<()> {
-  let target_location = if target_data.location_data.len() > 1 {
-    // fetch_add wraps around on overflow.
-    let index = target_data
-      .next_address_index
-      .fetch_add(1, Ordering::Relaxed);
-    &target_data.location_data[index % target_data.location_data.len()]
-  } else {
-    &target_data.location_data[0]
+  let (target_location, mut target_stream) = match setup_target_stream(addr, &target_data).await {
+    Ok(s) => s,
+    Err(e) => {
+      source_stream.try_shutdown().await?;
+      return Err(e);
+    }
   };
 
-  let mut target_stream =
-    match setup_target_stream(addr, &target_location, target_data.tcp_nodelay).await {
-      Ok(s) => s,
-      Err(e) => {
-        source_stream.try_shutdown().await?;
-        return Err(e);
-      }
-    };
+  if target_data.proxy_protocol {
+    let header = proxy_protocol_header(addr, local_addr);
+    if let Err(e) = target_stream.write_all(header.as_bytes()).await {
+      let (_, _) = join!(source_stream.try_shutdown(), target_stream.try_shutdown());
+      return Err(e);
+    }
+  }
 
   debug!(
     "Copying: {}:{} to {}",
@@ -57,7 +79,15 @@ pub async fn process_generic_stream<T: AsyncStream>(
     &target_location.location,
   );
 
-  let copy_result = copy_bidirectional(&mut source_stream, &mut target_stream, BUFFER_SIZE).await;
+  let copy_result = copy_bidirectional(
+    &mut source_stream,
+    &mut target_stream,
+    BUFFER_SIZE,
+    idle_timeout,
+    &traffic.up_bytes,
+    &traffic.down_bytes,
+  )
+  .await;
 
   debug!(
     "Shutdown: {}:{} to {}",
@@ -80,16 +110,147 @@ pub async fn process_generic_stream<T: AsyncStream>(
   Ok(())
 }
 
-async fn setup_target_stream(
+/// The PROXY protocol v1 header of a connection from `src` to `dst`.
+fn proxy_protocol_header(src: &std::net::SocketAddr, dst: &std::net::SocketAddr) -> String {
+  let protocol = match (src, dst) {
+    (std::net::SocketAddr::V4(_), std::net::SocketAddr::V4(_)) => "TCP4",
+    (std::net::SocketAddr::V6(_), std::net::SocketAddr::V6(_)) => "TCP6",
+    // The header can't mix families.
+    _ => return "PROXY UNKNOWN\r\n".to_string(),
+  };
+  format!(
+    "PROXY {} {} {} {} {}\r\n",
+    protocol,
+    src.ip(),
+    dst.ip(),
+    src.port(),
+    dst.port()
+  )
+}
+
+/// Connects to the next healthy upstream in round-robin order. When none of
+/// them is healthy, tries them all anyway, the health check may be stale.
+async fn setup_target_stream<'a>(
+  addr: &std::net::SocketAddr,
+  target_data: &'a TargetData,
+) -> std::io::Result<(&'a TargetLocationData, Box<TcpStream>)> {
+  let len = target_data.location_data.len();
+  // fetch_add wraps around on overflow. It also rotates the addresses of
+  // each upstream.
+  let start = target_data
+    .next_address_index
+    .fetch_add(1, Ordering::Relaxed);
+  let candidates = (0..len)
+    .map(|i| &target_data.location_data[(start + i) % len])
+    .collect::<Vec<_>>();
+  let unhealthy = target_data.unhealthy.read().await.clone();
+  let (healthy, skipped): (Vec<&TargetLocationData>, Vec<_>) = candidates
+    .iter()
+    .partition(|x| !unhealthy.contains(&x.location.to_string()));
+  for target_location in &skipped {
+    warn!("Skipping unhealthy upstream {}", target_location.location);
+  }
+  let candidates = match healthy.is_empty() {
+    true => {
+      warn!("All upstreams are unhealthy, trying them all anyway");
+      candidates
+    }
+    false => healthy,
+  };
+
+  let mut last_error = None;
+  for target_location in candidates {
+    match connect_target(addr, target_location, target_data, start).await {
+      Ok(stream) => return Ok((target_location, stream)),
+      Err(e) => {
+        warn!(
+          "Failed to connect to upstream {}: {}",
+          target_location.location, e
+        );
+        last_error = Some(e);
+      }
+    }
+  }
+  Err(
+    last_error
+      .unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "No upstream")),
+  )
+}
+
+/// Connects to `target_location` through the tunnel, if there is one and
+/// the upstream doesn't bypass it, or else directly.
+async fn connect_target(
+  addr: &std::net::SocketAddr,
+  target_location: &TargetLocationData,
+  target_data: &TargetData,
+  index: usize,
+) -> std::io::Result<Box<TcpStream>> {
+  match (&target_location.location, &target_data.tunnel) {
+    (Location::Address(location), Some(tunnel)) if !tunnel.bypasses(&location.address) => {
+      let tcp_stream = tunnel.connect(location).await?;
+      debug!("Connected to remote: {} through {:?}", addr, tunnel);
+      Ok(Box::new(tcp_stream))
+    }
+    _ => connect_address(addr, target_location, target_data, index).await,
+  }
+}
+
+/// Connects to an address of `target_location`, starting from the `index`th
+/// and skipping the ones that failed recently, unless they all did.
+async fn connect_address(
   addr: &std::net::SocketAddr,
   target_location: &TargetLocationData,
-  tcp_nodelay: bool,
+  target_data: &TargetData,
+  index: usize,
 ) -> std::io::Result<Box<TcpStream>> {
   match target_location.location {
     Location::Address(NetLocation { ref address, port }) => {
-      let target_addr = resolve_host((address.as_str(), port)).await?;
-      let tcp_stream = TcpStream::connect(target_addr).await?;
-      if tcp_nodelay {
+      let addresses = target_data
+        .dns
+        .resolve(address, port)
+        .await?
+        .into_iter()
+        .filter(|x| target_location.ip_version.matches(x))
+        .collect::<Vec<_>>();
+      let (alive, dead): (Vec<_>, Vec<_>) = addresses
+        .into_iter()
+        .partition(|x| !target_data.dns.is_dead(x));
+      let candidates = match alive.is_empty() {
+        true => dead,
+        false => alive,
+      };
+      if candidates.is_empty() {
+        return Err(std::io::Error::new(
+          std::io::ErrorKind::AddrNotAvailable,
+          "Unable to resolve host",
+        ));
+      }
+
+      let mut tcp_stream = None;
+      let mut last_error = None;
+      for i in 0..candidates.len() {
+        let target_addr = candidates[(index + i) % candidates.len()];
+        match TcpStream::connect(target_addr).await {
+          Ok(stream) => {
+            tcp_stream = Some(stream);
+            break;
+          }
+          Err(e) => {
+            warn!(
+              "Failed to connect to {} ({}): {}",
+              target_location.location, target_addr, e
+            );
+            target_data.dns.mark_dead(target_addr);
+            last_error = Some(e);
+          }
+        }
+      }
+      let tcp_stream = match (tcp_stream, last_error) {
+        (Some(tcp_stream), _) => tcp_stream,
+        (None, Some(e)) => return Err(e),
+        (None, None) => unreachable!("there is at least one candidate"),
+      };
+      if target_data.tcp_nodelay {
         if let Err(e) = tcp_stream.set_nodelay(true) {
           error!("Failed to set tcp_nodelay on target stream: {}", e);
         }
@@ -104,3 +265,352 @@ async fn setup_target_stream(
     }
   }
 }
+
+#[cfg(test)]
+mod test {
+  use tokio::{io::AsyncReadExt, net::TcpListener};
+
+  use super::*;
+  use crate::forward::dns::test::MockResolver;
+
+  const IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
+
+  /// An upstream answering every connection with `name`.
+  async fn mock_upstream(name: u8) -> TargetLocationData {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+      while let Ok((mut stream, _)) = listener.accept().await {
+        let _ = stream.write_all(&[name]).await;
+      }
+    });
+    TargetLocationData {
+      location: Location::Address(NetLocation {
+        address: "127.0.0.1".to_string(),
+        port,
+      }),
+      ip_version: IpVersion::Any,
+    }
+  }
+
+  #[tokio::test]
+  async fn upstreams_are_picked_round_robin() {
+    let target_data = Arc::new(TargetData {
+      location_data: vec![mock_upstream(b'a').await, mock_upstream(b'b').await],
+      next_address_index: Default::default(),
+      tcp_nodelay: false,
+      proxy_protocol: false,
+      unhealthy: Default::default(),
+      dns: Default::default(),
+      tunnel: None,
+    });
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    tokio::spawn(async move {
+      while let Ok((stream, client)) = proxy.accept().await {
+        let target_data = target_data.clone();
+        tokio::spawn(async move {
+          let local = stream.local_addr().unwrap();
+          let _ = process_generic_stream(
+            Box::new(stream),
+            &client,
+            &local,
+            target_data,
+            IDLE_TIMEOUT,
+            &Default::default(),
+          )
+          .await;
+        });
+      }
+    });
+
+    let mut picked = vec![];
+    for _ in 0..4 {
+      let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+      picked.push(client.read_u8().await.unwrap());
+    }
+    assert_eq!(picked, b"abab");
+  }
+
+  async fn first_byte(target_data: Arc<TargetData>) -> u8 {
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    tokio::spawn(async move {
+      let (stream, client) = proxy.accept().await.unwrap();
+      let local = stream.local_addr().unwrap();
+      let _ = process_generic_stream(
+        Box::new(stream),
+        &client,
+        &local,
+        target_data,
+        IDLE_TIMEOUT,
+        &Default::default(),
+      )
+      .await;
+    });
+    let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+    client.read_u8().await.unwrap()
+  }
+
+  #[tokio::test]
+  async fn unhealthy_upstreams_are_skipped() {
+    // Nothing listens there once the listener is dropped.
+    let port = TcpListener::bind("127.0.0.1:0")
+      .await
+      .unwrap()
+      .local_addr()
+      .unwrap()
+      .port();
+    let down = TargetLocationData {
+      location: Location::Address(NetLocation {
+        address: "127.0.0.1".to_string(),
+        port,
+      }),
+      ip_version: IpVersion::Any,
+    };
+    let up = mock_upstream(b'a').await;
+    let sni_map = Arc::new(
+      crate::forward::SniMap::new(
+        [(
+          "a.com".to_string(),
+          vec![down.location.to_string(), up.location.to_string()],
+        )]
+        .into(),
+        Default::default(),
+        None,
+      )
+      .unwrap(),
+    );
+    let checker =
+      crate::forward::HealthChecker::new(sni_map.clone(), std::time::Duration::from_secs(30));
+    checker.check().await;
+    let unhealthy = sni_map.unhealthy.clone();
+    assert!(unhealthy.read().await.contains(&down.location.to_string()));
+    assert_eq!(unhealthy.read().await.len(), 1);
+
+    let target_data = Arc::new(TargetData {
+      location_data: vec![down, up],
+      next_address_index: Default::default(),
+      tcp_nodelay: false,
+      proxy_protocol: false,
+      unhealthy,
+      dns: Default::default(),
+      tunnel: None,
+    });
+    for _ in 0..2 {
+      assert_eq!(first_byte(target_data.clone()).await, b'a');
+    }
+  }
+
+  #[tokio::test]
+  async fn dead_addresses_are_skipped() {
+    // Nothing listens there once the listener is dropped.
+    let down = TcpListener::bind("127.0.0.1:0")
+      .await
+      .unwrap()
+      .local_addr()
+      .unwrap();
+    let Location::Address(NetLocation { port, .. }) = mock_upstream(b'a').await.location;
+    let up = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+    let resolver = Arc::new(MockResolver::default());
+    *resolver.addresses.lock().unwrap() = vec![down, up];
+    let dns = Arc::new(DnsCache::new(resolver, std::time::Duration::from_secs(60)));
+    let target_data = Arc::new(TargetData {
+      location_data: vec![TargetLocationData {
+        location: Location::Address(NetLocation {
+          address: "upstream.test".to_string(),
+          port: 443,
+        }),
+        ip_version: IpVersion::Any,
+      }],
+      next_address_index: Default::default(),
+      tcp_nodelay: false,
+      proxy_protocol: false,
+      unhealthy: Default::default(),
+      dns: dns.clone(),
+      tunnel: None,
+    });
+    for _ in 0..3 {
+      assert_eq!(first_byte(target_data.clone()).await, b'a');
+    }
+    assert!(dns.is_dead(&down));
+    assert!(!dns.is_dead(&up));
+  }
+
+  #[tokio::test]
+  async fn forwarded_bytes_are_counted_while_copying() {
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    let target_data = Arc::new(TargetData {
+      location_data: vec![TargetLocationData {
+        location: Location::Address(NetLocation {
+          address: "127.0.0.1".to_string(),
+          port,
+        }),
+        ip_version: IpVersion::Any,
+      }],
+      next_address_index: Default::default(),
+      tcp_nodelay: false,
+      proxy_protocol: false,
+      unhealthy: Default::default(),
+      dns: Default::default(),
+      tunnel: None,
+    });
+    let traffic = Arc::new(Traffic::default());
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let proxy_traffic = traffic.clone();
+    tokio::spawn(async move {
+      let (stream, client) = proxy.accept().await.unwrap();
+      let local = stream.local_addr().unwrap();
+      let _ = process_generic_stream(
+        Box::new(stream),
+        &client,
+        &local,
+        target_data,
+        IDLE_TIMEOUT,
+        &proxy_traffic,
+      )
+      .await;
+    });
+
+    let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+    client.write_all(b"hello").await.unwrap();
+    let (mut stream, _) = upstream.accept().await.unwrap();
+    stream.read_exact(&mut [0; 5]).await.unwrap();
+    stream.write_all(b"hi").await.unwrap();
+    client.read_exact(&mut [0; 2]).await.unwrap();
+    // Both streams are still open.
+    assert_eq!(traffic.up_bytes.load(Ordering::Relaxed), 5);
+    assert_eq!(traffic.down_bytes.load(Ordering::Relaxed), 2);
+  }
+
+  #[tokio::test]
+  async fn proxy_protocol_header_is_sent_first() {
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    let target_data = Arc::new(TargetData {
+      location_data: vec![TargetLocationData {
+        location: Location::Address(NetLocation {
+          address: "127.0.0.1".to_string(),
+          port,
+        }),
+        ip_version: IpVersion::Any,
+      }],
+      next_address_index: Default::default(),
+      tcp_nodelay: false,
+      proxy_protocol: true,
+      unhealthy: Default::default(),
+      dns: Default::default(),
+      tunnel: None,
+    });
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    tokio::spawn(async move {
+      let (stream, client) = proxy.accept().await.unwrap();
+      let local = stream.local_addr().unwrap();
+      let _ = process_generic_stream(
+        Box::new(stream),
+        &client,
+        &local,
+        target_data,
+        IDLE_TIMEOUT,
+        &Default::default(),
+      )
+      .await;
+    });
+
+    let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+    client.write_all(b"hello").await.unwrap();
+    let client_addr = client.local_addr().unwrap();
+    let (mut stream, _) = upstream.accept().await.unwrap();
+    let expected = format!(
+      "PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nhello",
+      client_addr.port(),
+      proxy_addr.port()
+    );
+    let mut received = vec![0; expected.len()];
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(String::from_utf8(received).unwrap(), expected);
+  }
+
+  #[tokio::test]
+  async fn idle_connections_are_closed() {
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    let target_data = Arc::new(TargetData {
+      location_data: vec![TargetLocationData {
+        location: Location::Address(NetLocation {
+          address: "127.0.0.1".to_string(),
+          port,
+        }),
+        ip_version: IpVersion::Any,
+      }],
+      next_address_index: Default::default(),
+      tcp_nodelay: false,
+      proxy_protocol: false,
+      unhealthy: Default::default(),
+      dns: Default::default(),
+      tunnel: None,
+    });
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let idle_timeout = std::time::Duration::from_millis(300);
+    tokio::spawn(async move {
+      while let Ok((stream, client)) = proxy.accept().await {
+        let target_data = target_data.clone();
+        tokio::spawn(async move {
+          let local = stream.local_addr().unwrap();
+          let _ = process_generic_stream(
+            Box::new(stream),
+            &client,
+            &local,
+            target_data,
+            idle_timeout,
+            &Default::default(),
+          )
+          .await;
+        });
+      }
+    });
+
+    let start = std::time::Instant::now();
+    let mut clients = vec![];
+    let mut upstreams = vec![];
+    for _ in 0..2 {
+      clients.push(TcpStream::connect(proxy_addr).await.unwrap());
+      upstreams.push(upstream.accept().await.unwrap().0);
+    }
+    // Bytes keep the connection open past the timeout.
+    tokio::time::sleep(idle_timeout / 2).await;
+    clients[0].write_all(b"hi").await.unwrap();
+    let mut buf = [0; 2];
+    upstreams[0].read_exact(&mut buf).await.unwrap();
+
+    // The idle one goes first, then the other one a timeout after its bytes.
+    for (client, closed_after) in [(1, idle_timeout), (0, idle_timeout * 3 / 2)] {
+      let read = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        clients[client].read(&mut buf),
+      )
+      .await
+      .unwrap();
+      assert_eq!(read.unwrap_or(0), 0);
+      assert!(start.elapsed() >= closed_after, "{:?}", start.elapsed());
+    }
+    for upstream in &mut upstreams {
+      assert_eq!(upstream.read(&mut buf).await.unwrap_or(0), 0);
+    }
+  }
+
+  #[test]
+  fn proxy_protocol_header_needs_one_family() {
+    let v4 = "1.2.3.4:5".parse().unwrap();
+    let v6 = "[::1]:6".parse().unwrap();
+    assert_eq!(
+      proxy_protocol_header(&v6, &v6),
+      "PROXY TCP6 ::1 ::1 6 6\r\n"
+    );
+    assert_eq!(proxy_protocol_header(&v4, &v6), "PROXY UNKNOWN\r\n");
+  }
+}
@@ -1,11 +1,47 @@
 use tokio::net::{lookup_host, ToSocketAddrs};
 
-pub async fn resolve_host<T>(host: T) -> std::io::Result<std::net::SocketAddr>
+/// Which address family to connect with, when a host has both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum IpVersion {
+  V4,
+  V6,
+  #[default]
+  Any,
+}
+
+impl IpVersion {
+  pub fn matches(&self, addr: &std::net::SocketAddr) -> bool {
+    match self {
+      IpVersion::V4 => addr.is_ipv4(),
+      IpVersion::V6 => addr.is_ipv6(),
+      IpVersion::Any => true,
+    }
+  }
+}
+
+pub async fn resolve_host<T>(
+  host: T,
+  ip_version: IpVersion,
+) -> std::io::Result<std::net::SocketAddr>
 where
   T: ToSocketAddrs,
 {
   lookup_host(host)
     .await?
-    .next()
+    .find(|addr| ip_version.matches(addr))
     .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "Unable to resolve host"))
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[tokio::test]
+  async fn addresses_are_picked_by_family() {
+    let v4 = resolve_host("127.0.0.1:80", IpVersion::V4).await.unwrap();
+    assert!(v4.is_ipv4());
+    assert!(resolve_host("127.0.0.1:80", IpVersion::V6).await.is_err());
+    let v6 = resolve_host("[::1]:80", IpVersion::Any).await.unwrap();
+    assert!(v6.is_ipv6());
+  }
+}
@@ -0,0 +1,169 @@
+use std::io::{Error, ErrorKind};
+
+use anyhow::{anyhow, bail};
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::TcpStream,
+};
+
+use crate::forward::location::NetLocation;
+
+/// Longest response to a CONNECT we read before giving up on the proxy.
+const MAX_RESPONSE_HEAD: usize = 8192;
+
+/// An HTTP proxy the SNI proxy reaches its upstreams through, with CONNECT.
+#[derive(Debug, Clone)]
+pub struct HttpTunnel {
+  proxy: NetLocation,
+  /// Hosts connected to directly, a host also bypasses its subdomains.
+  bypass: Vec<String>,
+}
+
+impl HttpTunnel {
+  /// `url` is like `http://127.0.0.1:7890`, `bypass` like `--upstream-proxy-bypass`.
+  pub fn new(url: &str, bypass: &[String]) -> anyhow::Result<Self> {
+    let parsed =
+      reqwest::Url::parse(url).map_err(|e| anyhow!("Bad upstream proxy {}: {}", url, e))?;
+    if parsed.scheme() != "http" {
+      bail!(
+        "The SNI proxy can only tunnel through http:// proxies, not {}",
+        url
+      );
+    }
+    let (Some(address), Some(port)) = (parsed.host_str(), parsed.port_or_known_default()) else {
+      bail!("Bad upstream proxy {}: no host", url);
+    };
+    Ok(Self {
+      proxy: NetLocation {
+        address: address.to_string(),
+        port,
+      },
+      bypass: bypass
+        .iter()
+        .map(|x| x.trim().trim_start_matches('.').to_lowercase())
+        .filter(|x| !x.is_empty())
+        .collect(),
+    })
+  }
+
+  pub fn bypasses(&self, host: &str) -> bool {
+    let host = host.to_lowercase();
+    self
+      .bypass
+      .iter()
+      .any(|x| host == *x || host.ends_with(&format!(".{}", x)))
+  }
+
+  /// Connects to the proxy and asks it for a tunnel to `target`, the proxy
+  /// resolves it.
+  pub async fn connect(&self, target: &NetLocation) -> std::io::Result<TcpStream> {
+    let mut stream = TcpStream::connect((self.proxy.address.as_str(), self.proxy.port)).await?;
+    stream
+      .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target).as_bytes())
+      .await?;
+
+    // Byte by byte, so nothing after the response head is taken away from
+    // the tunnel.
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+      if head.len() >= MAX_RESPONSE_HEAD {
+        return Err(Error::new(
+          ErrorKind::InvalidData,
+          "CONNECT response too long",
+        ));
+      }
+      head.push(stream.read_u8().await?);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status = head.split_whitespace().nth(1).unwrap_or_default();
+    if status != "200" {
+      return Err(Error::new(
+        ErrorKind::ConnectionRefused,
+        format!(
+          "Proxy {} refused to CONNECT {}: {}",
+          self.proxy,
+          target,
+          head.lines().next().unwrap_or_default()
+        ),
+      ));
+    }
+    Ok(stream)
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+  use std::sync::{Arc, Mutex};
+
+  use tokio::net::TcpListener;
+
+  use super::*;
+
+  /// A CONNECT proxy, keeping the targets it was asked for.
+  pub(crate) async fn mock_connect_proxy() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let targets = Arc::new(Mutex::new(vec![]));
+    let seen = targets.clone();
+    tokio::spawn(async move {
+      while let Ok((mut client, _)) = listener.accept().await {
+        let seen = seen.clone();
+        tokio::spawn(async move {
+          let mut head = vec![];
+          while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await.unwrap());
+          }
+          let head = String::from_utf8(head).unwrap();
+          let target = head.split_whitespace().nth(1).unwrap().to_string();
+          seen.lock().unwrap().push(target.clone());
+          let Ok(mut upstream) = TcpStream::connect(target).await else {
+            let _ = client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
+            return;
+          };
+          client
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+          let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+        });
+      }
+    });
+    (url, targets)
+  }
+
+  #[tokio::test]
+  async fn connections_go_through_the_tunnel() {
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = NetLocation {
+      address: "127.0.0.1".to_string(),
+      port: upstream.local_addr().unwrap().port(),
+    };
+    tokio::spawn(async move {
+      let (mut stream, _) = upstream.accept().await.unwrap();
+      stream.write_all(b"hi").await.unwrap();
+    });
+    let (url, targets) = mock_connect_proxy().await;
+    let tunnel = HttpTunnel::new(&url, &[]).unwrap();
+
+    let mut stream = tunnel.connect(&target).await.unwrap();
+    let mut received = [0; 2];
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"hi");
+    assert_eq!(*targets.lock().unwrap(), vec![target.to_string()]);
+  }
+
+  #[test]
+  fn hosts_and_subdomains_are_bypassed() {
+    let tunnel = HttpTunnel::new(
+      "http://127.0.0.1:7890",
+      &["kiva.moe".to_string(), ".udon.dance".to_string()],
+    )
+    .unwrap();
+    assert!(tunnel.bypasses("kiva.moe"));
+    assert!(tunnel.bypasses("ud-nya.KIVA.moe"));
+    assert!(tunnel.bypasses("api.udon.dance"));
+    assert!(!tunnel.bypasses("notkiva.moe"));
+    assert!(HttpTunnel::new("socks5h://127.0.0.1:7890", &[]).is_err());
+    assert!(HttpTunnel::new("not a url", &[]).is_err());
+  }
+}
@@ -2,39 +2,137 @@ use std::{
   collections::HashMap,
   convert::Infallible,
   net::{IpAddr, SocketAddr},
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
 };
 
+use anyhow::{anyhow, bail};
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use ipnet::IpNet;
 use itertools::Either;
-use log::{debug, info, trace, warn};
-use serde_derive::Deserialize;
+use log::{debug, error, info, trace, warn};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 use warp::{
-  addr::remote, http::StatusCode, hyper, path::FullPath, reject::Reject, Filter, Rejection, Reply,
+  addr::remote,
+  http::{Method, StatusCode},
+  hyper,
+  multipart::FormData,
+  path::FullPath,
+  reject::Reject,
+  Filter, Rejection, Reply,
 };
-use warp_real_ip::get_forwarded_for;
 
 use crate::{
   cdn::{
-    proxy::{InspectingOpts, ProxyOpts},
-    receipt::{RoomId, UserId},
+    compensator::CompensatorTask,
+    events::CacheEvent,
+    proxy::InspectingOpts,
+    range::{etag_matches, Conditionals},
+    receipt::{ReceiptEvent, ReceiptId, RoomId, UserId},
     CdnFetchResult,
   },
-  ffmpeg::{ffmpeg_audio_compensation, ffmpeg_copy},
-  types::SongId,
-  AppService,
+  ffmpeg::{
+    ffmpeg_audio_compensation, ffmpeg_copy, ffmpeg_extract_thumbnail, ffmpeg_extract_waveform,
+    ffmpeg_normalize_audio, ffmpeg_probe,
+  },
+  forward::parse_proxy_targets,
+  metrics::{render_metrics, MetricsService, MetricsServiceImpl, ROUTE_AYA, ROUTE_WANNA_DANCE},
+  types::{CategoryId, SongId},
+  AppOpts, AppService,
 };
 
-pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
+/// Serves HTTP until `shutdown` fires, then stops accepting connections and
+/// waits for the ones in flight to finish.
+pub async fn serve_video_http(
+  app: AppService,
+  shutdown: tokio::sync::oneshot::Receiver<()>,
+) -> crate::Result<()> {
   let socket = app
     .opts
     .listen
     .parse::<SocketAddr>()
     .expect("Failed to parse listen address");
 
+  let routes = routes(&app);
+  let in_flight = app.http_in_flight.clone();
+  let shutdown = async move {
+    let _ = shutdown.await;
+    info!(
+      "HTTP: shutting down, draining {} requests in flight",
+      in_flight.load(Ordering::Relaxed)
+    );
+  };
+
+  match (&app.opts.tls_cert, &app.opts.tls_key) {
+    (Some(cert), Some(key)) => {
+      // The SNI proxy passes TLS through untouched, so it can't share a port
+      // with a server terminating it.
+      if let Some(sni) = app
+        .opts
+        .builtin_sni_listen
+        .as_ref()
+        .filter(|x| !x.is_empty())
+      {
+        if sni.parse::<SocketAddr>().ok() == Some(socket) {
+          bail!(
+            "--listen {} is also --builtin-sni-listen, TLS termination needs a port of its own",
+            socket
+          );
+        }
+      }
+      let cert = read_pem(cert, "certificate").await?;
+      let key = read_pem(key, "private key").await?;
+      let (socket, server) = warp::serve(routes)
+        .tls()
+        .cert(cert)
+        .key(key)
+        .bind_with_graceful_shutdown(socket, shutdown);
+      info!("Listening on https://{}", socket);
+      info!("Have a good day!");
+      server.await;
+    }
+    (None, None) => {
+      let (socket, server) = warp::serve(routes).bind_with_graceful_shutdown(socket, shutdown);
+      info!("Listening on http://{}", socket);
+      info!("Have a good day!");
+      server.await;
+    }
+    _ => bail!("--tls-cert and --tls-key must be set together"),
+  }
+
+  info!("HTTP: all requests drained");
+  Ok(())
+}
+
+/// Reads the PEM file at `path`, failing clearly where warp would panic.
+async fn read_pem(path: &str, what: &str) -> crate::Result<Vec<u8>> {
+  let pem = tokio::fs::read(path).await.map_err(|e| {
+    error!("Failed to read TLS {} {}: {}", what, path, e);
+    anyhow!("Failed to read TLS {} {}: {}", what, path, e)
+  })?;
+  if !String::from_utf8_lossy(&pem).contains("-----BEGIN ") {
+    error!("TLS {} {} is not in PEM format", what, path);
+    bail!("TLS {} {} is not in PEM format", what, path);
+  }
+  Ok(pem)
+}
+
+/// Most songs `POST /api/{v}/songs/exists` checks at once.
+const MAX_SONGS_EXIST_IDS: usize = 1000;
+
+pub fn routes(
+  app: &AppService,
+) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
   let aya_root = warp::get()
     .and(warp::path!("aya-api" / String / "aya"))
-    .and(with_service(&app))
-    .and(real_ip())
+    .and(with_service(app))
+    .and(real_ip(app))
     .and_then(
       |version: String, _app: AppService, remote: Option<IpAddr>| async move {
         Ok::<_, Rejection>(
@@ -50,8 +148,8 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
 
   let aya_videos = warp::get()
     .and(warp::path!("api" / String / "videos" / String))
-    .and(with_service(&app))
-    .and(real_ip())
+    .and(with_service(app))
+    .and(real_ip(app))
     .and_then(
       |_version: String, id_mp4: String, app: AppService, remote: Option<IpAddr>| async move {
         let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
@@ -59,6 +157,10 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
           .trim_end_matches(".mp4")
           .parse::<SongId>()
           .map_err(|_| warp::reject::custom(CustomRejection::BadVideoId))?;
+        if let Err(retry_after) = app.token_rate_limiter.hit(remote).await {
+          warn!("Too many token requests, id={}, client={}", id, remote);
+          return Ok(too_many_requests(retry_after));
+        }
         let serve = app
           .cdn
           .serve_token(id, remote)
@@ -71,9 +173,31 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
               "[MISS] Cache {} miss: redirect to https://api.udon.dance",
               id
             );
-            format!("https://api.udon.dance/Api/Songs/play?id={}", id)
+            app
+              .metrics
+              .cdn_cache_misses
+              .with_label_values(&[ROUTE_AYA])
+              .inc();
+            let location = format!("https://api.udon.dance/Api/Songs/play?id={}", id);
+            app.cache_events.emit(CacheEvent::Miss {
+              id,
+              redirected_to: Some(location.clone()),
+            });
+            location
+          }
+          CdnFetchResult::Missing(status) => {
+            info!(
+              "[MISS] Cache {} miss: upstream answered {} recently",
+              id, status
+            );
+            return Ok(missing_upstream(id, status));
           }
           CdnFetchResult::Hit(token) => {
+            app
+              .metrics
+              .tokens_issued
+              .with_label_values(&[ROUTE_AYA])
+              .inc();
             // Found in our CDN, let's redirect to the resource gateway.
             // Note: in prior versions, we used the format `{token}.mp4`,
             // which turned out it's not caching-friendly.
@@ -90,18 +214,24 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
     );
 
   let aya_video_files = warp::get()
+    .or(warp::head())
+    .unify()
     .and(warp::path!("v" / String))
     .and(warp::path::end())
+    .and(warp::method())
     .and(warp::query::<HashMap<String, String>>())
-    .and(with_service(&app))
-    .and(real_ip())
+    .and(with_service(app))
+    .and(real_ip(app))
     .and(crate::cdn::range::filter_range())
+    .and(crate::cdn::range::filter_conditionals())
     .and_then(
       |id_mp4: String,
+       method: Method,
        qs: HashMap<String, String>,
        app: AppService,
        remote: Option<IpAddr>,
-       range: Option<String>| async move {
+       range: Option<String>,
+       conditionals: Conditionals| async move {
         let id = id_mp4
           .trim_end_matches(".mp4")
           .parse::<SongId>()
@@ -117,9 +247,9 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
           //   return Err(warp::reject::custom(CustomRejection::BadToken));
           // }
         };
-        let backing_cdn = match qs.get("t") {
-          Some(t) if t == "wd" => &app.cdn,
-          _ => &app.cdn,
+        let (backing_cdn, route) = match qs.get("t") {
+          Some(t) if t == "wd" => (&app.cdn, ROUTE_WANNA_DANCE),
+          _ => (&app.cdn, ROUTE_AYA),
         };
         let video_file = match backing_cdn
           .serve_file(Some(id), token, remote.clone())
@@ -135,108 +265,369 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
           }
           Err(e) => {
             warn!("Bad token, id={}, client={}: {:?}", id, remote, e);
+            app.metrics.token_verification_failures.inc();
             return Err(warp::reject::custom(CustomRejection::BadToken));
           }
         };
 
-        info!("[HIT] Cache {} found: serving {}", id, video_file);
-        serve_video_mp4(app, id, range, video_file, None).await
-      },
-    );
-  //
-  // let aya_song_index_get = warp::get()
-  //   .and(warp::path!("aya-api" / String / "songs"))
-  //   .and(with_service(&app))
-  //   .and(real_ip())
-  //   .and_then(
-  //     |_version: String, app: AppService, remote: Option<IpAddr>| async move {
-  //       let _ =
-  // remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;       let
-  // index = match app.index.get_index(false).await {         Ok(index) =>
-  // index,         Err(e) => {
-  //           warn!("Failed to get index: {:?}", e);
-  //           return Err(warp::reject::custom(CustomRejection::IndexNotReady));
-  //         }
-  //       };
-  //       Ok::<_, Rejection>(warp::reply::json(&index).into_response())
-  //     },
-  //   );
-  //
-  // let aya_song_index_clear = warp::delete()
-  //   .and(warp::path!("aya-api" / String / "songs"))
-  //   .and(with_service(&app))
-  //   .and(real_ip())
-  //   .and_then(
-  //     |_version: String, app: AppService, remote: Option<IpAddr>| async move {
-  //       let _ =
-  // remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;       let
-  // hosts = app         .opts
-  //         .admin_src_host
-  //         .as_ref()
-  //         .ok_or(warp::reject::custom(CustomRejection::AreYouTryingToHackMe))?;
-  //       for host in hosts {
-  //         info!("admin src host: {}", host);
-  //         // If the host is a valid IP, we will check the remote IP
-  //         let ip = match host.parse::<IpAddr>() {
-  //           Ok(ip) => Some(ip),
-  //           // If it is a hostname? `resolve_host` needs a socket address, so
-  // give it a port           Err(_) => {
-  //             match
-  // crate::forward::tokio_util::resolve_host(format!("{}:11451", host)).await {
-  //               Ok(sock) => Some(sock.ip()),
-  //               Err(e) => {
-  //                 warn!(
-  //                   "failed to resolve admin src host {}: {:?}, trying next
-  // one",                   host, e
-  //                 );
-  //                 continue;
-  //               }
-  //             }
-  //           }
-  //         };
-  //
-  //         if ip == remote {
-  //           info!(
-  //             "remote IP matches admin src host: remote={:?}, admin={:?}",
-  //             remote, ip
-  //           );
-  //           info!("admin says to rebuild the index, yes sir! admin={:?}", ip);
-  //           match app.index.get_index(true).await {
-  //             Ok(_) => info!("Index built"),
-  //             Err(e) => warn!("Failed to clear index: {:?}", e),
-  //           }
-  //           return Ok::<_, Rejection>(
-  //             warp::reply::json(&json!({"message": "ok"})).into_response(),
-  //           );
-  //         } else {
-  //           warn!(
-  //             "remote IP does not match admin src host: remote={:?},
-  // admin={:?}",             remote, ip
-  //           );
-  //         }
-  //       }
-  //
-  //       error!(
-  //         "someone is trying to clear the index without permission!
-  // remote={:?}",         remote
-  //       );
-  //       return
-  // Err(warp::reject::custom(CustomRejection::AreYouTryingToHackMe));     },
-  //   );
-  //
-  // let aya_song_index = aya_song_index_get.or(aya_song_index_clear);
+        serve_cache_hit(
+          app,
+          route,
+          id,
+          range,
+          conditionals,
+          video_file,
+          None,
+          method == Method::HEAD,
+        )
+        .await
+      },
+    );
+
+  let aya_thumbnails = warp::get()
+    .and(warp::path!("api" / String / "thumbnails" / String))
+    .and(with_service(app))
+    .and_then(
+      |_version: String, id_webp: String, app: AppService| async move {
+        let id = id_webp
+          .strip_suffix(".webp")
+          .and_then(|x| x.parse::<SongId>().ok())
+          .ok_or_else(|| warp::reject::custom(CustomRejection::BadVideoId))?;
+        serve_thumbnail_webp(app, id).await
+      },
+    );
+
+  let aya_waveforms = warp::get()
+    .and(warp::path!("api" / String / "waveform" / String))
+    .and(with_service(app))
+    .and_then(
+      |_version: String, id_json: String, app: AppService| async move {
+        let id = id_json
+          .strip_suffix(".json")
+          .and_then(|x| x.parse::<SongId>().ok())
+          .ok_or_else(|| warp::reject::custom(CustomRejection::BadVideoId))?;
+        serve_waveform_json(app, id).await
+      },
+    );
+
+  let aya_song_index_get = warp::get()
+    .and(warp::path!("aya-api" / String / "songs"))
+    .and(warp::query::<HashMap<String, String>>())
+    .and(warp::header::headers_cloned())
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(
+      |_version: String,
+       qs: HashMap<String, String>,
+       headers: warp::http::HeaderMap,
+       app: AppService,
+       remote: Option<IpAddr>| async move {
+        let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        let rebuild = qs.get("rebuild").map(|x| x == "true").unwrap_or(false);
+        if rebuild {
+          ensure_admin_src_host(&app, remote).await?;
+          info!("admin {} says to rebuild the index, yes sir!", remote);
+        }
+        let index = match app.index.get_encoded_index(rebuild).await {
+          Ok(index) => index,
+          Err(e) => {
+            warn!("Failed to get index: {:?}", e);
+            return Err(warp::reject::custom(CustomRejection::IndexNotReady));
+          }
+        };
+        let response = warp::http::Response::builder()
+          .header(warp::http::header::ETAG, &index.etag)
+          .header(warp::http::header::VARY, "Accept-Encoding");
+        let not_modified = Conditionals::from_headers(&headers)
+          .if_none_match
+          .is_some_and(|x| etag_matches(&x, &index.etag));
+        let response = match (not_modified, accepts_gzip(&headers)) {
+          (true, _) => response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(hyper::Body::empty()),
+          (false, true) => response
+            .header(warp::http::header::CONTENT_TYPE, "application/json")
+            .header(warp::http::header::CONTENT_ENCODING, "gzip")
+            .body(hyper::Body::from(index.gzip.clone())),
+          (false, false) => response
+            .header(warp::http::header::CONTENT_TYPE, "application/json")
+            .body(hyper::Body::from(index.json.clone())),
+        };
+        Ok::<_, Rejection>(response.unwrap())
+      },
+    );
+
+  let aya_song_index_clear = warp::delete()
+    .and(warp::path!("aya-api" / String / "songs"))
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(
+      |_version: String, app: AppService, remote: Option<IpAddr>| async move {
+        let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        ensure_admin_src_host(&app, remote).await?;
+        info!("admin {} says to rebuild the index, yes sir!", remote);
+        if let Err(e) = app.index.get_index(true).await {
+          warn!("Failed to rebuild index: {:?}", e);
+          return Err(warp::reject::custom(CustomRejection::IndexNotReady));
+        }
+        info!("Index built");
+        Ok::<_, Rejection>(warp::reply::json(&json!({"message": "ok"})).into_response())
+      },
+    );
+
+  let aya_song_index_diff = warp::get()
+    .and(warp::path!("aya-api" / String / "songs" / "diff"))
+    .and(warp::query::<HashMap<String, String>>())
+    .and(with_service(app))
+    .and_then(
+      |_version: String, qs: HashMap<String, String>, app: AppService| async move {
+        let since = qs
+          .get("since")
+          .and_then(|x| x.parse::<i64>().ok())
+          .unwrap_or(0);
+        match app.index.diff(since).await {
+          Ok(Some(diff)) => Ok::<_, Rejection>(warp::reply::json(&diff).into_response()),
+          Ok(None) => Ok(StatusCode::NOT_MODIFIED.into_response()),
+          Err(e) => {
+            warn!("Failed to get index: {:?}", e);
+            Err(warp::reject::custom(CustomRejection::IndexNotReady))
+          }
+        }
+      },
+    );
+
+  let aya_song_index = aya_song_index_get
+    .or(aya_song_index_clear)
+    .or(aya_song_index_diff);
+
+  #[derive(Debug, Clone, Deserialize)]
+  struct SongSearchQuery {
+    #[serde(default)]
+    q: String,
+    limit: Option<usize>,
+    category: Option<CategoryId>,
+  }
+
+  let aya_song_search = warp::get()
+    .and(
+      // `search` is the older path, answering with the songs alone.
+      warp::path!("aya-api" / String / "search")
+        .map(|_version: String| false)
+        .or(warp::path!("aya-api" / String / "songs" / "search").map(|_version: String| true))
+        .unify(),
+    )
+    .and(warp::query::<SongSearchQuery>())
+    .and(with_service(app))
+    .and_then(
+      |with_updated_at: bool, query: SongSearchQuery, app: AppService| async move {
+        let search = match app
+          .index
+          .search(&query.q, query.category, query.limit.unwrap_or(20))
+          .await
+        {
+          Ok(search) => search,
+          Err(e) => {
+            warn!("Failed to get index: {:?}", e);
+            return Err(warp::reject::custom(CustomRejection::IndexNotReady));
+          }
+        };
+        Ok::<_, Rejection>(match with_updated_at {
+          true => warp::reply::json(&search).into_response(),
+          false => warp::reply::json(&search.songs).into_response(),
+        })
+      },
+    );
+
+  let aya_song_get = warp::get()
+    .and(warp::path!("api" / String / "songs" / SongId))
+    .and(with_service(app))
+    .and_then(|_version: String, id: SongId, app: AppService| async move {
+      let song = find_song(&app, id)
+        .await
+        .ok_or_else(warp::reject::not_found)?;
+      Ok::<_, Rejection>(warp::reply::json(&song).into_response())
+    });
+
+  #[derive(Debug, Clone, Deserialize)]
+  struct SongsExist {
+    ids: Vec<SongId>,
+  }
+
+  let aya_songs_exist = warp::post()
+    .and(warp::path!("api" / String / "songs" / "exists"))
+    .and(json_body(app))
+    .and(with_service(app))
+    .and_then(
+      |_version: String, query: SongsExist, app: AppService| async move {
+        if query.ids.len() > MAX_SONGS_EXIST_IDS {
+          return Err(warp::reject::custom(CustomRejection::TooManySongIds));
+        }
+        let (cached, missing): (Vec<_>, Vec<_>) = app
+          .cdn
+          .batch_exists(&query.ids)
+          .await
+          .into_iter()
+          .partition(|(_, available)| *available);
+        Ok::<_, Rejection>(
+          warp::reply::json(&json!({
+            "cached": cached.into_iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            "missing": missing.into_iter().map(|(id, _)| id).collect::<Vec<_>>(),
+          }))
+          .into_response(),
+        )
+      },
+    );
+
+  let aya_token_revoke = warp::delete()
+    .and(warp::path!("aya-api" / String / "tokens" / String))
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(
+      |_version: String, token: String, app: AppService, remote: Option<IpAddr>| async move {
+        let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        ensure_admin_src_host(&app, remote).await?;
+        if !app.cdn.revoke_token(token.clone()).await {
+          return Err(warp::reject::custom(CustomRejection::BadToken));
+        }
+        info!("admin {} revoked token {}", remote, token);
+        Ok::<_, Rejection>(warp::reply::json(&json!({"message": "ok"})).into_response())
+      },
+    );
+
+  let aya_cache_health = warp::get()
+    .and(warp::path!("aya-api" / String / "cache" / "health"))
+    .and(warp::query::<HashMap<String, String>>())
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(
+      |_version: String,
+       qs: HashMap<String, String>,
+       app: AppService,
+       remote: Option<IpAddr>| async move {
+        let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        ensure_admin_src_host(&app, remote).await?;
+        let report = match qs.get("fix").map(|x| x == "true").unwrap_or(false) {
+          true => {
+            info!("admin {} says to fix the cache, yes sir!", remote);
+            app.cdn.fix_integrity().await
+          }
+          false => app.cdn.integrity_report().await,
+        };
+        let mut report = json!(report);
+        report["missing_upstream"] = json!(app.cdn.missing_upstream().await);
+        Ok::<_, Rejection>(warp::reply::json(&report).into_response())
+      },
+    );
+
+  let aya_cache_purge = warp::delete()
+    .and(warp::path!("aya-api" / String / "cache" / SongId))
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(
+      |_version: String, id: SongId, app: AppService, remote: Option<IpAddr>| async move {
+        let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        ensure_admin_src_host(&app, remote).await?;
+        info!("admin {} says to purge song {}, yes sir!", remote, id);
+        let files = app.cdn.purge_song(id).await;
+        Ok::<_, Rejection>(warp::reply::json(&json!({"id": id, "files": files})).into_response())
+      },
+    );
+
+  let aya_cache_prewarm = warp::post()
+    .and(warp::path!("aya-api" / String / "cache" / "prewarm"))
+    .and(admin_ip(app))
+    .and(json_body(app))
+    .and(with_service(app))
+    .and_then(
+      |_version: String, remote: IpAddr, ids: Vec<SongId>, app: AppService| async move {
+        info!(
+          "admin {} says to prewarm {} songs, yes sir!",
+          remote,
+          ids.len()
+        );
+        let job = app.prewarm.start(app.clone(), ids).await;
+        Ok::<_, Rejection>(warp::reply::json(&json!({ "job": job })).into_response())
+      },
+    );
+
+  let aya_cache_prewarm_progress = warp::get()
+    .and(warp::path!(
+      "aya-api" / String / "cache" / "prewarm" / String
+    ))
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(
+      |_version: String, job: String, app: AppService, remote: Option<IpAddr>| async move {
+        let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        ensure_admin_src_host(&app, remote).await?;
+        let progress = app
+          .prewarm
+          .progress(&job)
+          .await
+          .ok_or_else(warp::reject::not_found)?;
+        Ok::<_, Rejection>(warp::reply::json(&progress).into_response())
+      },
+    );
+
+  let aya_sni_stats = warp::get()
+    .and(warp::path!("aya-api" / String / "sni" / "stats"))
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(
+      |_version: String, app: AppService, remote: Option<IpAddr>| async move {
+        let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        ensure_admin_src_host(&app, remote).await?;
+        Ok::<_, Rejection>(warp::reply::json(&app.sni.traffic()).into_response())
+      },
+    );
+
+  let aya_sni_reload = warp::post()
+    .and(warp::path!("aya-api" / String / "sni" / "reload"))
+    .and(admin_ip(app))
+    .and(with_service(app))
+    .and(warp::body::content_length_limit(app.opts.max_body_bytes))
+    .and(warp::body::bytes())
+    .and_then(
+      |_version: String, remote: IpAddr, app: AppService, body: bytes::Bytes| async move {
+        // Same format as --builtin-sni-proxy.
+        let target_defs = String::from_utf8_lossy(&body)
+          .split(',')
+          .map(str::trim)
+          .filter(|x| !x.is_empty())
+          .map(String::from)
+          .collect::<Vec<_>>();
+        if let Err(e) = app.sni.reload(parse_proxy_targets(&target_defs)).await {
+          warn!("admin {} sent bad SNI proxy targets: {:?}", remote, e);
+          return Err(warp::reject::custom(CustomRejection::BadProxyTarget));
+        }
+        info!("admin {} reloaded SNI proxy targets", remote);
+        Ok::<_, Rejection>(warp::reply::json(&app.sni.mappings().await).into_response())
+      },
+    );
 
   // Join them all!
   let aya = aya_root
-    // .or(aya_song_index)
+    .or(aya_song_index)
+    .or(aya_song_search)
     .or(aya_videos)
-    .or(aya_video_files);
+    .or(aya_video_files)
+    .or(aya_thumbnails)
+    .or(aya_waveforms)
+    .or(aya_song_get)
+    .or(aya_songs_exist)
+    .or(aya_token_revoke)
+    .or(aya_cache_health)
+    .or(aya_cache_purge)
+    .or(aya_cache_prewarm)
+    .or(aya_cache_prewarm_progress)
+    .or(aya_sni_stats)
+    .or(aya_sni_reload);
 
   // http://api.udon.dance/Api/Songs/play?id=1021
   let wanna_dance_play = warp::path!("Api" / "Songs" / "play")
     .and(warp::query::<HashMap<String, String>>())
-    .and(with_service(&app))
-    .and(real_ip())
+    .and(with_service(app))
+    .and(real_ip(app))
     .and_then(
       |query: HashMap<String, String>, app: AppService, remote: Option<IpAddr>| async move {
         let id = query
@@ -245,6 +636,10 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
           .parse::<SongId>()
           .map_err(|_| warp::reject::custom(CustomRejection::BadVideoId))?;
         let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        if let Err(retry_after) = app.token_rate_limiter.hit(remote).await {
+          warn!("Too many token requests, id={}, client={}", id, remote);
+          return Ok(too_many_requests(retry_after));
+        }
         let serve = app
           .cdn
           .serve_token(id, remote)
@@ -256,10 +651,32 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
               "[MISS] Cache {} miss: redirect to https://api.udon.dance",
               id
             );
+            app
+              .metrics
+              .cdn_cache_misses
+              .with_label_values(&[ROUTE_WANNA_DANCE])
+              .inc();
             // Not found in our CDN, let's redirect to api.udon.dance
-            format!("https://api.udon.dance/Api/Songs/play?id={}", id)
+            let location = format!("https://api.udon.dance/Api/Songs/play?id={}", id);
+            app.cache_events.emit(CacheEvent::Miss {
+              id,
+              redirected_to: Some(location.clone()),
+            });
+            location
+          }
+          CdnFetchResult::Missing(status) => {
+            info!(
+              "[MISS] Cache {} miss: upstream answered {} recently",
+              id, status
+            );
+            return Ok(missing_upstream(id, status));
           }
           CdnFetchResult::Hit(token) => {
+            app
+              .metrics
+              .tokens_issued
+              .with_label_values(&[ROUTE_WANNA_DANCE])
+              .inc();
             // Found in our CDN, let's redirect to the resource gateway.
             // Note: in prior versions, we used the format `{token}.mp4`,
             // which turned out it's not caching-friendly.
@@ -275,11 +692,25 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
       },
     );
 
+  // https://api.udon.dance/Api/Songs/list
+  let wanna_dance_songs_list = warp::get()
+    .and(warp::path!("Api" / "Songs" / "list"))
+    .and(with_service(app))
+    .and_then(|app: AppService| async move {
+      match app.songs_list.get(&app).await {
+        Ok(list) => Ok::<_, Rejection>(warp::reply::json(&list).into_response()),
+        Err(e) => {
+          warn!("Failed to get the songs list: {:?}", e);
+          Err(warp::reject::custom(CustomRejection::IndexNotReady))
+        }
+      }
+    });
+
   // https://api.udon.dance/Api/..
   let wanna_dance_other_api = warp::path!("Api" / ..)
     .and(warp::path::full())
     .and(warp::get())
-    .and(with_service(&app))
+    .and(with_service(app))
     .and_then(|full: FullPath, _app: AppService| async move {
       let path = format!("{}", full.as_str());
       debug!("GET {}", path);
@@ -296,9 +727,10 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
   // https://play.udon.dance/files/2403/1-660524b46664a.mp4?e=b03f9584f49350599d6d641d74b0b547&s=13959733
   let wanna_dance_play_cache = warp::path!("files" / String / String)
     .and(warp::path::end())
+    .and(warp::method())
     .and(warp::query::<HashMap<String, String>>())
-    .and(with_service(&app))
-    .and(real_ip())
+    .and(with_service(app))
+    .and(real_ip(app))
     .and(remote())
     .and(crate::cdn::range::filter_range())
     .and(warp::header::headers_cloned())
@@ -306,6 +738,7 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
     .and_then(
       |date: String,
        file: String,
+       method: Method,
        query: HashMap<String, String>,
        app: AppService,
        real_ip: Option<IpAddr>,
@@ -336,69 +769,354 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
           .await;
         match available {
           true => {
-            info!("[HIT] Cache {} found: serving {}", id, cache_file);
-            serve_video_mp4(app, id, range, cache_file, Some(e.clone())).await
+            serve_cache_hit(
+              app,
+              ROUTE_WANNA_DANCE,
+              id,
+              range,
+              Conditionals::from_headers(&headers),
+              cache_file,
+              Some(e.clone()),
+              method == Method::HEAD,
+            )
+            .await
           }
           _ => {
-            let (upstream_dns, host_override) = match headers
-              .get(warp::http::header::HOST)
-              .map(|x| x.to_str().ok())
-              .flatten()
-            {
-              Some("nya.xin.moe") => (
-                &app.opts.cache_upstream_ud_domestic,
-                "nya.xin.moe".to_string(),
-              ),
-              _ => (
-                &app.opts.cache_upstream_ud_oversea,
-                "play.udon.dance".to_string(),
-              ),
+            let downloading = match method {
+              // Nothing to cache from a HEAD, just ask upstream.
+              Method::HEAD => None,
+              _ => match app.cdn.begin_download(id).await {
+                Either::Left(downloading) => Some(downloading),
+                Either::Right(mut rx) => {
+                  info!(
+                    "[WAIT] Cache {} is being downloaded by another client, waiting",
+                    id
+                  );
+                  let _ = rx.changed().await;
+                  let (_, cache_file, _, available) = app
+                    .cdn
+                    .serve_local_cache(id, file.clone(), e.clone(), s, remote)
+                    .await;
+                  if available {
+                    return serve_cache_hit(
+                      app,
+                      ROUTE_WANNA_DANCE,
+                      id,
+                      range,
+                      Conditionals::from_headers(&headers),
+                      cache_file,
+                      Some(e.clone()),
+                      method == Method::HEAD,
+                    )
+                    .await;
+                  }
+                  // The other download failed, just proxy without caching.
+                  None
+                }
+              },
             };
+            let upstreams = app.file_upstreams(
+              headers
+                .get(warp::http::header::HOST)
+                .and_then(|x| x.to_str().ok()),
+              &format!("/files/{}/{}?e={}&s={}", date, file, e, s),
+            );
             info!(
               "[MISS] Cache {} miss ({}): fetch from {} (DNS: {})",
-              id, cache_file, host_override, upstream_dns,
+              id,
+              cache_file,
+              upstreams[0].host_override.as_deref().unwrap_or_default(),
+              upstreams[0].name(),
             );
-            crate::cdn::proxy::proxy_and_inspecting(
-              format!(
-                "http://{}/files/{}/{}?e={}&s={}",
-                upstream_dns, date, file, e, s
-              ),
-              reqwest::Method::GET,
+            app
+              .metrics
+              .cdn_cache_misses
+              .with_label_values(&[ROUTE_WANNA_DANCE])
+              .inc();
+            app.cache_events.emit(CacheEvent::Miss {
+              id,
+              redirected_to: None,
+            });
+            let timer = app.metrics.proxy_upstream_duration.start_timer();
+            let response = crate::cdn::proxy::proxy_and_inspecting(
+              match method {
+                Method::HEAD => reqwest::Method::HEAD,
+                _ => reqwest::Method::GET,
+              },
               headers,
               body,
-              ProxyOpts {
-                host_override: Some(host_override),
-                user_agent_override: Some(format!(
-                  "WannaDanceSelfHostedCDN/{}.{}",
-                  crate::MY_VERSION_ID,
-                  crate::my_git_hash(),
-                )),
-                allow_304: app.opts.proxy_allow_304,
-              },
-              Some(InspectingOpts {
+              app.proxy_opts(id, upstreams),
+              downloading.map(|downloading| InspectingOpts {
                 id,
                 download_tmp,
                 cache_file,
                 metadata_json,
                 etag: e.clone(),
                 expected_size: s,
+                downloading,
+                partial_file: app.cdn.partial_download_path(&file),
+                cdn: app.cdn.clone(),
               }),
             )
-            .await
+            .await;
+            timer.observe_duration();
+            if let Ok(response) = &response {
+              app.cdn.record_upstream_status(id, response.status()).await;
+            }
+            let proxied = app.metrics.proxy_upstream_bytes.clone();
+            response.map(|x| x.map(|body| MetricsServiceImpl::count_body(proxied, body)))
           }
         }
       },
     );
 
+  let admin_cache_evict = warp::delete()
+    .and(warp::path!("admin" / "cache" / "evict"))
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(|app: AppService, remote: Option<IpAddr>| async move {
+      let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+      ensure_admin_src_host(&app, remote).await?;
+      info!("admin {} says to evict the cache, yes sir!", remote);
+      let evicted = app.cdn.evict_until_fits().await.map_err(|e| {
+        warn!("Failed to evict cache: {:?}", e);
+        warp::reject::custom(CustomRejection::CacheDirNotAvailable)
+      })?;
+      Ok::<_, Rejection>(
+        warp::reply::json(&json!({
+          "message": "ok",
+          "evicted": evicted,
+        }))
+        .into_response(),
+      )
+    });
+
+  let admin_cache_evict_song = warp::delete()
+    .and(warp::path!("admin" / "cache" / SongId))
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(
+      |id: SongId, app: AppService, remote: Option<IpAddr>| async move {
+        let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        ensure_admin_src_host(&app, remote).await?;
+        info!("admin {} says to evict song {} from the cache", remote, id);
+        let evicted = app.cdn.evict_song(id).await.map_err(|e| {
+          warn!("Failed to evict song {}: {:?}", id, e);
+          warp::reject::custom(CustomRejection::CacheDirNotAvailable)
+        })?;
+        Ok::<_, Rejection>(
+          warp::reply::json(&json!({
+            "id": id,
+            "evicted": evicted,
+          }))
+          .into_response(),
+        )
+      },
+    );
+
+  let admin_cache_events = warp::get()
+    .and(warp::path!("admin" / "cache" / "events"))
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(|app: AppService, remote: Option<IpAddr>| async move {
+      let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+      ensure_admin_src_host(&app, remote).await?;
+      let events = BroadcastStream::new(app.cache_events.subscribe()).filter_map(move |event| {
+        let event = match event {
+          Ok(event) => match warp::sse::Event::default().json_data(&event) {
+            Ok(sse) => Some(sse),
+            Err(e) => {
+              warn!("Failed to serialize cache event: {:?}", e);
+              None
+            }
+          },
+          Err(BroadcastStreamRecvError::Lagged(n)) => {
+            warn!("Cache event stream of {} lagged {} events", remote, n);
+            None
+          }
+        };
+        futures::future::ready(event.map(Ok::<_, Infallible>))
+      });
+      Ok::<_, Rejection>(warp::sse::reply(
+        warp::sse::keep_alive()
+          .interval(std::time::Duration::from_secs(15))
+          .stream(events),
+      ))
+    });
+
+  let admin_cache_usage = warp::get()
+    .and(warp::path!("admin" / "cache" / "usage"))
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(|app: AppService, remote: Option<IpAddr>| async move {
+      let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+      ensure_admin_src_host(&app, remote).await?;
+      let usage = app.cdn.cache_usage().await.map_err(|e| {
+        warn!("Failed to get cache usage: {:?}", e);
+        warp::reject::custom(CustomRejection::CacheDirNotAvailable)
+      })?;
+      Ok::<_, Rejection>(warp::reply::json(&usage).into_response())
+    });
+
+  let admin_cache_list = warp::get()
+    .and(warp::path!("admin" / "cache"))
+    .and(warp::query::<HashMap<String, String>>())
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(
+      |qs: HashMap<String, String>, app: AppService, remote: Option<IpAddr>| async move {
+        let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        ensure_admin_src_host(&app, remote).await?;
+        let offset = qs
+          .get("offset")
+          .and_then(|x| x.parse::<usize>().ok())
+          .unwrap_or(0);
+        let limit = qs
+          .get("limit")
+          .and_then(|x| x.parse::<usize>().ok())
+          .unwrap_or(100);
+        let videos = app.cdn.list_cached_videos().await.map_err(|e| {
+          warn!("Failed to list cached videos: {:?}", e);
+          warp::reject::custom(CustomRejection::CacheDirNotAvailable)
+        })?;
+        let videos = videos
+          .into_iter()
+          .skip(offset)
+          .take(limit)
+          .collect::<Vec<_>>();
+        Ok::<_, Rejection>(warp::reply::json(&videos).into_response())
+      },
+    );
+
+  let admin_metrics = warp::get()
+    .and(warp::path!("metrics"))
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(|app: AppService, remote: Option<IpAddr>| async move {
+      let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+      ensure_admin_src_host(&app, remote).await?;
+      let metrics = render_metrics(&app).await.map_err(|e| {
+        warn!("Failed to render metrics: {:?}", e);
+        warp::reject::custom(CustomRejection::MetricsNotAvailable)
+      })?;
+      Ok::<_, Rejection>(warp::reply::with_header(
+        metrics,
+        warp::http::header::CONTENT_TYPE,
+        "text/plain; version=0.0.4",
+      ))
+    });
+
+  let admin_compensator_status = warp::get()
+    .and(warp::path!("admin" / "compensator" / "status"))
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(|app: AppService, remote: Option<IpAddr>| async move {
+      let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+      ensure_admin_src_host(&app, remote).await?;
+      Ok::<_, Rejection>(
+        warp::reply::json(&json!({
+          "running": app.compensator.running(),
+        }))
+        .into_response(),
+      )
+    });
+
+  let admin_override_upload = warp::post()
+    .and(warp::path!("admin" / "override" / String))
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and(warp::multipart::form().max_length(app.opts.video_override_max_bytes))
+    .and_then(
+      |id_mp4: String, app: AppService, remote: Option<IpAddr>, form: FormData| async move {
+        let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        ensure_admin_src_host(&app, remote).await?;
+        let id = id_mp4
+          .strip_suffix(".mp4")
+          .and_then(|x| x.parse::<SongId>().ok())
+          .ok_or_else(|| warp::reject::custom(CustomRejection::BadVideoId))?;
+        save_override_video(app, id, form).await
+      },
+    );
+
+  let admin_override_put = warp::put()
+    .and(warp::path!("aya-api" / String / "overrides" / SongId))
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and(warp::body::stream())
+    .and_then(
+      |_version: String, id: SongId, app: AppService, remote: Option<IpAddr>, body| async move {
+        let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        ensure_admin_src_host(&app, remote).await?;
+        put_override_video(app, id, Box::pin(body)).await
+      },
+    );
+
+  let admin_override_list = warp::get()
+    .and(warp::path!("aya-api" / String / "overrides"))
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(
+      |_version: String, app: AppService, remote: Option<IpAddr>| async move {
+        let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        ensure_admin_src_host(&app, remote).await?;
+        let overrides = app
+          .video_overrides()
+          .await
+          .ok_or_else(|| warp::reject::custom(CustomRejection::OverrideDirNotAvailable))?
+          .map_err(|e| {
+            warn!("Failed to list override videos: {:?}", e);
+            warp::reject::custom(CustomRejection::OverrideDirNotAvailable)
+          })?;
+        Ok::<_, Rejection>(warp::reply::json(&overrides).into_response())
+      },
+    );
+
+  let admin_override_delete = warp::delete()
+    .and(
+      warp::path!("admin" / "override" / SongId)
+        .or(warp::path!("aya-api" / String / "overrides" / SongId).map(|_, id| id))
+        .unify(),
+    )
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(
+      |id: SongId, app: AppService, remote: Option<IpAddr>| async move {
+        let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        ensure_admin_src_host(&app, remote).await?;
+        let file = app
+          .video_override_file(id)
+          .ok_or_else(warp::reject::not_found)?;
+        tokio::fs::remove_file(&file).await.map_err(|e| {
+          warn!("Failed to delete override video {}: {:?}", file, e);
+          warp::reject::custom(CustomRejection::OverrideDirNotAvailable)
+        })?;
+        info!("admin {} deleted the override video of song {}", remote, id);
+        Ok::<_, Rejection>(warp::reply::json(&json!({ "id": id })).into_response())
+      },
+    );
+
+  let admin = admin_cache_evict
+    .or(admin_cache_usage)
+    .or(admin_cache_list)
+    .or(admin_cache_evict_song)
+    .or(admin_cache_events)
+    .or(admin_metrics)
+    .or(admin_compensator_status)
+    .or(admin_override_upload)
+    .or(admin_override_put)
+    .or(admin_override_list)
+    .or(admin_override_delete);
+
   let wanna_dance = wanna_dance_play
+    .or(wanna_dance_songs_list)
     .or(wanna_dance_play_cache)
     .or(wanna_dance_other_api);
 
   // Typewriter gateway
   let typewriter = warp::get()
     .and(warp::path!("typewriter" / String))
-    .and(with_service(&app))
-    .and(real_ip())
+    .and(with_service(app))
+    .and(real_ip(app))
     .and_then(
       |token: String, app: AppService, client: Option<IpAddr>| async move {
         let client = client.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
@@ -421,7 +1139,7 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
   // Remote receipt gateway
   let receipt_get = warp::get()
     .and(warp::path!("r" / RoomId))
-    .and(with_service(&app))
+    .and(with_service(app))
     .and_then(|room_id: RoomId, app: AppService| async move {
       let receipts = app.receipt.receipts(room_id).await;
       Ok::<_, Rejection>(warp::reply::json(&receipts).into_response())
@@ -438,11 +1156,36 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
 
   let receipt_post = warp::post()
     .and(warp::path!("r" / RoomId))
-    .and(warp::body::json())
-    .and(with_service(&app))
+    .and(json_body(app))
+    .and(with_service(app))
+    .and(real_ip(app))
     .and_then(
-      |room_id: RoomId, create: ReceiptCreate, app: AppService| async move {
+      |room_id: RoomId, create: ReceiptCreate, app: AppService, remote: Option<IpAddr>| async move {
         debug!("create receipt: {:?}", &create);
+        if let Some(remote) = remote {
+          if let Err(retry_after) = app.receipt_rate_limiter.hit(remote).await {
+            warn!("Too many receipts, room={}, client={}", room_id, remote);
+            let retry_after = retry_after.as_secs().max(1);
+            let format = format!(
+              "create receipt failed: too many receipts from {}, retry in {}s",
+              remote, retry_after
+            );
+            return Ok(
+              warp::reply::with_header(
+                warp::reply::with_status(
+                  warp::reply::json(&json!({
+                    "message": format,
+                    "receipt": null,
+                  })),
+                  StatusCode::TOO_MANY_REQUESTS,
+                ),
+                warp::http::header::RETRY_AFTER,
+                retry_after,
+              )
+              .into_response(),
+            );
+          }
+        }
         let song = match (create.id, create.url) {
           (Some(song_id), _) => Either::Left(song_id),
           (_, Some(song_url)) => Either::Right(song_url.trim().to_string()),
@@ -467,7 +1210,10 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
           )
           .await
         {
-          Ok(receipt) => receipt,
+          Ok(receipt) => {
+            app.metrics.receipts_created.inc();
+            receipt
+          }
           Err(e) => {
             let format = format!("create receipt failed: {:?}", e);
             return Ok(
@@ -489,22 +1235,173 @@ pub async fn serve_video_http(app: AppService) -> crate::Result<()> {
       },
     );
 
-  let receipt = receipt_get.or(receipt_post);
-
-  // Ok, let's run the server
-  let routes = aya
-    .or(wanna_dance)
-    .or(typewriter)
-    .or(receipt)
-    .with(cors())
-    .recover(handle_rejection);
-
-  info!("Listening on http://{}", socket);
-  info!("Have a good day!");
-  warp::serve(routes).run(socket).await;
+  #[derive(Debug, Clone, Deserialize)]
+  struct ReceiptDelete {
+    sender: Option<UserId>,
+  }
 
-  Ok(())
-}
+  let receipt_delete = warp::delete()
+    .and(warp::path!("r" / RoomId / ReceiptId))
+    .and(warp::query::<ReceiptDelete>())
+    .and(with_service(app))
+    .and(real_ip(app))
+    .and_then(
+      |room_id: RoomId,
+       receipt_id: ReceiptId,
+       delete: ReceiptDelete,
+       app: AppService,
+       remote: Option<IpAddr>| async move {
+        let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+        // Senders may cancel their own receipts, admins may cancel any.
+        let sender = app
+          .receipt
+          .get_receipt(&receipt_id)
+          .await
+          .and_then(|r| r.sender);
+        let is_sender = match (delete.sender, sender) {
+          (Some(a), Some(b)) => a.trim() == b,
+          _ => false,
+        };
+        if !is_sender {
+          ensure_admin_src_host(&app, remote).await?;
+        }
+        let deleted = match app.receipt.delete_receipt(room_id, receipt_id).await {
+          Ok(deleted) => deleted,
+          Err(e) => {
+            let format = format!("delete receipt failed: {:?}", e);
+            return Ok(
+              warp::reply::json(&json!({
+                "message": format,
+                "deleted": false,
+              }))
+              .into_response(),
+            );
+          }
+        };
+        Ok::<_, Rejection>(
+          warp::reply::json(&json!({
+            "message": if deleted { "ok" } else { "receipt not found" },
+            "deleted": deleted,
+          }))
+          .into_response(),
+        )
+      },
+    );
+
+  let receipt_ack = warp::post()
+    .and(warp::path!("r" / RoomId / ReceiptId / "ack"))
+    .and(with_service(app))
+    .and_then(
+      |room_id: RoomId, receipt_id: ReceiptId, app: AppService| async move {
+        let receipt = match app.receipt.ack_receipt(room_id, receipt_id).await {
+          Ok(receipt) => receipt,
+          Err(e) => {
+            let format = format!("ack receipt failed: {:?}", e);
+            return Ok(
+              warp::reply::json(&json!({
+                "message": format,
+                "receipt": null,
+              }))
+              .into_response(),
+            );
+          }
+        };
+        Ok::<_, Infallible>(
+          warp::reply::json(&json!({
+            "message": "ok",
+            "receipt": receipt,
+          }))
+          .into_response(),
+        )
+      },
+    );
+
+  let receipt_events = warp::get()
+    .and(warp::path!("r" / RoomId / "events"))
+    .and(with_service(app))
+    .map(|room_id: RoomId, app: AppService| {
+      let events =
+        BroadcastStream::new(app.receipt.subscribe(room_id.clone())).filter_map(move |event| {
+          let event = match event {
+            Ok(event) => receipt_sse_event(&event),
+            Err(BroadcastStreamRecvError::Lagged(n)) => {
+              warn!(
+                "Receipt event stream for room {} lagged {} events",
+                room_id, n
+              );
+              None
+            }
+          };
+          futures::future::ready(event.map(Ok::<_, Infallible>))
+        });
+      warp::sse::reply(
+        warp::sse::keep_alive()
+          .interval(std::time::Duration::from_secs(15))
+          .stream(events),
+      )
+    });
+
+  let receipt_ws = warp::path!("ws" / "r" / RoomId)
+    .and(warp::ws())
+    .and(with_service(app))
+    .map(|room_id: RoomId, ws: warp::ws::Ws, app: AppService| {
+      ws.on_upgrade(move |socket| serve_receipt_events(socket, room_id, app))
+    });
+
+  let receipt = receipt_get
+    .or(receipt_post)
+    .or(receipt_delete)
+    .or(receipt_ack)
+    .or(receipt_events)
+    .or(receipt_ws);
+
+  let healthz = warp::get()
+    .and(warp::path!("healthz"))
+    .and(with_service(app))
+    .and_then(|app: AppService| async move {
+      let (reply, status) = match app.cdn.check_health().await {
+        Ok(_) => (
+          json!({
+            "status": "ok",
+            "version": crate::MY_VERSION_ID.to_string(),
+            "git": crate::my_git_hash(),
+            "uptime_secs": app.started_at.elapsed().as_secs(),
+          }),
+          StatusCode::OK,
+        ),
+        Err(e) => {
+          warn!("Health check failed: {:?}", e);
+          (
+            json!({
+              "status": "degraded",
+              "reason": e.to_string(),
+            }),
+            StatusCode::SERVICE_UNAVAILABLE,
+          )
+        }
+      };
+      Ok::<_, Rejection>(warp::reply::with_status(warp::reply::json(&reply), status))
+    });
+
+  // Ok, let's put everything together
+  let routes = aya
+    .or(wanna_dance)
+    .or(typewriter)
+    .or(receipt)
+    .or(admin)
+    .or(healthz)
+    .with(cors(&app.opts))
+    .recover(handle_rejection);
+  // `header::optional` may reject, `headers_cloned` may not.
+  with_request_logging(app)
+    .and(warp::header::headers_cloned())
+    .and(routes)
+    .map(
+      |request: RequestLog, headers: warp::http::HeaderMap, reply| {
+        request.finish(plain_text_errors(&headers, Reply::into_response(reply)))
+      },
+    )
+}
 
 #[derive(Debug)]
 pub enum CustomRejection {
@@ -515,16 +1412,286 @@ pub enum CustomRejection {
   NoServeToken,
   IndexNotReady,
   CacheDirNotAvailable,
+  MetricsNotAvailable,
+  ThumbnailNotAvailable,
+  WaveformNotAvailable,
+  BadProxyTarget,
+  BadOverrideVideo,
+  OverrideDirNotAvailable,
+  OverrideVideoTooLarge,
+  TooManySongIds,
 }
 
 impl Reject for CustomRejection {}
 
-async fn handle_rejection(e: Rejection) -> Result<impl Reply, Infallible> {
+impl CustomRejection {
+  fn status(&self) -> StatusCode {
+    match self {
+      CustomRejection::BadToken | CustomRejection::AreYouTryingToHackMe => StatusCode::FORBIDDEN,
+      CustomRejection::BadVideoId
+      | CustomRejection::NoClientIP
+      | CustomRejection::BadProxyTarget
+      | CustomRejection::BadOverrideVideo
+      | CustomRejection::TooManySongIds => StatusCode::BAD_REQUEST,
+      CustomRejection::OverrideVideoTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+      CustomRejection::NoServeToken
+      | CustomRejection::IndexNotReady
+      | CustomRejection::CacheDirNotAvailable
+      | CustomRejection::OverrideDirNotAvailable => StatusCode::SERVICE_UNAVAILABLE,
+      CustomRejection::MetricsNotAvailable
+      | CustomRejection::ThumbnailNotAvailable
+      | CustomRejection::WaveformNotAvailable => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+
+  fn code(&self) -> &'static str {
+    match self {
+      CustomRejection::BadVideoId => "bad_video_id",
+      CustomRejection::BadToken => "bad_token",
+      CustomRejection::AreYouTryingToHackMe => "forbidden",
+      CustomRejection::NoClientIP => "no_client_ip",
+      CustomRejection::NoServeToken => "no_serve_token",
+      CustomRejection::IndexNotReady => "index_not_ready",
+      CustomRejection::CacheDirNotAvailable => "cache_dir_not_available",
+      CustomRejection::MetricsNotAvailable => "metrics_not_available",
+      CustomRejection::ThumbnailNotAvailable => "thumbnail_not_available",
+      CustomRejection::WaveformNotAvailable => "waveform_not_available",
+      CustomRejection::BadProxyTarget => "bad_proxy_target",
+      CustomRejection::BadOverrideVideo => "bad_override_video",
+      CustomRejection::OverrideDirNotAvailable => "override_dir_not_available",
+      CustomRejection::OverrideVideoTooLarge => "override_video_too_large",
+      CustomRejection::TooManySongIds => "too_many_song_ids",
+    }
+  }
+}
+
+/// Body of error responses, also attached to the response as an extension so
+/// `plain_text_errors` can find it.
+#[derive(Debug, Clone, Serialize)]
+struct ErrorBody {
+  error: String,
+  code: &'static str,
+}
+
+async fn handle_rejection(e: Rejection) -> Result<warp::reply::Response, Infallible> {
   trace!("handle_rejection: {:?}", &e);
-  Ok(warp::reply::with_status(
-    format!("Oops! {:?}", e),
-    StatusCode::BAD_REQUEST,
-  ))
+  let (status, code, error) = if let Some(rejection) = e.find::<CustomRejection>() {
+    (
+      rejection.status(),
+      rejection.code(),
+      format!("{:?}", rejection),
+    )
+  } else if e.find::<warp::reject::PayloadTooLarge>().is_some() {
+    (
+      StatusCode::PAYLOAD_TOO_LARGE,
+      "payload_too_large",
+      "Payload Too Large".to_string(),
+    )
+  } else if e.find::<warp::reject::LengthRequired>().is_some() {
+    (
+      StatusCode::LENGTH_REQUIRED,
+      "length_required",
+      "Length Required".to_string(),
+    )
+  } else if e.is_not_found() || e.find::<warp::reject::MethodNotAllowed>().is_some() {
+    // Filters checking the method come before the path in our routes, so an
+    // unknown path usually ends up as `MethodNotAllowed`.
+    (StatusCode::NOT_FOUND, "not_found", "Not Found".to_string())
+  } else {
+    (StatusCode::BAD_REQUEST, "bad_request", format!("{:?}", e))
+  };
+  let body = ErrorBody { error, code };
+  let mut response = warp::reply::with_status(warp::reply::json(&body), status).into_response();
+  response.extensions_mut().insert(body);
+  Ok(response)
+}
+
+/// Turns error responses into plain text for clients asking for it.
+fn plain_text_errors(
+  headers: &warp::http::HeaderMap,
+  response: warp::reply::Response,
+) -> warp::reply::Response {
+  let text = headers
+    .get(warp::http::header::ACCEPT)
+    .and_then(|x| x.to_str().ok())
+    .is_some_and(|x| x.contains("text/plain"));
+  match response.extensions().get::<ErrorBody>() {
+    Some(body) if text => {
+      let mut plain = warp::reply::with_status(format!("Oops! {}", body.error), response.status())
+        .into_response();
+      plain.headers_mut().extend(
+        response
+          .headers()
+          .iter()
+          .filter(|(name, _)| *name != warp::http::header::CONTENT_TYPE)
+          .map(|(name, value)| (name.clone(), value.clone())),
+      );
+      plain
+    }
+    _ => response,
+  }
+}
+
+fn receipt_sse_event(event: &ReceiptEvent) -> Option<warp::sse::Event> {
+  let sse = warp::sse::Event::default();
+  let sse = match event {
+    ReceiptEvent::Created(r) => sse.event("created").json_data(r),
+    ReceiptEvent::Acknowledged(r) => sse.event("acknowledged").json_data(r),
+    ReceiptEvent::Expired(id) => Ok(sse.event("expired").data(id)),
+    ReceiptEvent::Deleted(id) => Ok(sse.event("deleted").data(id)),
+  };
+  match sse {
+    Ok(sse) => Some(sse),
+    Err(e) => {
+      warn!("Failed to serialize receipt event: {:?}", e);
+      None
+    }
+  }
+}
+
+/// Pushes receipt events of `room_id` to the websocket until either side
+/// goes away.
+async fn serve_receipt_events(socket: warp::ws::WebSocket, room_id: RoomId, app: AppService) {
+  let mut events = app.receipt.subscribe(room_id.clone());
+  let (mut tx, mut rx) = socket.split();
+  loop {
+    let event = tokio::select! {
+      event = events.recv() => match event {
+        Ok(event) => event,
+        Err(RecvError::Lagged(n)) => {
+          warn!("Receipt websocket for room {} lagged {} events", room_id, n);
+          continue;
+        }
+        Err(RecvError::Closed) => break,
+      },
+      msg = rx.next() => match msg {
+        Some(Ok(msg)) if !msg.is_close() => continue,
+        _ => break,
+      },
+    };
+    let json = match serde_json::to_string(&event) {
+      Ok(json) => json,
+      Err(e) => {
+        warn!("Failed to serialize receipt event: {:?}", e);
+        continue;
+      }
+    };
+    if let Err(e) = tx.send(warp::ws::Message::text(json)).await {
+      debug!("Receipt websocket for room {} closed: {:?}", room_id, e);
+      break;
+    }
+  }
+}
+
+/// Counts the body of `response` as bytes served from the cache for `route`.
+fn count_served(
+  metrics: &MetricsService,
+  route: &str,
+  response: warp::http::Response<hyper::body::Body>,
+) -> warp::http::Response<hyper::body::Body> {
+  let served = metrics.cdn_served_bytes.with_label_values(&[route]);
+  response.map(|body| MetricsServiceImpl::count_body(served, body))
+}
+
+fn too_many_requests(
+  retry_after: std::time::Duration,
+) -> Result<warp::http::Response<String>, warp::http::Error> {
+  warp::http::Response::builder()
+    .status(StatusCode::TOO_MANY_REQUESTS)
+    .header(
+      warp::http::header::RETRY_AFTER,
+      retry_after.as_secs().max(1),
+    )
+    .body("Too many requests, slow down!".to_string())
+}
+
+/// Answers a request for song `id` the upstream said it doesn't have. Not a
+/// rejection, `wanna_dance_other_api` would redirect it to the upstream.
+fn missing_upstream(
+  id: SongId,
+  status: StatusCode,
+) -> Result<warp::http::Response<String>, warp::http::Error> {
+  let body = ErrorBody {
+    error: format!("Song {} is missing upstream ({})", id, status),
+    code: "song_missing_upstream",
+  };
+  warp::http::Response::builder()
+    .status(StatusCode::NOT_FOUND)
+    .header(warp::http::header::CONTENT_TYPE, "application/json")
+    .extension(body.clone())
+    .body(json!(body).to_string())
+}
+
+/// A request as it came in, to log along with its response.
+pub struct RequestLog {
+  method: warp::http::Method,
+  path: warp::path::FullPath,
+  ip: Option<IpAddr>,
+  start: std::time::Instant,
+  _in_flight: InFlight,
+}
+
+/// Counts a request in `AppServiceImpl::http_in_flight` until dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+  fn new(counter: &Arc<AtomicUsize>) -> Self {
+    counter.fetch_add(1, Ordering::Relaxed);
+    InFlight(counter.clone())
+  }
+}
+
+impl Drop for InFlight {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+impl RequestLog {
+  /// Logs the request and tells the client how long it took in
+  /// `X-Response-Time`, bodies may still take longer to stream.
+  fn finish(self, mut response: warp::reply::Response) -> warp::reply::Response {
+    let duration_ms = self.start.elapsed().as_millis();
+    info!(
+      "{} {} -> {} in {}ms from {}",
+      self.method,
+      self.path.as_str(),
+      response.status().as_u16(),
+      duration_ms,
+      self
+        .ip
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| "unknown".to_string()),
+    );
+    response.headers_mut().insert(
+      "X-Response-Time",
+      format!("{}ms", duration_ms).parse().unwrap(),
+    );
+    response
+  }
+}
+
+pub fn with_request_logging(
+  app: &AppService,
+) -> impl Filter<Extract = (RequestLog,), Error = Infallible> + Clone {
+  let in_flight = app.http_in_flight.clone();
+  warp::method()
+    .and(warp::path::full())
+    .and(real_ip(app))
+    .map(move |method, path, ip| RequestLog {
+      method,
+      path,
+      ip,
+      start: std::time::Instant::now(),
+      _in_flight: InFlight::new(&in_flight),
+    })
+}
+
+/// A JSON body of at most `--max-body-bytes`.
+fn json_body<T: DeserializeOwned + Send>(
+  app: &AppService,
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+  warp::body::content_length_limit(app.opts.max_body_bytes).and(warp::body::json())
 }
 
 pub fn with_service(
@@ -534,9 +1701,25 @@ pub fn with_service(
   warp::any().map(move || service.clone())
 }
 
-pub fn cors() -> warp::cors::Builder {
-  warp::cors()
-    .allow_any_origin()
+/// Whether the client takes gzip bodies, as per its `Accept-Encoding`.
+fn accepts_gzip(headers: &warp::http::HeaderMap) -> bool {
+  headers
+    .get_all(warp::http::header::ACCEPT_ENCODING)
+    .iter()
+    .filter_map(|x| x.to_str().ok())
+    .flat_map(|x| x.split(','))
+    .any(|x| {
+      let mut params = x.split(';').map(|x| x.trim());
+      params
+        .next()
+        .is_some_and(|x| x.eq_ignore_ascii_case("gzip"))
+        && params.all(|x| !matches!(x, "q=0" | "q=0.0" | "q=0.00" | "q=0.000"))
+    })
+}
+
+pub fn cors(opts: &AppOpts) -> warp::cors::Builder {
+  let cors = warp::cors()
+    .max_age(std::time::Duration::from_secs(opts.cors_max_age_secs))
     .allow_headers(vec![
       "Content-Type",
       "User-Agent",
@@ -548,46 +1731,581 @@ pub fn cors() -> warp::cors::Builder {
       "Access-Control-Request-Method",
       "Access-Control-Request-Headers",
     ])
-    .allow_methods(vec!["GET", "POST", "OPTIONS", "PUT", "DELETE"])
+    .allow_headers(opts.cors_allow_headers.iter().map(|x| x.trim()))
+    .expose_headers(opts.cors_expose_headers.iter().map(|x| x.trim()))
+    .allow_methods(vec!["GET", "POST", "OPTIONS", "PUT", "DELETE"]);
+  match opts.cors_allow_origins.iter().any(|x| x == "*") {
+    true => cors.allow_any_origin(),
+    false => cors.allow_origins(opts.cors_allow_origins.iter().map(|x| x.trim())),
+  }
+}
+
+/// Fails on `--cors-allow-origins` that warp would panic on, they must look
+/// like `https://example.com` or `http://localhost:8080`.
+pub fn check_cors_origins(origins: &[String]) -> crate::Result<()> {
+  for origin in origins.iter().map(|x| x.trim()).filter(|x| *x != "*") {
+    let valid = origin.contains("://")
+      && origin.parse::<warp::http::Uri>().is_ok_and(|uri| {
+        uri.scheme().is_some()
+          && uri.authority().is_some()
+          && uri.path() == "/"
+          && uri.query().is_none()
+      });
+    if !valid {
+      bail!(
+        "Bad CORS origin {}, expected something like https://example.com",
+        origin
+      );
+    }
+  }
+  Ok(())
+}
+
+/// Fails on header names in `option` that warp would panic on.
+pub fn check_cors_headers(option: &str, headers: &[String]) -> crate::Result<()> {
+  for header in headers {
+    if warp::http::header::HeaderName::from_bytes(header.trim().as_bytes()).is_err() {
+      bail!("Bad header name {:?} in {}", header, option);
+    }
+  }
+  Ok(())
+}
+
+/// Parses `--trusted-proxies`, IPs or CIDRs like `10.0.0.0/8` and `fd00::/8`.
+pub fn parse_trusted_proxies(proxies: &[String]) -> crate::Result<Vec<IpNet>> {
+  proxies
+    .iter()
+    .map(|x| x.trim())
+    .filter(|x| !x.is_empty())
+    .map(|x| match x.parse::<IpNet>() {
+      Ok(net) => Ok(net),
+      Err(_) => match x.parse::<IpAddr>() {
+        Ok(ip) => Ok(IpNet::from(ip)),
+        Err(_) => Err(anyhow!(
+          "Bad trusted proxy {}, expected an IP or a CIDR like 10.0.0.0/8",
+          x
+        )),
+      },
+    })
+    .collect()
+}
+
+/// The client of a request from `peer`. Forwarding headers are only believed
+/// when `peer` is a trusted proxy: `X-Forwarded-For` is walked from the right,
+/// skipping trusted hops, and `X-Real-IP` is used if there is none.
+pub fn client_ip(
+  peer: IpAddr,
+  forwarded_for: Option<&str>,
+  real_ip: Option<&str>,
+  trusted: &[IpNet],
+) -> IpAddr {
+  let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+  if !is_trusted(&peer) {
+    return peer;
+  }
+  if let Some(forwarded_for) = forwarded_for.filter(|x| !x.trim().is_empty()) {
+    let mut client = peer;
+    for hop in forwarded_for.rsplit(',') {
+      // Nothing left of a malformed hop can be vouched for.
+      let Some(ip) = parse_hop(hop) else {
+        break;
+      };
+      client = ip;
+      if !is_trusted(&ip) {
+        break;
+      }
+    }
+    return client;
+  }
+  real_ip.and_then(parse_hop).unwrap_or(peer)
+}
+
+/// An address in a forwarding header, some proxies add the port.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+  let hop = hop.trim();
+  hop
+    .parse::<IpAddr>()
+    .or_else(|_| hop.parse::<SocketAddr>().map(|x| x.ip()))
+    .ok()
+}
+
+/// The client IP as told by `client_ip`.
+pub fn real_ip(
+  app: &AppService,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Clone {
+  let app = app.clone();
+  remote().and(warp::header::headers_cloned()).map(
+    move |addr: Option<SocketAddr>, headers: warp::http::HeaderMap| {
+      let forwarded_for = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+      let real_ip = headers.get("x-real-ip").and_then(|x| x.to_str().ok());
+      addr.map(|addr| {
+        client_ip(
+          addr.ip(),
+          Some(forwarded_for.as_str()),
+          real_ip,
+          &app.trusted_proxies,
+        )
+      })
+    },
+  )
 }
 
-pub fn real_ip() -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Clone {
-  remote().and(get_forwarded_for()).map(
-    move |addr: Option<SocketAddr>, forwarded_for: Vec<IpAddr>| {
-      addr.map(|addr| forwarded_for.first().copied().unwrap_or(addr.ip()))
+/// The IP of an admin, rejecting anyone else before the rest of the request,
+/// like its body, is read.
+fn admin_ip(app: &AppService) -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Clone {
+  with_service(app).and(real_ip(app)).and_then(
+    |app: AppService, remote: Option<IpAddr>| async move {
+      let remote = remote.ok_or(warp::reject::custom(CustomRejection::NoClientIP))?;
+      ensure_admin_src_host(&app, remote).await?;
+      Ok::<_, Rejection>(remote)
     },
   )
 }
 
+/// Rejects with `AreYouTryingToHackMe` unless `remote` matches one of the
+/// `admin_src_host`, which can be either IPs or hostnames.
+pub async fn ensure_admin_src_host(app: &AppService, remote: IpAddr) -> Result<(), Rejection> {
+  let hosts = app
+    .opts
+    .admin_src_host
+    .as_ref()
+    .ok_or(warp::reject::custom(CustomRejection::AreYouTryingToHackMe))?;
+  for host in hosts {
+    // If the host is a valid IP, we will check the remote IP
+    let ip = match host.parse::<IpAddr>() {
+      Ok(ip) => ip,
+      // If it is a hostname? `resolve_host` needs a socket address, so give it a port
+      Err(_) => match crate::forward::tokio_util::resolve_host(
+        format!("{}:11451", host),
+        crate::forward::tokio_util::IpVersion::Any,
+      )
+      .await
+      {
+        Ok(sock) => sock.ip(),
+        Err(e) => {
+          warn!(
+            "failed to resolve admin src host {}: {:?}, trying next one",
+            host, e
+          );
+          continue;
+        }
+      },
+    };
+    if ip == remote {
+      debug!(
+        "remote IP matches admin src host: remote={:?}, admin={:?}",
+        remote, ip
+      );
+      return Ok(());
+    }
+  }
+
+  error!(
+    "someone is trying to use the admin API without permission! remote={:?}",
+    remote
+  );
+  Err(warp::reject::custom(CustomRejection::AreYouTryingToHackMe))
+}
+
+/// Serves `cache_file` of song `id`, counting it as a cache hit on `route`.
+#[allow(clippy::too_many_arguments)]
+async fn serve_cache_hit(
+  app: AppService,
+  route: &str,
+  id: SongId,
+  range: Option<String>,
+  conditionals: Conditionals,
+  cache_file: String,
+  md5: Option<String>,
+  head: bool,
+) -> Result<warp::http::Response<hyper::body::Body>, Rejection> {
+  info!("[HIT] Cache {} found: serving {}", id, cache_file);
+  app.metrics.cdn_cache_hits.with_label_values(&[route]).inc();
+  app.cache_events.emit(CacheEvent::Hit { id });
+  let metrics = app.metrics.clone();
+  serve_video_mp4(app, id, range, conditionals, cache_file, md5, head)
+    .await
+    .map(|x| count_served(&metrics, route, x))
+}
+
 pub async fn serve_video_mp4(
   app: AppService,
   id: SongId,
   range: Option<String>,
+  conditionals: Conditionals,
   video_file: String,
   md5: Option<String>,
+  head: bool,
+) -> Result<warp::http::Response<hyper::body::Body>, Rejection> {
+  // Uploaded by the admin, served as it is.
+  if let Some(file) = app.video_override_file(id) {
+    return crate::cdn::range::get_range(range, conditionals, None, &file, "video/mp4", head).await;
+  }
+  let md5 = match md5 {
+    Some(m) => m,
+    None => app.cdn.get_video_checksum(id).await.unwrap_or_default(),
+  };
+  let file = compensate_video_mp4(&app, id, &video_file, &md5).await;
+  // The checksum identifies the content, as long as we know it.
+  let etag = match md5.as_str() {
+    "" => None,
+    _ if file == video_file => Some(md5.clone()),
+    _ => Some(format!(
+      "{}-audio-offset-{}{}",
+      md5,
+      app.audio_offset(id),
+      match file.ends_with("-loudnorm.mp4") {
+        true => "-loudnorm",
+        false => "",
+      }
+    )),
+  };
+  let mut response = crate::cdn::range::get_range(
+    range,
+    conditionals,
+    etag.clone(),
+    file.as_str(),
+    "video/mp4",
+    head,
+  )
+  .await?;
+  if response.status() == StatusCode::NOT_MODIFIED {
+    debug!("Cache {} not modified: {:?}", id, etag);
+  }
+  if etag.is_some() {
+    response.headers_mut().insert(
+      warp::http::header::CACHE_CONTROL,
+      "public, max-age=86400".parse().unwrap(),
+    );
+  }
+  Ok(response)
+}
+
+/// Serves the thumbnail of a cached song, generating it on first request.
+pub async fn serve_thumbnail_webp(
+  app: AppService,
+  id: SongId,
 ) -> Result<warp::http::Response<hyper::body::Body>, Rejection> {
-  let audio_offset = app.opts.audio_compensation;
+  let (video_file, _, available) = app.cdn.get_video_file_path(id).await;
+  if !available {
+    return Err(warp::reject::not_found());
+  }
+  let thumbnail = format!("{}/{}/thumbnail.webp", app.cdn.video_path, id);
+  if !std::path::Path::new(thumbnail.as_str()).exists() {
+    let start = app
+      .cdn
+      .get_video_metadata(id)
+      .await
+      .map(|x| x.start)
+      .unwrap_or(0);
+    let seek_secs = start as f64 + app.opts.thumbnail_seek_offset;
+    // Write next to it first, so nobody gets served a half-written file.
+    let generating = format!("{}.{}", thumbnail, uuid::Uuid::new_v4().simple());
+    let output = generating.clone();
+    let generated = tokio::task::spawn_blocking(move || {
+      ffmpeg_extract_thumbnail(video_file.as_str(), output.as_str(), seek_secs)
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|x| x)
+    .and_then(|_| Ok(std::fs::rename(&generating, &thumbnail)?));
+    if let Err(e) = generated {
+      warn!("Failed to generate thumbnail for song {}: {:?}", id, e);
+      let _ = std::fs::remove_file(&generating);
+      return Err(warp::reject::custom(CustomRejection::ThumbnailNotAvailable));
+    }
+    info!("Generated thumbnail {} at {:.2}s", thumbnail, seek_secs);
+  }
+  crate::cdn::range::get_range(
+    None,
+    Conditionals::default(),
+    None,
+    thumbnail.as_str(),
+    "image/webp",
+    false,
+  )
+  .await
+}
+
+/// Serves the audio waveform of a cached song, generating it on first request.
+pub async fn serve_waveform_json(
+  app: AppService,
+  id: SongId,
+) -> Result<warp::http::Response<hyper::body::Body>, Rejection> {
+  let (video_file, _, available) = app.cdn.get_video_file_path(id).await;
+  if !available {
+    return Err(warp::reject::not_found());
+  }
+  let waveform = format!("{}/{}-waveform.json", app.cdn.cache_path, id);
+  if !std::path::Path::new(waveform.as_str()).exists() {
+    std::fs::create_dir_all(app.cdn.cache_path.as_str()).map_err(|e| {
+      warn!("Failed to create cache directory: {:?}", e);
+      warp::reject::custom(CustomRejection::CacheDirNotAvailable)
+    })?;
+    let samples_per_second = app.opts.waveform_samples_per_second;
+    // Write next to it first, so nobody gets served a half-written file.
+    let generating = format!("{}.{}", waveform, uuid::Uuid::new_v4().simple());
+    let output = generating.clone();
+    let generated = tokio::task::spawn_blocking(move || {
+      let samples = ffmpeg_extract_waveform(video_file.as_str(), samples_per_second)?;
+      std::fs::write(
+        output.as_str(),
+        serde_json::to_vec(&json!({ "samples": samples }))?,
+      )?;
+      anyhow::Ok(())
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|x| x)
+    .and_then(|_| Ok(std::fs::rename(&generating, &waveform)?));
+    if let Err(e) = generated {
+      warn!("Failed to generate waveform for song {}: {:?}", id, e);
+      let _ = std::fs::remove_file(&generating);
+      return Err(warp::reject::custom(CustomRejection::WaveformNotAvailable));
+    }
+    info!("Generated waveform {}", waveform);
+  }
+  crate::cdn::range::get_range(
+    None,
+    Conditionals::default(),
+    None,
+    waveform.as_str(),
+    "application/json",
+    false,
+  )
+  .await
+}
+
+/// Song `id` from the index, or its metadata.json if the index doesn't have it
+/// yet. An override video without either gets a placeholder.
+async fn find_song(app: &AppService, id: SongId) -> Option<aya_dance_types::Song> {
+  match app.index.get_index(false).await {
+    Ok(index) => {
+      // The first category has all the songs.
+      let song = index
+        .categories
+        .into_iter()
+        .next()
+        .and_then(|all| all.entries.into_iter().find(|song| song.id == id));
+      if song.is_some() {
+        return song;
+      }
+    }
+    Err(e) => warn!("Failed to get index: {:?}", e),
+  }
+  if let Some(song) = app.cdn.get_video_metadata(id).await {
+    return Some(song);
+  }
+  app.video_override_file(id).map(|_| aya_dance_types::Song {
+    id,
+    category: 0,
+    title: id.to_string(),
+    category_name: "".to_string(),
+    title_spell: id.to_string(),
+    player_index: 0,
+    volume: 1.0,
+    start: 0,
+    end: 0,
+    flip: false,
+    skip_random: false,
+    original_url: None,
+    checksum: None,
+  })
+}
+
+/// Saves the `video` field of `form` as the override video of song `id`, once
+/// `ffmpeg_probe` agrees it's an MP4 video.
+async fn save_override_video(
+  app: AppService,
+  id: SongId,
+  mut form: FormData,
+) -> Result<warp::http::Response<hyper::body::Body>, Rejection> {
+  let file = app
+    .video_override_path(id)
+    .ok_or_else(|| warp::reject::custom(CustomRejection::OverrideDirNotAvailable))?;
+  let part = loop {
+    match form.try_next().await {
+      Ok(Some(part)) if part.name() == "video" => break part,
+      Ok(Some(_)) => continue,
+      Ok(None) => return Err(warp::reject::custom(CustomRejection::BadOverrideVideo)),
+      Err(e) => {
+        warn!("Bad override video upload for song {}: {:?}", id, e);
+        return Err(warp::reject::custom(CustomRejection::BadOverrideVideo));
+      }
+    }
+  };
+
+  // Write next to it first, so nobody gets served a half-written file.
+  let dir = app.opts.video_override_path_ud.clone().unwrap_or_default();
+  let uploading = format!("{}/.{}-{}.mp4", dir, id, uuid::Uuid::new_v4().simple());
+  let max_bytes = app.opts.video_override_max_bytes;
+  let (size, checksum) = match write_stream(part.stream(), &dir, &uploading, max_bytes).await {
+    Ok(Some(x)) => x,
+    // Can't be, the form is limited to it already.
+    Ok(None) => {
+      let _ = tokio::fs::remove_file(&uploading).await;
+      return Err(warp::reject::custom(CustomRejection::OverrideVideoTooLarge));
+    }
+    Err(e) => {
+      warn!("Failed to save override video for song {}: {:?}", id, e);
+      let _ = tokio::fs::remove_file(&uploading).await;
+      return Err(warp::reject::custom(
+        CustomRejection::OverrideDirNotAvailable,
+      ));
+    }
+  };
+  let probing = uploading.clone();
+  match tokio::task::spawn_blocking(move || ffmpeg_probe(probing.as_str())).await {
+    Ok(Ok(probe)) if probe.is_mp4_video() => {}
+    probe => {
+      warn!("Override video for song {} is not an MP4: {:?}", id, probe);
+      let _ = tokio::fs::remove_file(&uploading).await;
+      return Err(warp::reject::custom(CustomRejection::BadOverrideVideo));
+    }
+  }
+  replace_override_video(&app, id, &uploading, &file).await?;
+  info!("Saved override video {} ({} bytes)", file, size);
+  Ok(
+    warp::reply::json(&json!({
+      "id": id,
+      "size": size,
+      "checksum": checksum,
+    }))
+    .into_response(),
+  )
+}
+
+/// Saves `body` as the override video of song `id`, once it looks like an MP4.
+/// Unlike `save_override_video`, only its first box is checked.
+async fn put_override_video<S, B>(
+  app: AppService,
+  id: SongId,
+  body: S,
+) -> Result<warp::http::Response<hyper::body::Body>, Rejection>
+where
+  S: futures::Stream<Item = Result<B, warp::Error>> + Unpin,
+  B: bytes::Buf,
+{
+  let file = app
+    .video_override_path(id)
+    .ok_or_else(|| warp::reject::custom(CustomRejection::OverrideDirNotAvailable))?;
+  // Write next to it first, so nobody gets served a half-written file.
+  let dir = app.opts.video_override_path_ud.clone().unwrap_or_default();
+  let uploading = format!("{}/.{}-{}.mp4", dir, id, uuid::Uuid::new_v4().simple());
+  let max_bytes = app.opts.video_override_max_bytes;
+  let (size, checksum) = match write_stream(body, &dir, &uploading, max_bytes).await {
+    Ok(Some(x)) if starts_with_ftyp(&uploading).await => x,
+    written => {
+      let rejection = match written {
+        Ok(Some(_)) => CustomRejection::BadOverrideVideo,
+        Ok(None) => CustomRejection::OverrideVideoTooLarge,
+        Err(e) => {
+          warn!("Failed to save override video for song {}: {:?}", id, e);
+          CustomRejection::OverrideDirNotAvailable
+        }
+      };
+      let _ = tokio::fs::remove_file(&uploading).await;
+      return Err(warp::reject::custom(rejection));
+    }
+  };
+  replace_override_video(&app, id, &uploading, &file).await?;
+  info!("Saved override video {} ({} bytes)", file, size);
+  Ok(
+    warp::reply::json(&json!({
+      "id": id,
+      "size": size,
+      "checksum": checksum,
+    }))
+    .into_response(),
+  )
+}
+
+/// Moves `uploading` in place of the override video `file` of song `id`.
+/// Whoever is being served the old one keeps getting it.
+async fn replace_override_video(
+  app: &AppService,
+  id: SongId,
+  uploading: &str,
+  file: &str,
+) -> Result<(), Rejection> {
+  if let Err(e) = tokio::fs::rename(uploading, file).await {
+    warn!("Failed to save override video {}: {:?}", file, e);
+    let _ = tokio::fs::remove_file(uploading).await;
+    return Err(warp::reject::custom(
+      CustomRejection::OverrideDirNotAvailable,
+    ));
+  }
+  let removed = app.cdn.remove_compensated(id).await;
+  if removed > 0 {
+    info!("Removed {} compensated videos of song {}", removed, id);
+  }
+  Ok(())
+}
+
+/// Writes `chunks` to `file` in `dir`, returning its size and MD5. `None` if
+/// it goes over `max_bytes`, the file is only written up to there.
+async fn write_stream<S, B>(
+  mut chunks: S,
+  dir: &str,
+  file: &str,
+  max_bytes: u64,
+) -> crate::Result<Option<(u64, String)>>
+where
+  S: futures::Stream<Item = Result<B, warp::Error>> + Unpin,
+  B: bytes::Buf,
+{
+  use tokio::io::AsyncWriteExt;
+
+  tokio::fs::create_dir_all(dir).await?;
+  let mut output = tokio::fs::File::create(file).await?;
+  let mut md5 = md5::Context::new();
+  let mut size = 0;
+  while let Some(mut chunk) = chunks.try_next().await? {
+    while chunk.has_remaining() {
+      let bytes = chunk.chunk();
+      let len = bytes.len();
+      size += len as u64;
+      if size > max_bytes {
+        return Ok(None);
+      }
+      md5.consume(bytes);
+      output.write_all(bytes).await?;
+      chunk.advance(len);
+    }
+  }
+  output.flush().await?;
+  Ok(Some((size, hex::encode(md5.compute().as_slice()))))
+}
+
+/// Whether `file` starts with an MP4 `ftyp` box.
+async fn starts_with_ftyp(file: &str) -> bool {
+  use tokio::io::AsyncReadExt;
+
+  let mut head = [0; 8];
+  match tokio::fs::File::open(file).await {
+    Ok(mut f) => f.read_exact(&mut head).await.is_ok() && &head[4..] == b"ftyp",
+    Err(_) => false,
+  }
+}
+
+/// Returns the audio compensated version of `video_file`, or `video_file`
+/// itself if compensation is disabled or failed.
+async fn compensate_video_mp4(app: &AppService, id: SongId, video_file: &str, md5: &str) -> String {
+  let audio_offset = app.audio_offset(id);
   if (audio_offset - 0.0).abs() > f64::EPSILON {
-    let md5 = match md5 {
-      Some(m) => m,
-      None => match app.cdn.get_video_file_path(id).await {
-        (_, metadata_json, avail) if avail => std::fs::File::open(metadata_json)
-          .map_err(|e| anyhow::anyhow!("Failed to open metadata: {:?}", e))
-          .and_then(|f| {
-            serde_json::from_reader::<_, aya_dance_types::Song>(f)
-              .map_err(|e| anyhow::anyhow!("Failed to parse metadata: {:?}", e))
-          })
-          .and_then(|s| {
-            s.checksum
-              .ok_or_else(|| anyhow::anyhow!("No checksum in metadata"))
-          })
-          .unwrap_or_default(),
-        _ => "".to_string(),
-      },
-    };
-    let compensated = format!(
-      "{}/{}-{}-audio-offset-{}.mp4",
-      app.cdn.cache_path, id, md5, audio_offset
+    // Marked, so toggling `--normalize-audio` doesn't serve the old file.
+    let loudnorm = match app.opts.normalize_audio {
+      true => "-loudnorm",
+      false => "",
+    };
+    let mut compensated = format!(
+      "{}/{}-{}-audio-offset-{}{}.mp4",
+      app.cdn.cache_path, id, md5, audio_offset, loudnorm
     );
     if !std::path::Path::new(compensated.as_str()).exists() {
       if let Err(e) = std::fs::create_dir_all(app.cdn.cache_path.as_str()) {
@@ -595,27 +2313,56 @@ pub async fn serve_video_mp4(
           "Failed to create cache directory, serving original video: {:?}",
           e
         );
-        return crate::cdn::range::get_range(range, video_file.as_str(), "video/mp4").await;
+        return video_file.to_string();
       }
 
+      let _compensating = match app.compensator.begin(compensated.as_str()) {
+        Either::Left(compensating) => compensating,
+        Either::Right(mut rx) => {
+          info!("Compensate {} is already running, waiting", id);
+          let _ = rx.changed().await;
+          if std::path::Path::new(compensated.as_str()).exists() {
+            info!("Serving compensated {}: {}", id, compensated);
+            return compensated;
+          }
+          // It failed, and so would we.
+          return video_file.to_string();
+        }
+      };
+      let _worker = app.compensator.acquire().await;
+      // Someone else may have compensated it while we waited.
+      if std::path::Path::new(compensated.as_str()).exists() {
+        info!("Serving compensated {}: {}", id, compensated);
+        return compensated;
+      }
+      let _running = MetricsServiceImpl::track(&app.metrics.audio_compensations_running);
+      let _task = app.compensator.track(CompensatorTask {
+        song_id: id,
+        audio_offset,
+      });
       let compensated_stage1 = format!(
         "{}/{}-{}-audio-offset-{}-nocopy.mp4",
         app.cdn.cache_path, id, md5, audio_offset
       );
 
       let start = std::time::Instant::now();
-      let stats = match ffmpeg_audio_compensation(
-        video_file.as_str(),
-        compensated_stage1.as_str(),
-        audio_offset,
-      ) {
+      // ffmpeg blocks, keep it off the workers serving HTTP.
+      let (input, output) = (video_file.to_string(), compensated_stage1.clone());
+      let hw_accel = app.opts.hw_accel;
+      let stats = tokio::task::spawn_blocking(move || {
+        ffmpeg_audio_compensation(input.as_str(), output.as_str(), audio_offset, hw_accel)
+      })
+      .await
+      .map_err(anyhow::Error::from)
+      .and_then(|x| x);
+      let stats = match stats {
         Ok(stats) => stats,
         Err(e) => {
           warn!(
             "Failed to compensate audio for song {}, serving original video: {:?}",
             id, e
           );
-          return crate::cdn::range::get_range(range, video_file.as_str(), "video/mp4").await;
+          return video_file.to_string();
         }
       };
 
@@ -629,13 +2376,60 @@ pub async fn serve_video_mp4(
         stats.audio_encode_secs,
       );
 
+      let mut compensated_stage1 = compensated_stage1;
+      if app.opts.normalize_audio {
+        let normalized = format!(
+          "{}/{}-{}-audio-offset-{}-loudnorm-nocopy.mp4",
+          app.cdn.cache_path, id, md5, audio_offset
+        );
+        let start = std::time::Instant::now();
+        let (input, output) = (compensated_stage1.clone(), normalized.clone());
+        let loudness = tokio::task::spawn_blocking(move || {
+          ffmpeg_normalize_audio(input.as_str(), output.as_str())
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|x| x);
+        match loudness {
+          Ok(loudness) => {
+            info!(
+              "Compensate {} (loudnorm, {:.2}s, I={:.1}LUFS, TP={:.1}dBTP, LRA={:.1}LU)",
+              id,
+              start.elapsed().as_secs_f64(),
+              loudness.input_i,
+              loudness.input_tp,
+              loudness.input_lra,
+            );
+            let _ = std::fs::remove_file(compensated_stage1.as_str());
+            compensated_stage1 = normalized;
+          }
+          Err(e) => {
+            warn!(
+              "Failed to normalize audio for song {}, serving it unnormalized: {:?}",
+              id, e
+            );
+            let _ = std::fs::remove_file(normalized.as_str());
+            compensated = format!(
+              "{}/{}-{}-audio-offset-{}.mp4",
+              app.cdn.cache_path, id, md5, audio_offset
+            );
+          }
+        }
+      }
+
       let start = std::time::Instant::now();
-      if let Err(e) = ffmpeg_copy(compensated_stage1.as_str(), compensated.as_str()) {
+      let (input, output) = (compensated_stage1.clone(), compensated.clone());
+      let copied =
+        tokio::task::spawn_blocking(move || ffmpeg_copy(input.as_str(), output.as_str()))
+          .await
+          .map_err(anyhow::Error::from)
+          .and_then(|x| x);
+      if let Err(e) = copied {
         warn!(
           "Failed to copy compensated audio for song {} (file: {}), serving original video: {:?}",
           id, compensated_stage1, e
         );
-        return crate::cdn::range::get_range(range, video_file.as_str(), "video/mp4").await;
+        return video_file.to_string();
       }
 
       info!(
@@ -652,7 +2446,1904 @@ pub async fn serve_video_mp4(
       }
     }
     info!("Serving compensated {}: {}", id, compensated);
-    return crate::cdn::range::get_range(range, compensated.as_str(), "video/mp4").await;
+    return compensated;
+  }
+  video_file.to_string()
+}
+
+#[cfg(test)]
+mod test {
+  use std::{
+    sync::{
+      atomic::{AtomicUsize, Ordering},
+      Arc,
+    },
+    time::Duration,
+  };
+
+  use clap::Parser;
+
+  use super::*;
+  use crate::{AppOpts, AppServiceImpl};
+
+  async fn test_app(song_ids: &[SongId], args: &[&str]) -> AppService {
+    let root = std::env::temp_dir().join(format!("wanna-cdn-test-{}", uuid::Uuid::new_v4()));
+    let video_path = root.join("song");
+    for id in song_ids {
+      let dir = video_path.join(id.to_string());
+      std::fs::create_dir_all(&dir).unwrap();
+      std::fs::write(dir.join("video.mp4"), b"not really a video").unwrap();
+      std::fs::write(dir.join("metadata.json"), b"{}").unwrap();
+    }
+    let opts = AppOpts::parse_from(
+      [
+        "wanna-cdn",
+        "--video-path-ud",
+        video_path.to_str().unwrap(),
+        "--cache-path-ud",
+        root.join("cache").to_str().unwrap(),
+        "--admin-src-host",
+        "127.0.0.1",
+      ]
+      .iter()
+      .chain(args),
+    );
+    AppServiceImpl::new(opts).await.unwrap()
+  }
+
+  async fn write_metadata(app: &AppService, id: SongId, checksum: &str) {
+    let (_, metadata_json, _) = app.cdn.get_video_file_path(id).await;
+    let metadata = aya_dance_types::Song {
+      id,
+      category: 0,
+      title: id.to_string(),
+      category_name: "".to_string(),
+      title_spell: "".to_string(),
+      player_index: 0,
+      volume: 0.0,
+      start: 0,
+      end: 0,
+      flip: false,
+      skip_random: false,
+      original_url: None,
+      checksum: Some(checksum.to_string()),
+    };
+    std::fs::write(metadata_json, serde_json::to_string(&metadata).unwrap()).unwrap();
+  }
+
+  #[tokio::test]
+  async fn revoked_token_is_rejected() {
+    let app = test_app(&[1], &[]).await;
+    let routes = routes(&app);
+    let admin = "127.0.0.1:11451".parse::<SocketAddr>().unwrap();
+    let client = "192.168.1.1:11451".parse::<SocketAddr>().unwrap();
+
+    let resp = warp::test::request()
+      .path("/api/v1/videos/1.mp4")
+      .remote_addr(client)
+      .reply(&routes)
+      .await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    let location = resp.headers()[warp::http::header::LOCATION]
+      .to_str()
+      .unwrap()
+      .to_string();
+    let token = location
+      .split("auth=")
+      .nth(1)
+      .and_then(|x| x.split('&').next())
+      .unwrap()
+      .to_string();
+
+    let resp = warp::test::request()
+      .path(&location)
+      .remote_addr(client)
+      .reply(&routes)
+      .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Only admins can revoke tokens
+    let resp = warp::test::request()
+      .method("DELETE")
+      .path(&format!("/aya-api/v1/tokens/{}", token))
+      .remote_addr(client)
+      .reply(&routes)
+      .await;
+    assert_ne!(resp.status(), StatusCode::OK);
+
+    let resp = warp::test::request()
+      .method("DELETE")
+      .path(&format!("/aya-api/v1/tokens/{}", token))
+      .remote_addr(admin)
+      .reply(&routes)
+      .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = warp::test::request()
+      .path(&location)
+      .remote_addr(client)
+      .reply(&routes)
+      .await;
+    assert!(String::from_utf8_lossy(resp.body()).contains("BadToken"));
+  }
+
+  #[tokio::test]
+  async fn metrics_are_admin_only() {
+    let app = test_app(&[1], &[]).await;
+    let routes = routes(&app);
+    let admin = "127.0.0.1:11451".parse::<SocketAddr>().unwrap();
+    let client = "192.168.1.1:11451".parse::<SocketAddr>().unwrap();
+
+    let resp = warp::test::request()
+      .path("/v/1.mp4")
+      .remote_addr(client)
+      .reply(&routes)
+      .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = warp::test::request()
+      .path("/metrics")
+      .remote_addr(client)
+      .reply(&routes)
+      .await;
+    assert_ne!(resp.status(), StatusCode::OK);
+
+    let resp = warp::test::request()
+      .path("/metrics")
+      .remote_addr(admin)
+      .reply(&routes)
+      .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(String::from_utf8_lossy(resp.body()).contains("cdn_cache_hits_total{route=\"aya\"} 1"));
+  }
+
+  #[tokio::test]
+  async fn metrics_count_requests_per_route() {
+    let app = test_app(&[1], &[]).await;
+    let routes = routes(&app);
+    let admin = "127.0.0.1:11451".parse::<SocketAddr>().unwrap();
+    let client = "192.168.1.1:11451".parse::<SocketAddr>().unwrap();
+    let request = |path: &str| {
+      warp::test::request()
+        .path(path)
+        .remote_addr(client)
+        .reply(&routes)
+    };
+
+    assert_eq!(
+      request("/api/v1/videos/1.mp4").await.status(),
+      StatusCode::FOUND
+    );
+    assert_eq!(
+      request("/Api/Songs/play?id=2").await.status(),
+      StatusCode::FOUND
+    );
+    assert_eq!(request("/v/1.mp4?t=wd").await.status(), StatusCode::OK);
+    assert_ne!(request("/v/1.mp4?auth=bad").await.status(), StatusCode::OK);
+    let resp = warp::test::request()
+      .method("POST")
+      .path("/r/room1")
+      .json(&json!({"target": "alice", "id": 1}))
+      .reply(&routes)
+      .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = warp::test::request()
+      .path("/metrics")
+      .remote_addr(admin)
+      .reply(&routes)
+      .await;
+    let metrics = String::from_utf8_lossy(resp.body()).to_string();
+    for line in [
+      "tokens_issued_total{route=\"aya\"} 1",
+      "cdn_cache_misses_total{route=\"wanna_dance\"} 1",
+      "cdn_cache_hits_total{route=\"wanna_dance\"} 1",
+      "cdn_served_bytes_total{route=\"wanna_dance\"} 18",
+      "token_verification_failures_total 1",
+      "receipts_created_total 1",
+    ] {
+      assert!(metrics.contains(line), "{} not in:\n{}", line, metrics);
+    }
+  }
+
+  #[tokio::test]
+  async fn concurrent_misses_download_once() {
+    let video = b"definitely a video".to_vec();
+    let md5 = hex::encode(md5::compute(&video).as_slice());
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let upstream = {
+      let video = video.clone();
+      let fetches = fetches.clone();
+      warp::path!("files" / String / String).then(move |_, _| {
+        let video = video.clone();
+        let fetches = fetches.clone();
+        async move {
+          fetches.fetch_add(1, Ordering::SeqCst);
+          // Keep the first download in flight while the second one arrives.
+          tokio::time::sleep(Duration::from_millis(200)).await;
+          video
+        }
+      })
+    };
+    let (upstream, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let upstream = upstream.to_string();
+    let app = test_app(&[], &["--cache-upstream-ud-oversea", &upstream]).await;
+    let routes = routes(&app);
+    let path = format!("/files/2403/2-abcdef.mp4?e={}&s={}", md5, video.len());
+    let request = |port: u16| {
+      warp::test::request()
+        .path(&path)
+        .remote_addr(SocketAddr::from(([192, 168, 1, 1], port)))
+        .reply(&routes)
+    };
+
+    let (a, b) = tokio::join!(request(11451), request(11452));
+    assert_eq!(a.body().as_ref(), video.as_slice());
+    assert_eq!(b.body().as_ref(), video.as_slice());
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    let (cache_file, _, available) = app.cdn.get_video_file_path(2).await;
+    assert!(available);
+    assert_eq!(std::fs::read(cache_file).unwrap(), video);
+  }
+
+  #[tokio::test]
+  async fn healthz_reports_unreadable_video_path() {
+    let app = test_app(&[1], &[]).await;
+    let resp = warp::test::request()
+      .path("/healthz")
+      .reply(&routes(&app))
+      .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    std::fs::remove_dir_all(&app.cdn.video_path).unwrap();
+    let resp = warp::test::request()
+      .path("/healthz")
+      .reply(&routes(&app))
+      .await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(String::from_utf8_lossy(resp.body()).contains("degraded"));
+  }
+
+  #[tokio::test]
+  async fn interrupted_download_is_resumed() {
+    let video = b"definitely a video, but a longer one".to_vec();
+    let md5 = hex::encode(md5::compute(&video).as_slice());
+    let ranges = Arc::new(std::sync::Mutex::new(vec![]));
+    let upstream = {
+      let video = video.clone();
+      let ranges = ranges.clone();
+      warp::path!("files" / String / String)
+        .and(warp::header::optional::<String>("range"))
+        .map(move |_, _, range: Option<String>| {
+          ranges.lock().unwrap().push(range.clone());
+          let from = range
+            .and_then(|x| x.strip_prefix("bytes=")?.strip_suffix('-')?.parse().ok())
+            .unwrap_or(0usize);
+          warp::http::Response::builder()
+            .status(if from > 0 { 206 } else { 200 })
+            .body(video[from..].to_vec())
+            .unwrap()
+        })
+    };
+    let (upstream, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let upstream = upstream.to_string();
+    let app = test_app(&[], &["--cache-upstream-ud-oversea", &upstream]).await;
+    let file = "3-abcdef.mp4";
+    std::fs::create_dir_all(&app.cdn.cache_path).unwrap();
+    std::fs::write(app.cdn.partial_download_path(file), &video[..10]).unwrap();
+
+    let resp = warp::test::request()
+      .path(&format!("/files/2403/{}?e={}&s={}", file, md5, video.len()))
+      .remote_addr("192.168.1.1:11451".parse().unwrap())
+      .reply(&routes(&app))
+      .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.body().as_ref(), video.as_slice());
+    assert_eq!(*ranges.lock().unwrap(), vec![Some("bytes=10-".to_string())]);
+    let (cache_file, _, available) = app.cdn.get_video_file_path(3).await;
+    assert!(available);
+    assert_eq!(std::fs::read(cache_file).unwrap(), video);
+    assert!(!std::path::Path::new(&app.cdn.partial_download_path(file)).exists());
+  }
+
+  #[tokio::test]
+  async fn songs_missing_upstream_are_not_redirected() {
+    let found = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let upstream = {
+      let found = found.clone();
+      warp::path!("files" / String / String).map(move |_, _| {
+        let status = match found.load(Ordering::SeqCst) {
+          true => StatusCode::OK,
+          false => StatusCode::NOT_FOUND,
+        };
+        warp::http::Response::builder()
+          .status(status)
+          .body(vec![])
+          .unwrap()
+      })
+    };
+    let (upstream, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    let upstream = upstream.to_string();
+    let app = test_app(&[], &["--cache-upstream-ud-oversea", &upstream]).await;
+    let routes = routes(&app);
+    let fetch = |method: &str| {
+      warp::test::request()
+        .method(method)
+        .path("/files/2403/3-abcdef.mp4?e=0123456789abcdef&s=0")
+        .remote_addr("192.168.1.1:11451".parse().unwrap())
+        .reply(&routes)
+    };
+    let play = || {
+      warp::test::request()
+        .path("/Api/Songs/play?id=3")
+        .remote_addr("192.168.1.1:11451".parse().unwrap())
+        .reply(&routes)
+    };
+
+    assert_eq!(play().await.status(), StatusCode::FOUND);
+    assert_eq!(fetch("GET").await.status(), StatusCode::NOT_FOUND);
+    let resp = play().await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["code"], "song_missing_upstream");
+    let resp = warp::test::request()
+      .path("/aya-api/v1/cache/health")
+      .remote_addr("127.0.0.1:11451".parse().unwrap())
+      .reply(&routes)
+      .await;
+    let report: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(report["missing_upstream"], json!({"3": 404}));
+
+    found.store(true, Ordering::SeqCst);
+    assert_eq!(fetch("HEAD").await.status(), StatusCode::OK);
+    assert_eq!(play().await.status(), StatusCode::FOUND);
+  }
+
+  #[tokio::test]
+  async fn songs_are_prewarmed_in_the_background() {
+    let video = b"definitely a video".to_vec();
+    let md5 = hex::encode(md5::compute(&video).as_slice());
+    let upstream = {
+      let (video, md5) = (video.clone(), md5.clone());
+      let size = video.len();
+      let api = warp::path!("Api" / "Songs" / "play")
+        .and(warp::query::<HashMap<String, String>>())
+        .map(move |qs: HashMap<String, String>| {
+          let response = warp::http::Response::builder();
+          match qs["id"].as_str() {
+            "5" => response.status(StatusCode::FOUND).header(
+              warp::http::header::LOCATION,
+              format!(
+                "https://play.udon.dance/files/2403/5-abcdef.mp4?e={}&s={}",
+                md5, size
+              ),
+            ),
+            _ => response.status(StatusCode::NOT_FOUND),
+          }
+          .body(vec![])
+          .unwrap()
+        });
+      let files = warp::path!("files" / "2403" / "5-abcdef.mp4").map(move || video.clone());
+      api.or(files)
+    };
+    let (upstream, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    let api = format!("http://{}", upstream);
+    let upstream = upstream.to_string();
+    let app = test_app(
+      &[1],
+      &[
+        "--cache-upstream-ud-oversea",
+        &upstream,
+        "--cache-upstream-ud-api",
+        &api,
+      ],
+    )
+    .await;
+    let routes = routes(&app);
+    let prewarm = |remote: &str| {
+      warp::test::request()
+        .method("POST")
+        .path("/aya-api/v1/cache/prewarm")
+        .json(&json!([1, 5, 6, 5]))
+        .remote_addr(remote.parse().unwrap())
+        .reply(&routes)
+    };
+
+    assert_eq!(
+      prewarm("192.168.1.1:11451").await.status(),
+      StatusCode::FORBIDDEN
+    );
+    // Refused before the body is parsed.
+    let resp = warp::test::request()
+      .method("POST")
+      .path("/aya-api/v1/cache/prewarm")
+      .body("not json")
+      .remote_addr("192.168.1.1:11451".parse().unwrap())
+      .reply(&routes)
+      .await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = prewarm("127.0.0.1:11451").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    let path = format!(
+      "/aya-api/v1/cache/prewarm/{}",
+      body["job"].as_str().unwrap()
+    );
+    let mut progress = json!(null);
+    for _ in 0..50 {
+      let resp = warp::test::request()
+        .path(&path)
+        .remote_addr("127.0.0.1:11451".parse().unwrap())
+        .reply(&routes)
+        .await;
+      progress = serde_json::from_slice(resp.body()).unwrap();
+      if progress["pending"] == 0 {
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(progress["done"], 2);
+    assert_eq!(progress["failed"], 1);
+    assert!(progress["errors"]["6"].as_str().unwrap().contains("404"));
+    let (video_file, _, available) = app.cdn.get_video_file_path(5).await;
+    assert!(available);
+    assert_eq!(std::fs::read(video_file).unwrap(), video);
+    assert_eq!(app.cdn.get_video_checksum(5).await, Some(md5));
+  }
+
+  #[tokio::test]
+  async fn songs_list_is_served_stale_when_upstream_fails() {
+    let up = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let upstream = {
+      let (up, fetches) = (up.clone(), fetches.clone());
+      warp::path!("Api" / "Songs" / "list").map(move || {
+        fetches.fetch_add(1, Ordering::SeqCst);
+        match up.load(Ordering::SeqCst) {
+          true => warp::reply::json(&json!({"songs": [1, 2]})).into_response(),
+          false => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+        }
+      })
+    };
+    let (upstream, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    let api = format!("http://{}", upstream);
+    let list = |app: AppService| async move {
+      let resp = warp::test::request()
+        .path("/Api/Songs/list")
+        .remote_addr("192.168.1.1:11451".parse().unwrap())
+        .reply(&routes(&app))
+        .await;
+      assert_eq!(resp.status(), StatusCode::OK);
+      serde_json::from_slice::<serde_json::Value>(resp.body()).unwrap()
+    };
+
+    // Nothing cached yet, the local index stands in.
+    up.store(false, Ordering::SeqCst);
+    let app = test_app(&[1], &["--cache-upstream-ud-api", &api]).await;
+    write_metadata(&app, 1, "").await;
+    let body = list(app.clone()).await;
+    assert_eq!(body["WDSelfHostedCDNStale"], true);
+    assert_eq!(body["categories"][0]["entries"][0]["id"], 1);
+
+    up.store(true, Ordering::SeqCst);
+    let body = list(app.clone()).await;
+    assert_eq!(body, json!({"songs": [1, 2], "WDSelfHostedCDNCached": []}));
+    // Fresh enough, the upstream is not asked again.
+    list(app.clone()).await;
+    assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+    // Another instance over the same cache directory, refreshing every time.
+    up.store(false, Ordering::SeqCst);
+    let opts = AppOpts {
+      songs_list_ttl_seconds: 0,
+      ..app.opts.clone()
+    };
+    let app = AppServiceImpl::new(opts).await.unwrap();
+    let body = list(app).await;
+    assert_eq!(
+      body,
+      json!({"songs": [1, 2], "WDSelfHostedCDNStale": true, "WDSelfHostedCDNCached": []})
+    );
+    assert_eq!(fetches.load(Ordering::SeqCst), 3);
+  }
+
+  #[tokio::test]
+  async fn songs_list_tells_which_songs_are_cached() {
+    let upstream = warp::path!("Api" / "Songs" / "list").map(|| warp::reply::json(&json!({})));
+    let (upstream, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    let api = format!("http://{}", upstream);
+    // 2 has no checksum and 3 no video, `serve_token` would miss them.
+    let app = test_app(&[1, 2, 3], &["--cache-upstream-ud-api", &api]).await;
+    write_metadata(&app, 1, "0123456789abcdef").await;
+    write_metadata(&app, 3, "0123456789abcdef").await;
+    let (video, _, _) = app.cdn.get_video_file_path(3).await;
+    std::fs::remove_file(video).unwrap();
+
+    let resp = warp::test::request()
+      .path("/Api/Songs/list")
+      .remote_addr("192.168.1.1:11451".parse().unwrap())
+      .reply(&routes(&app))
+      .await;
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["WDSelfHostedCDNCached"], json!([1]));
+  }
+
+  #[tokio::test]
+  async fn conditional_get_returns_not_modified() {
+    let app = test_app(&[1], &[]).await;
+    write_metadata(&app, 1, "0123456789abcdef").await;
+    let client = "192.168.1.1:11451".parse::<SocketAddr>().unwrap();
+
+    let resp = warp::test::request()
+      .path("/v/1.mp4")
+      .remote_addr(client)
+      .reply(&routes(&app))
+      .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let etag = resp.headers()[warp::http::header::ETAG].clone();
+    assert_eq!(etag, "\"0123456789abcdef\"");
+    assert_eq!(
+      resp.headers()[warp::http::header::CACHE_CONTROL],
+      "public, max-age=86400"
+    );
+
+    let resp = warp::test::request()
+      .path("/v/1.mp4")
+      .header(warp::http::header::IF_NONE_MATCH, etag)
+      .remote_addr(client)
+      .reply(&routes(&app))
+      .await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert!(resp.body().is_empty());
+  }
+
+  #[tokio::test]
+  async fn head_returns_headers_only() {
+    let app = test_app(&[1], &[]).await;
+    write_metadata(&app, 1, "0123456789abcdef").await;
+    let (video_file, _, _) = app.cdn.get_video_file_path(1).await;
+    let size = std::fs::metadata(&video_file).unwrap().len();
+    let client = "192.168.1.1:11451".parse::<SocketAddr>().unwrap();
+
+    let resp = warp::test::request()
+      .method("HEAD")
+      .path("/v/1.mp4")
+      .remote_addr(client)
+      .reply(&routes(&app))
+      .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-length"], size.to_string());
+    assert_eq!(resp.headers()["content-type"], "video/mp4");
+    assert_eq!(resp.headers()["accept-ranges"], "bytes");
+    assert_eq!(resp.headers()["etag"], "\"0123456789abcdef\"");
+    assert!(resp.body().is_empty());
+  }
+
+  #[tokio::test]
+  async fn normalized_videos_are_told_apart() {
+    let get = |args: &'static [&'static str]| async move {
+      let app = test_app(&[1], args).await;
+      write_metadata(&app, 1, "0123").await;
+      std::fs::create_dir_all(app.cdn.cache_path.as_str()).unwrap();
+      let cache = |name: &str| format!("{}/{}", app.cdn.cache_path, name);
+      std::fs::write(cache("1-0123-audio-offset-0.5.mp4"), b"compensated").unwrap();
+      std::fs::write(cache("1-0123-audio-offset-0.5-loudnorm.mp4"), b"normalized").unwrap();
+      warp::test::request()
+        .path("/v/1.mp4")
+        .remote_addr("192.168.1.1:11451".parse().unwrap())
+        .reply(&routes(&app))
+        .await
+    };
+
+    let resp = get(&["--audio-compensation", "0.5"]).await;
+    assert_eq!(resp.body().as_ref(), b"compensated");
+    assert_eq!(resp.headers()["etag"], "\"0123-audio-offset-0.5\"");
+    let resp = get(&["--audio-compensation", "0.5", "--normalize-audio"]).await;
+    assert_eq!(resp.body().as_ref(), b"normalized");
+    assert_eq!(resp.headers()["etag"], "\"0123-audio-offset-0.5-loudnorm\"");
+  }
+
+  #[tokio::test]
+  async fn corrupt_cache_is_downloaded_again() {
+    let video = b"definitely a video".to_vec();
+    let md5 = hex::encode(md5::compute(&video).as_slice());
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let upstream = {
+      let video = video.clone();
+      let fetches = fetches.clone();
+      warp::path!("files" / String / String).map(move |_, _| {
+        fetches.fetch_add(1, Ordering::SeqCst);
+        video.clone()
+      })
+    };
+    let (upstream, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let upstream = upstream.to_string();
+    let app = test_app(
+      &[4],
+      &[
+        "--cache-upstream-ud-oversea",
+        &upstream,
+        "--cache-verify-on-serve",
+      ],
+    )
+    .await;
+    let (cache_file, _, _) = app.cdn.get_video_file_path(4).await;
+    // Same size, different bytes.
+    std::fs::write(&cache_file, b"definitely a vide0").unwrap();
+    write_metadata(&app, 4, &md5).await;
+
+    let path = format!("/files/2403/4-abcdef.mp4?e={}&s={}", md5, video.len());
+    for _ in 0..2 {
+      let resp = warp::test::request()
+        .path(&path)
+        .remote_addr("192.168.1.1:11451".parse().unwrap())
+        .reply(&routes(&app))
+        .await;
+      assert_eq!(resp.body().as_ref(), video.as_slice());
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    assert_eq!(std::fs::read(cache_file).unwrap(), video);
+  }
+
+  #[tokio::test]
+  async fn corrupt_songs_are_reported_and_fixed() {
+    let app = test_app(&[1, 2, 3], &[]).await;
+    let (video, _, _) = app.cdn.get_video_file_path(1).await;
+    let md5 = hex::encode(md5::compute(std::fs::read(&video).unwrap()).as_slice());
+    write_metadata(&app, 1, &md5).await;
+    write_metadata(&app, 2, "0123456789abcdef").await;
+    write_metadata(&app, 3, &md5).await;
+    std::fs::remove_file(app.cdn.get_video_file_path(3).await.0).unwrap();
+    app.cdn.scan_integrity().await.unwrap();
+
+    let admin = "127.0.0.1:11451".parse::<SocketAddr>().unwrap();
+    let resp = warp::test::request()
+      .path("/aya-api/v1/cache/health")
+      .remote_addr(admin)
+      .reply(&routes(&app))
+      .await;
+    let report: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(report["ok"], 1);
+    assert_eq!(report["corrupt"], 1);
+    assert_eq!(report["missing"], 1);
+    assert_eq!(report["bad"][0], json!({"id": 2, "state": "corrupt"}));
+    assert_eq!(report["bad"][1], json!({"id": 3, "state": "missing"}));
+
+    let resp = warp::test::request()
+      .path("/aya-api/v1/cache/health?fix=true")
+      .remote_addr(admin)
+      .reply(&routes(&app))
+      .await;
+    let report: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(report["ok"], 1);
+    assert_eq!(report["corrupt"], 0);
+    assert_eq!(report["bad"], json!([]));
+    assert!(app.cdn.get_video_file_path(1).await.2);
+    assert!(!std::path::Path::new(&format!("{}/2", app.cdn.video_path)).exists());
+  }
+
+  #[tokio::test]
+  async fn receipt_events_are_pushed() {
+    let app = test_app(&[], &["--receipt-default-expire-seconds", "1"]).await;
+    let mut ws = warp::test::ws()
+      .path("/ws/r/room1")
+      .handshake(routes(&app))
+      .await
+      .unwrap();
+
+    for room in ["room2", "room1"] {
+      let resp = warp::test::request()
+        .method("POST")
+        .path(&format!("/r/{}", room))
+        .json(&json!({"target": "alice", "id": 1}))
+        .reply(&routes(&app))
+        .await;
+      assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    let event: serde_json::Value =
+      serde_json::from_str(ws.recv().await.unwrap().to_str().unwrap()).unwrap();
+    assert_eq!(event["type"], "Created");
+    assert_eq!(event["data"]["room_id"], "room1");
+    let receipt_id = event["data"]["receipt_id"].clone();
+
+    let event: serde_json::Value =
+      serde_json::from_str(ws.recv().await.unwrap().to_str().unwrap()).unwrap();
+    assert_eq!(event, json!({"type": "Expired", "data": receipt_id}));
+  }
+
+  #[tokio::test]
+  async fn receipt_events_are_streamed() {
+    let app = test_app(&[], &[]).await;
+    let (addr, server) = warp::serve(routes(&app)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    let resp = reqwest::get(format!("http://{}/r/room1/events", addr))
+      .await
+      .unwrap();
+    assert_eq!(resp.headers()["content-type"], "text/event-stream");
+    let mut body = resp.bytes_stream();
+
+    let created = app
+      .receipt
+      .create_receipt(
+        "room1".to_string(),
+        "alice".to_string(),
+        Either::Left(1),
+        None,
+        None,
+      )
+      .await
+      .unwrap();
+    let mut received = String::new();
+    while !received.ends_with("\n\n") {
+      received += std::str::from_utf8(&body.next().await.unwrap().unwrap()).unwrap();
+    }
+    let data = received
+      .lines()
+      .find_map(|line| line.strip_prefix("data:"))
+      .unwrap();
+    let receipt: serde_json::Value = serde_json::from_str(data).unwrap();
+    assert!(received.starts_with("event:created\n"));
+    assert_eq!(receipt["receipt_id"], created.receipt_id);
+  }
+
+  #[tokio::test]
+  async fn cache_events_are_streamed() {
+    let app = test_app(&[1], &[]).await;
+    let (addr, server) = warp::serve(routes(&app)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    let resp = reqwest::get(format!("http://{}/admin/cache/events", addr))
+      .await
+      .unwrap();
+    assert_eq!(resp.headers()["content-type"], "text/event-stream");
+    let mut body = resp.bytes_stream();
+
+    for path in ["/Api/Songs/play?id=2", "/v/1.mp4"] {
+      warp::test::request()
+        .path(path)
+        .remote_addr("192.168.1.1:11451".parse().unwrap())
+        .reply(&routes(&app))
+        .await;
+    }
+    let mut received = String::new();
+    while received.matches("\n\n").count() < 2 {
+      received += std::str::from_utf8(&body.next().await.unwrap().unwrap()).unwrap();
+    }
+    let events = received
+      .lines()
+      .filter_map(|line| line.strip_prefix("data:"))
+      .map(|data| serde_json::from_str::<serde_json::Value>(data).unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      events,
+      vec![
+        json!({
+          "type": "miss",
+          "id": 2,
+          "redirected_to": "https://api.udon.dance/Api/Songs/play?id=2",
+        }),
+        json!({"type": "hit", "id": 1}),
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn songs_are_searched_by_title() {
+    let app = test_app(&[1, 2, 12], &[]).await;
+    for id in [1, 2, 12] {
+      write_metadata(&app, id, "").await;
+    }
+    let search = |qs: &'static str| {
+      let app = app.clone();
+      async move {
+        let resp = warp::test::request()
+          .path(&format!("/aya-api/v1/search?{}", qs))
+          .reply(&routes(&app))
+          .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let songs: Vec<aya_dance_types::Song> = serde_json::from_slice(resp.body()).unwrap();
+        let mut ids = songs.into_iter().map(|x| x.id).collect::<Vec<_>>();
+        ids.sort();
+        ids
+      }
+    };
+    assert_eq!(search("q=1").await, vec![1, 12]);
+    assert_eq!(search("q=1&limit=1").await.len(), 1);
+    assert_eq!(search("q=3").await, Vec::<SongId>::new());
+  }
+
+  #[tokio::test]
+  async fn songs_are_searched_with_index_age() {
+    let app = test_app(&[1, 2], &[]).await;
+    for (id, title, title_spell) in [
+      (1, "极乐净土", "ji le jing tu"),
+      (2, "千本桜", "qian ben ying"),
+    ] {
+      let (_, metadata_json, _) = app.cdn.get_video_file_path(id).await;
+      let song = json!({
+        "id": id, "category": id, "title": title, "categoryName": "", "titleSpell": title_spell,
+        "playerIndex": 0, "volume": 0.0, "start": 0, "end": 0, "flip": false,
+        "skipRandom": false, "originalUrl": null, "checksum": null,
+      });
+      std::fs::write(&metadata_json, song.to_string()).unwrap();
+    }
+    let routes = routes(&app);
+    let search = |qs: &str| {
+      warp::test::request()
+        .path(&format!("/aya-api/v1/songs/search?{}", qs))
+        .reply(&routes)
+    };
+
+    let resp = search("q=%E5%87%80%E5%9C%9F").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["songs"][0]["id"], 1);
+    let updated_at = body["updated_at"].as_i64().unwrap();
+
+    let body: serde_json::Value =
+      serde_json::from_slice(search("q=QIAN+ben").await.body()).unwrap();
+    assert_eq!(body["songs"].as_array().unwrap().len(), 1);
+    assert_eq!(body["songs"][0]["id"], 2);
+    // The cached index is searched, not rebuilt.
+    assert_eq!(body["updated_at"], updated_at);
+
+    let body: serde_json::Value =
+      serde_json::from_slice(search("q=i&category=2").await.body()).unwrap();
+    assert_eq!(body["songs"].as_array().unwrap().len(), 1);
+    assert_eq!(body["songs"][0]["id"], 2);
+  }
+
+  #[tokio::test]
+  async fn index_is_rebuilt_by_admin_only() {
+    let app = test_app(&[1, 2], &[]).await;
+    write_metadata(&app, 1, "").await;
+    let routes = routes(&app);
+    let clear = |remote: &str| {
+      warp::test::request()
+        .method("DELETE")
+        .path("/aya-api/v1/songs")
+        .remote_addr(remote.parse().unwrap())
+        .reply(&routes)
+    };
+    assert_eq!(
+      clear("192.168.1.1:11451").await.status(),
+      StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+      app.index.get_index(false).await.unwrap().categories[0]
+        .entries
+        .len(),
+      1
+    );
+
+    write_metadata(&app, 2, "").await;
+    assert_eq!(clear("127.0.0.1:11451").await.status(), StatusCode::OK);
+    assert_eq!(
+      app.index.get_index(false).await.unwrap().categories[0]
+        .entries
+        .len(),
+      2
+    );
+  }
+
+  #[tokio::test]
+  async fn song_index_is_cached_by_clients() {
+    use std::io::Read;
+
+    let app = test_app(&[1, 2], &[]).await;
+    write_metadata(&app, 1, "").await;
+    let routes = routes(&app);
+    let get = |headers: &[(&str, &str)]| {
+      headers
+        .iter()
+        .fold(
+          warp::test::request()
+            .path("/aya-api/v1/songs")
+            .remote_addr("192.168.1.1:11451".parse().unwrap()),
+          |req, (name, value)| req.header(*name, *value),
+        )
+        .reply(&routes)
+    };
+
+    let resp = get(&[]).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let etag = resp.headers()["etag"].to_str().unwrap().to_string();
+    let json = resp.body().clone();
+    assert_eq!(
+      serde_json::from_slice::<serde_json::Value>(&json).unwrap()["categories"][0]["entries"][0]
+        ["id"],
+      1
+    );
+
+    let resp = get(&[("Accept-Encoding", "deflate, gzip;q=0.8")]).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    assert_eq!(resp.headers()["etag"], etag.as_str());
+    let mut decompressed = vec![];
+    flate2::read::GzDecoder::new(&resp.body()[..])
+      .read_to_end(&mut decompressed)
+      .unwrap();
+    assert_eq!(decompressed, json);
+    let resp = get(&[("Accept-Encoding", "gzip;q=0")]).await;
+    assert!(!resp.headers().contains_key("content-encoding"));
+
+    let resp = get(&[("If-None-Match", etag.as_str())]).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert!(resp.body().is_empty());
+
+    write_metadata(&app, 2, "").await;
+    app.index.refresh_song(2).await;
+    let resp = get(&[("If-None-Match", etag.as_str())]).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_ne!(resp.headers()["etag"], etag.as_str());
+  }
+
+  #[tokio::test]
+  async fn compensator_status_is_admin_only() {
+    let app = test_app(&[], &[]).await;
+    let routes = routes(&app);
+    let status = |remote: &str| {
+      warp::test::request()
+        .path("/admin/compensator/status")
+        .remote_addr(remote.parse().unwrap())
+        .reply(&routes)
+    };
+    assert_eq!(
+      status("192.168.1.1:11451").await.status(),
+      StatusCode::FORBIDDEN
+    );
+
+    let _task = app.compensator.track(CompensatorTask {
+      song_id: 1,
+      audio_offset: 0.5,
+    });
+    let resp = status("127.0.0.1:11451").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["running"][0]["song_id"], 1);
+    assert_eq!(body["running"][0]["audio_offset"], 0.5);
+  }
+
+  #[tokio::test]
+  async fn sni_proxy_targets_are_reloaded_by_admin_only() {
+    let app = test_app(&[], &[]).await;
+    let routes = routes(&app);
+    let reload = |remote: &str, body: &str| {
+      warp::test::request()
+        .method("POST")
+        .path("/aya-api/v1/sni/reload")
+        .remote_addr(remote.parse().unwrap())
+        .body(body.to_string())
+        .reply(&routes)
+    };
+    let configured = app.sni.mappings().await;
+    assert_eq!(
+      reload("192.168.1.1:11451", "a.com=h1:443").await.status(),
+      StatusCode::FORBIDDEN
+    );
+    assert_eq!(app.sni.mappings().await, configured);
+    // Rejected before the body is read.
+    let too_large = "a".repeat(app.opts.max_body_bytes as usize + 1);
+    assert_eq!(
+      reload("192.168.1.1:11451", &too_large).await.status(),
+      StatusCode::FORBIDDEN
+    );
+
+    let resp = reload("127.0.0.1:11451", "a.com=h1:443, h2:443,b.com=h3:443").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["a.com"], json!(["h1:443", "h2:443"]));
+    assert_eq!(body["b.com"], json!(["h3:443"]));
+
+    // A bad target keeps the mappings as they were.
+    let resp = reload("127.0.0.1:11451", "a.com=h4:443,b.com=h5").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(app.sni.mappings().await.len(), 2);
+    assert_eq!(app.sni.mappings().await["a.com"], vec!["h1:443", "h2:443"]);
+  }
+
+  #[tokio::test]
+  async fn sni_stats_are_shown_to_admin_only() {
+    let app = test_app(&[], &[]).await;
+    let routes = routes(&app);
+    let get = |remote: &str| {
+      warp::test::request()
+        .path("/aya-api/v1/sni/stats")
+        .remote_addr(remote.parse().unwrap())
+        .reply(&routes)
+    };
+    assert_eq!(
+      get("192.168.1.1:11451").await.status(),
+      StatusCode::FORBIDDEN
+    );
+    let resp = get("127.0.0.1:11451").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body, json!({}));
+  }
+
+  fn override_upload(path: &str, remote: &str, video: &[u8]) -> warp::test::RequestBuilder {
+    let boundary = "override-boundary";
+    let mut body = format!(
+      "--{b}\r\nContent-Disposition: form-data; name=\"video\"; filename=\"1.mp4\"\r\n\
+       Content-Type: video/mp4\r\n\r\n",
+      b = boundary
+    )
+    .into_bytes();
+    body.extend_from_slice(video);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    warp::test::request()
+      .method("POST")
+      .path(path)
+      .remote_addr(remote.parse().unwrap())
+      .header(
+        "content-type",
+        format!("multipart/form-data; boundary={}", boundary),
+      )
+      .body(body)
+  }
+
+  #[tokio::test]
+  async fn override_videos_are_checked_before_saving() {
+    let app = test_app(&[1], &[]).await;
+    let dir = format!("{}/override", app.cdn.cache_path);
+    let resp = override_upload("/admin/override/1.mp4", "127.0.0.1:11451", b"nope")
+      .reply(&routes(&app))
+      .await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let app = test_app(&[1], &["--video-override-path-ud", dir.as_str()]).await;
+    let routes = routes(&app);
+    let resp = override_upload("/admin/override/1.mp4", "192.168.1.1:11451", b"nope")
+      .reply(&routes)
+      .await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = override_upload("/admin/override/1.mp4", "127.0.0.1:11451", b"not a video")
+      .reply(&routes)
+      .await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["code"], "bad_override_video");
+    assert!(app.video_override_file(1).is_none());
+    // Nothing is left behind either.
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+  }
+
+  /// Just enough of an MP4 for `starts_with_ftyp`.
+  fn fake_mp4(fill: u8, len: usize) -> Vec<u8> {
+    let mut video = b"\0\0\0\x18ftypisom".to_vec();
+    video.resize(len, fill);
+    video
+  }
+
+  #[tokio::test]
+  async fn overrides_are_managed_over_http() {
+    let root = std::env::temp_dir().join(format!("wanna-cdn-test-{}", uuid::Uuid::new_v4()));
+    let dir = root.to_str().unwrap().to_string();
+    let app = test_app(
+      &[],
+      &[
+        "--video-override-path-ud",
+        dir.as_str(),
+        "--video-override-max-bytes",
+        "64",
+      ],
+    )
+    .await;
+    let routes = routes(&app);
+    std::fs::create_dir_all(app.cdn.cache_path.as_str()).unwrap();
+    let compensated = format!("{}/1-0123-audio-offset-0.5.mp4", app.cdn.cache_path);
+    std::fs::write(&compensated, b"compensated").unwrap();
+    let request = |method: &str, path: &str, remote: &str| {
+      warp::test::request()
+        .method(method)
+        .path(path)
+        .remote_addr(remote.parse().unwrap())
+    };
+    let admin = "127.0.0.1:11451";
+
+    let put = |video: Vec<u8>| request("PUT", "/aya-api/v1/overrides/1", admin).body(video);
+    assert_eq!(
+      request("PUT", "/aya-api/v1/overrides/1", "192.168.1.1:11451")
+        .body(fake_mp4(0, 32))
+        .reply(&routes)
+        .await
+        .status(),
+      StatusCode::FORBIDDEN
+    );
+    let resp = put(b"not a video at all".to_vec()).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = put(fake_mp4(0, 65)).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(app.video_override_file(1).is_none());
+    assert!(std::path::Path::new(&compensated).exists());
+
+    let resp = put(fake_mp4(0, 64)).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["size"], 64);
+    assert!(!std::path::Path::new(&compensated).exists());
+    // Nothing is left behind.
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+    let resp = request("GET", "/aya-api/v1/overrides", admin)
+      .reply(&routes)
+      .await;
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body[0]["id"], 1);
+    assert_eq!(body[0]["size"], 64);
+    assert_eq!(body.as_array().unwrap().len(), 1);
+
+    let resp = request("DELETE", "/aya-api/v1/overrides/1", admin)
+      .reply(&routes)
+      .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = request("GET", "/aya-api/v1/overrides", admin)
+      .reply(&routes)
+      .await;
+    assert_eq!(resp.body().as_ref(), b"[]");
+  }
+
+  #[tokio::test]
+  async fn overrides_are_replaced_while_served() {
+    let root = std::env::temp_dir().join(format!("wanna-cdn-test-{}", uuid::Uuid::new_v4()));
+    let dir = root.to_str().unwrap().to_string();
+    let app = test_app(&[1], &["--video-override-path-ud", dir.as_str()]).await;
+    let old = fake_mp4(b'o', 8 << 20);
+    let new = fake_mp4(b'n', 1 << 10);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(format!("{}/1.mp4", dir), &old).unwrap();
+    let (addr, server) = warp::serve(routes(&app)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let serving = reqwest::get(format!("http://{}/v/1.mp4", addr))
+      .await
+      .unwrap();
+    let resp = reqwest::Client::new()
+      .put(format!("http://{}/aya-api/v1/overrides/1", addr))
+      .body(new.clone())
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+
+    assert!(serving.bytes().await.unwrap() == old);
+    let served = reqwest::get(format!("http://{}/v/1.mp4", addr))
+      .await
+      .unwrap()
+      .bytes()
+      .await
+      .unwrap();
+    assert!(served == new);
+  }
+
+  #[tokio::test]
+  async fn override_videos_are_served_until_deleted() {
+    let root = std::env::temp_dir().join(format!("wanna-cdn-test-{}", uuid::Uuid::new_v4()));
+    let dir = root.to_str().unwrap().to_string();
+    let app = test_app(&[1], &["--video-override-path-ud", dir.as_str()]).await;
+    let routes = routes(&app);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(format!("{}/1.mp4", dir), b"override").unwrap();
+    let get = || {
+      warp::test::request()
+        .path("/v/1.mp4")
+        .remote_addr("192.168.1.1:11451".parse().unwrap())
+        .reply(&routes)
+    };
+    let delete = |remote: &str| {
+      warp::test::request()
+        .method("DELETE")
+        .path("/admin/override/1")
+        .remote_addr(remote.parse().unwrap())
+        .reply(&routes)
+    };
+
+    let resp = get().await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.body().as_ref(), b"override");
+
+    assert_eq!(
+      delete("192.168.1.1:11451").await.status(),
+      StatusCode::FORBIDDEN
+    );
+    assert_eq!(delete("127.0.0.1:11451").await.status(), StatusCode::OK);
+    assert_eq!(
+      delete("127.0.0.1:11451").await.status(),
+      StatusCode::NOT_FOUND
+    );
+    assert_eq!(get().await.body().as_ref(), b"not really a video");
+  }
+
+  #[tokio::test]
+  async fn songs_are_looked_up_one_by_one() {
+    let root = std::env::temp_dir().join(format!("wanna-cdn-test-{}", uuid::Uuid::new_v4()));
+    let dir = root.to_str().unwrap().to_string();
+    let app = test_app(&[1, 2], &["--video-override-path-ud", dir.as_str()]).await;
+    write_metadata(&app, 1, "0123456789abcdef").await;
+    app.index.get_index(true).await.unwrap();
+    // Not in the index built above.
+    write_metadata(&app, 2, "fedcba9876543210").await;
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(format!("{}/3.mp4", dir), b"override").unwrap();
+    let routes = routes(&app);
+    let get = |id: SongId| {
+      warp::test::request()
+        .path(&format!("/api/v1/songs/{}", id))
+        .reply(&routes)
+    };
+
+    for (id, checksum) in [
+      (1, json!("0123456789abcdef")),
+      (2, json!("fedcba9876543210")),
+      (3, json!(null)),
+    ] {
+      let resp = get(id).await;
+      assert_eq!(resp.status(), StatusCode::OK);
+      let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+      assert_eq!(body["id"], id);
+      assert_eq!(body["checksum"], checksum);
+    }
+
+    let resp = get(4).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["code"], "not_found");
+  }
+
+  #[tokio::test]
+  async fn song_index_changes_are_diffed() {
+    let app = test_app(&[], &[]).await;
+    for id in [1, 2] {
+      std::fs::create_dir_all(format!("{}/{}", app.cdn.video_path, id)).unwrap();
+      write_metadata(&app, id, "0123456789abcdef").await;
+    }
+    let routes = routes(&app);
+    let diff = |since: i64| {
+      warp::test::request()
+        .path(&format!("/aya-api/v1/songs/diff?since={}", since))
+        .reply(&routes)
+    };
+    let ids = |body: &serde_json::Value, field: &str| {
+      body[field]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| x.as_u64().or_else(|| x["id"].as_u64()).unwrap())
+        .collect::<Vec<_>>()
+    };
+
+    let resp = diff(0).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(ids(&body, "added"), vec![1, 2]);
+    assert_eq!(ids(&body, "removed"), Vec::<u64>::new());
+    let updated_at = body["updated_at"].as_i64().unwrap();
+    assert_eq!(diff(updated_at).await.status(), StatusCode::NOT_MODIFIED);
+
+    // Song 1 hasn't changed in a while.
+    let an_hour_ago = std::time::SystemTime::now() - Duration::from_secs(3600);
+    std::fs::File::open(format!("{}/1", app.cdn.video_path))
+      .unwrap()
+      .set_modified(an_hour_ago)
+      .unwrap();
+    std::fs::remove_dir_all(format!("{}/2", app.cdn.video_path)).unwrap();
+    app.index.get_index(true).await.unwrap();
+    let body: serde_json::Value =
+      serde_json::from_slice(diff(updated_at - 1).await.body()).unwrap();
+    assert_eq!(ids(&body, "added"), Vec::<u64>::new());
+    assert_eq!(ids(&body, "removed"), vec![2]);
+  }
+
+  #[tokio::test]
+  async fn songs_are_checked_in_batches() {
+    let app = test_app(&[1, 3], &[]).await;
+    let routes = routes(&app);
+    let exist = |ids: Vec<SongId>| {
+      warp::test::request()
+        .method("POST")
+        .path("/api/v1/songs/exists")
+        .json(&json!({ "ids": ids }))
+        .reply(&routes)
+    };
+
+    let resp = exist(vec![3, 2, 1]).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body, json!({"cached": [3, 1], "missing": [2]}));
+
+    let resp = exist((0..=MAX_SONGS_EXIST_IDS as SongId).collect()).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn cached_videos_are_listed_by_page() {
+    let app = test_app(&[3, 1, 2], &[]).await;
+    write_metadata(&app, 2, "0123456789abcdef").await;
+    std::fs::remove_file(app.cdn.get_video_file_path(3).await.1).unwrap();
+    let routes = routes(&app);
+    let list = |path: &str, remote: &str| {
+      warp::test::request()
+        .path(path)
+        .remote_addr(remote.parse().unwrap())
+        .reply(&routes)
+    };
+
+    assert_eq!(
+      list("/admin/cache", "192.168.1.1:11451").await.status(),
+      StatusCode::FORBIDDEN
+    );
+    let resp = list("/admin/cache", "127.0.0.1:11451").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    let ids = body
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|x| x["id"].as_u64().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(ids, vec![1, 2, 3]);
+    assert_eq!(body[1]["checksum"], "0123456789abcdef");
+    assert_eq!(body[1]["size_bytes"], b"not really a video".len());
+    assert_eq!(body[2]["has_metadata"], false);
+
+    let resp = list("/admin/cache?offset=1&limit=1", "127.0.0.1:11451").await;
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["id"], 2);
+  }
+
+  #[tokio::test]
+  async fn songs_are_evicted_one_by_one() {
+    let app = test_app(&[1, 11], &[]).await;
+    let routes = routes(&app);
+    std::fs::create_dir_all(app.cdn.cache_path.as_str()).unwrap();
+    let compensated = format!("{}/1-0123-audio-offset-0.5.mp4", app.cdn.cache_path);
+    let other = format!("{}/11-0123-audio-offset-0.5.mp4", app.cdn.cache_path);
+    for file in [&compensated, &other] {
+      std::fs::write(file, b"compensated").unwrap();
+    }
+    let evict = |remote: &str| {
+      warp::test::request()
+        .method("DELETE")
+        .path("/admin/cache/1")
+        .remote_addr(remote.parse().unwrap())
+        .reply(&routes)
+    };
+
+    assert_eq!(
+      evict("192.168.1.1:11451").await.status(),
+      StatusCode::FORBIDDEN
+    );
+    let resp = evict("127.0.0.1:11451").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["evicted"], true);
+    assert!(!app.cdn.get_video_file_path(1).await.2);
+    assert!(!std::path::Path::new(&compensated).exists());
+    assert!(app.cdn.get_video_file_path(11).await.2);
+    assert!(std::path::Path::new(&other).exists());
+
+    let body: serde_json::Value =
+      serde_json::from_slice(evict("127.0.0.1:11451").await.body()).unwrap();
+    assert_eq!(body["evicted"], false);
+  }
+
+  #[tokio::test]
+  async fn checksums_are_kept_until_evicted() {
+    let app = test_app(&[1], &[]).await;
+    write_metadata(&app, 1, "0123456789abcdef").await;
+    assert_eq!(
+      app.cdn.get_video_checksum(1).await.as_deref(),
+      Some("0123456789abcdef")
+    );
+    // Not read from disk again.
+    write_metadata(&app, 1, "fedcba9876543210").await;
+    assert_eq!(
+      app.cdn.get_video_checksum(1).await.as_deref(),
+      Some("0123456789abcdef")
+    );
+
+    assert!(app.cdn.evict_song(1).await.unwrap());
+    assert_eq!(app.cdn.get_video_checksum(1).await, None);
+  }
+
+  #[tokio::test]
+  async fn songs_are_purged_file_by_file() {
+    let override_dir =
+      std::env::temp_dir().join(format!("wanna-cdn-test-{}", uuid::Uuid::new_v4()));
+    let override_dir = override_dir.to_str().unwrap().to_string();
+    let app = test_app(
+      &[1, 11],
+      &["--video-override-path-ud", override_dir.as_str()],
+    )
+    .await;
+    let routes = routes(&app);
+    std::fs::create_dir_all(&override_dir).unwrap();
+    std::fs::write(format!("{}/1.mp4", override_dir), b"override").unwrap();
+    std::fs::create_dir_all(app.cdn.cache_path.as_str()).unwrap();
+    let cache = |name: &str| format!("{}/{}", app.cdn.cache_path, name);
+    for name in [
+      "1-0123-audio-offset-0.5.mp4",
+      "11451_0123abcd_1-0123.mp4",
+      "11-0123-audio-offset-0.5.mp4",
+    ] {
+      std::fs::write(cache(name), b"cached").unwrap();
+    }
+    let purge = |remote: &str| {
+      warp::test::request()
+        .method("DELETE")
+        .path("/aya-api/v1/cache/1")
+        .remote_addr(remote.parse().unwrap())
+        .reply(&routes)
+    };
+
+    assert_eq!(
+      purge("192.168.1.1:11451").await.status(),
+      StatusCode::FORBIDDEN
+    );
+    let resp = purge("127.0.0.1:11451").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    let (video, metadata_json, _) = app.cdn.get_video_file_path(1).await;
+    let mut expected = vec![
+      cache("1-0123-audio-offset-0.5.mp4"),
+      cache("11451_0123abcd_1-0123.mp4"),
+      video,
+      metadata_json,
+    ];
+    expected.sort();
+    let purged = body["files"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .inspect(|x| assert_eq!(x["removed"], true))
+      .map(|x| x["path"].as_str().unwrap().to_string())
+      .collect::<Vec<_>>();
+    assert_eq!(purged, expected);
+    assert!(!std::path::Path::new(&format!("{}/1", app.cdn.video_path)).exists());
+    assert!(app.cdn.get_video_file_path(11).await.2);
+    assert!(std::path::Path::new(&cache("11-0123-audio-offset-0.5.mp4")).exists());
+    assert!(std::path::Path::new(&format!("{}/1.mp4", override_dir)).exists());
+
+    let body: serde_json::Value =
+      serde_json::from_slice(purge("127.0.0.1:11451").await.body()).unwrap();
+    assert_eq!(body["files"], json!([]));
+  }
+
+  #[tokio::test]
+  async fn waveforms_are_served_from_cache() {
+    let app = test_app(&[1], &[]).await;
+    let routes = routes(&app);
+    std::fs::create_dir_all(app.cdn.cache_path.as_str()).unwrap();
+    std::fs::write(
+      format!("{}/1-waveform.json", app.cdn.cache_path),
+      r#"{"samples":[0.5,0.25]}"#,
+    )
+    .unwrap();
+    let get = |path: &str| warp::test::request().path(path).reply(&routes);
+
+    let resp = get("/api/v1/waveform/1.json").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-type"], "application/json");
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["samples"], json!([0.5, 0.25]));
+
+    assert_eq!(
+      get("/api/v1/waveform/2.json").await.status(),
+      StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+      get("/api/v1/waveform/1.webp").await.status(),
+      StatusCode::BAD_REQUEST
+    );
+  }
+
+  #[tokio::test]
+  async fn response_time_is_reported() {
+    let app = test_app(&[], &[]).await;
+    let routes = routes(&app);
+    for path in ["/healthz", "/no/such/path"] {
+      let resp = warp::test::request().path(path).reply(&routes).await;
+      let response_time = resp.headers()["x-response-time"].to_str().unwrap();
+      assert!(
+        response_time
+          .strip_suffix("ms")
+          .is_some_and(|x| x.parse::<u128>().is_ok()),
+        "{}",
+        response_time
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn receipts_can_be_deleted_by_sender() {
+    let app = test_app(&[], &[]).await;
+    let client = "192.168.1.1:11451".parse::<SocketAddr>().unwrap();
+    let resp = warp::test::request()
+      .method("POST")
+      .path("/r/room1")
+      .json(&json!({"target": "alice", "id": 1, "sender": "bob"}))
+      .reply(&routes(&app))
+      .await;
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    let receipt_id = body["receipt"]["receipt_id"].as_str().unwrap().to_string();
+
+    let delete = |path: String| {
+      let app = app.clone();
+      async move {
+        warp::test::request()
+          .method("DELETE")
+          .path(&path)
+          .remote_addr(client)
+          .reply(&routes(&app))
+          .await
+      }
+    };
+    let resp = delete(format!("/r/room1/{}?sender=mallory", receipt_id)).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = delete(format!("/r/room2/{}?sender=bob", receipt_id)).await;
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["deleted"], false);
+    let resp = delete(format!("/r/room1/{}?sender=bob", receipt_id)).await;
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["deleted"], true);
+    assert!(app.receipt.receipts("room1".to_string()).await.is_empty());
+  }
+
+  #[tokio::test]
+  async fn rejections_are_reported_as_json() {
+    let app = test_app(&[1], &[]).await;
+    let routes = routes(&app);
+    let client = "192.168.1.1:11451".parse::<SocketAddr>().unwrap();
+    for (path, status, code) in [
+      ("/v/1.mp4?auth=bad", StatusCode::FORBIDDEN, "bad_token"),
+      ("/v/nope.mp4", StatusCode::BAD_REQUEST, "bad_video_id"),
+      ("/metrics", StatusCode::FORBIDDEN, "forbidden"),
+      ("/no/such/route", StatusCode::NOT_FOUND, "not_found"),
+    ] {
+      let resp = warp::test::request()
+        .path(path)
+        .remote_addr(client)
+        .reply(&routes)
+        .await;
+      assert_eq!(resp.status(), status, "{}", path);
+      let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+      assert_eq!(body["code"], code, "{}", path);
+      assert!(body["error"].is_string());
+    }
+
+    let resp = warp::test::request()
+      .path("/v/1.mp4?auth=bad")
+      .header("accept", "text/plain")
+      .remote_addr(client)
+      .reply(&routes)
+      .await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(resp.body(), "Oops! BadToken");
+  }
+
+  #[tokio::test]
+  async fn receipts_can_be_acked_then_deleted() {
+    let app = test_app(&[], &[]).await;
+    let routes = routes(&app);
+    let client = "192.168.1.1:11451".parse::<SocketAddr>().unwrap();
+    let post = |path: String| {
+      warp::test::request()
+        .method("POST")
+        .path(&path)
+        .remote_addr(client)
+        .json(&json!({"target": "alice", "id": 1, "sender": "bob"}))
+        .reply(&routes)
+    };
+    let delete = |path: String| {
+      warp::test::request()
+        .method("DELETE")
+        .path(&path)
+        .remote_addr(client)
+        .reply(&routes)
+    };
+    let body = |resp: warp::http::Response<bytes::Bytes>| {
+      serde_json::from_slice::<serde_json::Value>(resp.body()).unwrap()
+    };
+
+    let receipt = body(post("/r/room1".to_string()).await)["receipt"].clone();
+    let receipt_id = receipt["receipt_id"].as_str().unwrap().to_string();
+    assert_eq!(receipt["acknowledged_at"], serde_json::Value::Null);
+
+    let resp = body(post(format!("/r/room2/{}/ack", receipt_id)).await);
+    assert_eq!(resp["receipt"], serde_json::Value::Null);
+    let resp = body(post(format!("/r/room1/{}/ack", receipt_id)).await);
+    assert!(resp["receipt"]["acknowledged_at"].is_i64());
+    let receipts = app.receipt.receipts("room1".to_string()).await;
+    assert!(receipts[0].acknowledged_at.is_some());
+
+    let resp = body(delete(format!("/r/room2/{}?sender=bob", receipt_id)).await);
+    assert_eq!(resp["deleted"], false);
+    let resp = body(delete(format!("/r/room1/{}?sender=bob", receipt_id)).await);
+    assert_eq!(resp["deleted"], true);
+
+    let resp = body(post(format!("/r/room1/{}/ack", receipt_id)).await);
+    assert_eq!(resp["receipt"], serde_json::Value::Null);
+    assert!(resp["message"]
+      .as_str()
+      .unwrap()
+      .contains("not found or expired"));
+  }
+
+  #[tokio::test]
+  async fn receipts_are_rate_limited_per_client() {
+    let app = test_app(&[], &["--rate-limit-receipts-per-minute", "2"]).await;
+    let routes = routes(&app);
+    let post = |client: &str, target: &str| {
+      warp::test::request()
+        .method("POST")
+        .path("/r/room1")
+        .remote_addr(client.parse::<SocketAddr>().unwrap())
+        .json(&json!({"target": target, "id": 1}))
+        .reply(&routes)
+    };
+    for target in ["alice", "bob"] {
+      assert_eq!(
+        post("192.168.1.1:11451", target).await.status(),
+        StatusCode::OK
+      );
+    }
+    let resp = post("192.168.1.1:11451", "carol").await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(resp.headers().contains_key(warp::http::header::RETRY_AFTER));
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert!(body["message"].as_str().unwrap().contains("retry in"));
+
+    assert_eq!(
+      post("192.168.1.2:11451", "carol").await.status(),
+      StatusCode::OK
+    );
+  }
+
+  #[tokio::test]
+  async fn bad_tls_setups_fail_clearly() {
+    let dir = std::env::temp_dir().join(format!("wanna-cdn-test-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let not_pem = dir.join("not.pem");
+    std::fs::write(&not_pem, b"hello").unwrap();
+    let not_pem = not_pem.to_str().unwrap();
+    let missing = dir.join("missing.pem");
+    let missing = missing.to_str().unwrap();
+
+    async fn serve(args: &[&str]) -> String {
+      let app = test_app(&[], &[&["--listen", "127.0.0.1:0"], args].concat()).await;
+      let (_shutdown, shutdown_rx) = tokio::sync::oneshot::channel();
+      serve_video_http(app, shutdown_rx)
+        .await
+        .unwrap_err()
+        .to_string()
+    }
+    assert!(serve(&["--tls-cert", not_pem])
+      .await
+      .contains("must be set together"));
+    assert!(serve(&["--tls-cert", missing, "--tls-key", not_pem])
+      .await
+      .contains("Failed to read TLS certificate"));
+    assert!(serve(&["--tls-cert", not_pem, "--tls-key", not_pem])
+      .await
+      .contains("not in PEM format"));
+    assert!(serve(&[
+      "--tls-cert",
+      not_pem,
+      "--tls-key",
+      not_pem,
+      "--builtin-sni-listen",
+      "127.0.0.1:0"
+    ])
+    .await
+    .contains("port of its own"));
+  }
+
+  #[tokio::test]
+  async fn server_stops_on_shutdown() {
+    let app = test_app(&[], &["--listen", "127.0.0.1:0"]).await;
+    let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel();
+    let server = tokio::spawn(serve_video_http(app.clone(), shutdown_rx));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!server.is_finished());
+
+    shutdown.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), server)
+      .await
+      .unwrap()
+      .unwrap()
+      .unwrap();
+    assert_eq!(app.http_in_flight.load(Ordering::Relaxed), 0);
+  }
+
+  #[tokio::test]
+  async fn oversized_bodies_are_refused() {
+    let app = test_app(&[], &["--max-body-bytes", "64"]).await;
+    let routes = routes(&app);
+    let post = |path: &str, body: serde_json::Value| {
+      warp::test::request()
+        .method("POST")
+        .path(path)
+        .remote_addr("127.0.0.1:11451".parse().unwrap())
+        .json(&body)
+        .reply(&routes)
+    };
+
+    let resp = post("/r/room1", json!({"target": "alice", "id": 1})).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = post(
+      "/r/room1",
+      json!({"target": "alice", "message": "a".repeat(64)}),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["code"], "payload_too_large");
+
+    let ids = (1..100).collect::<Vec<SongId>>();
+    let resp = post("/api/v1/songs/exists", json!({ "ids": ids })).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+  }
+
+  #[tokio::test]
+  async fn cors_policy_is_configurable() {
+    let preflight = |origin: &'static str| {
+      warp::test::request()
+        .method("OPTIONS")
+        .path("/healthz")
+        .header("Origin", origin)
+        .header("Access-Control-Request-Method", "GET")
+    };
+
+    let app = test_app(&[], &[]).await;
+    let resp = preflight("https://anywhere.example")
+      .reply(&routes(&app))
+      .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["access-control-max-age"], "3600");
+
+    let app = test_app(
+      &[],
+      &[
+        "--cors-allow-origins",
+        "https://vrchat.com,http://localhost:8080",
+        "--cors-max-age-secs",
+        "60",
+      ],
+    )
+    .await;
+    let filter = routes(&app);
+    let resp = preflight("http://localhost:8080").reply(&filter).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+      resp.headers()["access-control-allow-origin"],
+      "http://localhost:8080"
+    );
+    assert_eq!(resp.headers()["access-control-max-age"], "60");
+    let resp = preflight("https://anywhere.example").reply(&filter).await;
+    assert_ne!(resp.status(), StatusCode::OK);
+    assert!(!resp.headers().contains_key("access-control-allow-origin"));
+
+    let app = test_app(
+      &[],
+      &[
+        "--cors-allow-headers",
+        "X-Requested-With",
+        "--cors-expose-headers",
+        "Content-Range, X-Response-Time",
+      ],
+    )
+    .await;
+    let filter = routes(&app);
+    let resp = preflight("https://anywhere.example")
+      .header("Access-Control-Request-Headers", "x-requested-with")
+      .reply(&filter)
+      .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let allowed = resp.headers()["access-control-allow-headers"]
+      .to_str()
+      .unwrap();
+    assert!(allowed.contains("x-requested-with"), "{}", allowed);
+    let resp = warp::test::request()
+      .path("/healthz")
+      .header("Origin", "https://anywhere.example")
+      .reply(&filter)
+      .await;
+    let exposed = resp.headers()["access-control-expose-headers"]
+      .to_str()
+      .unwrap();
+    assert!(exposed.contains("content-range"), "{}", exposed);
+    assert!(exposed.contains("x-response-time"), "{}", exposed);
+
+    assert!(check_cors_headers("--cors-allow-headers", &["X-Requested-With".to_string()]).is_ok());
+    assert!(check_cors_headers("--cors-allow-headers", &["X Requested".to_string()]).is_err());
+    assert!(check_cors_origins(&["*".to_string()]).is_ok());
+    assert!(check_cors_origins(&["vrchat.com".to_string()]).is_err());
+    assert!(check_cors_origins(&["https://vrchat.com/home".to_string()]).is_err());
+  }
+
+  #[test]
+  fn forwarding_headers_are_only_believed_from_trusted_proxies() {
+    let trusted = parse_trusted_proxies(&[
+      "10.0.0.0/8".to_string(),
+      "fd00::/8".to_string(),
+      "192.168.1.1".to_string(),
+    ])
+    .unwrap();
+    let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+    let client = |peer: &str, xff: Option<&str>, x_real_ip: Option<&str>| {
+      client_ip(ip(peer), xff, x_real_ip, &trusted)
+    };
+
+    // Spoofed by a direct client
+    assert_eq!(client("1.2.3.4", Some("127.0.0.1"), None), ip("1.2.3.4"));
+    assert_eq!(client("1.2.3.4", None, Some("127.0.0.1")), ip("1.2.3.4"));
+    // The client can still prepend whatever it likes
+    assert_eq!(
+      client("10.0.0.1", Some("127.0.0.1, 1.2.3.4"), None),
+      ip("1.2.3.4")
+    );
+    // Nested proxies
+    assert_eq!(
+      client("10.0.0.1", Some("1.2.3.4, 192.168.1.1, 10.1.1.1"), None),
+      ip("1.2.3.4")
+    );
+    assert_eq!(
+      client("fd00::1", Some("2001:db8::1, fd12::2"), None),
+      ip("2001:db8::1")
+    );
+    assert_eq!(
+      client("10.0.0.1", Some("1.2.3.4:5678, [fd00::2]:80"), None),
+      ip("1.2.3.4")
+    );
+    // Only trusted hops
+    assert_eq!(
+      client("10.0.0.1", Some("10.0.0.2"), Some("1.2.3.4")),
+      ip("10.0.0.2")
+    );
+    assert_eq!(client("10.0.0.1", None, Some("1.2.3.4")), ip("1.2.3.4"));
+    assert_eq!(client("10.0.0.1", Some(" "), None), ip("10.0.0.1"));
+    // Malformed hops
+    assert_eq!(
+      client("10.0.0.1", Some("1.2.3.4, unknown, 10.0.0.2"), None),
+      ip("10.0.0.2")
+    );
+    assert_eq!(client("10.0.0.1", Some("garbage"), None), ip("10.0.0.1"));
+    assert_eq!(client("10.0.0.1", None, Some("garbage")), ip("10.0.0.1"));
+
+    assert!(parse_trusted_proxies(&["10.0.0.0/33".to_string()]).is_err());
+    assert!(parse_trusted_proxies(&["localhost".to_string()]).is_err());
+  }
+
+  #[tokio::test]
+  async fn forwarded_admins_need_a_trusted_proxy() {
+    let proxy = "10.0.0.1:11451".parse::<SocketAddr>().unwrap();
+    let metrics = |app: AppService| async move {
+      let routes = routes(&app);
+      warp::test::request()
+        .path("/metrics")
+        .remote_addr(proxy)
+        .header("X-Forwarded-For", "127.0.0.1")
+        .reply(&routes)
+        .await
+        .status()
+    };
+
+    let app = test_app(&[], &[]).await;
+    assert_ne!(metrics(app).await, StatusCode::OK);
+
+    let app = test_app(&[], &["--trusted-proxies", "10.0.0.0/8,::1"]).await;
+    assert_eq!(metrics(app).await, StatusCode::OK);
   }
-  crate::cdn::range::get_range(range, video_file.as_str(), "video/mp4").await
 }
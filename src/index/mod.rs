@@ -1,11 +1,17 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, io::Write, path::Path, sync::Arc, time::UNIX_EPOCH};
 
 use aya_dance_types::songs_to_index;
 pub use aya_dance_types::SongIndex;
+use bytes::Bytes;
+use flate2::{write::GzEncoder, Compression};
 use log::{debug, warn};
-use tokio::sync::Mutex;
+use serde_derive::Serialize;
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
 
-use crate::{types::Song, Result};
+use crate::{
+  types::{CategoryId, Song, SongId},
+  Result,
+};
 
 pub mod watch;
 
@@ -13,6 +19,50 @@ pub mod watch;
 pub struct IndexServiceImpl {
   pub video_path: String,
   pub index: Mutex<Option<SongIndex>>,
+  /// Songs gone from the index, with the `updated_at` of the index that
+  /// dropped them.
+  removed: Mutex<BTreeMap<SongId, i64>>,
+  /// `index` encoded for clients, cleared whenever `index` changes.
+  encoded: std::sync::Mutex<Option<Arc<EncodedIndex>>>,
+}
+
+/// The index as served to clients, so it isn't encoded again per request.
+#[derive(Debug)]
+pub struct EncodedIndex {
+  /// Quoted, changes with both `updated_at` and the content of the index.
+  pub etag: String,
+  pub json: Bytes,
+  pub gzip: Bytes,
+}
+
+impl EncodedIndex {
+  fn new(index: &SongIndex) -> Result<Self> {
+    let json = serde_json::to_vec(index)?;
+    let mut gzip = GzEncoder::new(vec![], Compression::default());
+    gzip.write_all(&json)?;
+    Ok(EncodedIndex {
+      etag: format!("\"{}-{:x}\"", index.updated_at, md5::compute(&json)),
+      json: json.into(),
+      gzip: gzip.finish()?.into(),
+    })
+  }
+}
+
+/// What changed in the index since some point in time, see
+/// `IndexServiceImpl::diff`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SongIndexDiff {
+  pub added: Vec<Song>,
+  pub removed: Vec<SongId>,
+  pub updated_at: i64,
+}
+
+/// Songs found by `IndexServiceImpl::search`, best matches first.
+#[derive(Debug, Clone, Serialize)]
+pub struct SongSearch {
+  pub songs: Vec<Song>,
+  /// When the index searched was built, so clients can tell how stale it is.
+  pub updated_at: i64,
 }
 
 pub type IndexService = Arc<IndexServiceImpl>;
@@ -22,22 +72,128 @@ impl IndexServiceImpl {
     Ok(Arc::new(IndexServiceImpl {
       video_path,
       index: Default::default(),
+      removed: Default::default(),
+      encoded: Default::default(),
     }))
   }
 }
 
 impl IndexServiceImpl {
   pub async fn get_index(&self, force_rebuild: bool) -> Result<SongIndex> {
+    Ok(self.lock_index(force_rebuild).await?.clone())
+  }
+
+  /// The index encoded for clients, see `EncodedIndex`.
+  pub async fn get_encoded_index(&self, force_rebuild: bool) -> Result<Arc<EncodedIndex>> {
+    // Holding the index lock, so the encoding can't be of an older index.
+    let index = self.lock_index(force_rebuild).await?;
+    let mut encoded = self.encoded.lock().unwrap();
+    if let Some(encoded) = &*encoded {
+      return Ok(encoded.clone());
+    }
+    let result = Arc::new(EncodedIndex::new(&index)?);
+    *encoded = Some(result.clone());
+    Ok(result)
+  }
+
+  /// Locks the cached index, building it first if there is none yet or
+  /// `force_rebuild` is set.
+  async fn lock_index(&self, force_rebuild: bool) -> Result<MappedMutexGuard<'_, SongIndex>> {
     let mut index = self.index.lock().await;
-    if force_rebuild {
-      *index = None;
+    let previous = match force_rebuild {
+      true => index.take(),
+      false => None,
+    };
+    if index.is_none() {
+      let result = self.build_index().await?;
+      if let Some(previous) = &previous {
+        self.track_removed(previous, &result).await;
+      }
+      *index = Some(result);
+      *self.encoded.lock().unwrap() = None;
+    }
+    Ok(MutexGuard::map(index, |x| {
+      x.as_mut().expect("the index was just built")
+    }))
+  }
+
+  /// Remembers the songs in `previous` but not in `current`, and forgets the
+  /// ones that came back.
+  pub(crate) async fn track_removed(&self, previous: &SongIndex, current: &SongIndex) {
+    // The first category always holds all the songs, see `songs_to_index`.
+    let ids = |index: &SongIndex| {
+      index
+        .categories
+        .first()
+        .map(|x| x.entries.iter().map(|x| x.id).collect::<Vec<_>>())
+        .unwrap_or_default()
+    };
+    let current_ids = ids(current);
+    let mut removed = self.removed.lock().await;
+    for id in ids(previous) {
+      if !current_ids.contains(&id) {
+        removed.insert(id, current.updated_at);
+      }
+    }
+    for id in current_ids {
+      removed.remove(&id);
+    }
+  }
+
+  /// The songs whose directory was modified after `since`, and the ones
+  /// removed since, or `None` if the index didn't change at all.
+  pub async fn diff(&self, since: i64) -> Result<Option<SongIndexDiff>> {
+    let index = self.get_index(false).await?;
+    if index.updated_at <= since {
+      return Ok(None);
     }
-    if let Some(index) = &*index {
-      return Ok(index.clone());
+    let mut added = vec![];
+    for song in index
+      .categories
+      .into_iter()
+      .next()
+      .map(|x| x.entries)
+      .unwrap_or_default()
+    {
+      let dir = Path::new(&self.video_path).join(song.id.to_string());
+      let modified = tokio::fs::metadata(&dir)
+        .await
+        .and_then(|x| x.modified())
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_secs() as i64);
+      // Better sent twice than missed.
+      if modified.is_none_or(|x| x > since) {
+        added.push(song);
+      }
     }
-    let result = self.build_index().await?;
-    *index = Some(result.clone());
-    Ok(result) // implicitly drop the lock
+    let removed = self
+      .removed
+      .lock()
+      .await
+      .iter()
+      .filter(|(_, removed_at)| **removed_at > since)
+      .map(|(id, _)| *id)
+      .collect();
+    Ok(Some(SongIndexDiff {
+      added,
+      removed,
+      updated_at: index.updated_at,
+    }))
+  }
+
+  /// Searches the cached index, it is only built if there is none yet.
+  pub async fn search(
+    &self,
+    query: &str,
+    category: Option<CategoryId>,
+    limit: usize,
+  ) -> Result<SongSearch> {
+    let index = self.get_index(false).await?;
+    Ok(SongSearch {
+      songs: search_songs(&index, query, category, limit),
+      updated_at: index.updated_at,
+    })
   }
 
   pub async fn build_index(&self) -> Result<SongIndex> {
@@ -52,39 +208,8 @@ impl IndexServiceImpl {
     let mut cursor = tokio::fs::read_dir(path).await?;
     while let Some(entry) = cursor.next_entry().await? {
       let path = entry.path();
-      if path.is_dir() {
-        let metadata_path = path.join("metadata.json");
-        if metadata_path.exists() {
-          let metadata = match tokio::fs::read_to_string(&metadata_path).await {
-            Ok(metadata) => metadata,
-            Err(e) => {
-              warn!(
-                "Failed to read metadata file {}: {:?}",
-                metadata_path.to_str().unwrap_or("<unknown-file>"),
-                e
-              );
-              continue;
-            }
-          };
-          let song: Song = match serde_json::from_str(&metadata) {
-            Ok(song) => song,
-            Err(e) => {
-              warn!(
-                "Failed to parse metadata file {}: {:?}",
-                metadata_path.to_str().unwrap_or("<unknown-file>"),
-                e
-              );
-              continue;
-            }
-          };
-          if song.id.to_string() != path.file_name().unwrap_or_default().to_string_lossy() {
-            warn!(
-              "Song id mismatch: {} (directory) != {} (metadata), skipping",
-              path.file_name().unwrap().to_string_lossy(),
-              song.id
-            );
-            continue;
-          }
+      if path.is_dir() && path.join("metadata.json").exists() {
+        if let Some(song) = read_song(&path).await {
           songs.push(song);
         }
       }
@@ -93,3 +218,154 @@ impl IndexServiceImpl {
     Ok(songs_to_index(songs))
   }
 }
+
+/// The songs whose `title` or `title_spell` contains every whitespace
+/// separated term of `query`, ignoring case, best matches first and then by
+/// id.
+pub fn search_songs(
+  index: &SongIndex,
+  query: &str,
+  category: Option<CategoryId>,
+  limit: usize,
+) -> Vec<Song> {
+  let query = query.to_lowercase();
+  let terms = query.split_whitespace().collect::<Vec<_>>();
+  // The first category always holds all the songs, see `songs_to_index`.
+  let mut found = index
+    .categories
+    .first()
+    .map(|x| x.entries.as_slice())
+    .unwrap_or_default()
+    .iter()
+    .filter(|song| category.is_none_or(|x| song.category == x))
+    .filter_map(|song| {
+      let title = song.title.to_lowercase();
+      let title_spell = song.title_spell.to_lowercase();
+      let mut score = 0;
+      for term in &terms {
+        match match_score(&title, term).max(match_score(&title_spell, term)) {
+          0 => return None,
+          x => score += x,
+        }
+      }
+      Some((score, song))
+    })
+    .collect::<Vec<_>>();
+  found.sort_by(|(a_score, a), (b_score, b)| b_score.cmp(a_score).then(a.id.cmp(&b.id)));
+  found
+    .into_iter()
+    .take(limit)
+    .map(|(_, song)| song.clone())
+    .collect()
+}
+
+/// How well `term` matches `text`: 3 for a prefix, 2 at the start of a word,
+/// 1 anywhere else and 0 not at all.
+fn match_score(text: &str, term: &str) -> u32 {
+  let mut score = 0;
+  for (i, _) in text.match_indices(term) {
+    if i == 0 {
+      return 3;
+    }
+    let at_word = text[..i]
+      .chars()
+      .next_back()
+      .is_some_and(|x| !x.is_alphanumeric());
+    score = score.max(if at_word { 2 } else { 1 });
+  }
+  score
+}
+
+/// Reads the song in directory `path`, the directory name being its id.
+async fn read_song(path: &Path) -> Option<Song> {
+  let metadata_path = path.join("metadata.json");
+  let metadata = match tokio::fs::read_to_string(&metadata_path).await {
+    Ok(metadata) => metadata,
+    Err(e) => {
+      warn!(
+        "Failed to read metadata file {}: {:?}",
+        metadata_path.to_str().unwrap_or("<unknown-file>"),
+        e
+      );
+      return None;
+    }
+  };
+  let song: Song = match serde_json::from_str(&metadata) {
+    Ok(song) => song,
+    Err(e) => {
+      warn!(
+        "Failed to parse metadata file {}: {:?}",
+        metadata_path.to_str().unwrap_or("<unknown-file>"),
+        e
+      );
+      return None;
+    }
+  };
+  if song.id.to_string() != path.file_name().unwrap_or_default().to_string_lossy() {
+    warn!(
+      "Song id mismatch: {} (directory) != {} (metadata), skipping",
+      path.file_name().unwrap_or_default().to_string_lossy(),
+      song.id
+    );
+    return None;
+  }
+  Some(song)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn song(id: SongId, category: CategoryId, title: &str, title_spell: &str) -> Song {
+    Song {
+      id,
+      category,
+      title: title.to_string(),
+      category_name: category.to_string(),
+      title_spell: title_spell.to_string(),
+      player_index: 0,
+      volume: 0.0,
+      start: 0,
+      end: 0,
+      flip: false,
+      skip_random: false,
+      original_url: None,
+      checksum: None,
+    }
+  }
+
+  #[test]
+  fn songs_are_searched_by_relevance() {
+    let index = songs_to_index(vec![
+      song(1, 1, "极乐净土 - GARNiDELiA", "ji le jing tu - GARNiDELiA"),
+      song(2, 1, "千本桜 - 初音ミク", "qian ben ying - Hatsune Miku"),
+      song(
+        3,
+        2,
+        "恋爱循环 - 花泽香菜",
+        "lian ai xun huan - Hanazawa Kana",
+      ),
+      song(4, 2, "Lovely Cat", "Lovely Cat"),
+      song(5, 1, "Jingle Bells", "Jingle Bells"),
+      song(6, 2, "Hello", "Hello"),
+      song(7, 2, "Bad Love", "Bad Love"),
+    ]);
+    let ids = |query: &str, category: Option<CategoryId>| {
+      search_songs(&index, query, category, 20)
+        .into_iter()
+        .map(|x| x.id)
+        .collect::<Vec<_>>()
+    };
+
+    assert_eq!(ids("净土", None), vec![1]);
+    assert_eq!(ids("初音", None), vec![2]);
+    // Prefix, then word start, then anywhere.
+    assert_eq!(ids("JING", None), vec![5, 1]);
+    assert_eq!(ids("lo", None), vec![4, 7, 6]);
+    assert_eq!(ids("lian  AI", None), vec![3]);
+    assert_eq!(ids("lian miku", None), Vec::<SongId>::new());
+    assert_eq!(ids("", Some(2)), vec![3, 4, 6, 7]);
+    assert_eq!(ids("lovely", Some(1)), Vec::<SongId>::new());
+    assert_eq!(search_songs(&index, "", None, 2).len(), 2);
+  }
+}
@@ -1 +1,239 @@
+use std::{collections::BTreeSet, path::Path, time::Duration};
 
+use aya_dance_types::songs_to_index;
+use log::{debug, info, warn};
+use notify::{RecursiveMode, Watcher};
+
+use crate::{
+  index::{read_song, IndexService, IndexServiceImpl},
+  types::SongId,
+  Result,
+};
+
+impl IndexServiceImpl {
+  /// Re-reads song `id` from disk into the cached index, removing it if its
+  /// metadata is gone. Does nothing if the index was never built.
+  pub async fn refresh_song(&self, id: SongId) {
+    let mut index = self.index.lock().await;
+    let Some(cached) = &*index else {
+      return;
+    };
+    let song = read_song(&Path::new(&self.video_path).join(id.to_string())).await;
+    // The first category always holds all the songs, see `songs_to_index`.
+    let mut songs = cached
+      .categories
+      .first()
+      .map(|x| x.entries.clone())
+      .unwrap_or_default();
+    songs.retain(|x| x.id != id);
+    match song {
+      Some(song) => {
+        debug!("Index: upsert song {}", id);
+        songs.push(song);
+      }
+      None => debug!("Index: remove song {}", id),
+    }
+    let updated = songs_to_index(songs);
+    self.track_removed(cached, &updated).await;
+    *index = Some(updated);
+    *self.encoded.lock().unwrap() = None;
+  }
+}
+
+/// Returns the song whose directory `path` is in, if it is interesting for the
+/// index: its `metadata.json` or the directory itself.
+fn song_of_path(video_path: &Path, path: &Path) -> Option<SongId> {
+  let relative = path.strip_prefix(video_path).ok()?;
+  let mut components = relative.components();
+  let id = components
+    .next()?
+    .as_os_str()
+    .to_str()?
+    .parse::<SongId>()
+    .ok()?;
+  match components.next() {
+    None => Some(id),
+    Some(x) if x.as_os_str() == "metadata.json" => Some(id),
+    Some(_) => None,
+  }
+}
+
+/// How long changes must settle before they are applied, the cache writer
+/// creates tmp files and then renames them.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Adds the songs touched by `event` to `changed`, returns whether changes
+/// may have been missed so the whole index has to be rebuilt.
+fn collect_changes(
+  video_path: &Path,
+  event: notify::Result<notify::Event>,
+  changed: &mut BTreeSet<SongId>,
+) -> bool {
+  let event = match event {
+    Ok(event) => event,
+    Err(e) => {
+      warn!("Index: watch error: {:?}", e);
+      return true;
+    }
+  };
+  if event.need_rescan() {
+    warn!("Index: watcher overflowed, changes may be missed");
+    return true;
+  }
+  if !event.kind.is_access() {
+    changed.extend(
+      event
+        .paths
+        .iter()
+        .filter_map(|x| song_of_path(video_path, x)),
+    );
+  }
+  false
+}
+
+/// Keeps the cached index up to date with the changes in `video_path`, so new
+/// downloads show up without a full rescan.
+pub async fn serve_index_watcher(index: IndexService) -> Result<()> {
+  let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+  let mut watcher = notify::recommended_watcher(move |event| {
+    let _ = tx.send(event);
+  })?;
+  // Events come with canonical paths on some platforms.
+  let video_path = tokio::fs::canonicalize(&index.video_path).await?;
+  watcher.watch(&video_path, RecursiveMode::Recursive)?;
+  info!("Index: watching {} for changes", index.video_path);
+
+  while let Some(event) = rx.recv().await {
+    let mut changed = BTreeSet::new();
+    let mut rescan = collect_changes(&video_path, event, &mut changed);
+    while let Ok(Some(event)) = tokio::time::timeout(DEBOUNCE, rx.recv()).await {
+      rescan |= collect_changes(&video_path, event, &mut changed);
+    }
+    if rescan {
+      info!("Index: rebuilding from {}", index.video_path);
+      if let Err(e) = index.get_index(true).await {
+        warn!("Index: failed to rebuild: {:?}", e);
+      }
+      continue;
+    }
+    for id in changed {
+      index.refresh_song(id).await;
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn write_song(video_path: &Path, id: SongId) {
+    let dir = video_path.join(id.to_string());
+    std::fs::create_dir_all(&dir).unwrap();
+    let song = aya_dance_types::Song {
+      id,
+      category: 0,
+      title: id.to_string(),
+      category_name: "Test".to_string(),
+      title_spell: "".to_string(),
+      player_index: 0,
+      volume: 0.0,
+      start: 0,
+      end: 0,
+      flip: false,
+      skip_random: false,
+      original_url: None,
+      checksum: None,
+    };
+    std::fs::write(
+      dir.join("metadata.json"),
+      serde_json::to_vec(&song).unwrap(),
+    )
+    .unwrap();
+  }
+
+  fn song_ids(index: &aya_dance_types::SongIndex) -> Vec<SongId> {
+    index.categories[0].entries.iter().map(|x| x.id).collect()
+  }
+
+  #[tokio::test]
+  async fn songs_are_refreshed_without_rescan() {
+    let video_path = std::env::temp_dir().join(format!("wanna-cdn-index-{}", uuid::Uuid::new_v4()));
+    write_song(&video_path, 1);
+    let index = IndexServiceImpl::new(video_path.to_str().unwrap().to_string())
+      .await
+      .unwrap();
+    assert_eq!(song_ids(&index.get_index(false).await.unwrap()), vec![1]);
+
+    write_song(&video_path, 2);
+    assert_eq!(
+      song_of_path(&video_path, &video_path.join("2").join("metadata.json")),
+      Some(2)
+    );
+    assert_eq!(
+      song_of_path(&video_path, &video_path.join("2").join("video.mp4")),
+      None
+    );
+    index.refresh_song(2).await;
+    assert_eq!(song_ids(&index.get_index(false).await.unwrap()), vec![1, 2]);
+
+    std::fs::remove_dir_all(video_path.join("1")).unwrap();
+    index.refresh_song(1).await;
+    assert_eq!(song_ids(&index.get_index(false).await.unwrap()), vec![2]);
+    std::fs::remove_dir_all(&video_path).unwrap();
+  }
+
+  #[tokio::test]
+  async fn index_converges_with_the_video_path() {
+    let video_path = std::env::temp_dir().join(format!("wanna-cdn-index-{}", uuid::Uuid::new_v4()));
+    write_song(&video_path, 1);
+    let index = IndexServiceImpl::new(video_path.to_str().unwrap().to_string())
+      .await
+      .unwrap();
+    index.get_index(false).await.unwrap();
+    let watcher = tokio::spawn(serve_index_watcher(index.clone()));
+    // Let the watcher start watching.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let converges = |expected: Vec<SongId>| {
+      let index = index.clone();
+      async move {
+        for _ in 0..50 {
+          if song_ids(&index.get_index(false).await.unwrap()) == expected {
+            return true;
+          }
+          tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        false
+      }
+    };
+    write_song(&video_path, 2);
+    write_song(&video_path, 3);
+    assert!(converges(vec![1, 2, 3]).await);
+    std::fs::remove_dir_all(video_path.join("1")).unwrap();
+    assert!(converges(vec![2, 3]).await);
+
+    watcher.abort();
+    std::fs::remove_dir_all(&video_path).unwrap();
+  }
+
+  #[test]
+  fn missed_changes_need_a_rebuild() {
+    let video_path = Path::new("/songs");
+    let mut changed = BTreeSet::new();
+    let event = notify::Event::new(notify::EventKind::Create(notify::event::CreateKind::File))
+      .add_path(video_path.join("7").join("metadata.json"))
+      .add_path(video_path.join("8").join("video.mp4.tmp"));
+    assert!(!collect_changes(video_path, Ok(event), &mut changed));
+    assert_eq!(changed, BTreeSet::from([7]));
+
+    let overflow =
+      notify::Event::new(notify::EventKind::Other).set_flag(notify::event::Flag::Rescan);
+    assert!(collect_changes(video_path, Ok(overflow), &mut changed));
+    assert!(collect_changes(
+      video_path,
+      Err(notify::Error::generic("overflow")),
+      &mut changed
+    ));
+  }
+}
@@ -1,15 +1,37 @@
 extern crate core;
 
-use std::{sync::Arc, time::Duration};
+use std::{
+  collections::HashMap,
+  net::IpAddr,
+  sync::{atomic::AtomicUsize, Arc},
+  time::{Duration, Instant},
+};
 
+use anyhow::{anyhow, bail};
 use clap::Parser;
+use serde_derive::{Deserialize, Serialize};
 
 use crate::{
   cdn::{
+    compensator::{CompensatorService, CompensatorServiceImpl},
+    events::CacheEventBus,
+    prewarm::{PrewarmService, PrewarmServiceImpl},
+    proxy::{default_reqwest_client, upstream_proxy, ProxyOpts, Upstream, UpstreamFailures},
     receipt::{ReceiptService, ReceiptServiceImpl},
+    songs_list::{SongsListService, SongsListServiceImpl},
     CdnService, CdnServiceImpl,
   },
+  ffmpeg::HwAccelBackend,
+  forward::{
+    dns::{DnsCache, SystemResolver},
+    parse_proxy_targets,
+    tunnel::HttpTunnel,
+    SniMap,
+  },
+  index::{IndexService, IndexServiceImpl},
+  metrics::{MetricsService, MetricsServiceImpl},
   rtsp::{TypewriterService, TypewriterServiceImpl},
+  types::{ratelimit::RateLimiter, SongId},
 };
 
 pub mod cdn;
@@ -17,6 +39,7 @@ pub mod ffmpeg;
 pub mod forward;
 pub mod http;
 pub mod index;
+pub mod metrics;
 pub mod rtsp;
 pub mod types;
 
@@ -30,14 +53,53 @@ pub struct AppOpts {
   pub video_path_ud: String,
   #[clap(long, env, default_value = "./wannadance-cache")]
   pub cache_path_ud: String,
+  #[clap(long, env)]
+  pub video_override_path_ud: Option<String>,
+  #[clap(long, env, default_value = "2147483648")]
+  pub video_override_max_bytes: u64,
+  #[clap(long, env, default_value = "1048576")]
+  pub max_body_bytes: u64,
+
+  #[clap(long, env)]
+  pub cache_max_bytes: Option<u64>,
+  #[clap(long, env, default_value = "600")]
+  pub cache_evict_interval_seconds: u64,
+  #[clap(long, env, default_value = "false")]
+  pub cache_verify_on_serve: bool,
+  #[clap(long, env, default_value = "86400")]
+  pub cache_scan_interval_seconds: u64,
 
   #[clap(long, env, default_value = "ud-play.kiva.moe")]
   pub cache_upstream_ud_oversea: String,
   #[clap(long, env, default_value = "ud-nya.kiva.moe")]
   pub cache_upstream_ud_domestic: String,
+  #[clap(long, env, default_value = "https://api.udon.dance")]
+  pub cache_upstream_ud_api: String,
+  #[clap(long, env, default_value = "2")]
+  pub cache_prewarm_concurrency: usize,
+  #[clap(long, env, default_value = "300")]
+  pub songs_list_ttl_seconds: u64,
 
   #[clap(short = 'l', long, env, default_value = "0.0.0.0:80")]
   pub listen: String,
+  #[clap(long, env)]
+  pub tls_cert: Option<String>,
+  #[clap(long, env)]
+  pub tls_key: Option<String>,
+  #[clap(long, env, value_delimiter = ',', default_value = "*")]
+  pub cors_allow_origins: Vec<String>,
+  #[clap(long, env, value_delimiter = ',')]
+  pub cors_allow_headers: Vec<String>,
+  #[clap(long, env, value_delimiter = ',')]
+  pub cors_expose_headers: Vec<String>,
+  #[clap(long, env, default_value = "3600")]
+  pub cors_max_age_secs: u64,
+  #[clap(long, env, value_delimiter = ',')]
+  pub trusted_proxies: Vec<String>,
+  #[clap(long, env, default_value = "0")]
+  pub worker_threads: usize,
+  #[clap(long, env, default_value = "0")]
+  pub blocking_threads: usize,
   #[clap(long, env, default_value = "0.0.0.0:443")]
   pub builtin_sni_listen: Option<String>,
   #[clap(
@@ -47,22 +109,76 @@ pub struct AppOpts {
     default_value = "api.udon.dance=ud-orig.kiva.moe:443,nya.xin.moe=ud-nya.kiva.moe:443"
   )]
   pub builtin_sni_proxy: Option<Vec<String>>,
+  #[clap(long, env, default_value = "30")]
+  pub sni_health_check_interval_secs: u64,
+  #[clap(long, env, default_value = "1024")]
+  pub sni_max_connections: usize,
+  #[clap(long, env, default_value = "300")]
+  pub sni_idle_timeout_seconds: u64,
+  #[clap(long, env, default_value = "60")]
+  pub sni_dns_ttl_seconds: u64,
 
   #[clap(short = 'w', long, env)]
   pub rtsp_listen: Option<String>,
+  #[clap(long, env, default_value = "60")]
+  pub rtsp_idle_timeout_seconds: u64,
+  #[clap(long, env, default_value = "600")]
+  pub typewriter_expire_seconds: u64,
+  #[clap(long, env, default_value = "256")]
+  pub typewriter_max_letters: usize,
   #[clap(long, env, default_value = "5")]
   pub receipt_max_per_user_per_sender: usize,
+  #[clap(long, env, default_value = "10")]
+  pub receipt_max_per_target: usize,
+  #[clap(long, env, default_value = "100")]
+  pub receipt_max_per_room: usize,
   #[clap(long, env, default_value = "300")]
   pub receipt_default_expire_seconds: u64,
+  #[clap(long, env)]
+  pub receipt_store_path: Option<String>,
 
   #[clap(long, env, value_delimiter = ',')]
   pub admin_src_host: Option<Vec<String>>,
+  #[clap(long, env, default_value = "86400")]
+  pub token_revoke_expire_seconds: u64,
+  #[clap(long, env, default_value = "600")]
+  pub missing_song_expire_seconds: u64,
+  #[clap(long, env, default_value = "60")]
+  pub rate_limit_tokens_per_minute: u32,
+  #[clap(long, env, default_value = "10")]
+  pub rate_limit_receipts_per_minute: u32,
 
   #[clap(long, env, default_value = "false")]
   pub proxy_allow_304: bool,
+  #[clap(long, env, default_value = "5000")]
+  pub proxy_connect_timeout_ms: u64,
+  #[clap(long, env, default_value = "30000")]
+  pub proxy_read_timeout_ms: u64,
+  #[clap(long, env, default_value = "2")]
+  pub proxy_retries: u32,
+  #[clap(long, env, default_value = "60")]
+  pub proxy_failover_seconds: u64,
+  #[clap(long, env)]
+  pub upstream_proxy_url: Option<String>,
+  #[clap(long, env, value_delimiter = ',')]
+  pub upstream_proxy_bypass: Option<Vec<String>>,
+  #[clap(long, env, default_value = "false")]
+  pub sni_use_upstream_proxy: bool,
 
   #[clap(long, env, default_value = "0")]
   pub audio_compensation: f64,
+  #[clap(long, env)]
+  pub audio_offset_overrides: Option<String>,
+  #[clap(long, env, default_value = "2")]
+  pub audio_compensator_workers: usize,
+  #[clap(long, env, value_enum, default_value = "none")]
+  pub hw_accel: HwAccelBackend,
+  #[clap(long, env, default_value = "false")]
+  pub normalize_audio: bool,
+  #[clap(long, env, default_value = "5")]
+  pub thumbnail_seek_offset: f64,
+  #[clap(long, env, default_value = "10")]
+  pub waveform_samples_per_second: u32,
 }
 
 #[derive(Debug)]
@@ -71,26 +187,254 @@ pub struct AppServiceImpl {
   pub typewriter: TypewriterService,
   pub cdn: CdnService,
   pub receipt: ReceiptService,
+  pub index: IndexService,
+  pub token_rate_limiter: RateLimiter<IpAddr>,
+  pub receipt_rate_limiter: RateLimiter<IpAddr>,
+  pub metrics: MetricsService,
+  pub compensator: CompensatorService,
+  pub sni: Arc<SniMap>,
+  /// Sends requests to the upstream CDN.
+  pub proxy_client: reqwest::Client,
+  /// Shared by all upstream requests, so they avoid the ones that just failed.
+  pub upstream_failures: Arc<UpstreamFailures>,
+  pub cache_events: CacheEventBus,
+  pub prewarm: PrewarmService,
+  pub songs_list: SongsListService,
+  pub started_at: Instant,
+  /// Requests the HTTP server is handling right now.
+  pub http_in_flight: Arc<AtomicUsize>,
+  /// Peers whose `X-Forwarded-For` and `X-Real-IP` are believed.
+  pub trusted_proxies: Vec<ipnet::IpNet>,
+  /// Songs whose audio offset differs from `opts.audio_compensation`.
+  pub audio_offsets: HashMap<SongId, f64>,
 }
 
 pub type AppService = Arc<AppServiceImpl>;
 
 impl AppServiceImpl {
   pub async fn new(opts: AppOpts) -> Result<AppService> {
-    let cdn = CdnServiceImpl::new(opts.video_path_ud.clone(), opts.cache_path_ud.clone());
-    let typewriter = Arc::new(TypewriterServiceImpl::default());
+    http::check_cors_origins(&opts.cors_allow_origins)?;
+    http::check_cors_headers("--cors-allow-headers", &opts.cors_allow_headers)?;
+    http::check_cors_headers("--cors-expose-headers", &opts.cors_expose_headers)?;
+    let trusted_proxies = http::parse_trusted_proxies(&opts.trusted_proxies)?;
+    ensure_dir("--video-path-ud", &opts.video_path_ud)?;
+    ensure_dir("--cache-path-ud", &opts.cache_path_ud)?;
+    if let Some(dir) = &opts.video_override_path_ud {
+      ensure_dir("--video-override-path-ud", dir)?;
+    }
+    let cdn = CdnServiceImpl::new(
+      opts.video_path_ud.clone(),
+      opts.cache_path_ud.clone(),
+      Duration::from_secs(opts.token_revoke_expire_seconds),
+      opts.cache_max_bytes,
+      opts.cache_verify_on_serve,
+      Duration::from_secs(opts.missing_song_expire_seconds),
+    );
+    let typewriter = TypewriterServiceImpl::new(
+      Duration::from_secs(opts.typewriter_expire_seconds),
+      opts.typewriter_max_letters,
+    );
     let receipt = ReceiptServiceImpl::new(
       opts.receipt_max_per_user_per_sender,
+      opts.receipt_max_per_target,
+      opts.receipt_max_per_room,
       Duration::from_secs(opts.receipt_default_expire_seconds),
+      opts.receipt_store_path.clone(),
     )
     .await?;
+    let index = IndexServiceImpl::new(opts.video_path_ud.clone()).await?;
+    let token_rate_limiter =
+      RateLimiter::new(opts.rate_limit_tokens_per_minute, Duration::from_secs(60));
+    let receipt_rate_limiter =
+      RateLimiter::new(opts.rate_limit_receipts_per_minute, Duration::from_secs(60));
+    let metrics = MetricsServiceImpl::new()?;
+    let audio_offsets = match &opts.audio_offset_overrides {
+      Some(path) => load_audio_offsets(&std::fs::read_to_string(path)?)?,
+      None => HashMap::new(),
+    };
+    let compensator = CompensatorServiceImpl::new(opts.audio_compensator_workers);
+    let bypass = opts.upstream_proxy_bypass.as_deref().unwrap_or_default();
+    let tunnel = match (&opts.upstream_proxy_url, opts.sni_use_upstream_proxy) {
+      (Some(url), true) => Some(Arc::new(HttpTunnel::new(url, bypass)?)),
+      (None, true) => bail!("--sni-use-upstream-proxy needs --upstream-proxy-url"),
+      (_, false) => None,
+    };
+    let sni = Arc::new(SniMap::new(
+      parse_proxy_targets(opts.builtin_sni_proxy.as_deref().unwrap_or_default()),
+      Arc::new(DnsCache::new(
+        Arc::new(SystemResolver),
+        Duration::from_secs(opts.sni_dns_ttl_seconds),
+      )),
+      tunnel,
+    )?);
+    let proxy_client = default_reqwest_client(
+      Duration::from_millis(opts.proxy_connect_timeout_ms),
+      Duration::from_millis(opts.proxy_read_timeout_ms),
+      match &opts.upstream_proxy_url {
+        Some(url) => Some(upstream_proxy(url, bypass)?),
+        None => None,
+      },
+    );
+    let upstream_failures = Arc::new(UpstreamFailures::new(Duration::from_secs(
+      opts.proxy_failover_seconds,
+    )));
+    let prewarm = PrewarmServiceImpl::new(opts.cache_prewarm_concurrency);
+    let songs_list = SongsListServiceImpl::new(
+      &opts.cache_path_ud,
+      Duration::from_secs(opts.songs_list_ttl_seconds),
+    );
     Ok(Arc::new(AppServiceImpl {
       opts,
       cdn,
       typewriter,
       receipt,
+      index,
+      token_rate_limiter,
+      receipt_rate_limiter,
+      metrics,
+      compensator,
+      sni,
+      proxy_client,
+      upstream_failures,
+      cache_events: CacheEventBus::new(),
+      prewarm,
+      songs_list,
+      started_at: Instant::now(),
+      http_in_flight: Default::default(),
+      trusted_proxies,
+      audio_offsets,
     }))
   }
+
+  /// Where `path` (`/files/..`) is fetched from: the upstream CDN for `host`
+  /// first, then the other one to fail over to.
+  pub fn file_upstreams(&self, host: Option<&str>, path: &str) -> Vec<Upstream> {
+    let domestic = (&self.opts.cache_upstream_ud_domestic, "nya.xin.moe");
+    let oversea = (&self.opts.cache_upstream_ud_oversea, "play.udon.dance");
+    let (preferred, fallback) = match host {
+      Some("nya.xin.moe") => (domestic, oversea),
+      _ => (oversea, domestic),
+    };
+    [preferred, fallback]
+      .into_iter()
+      .map(|(dns, host)| Upstream {
+        uri: format!("http://{}{}", dns, path),
+        host_override: Some(host.to_string()),
+      })
+      .collect()
+  }
+
+  /// How song `id` is fetched from `upstreams`.
+  pub fn proxy_opts(&self, id: SongId, upstreams: Vec<Upstream>) -> ProxyOpts {
+    ProxyOpts {
+      client: self.proxy_client.clone(),
+      id,
+      upstreams,
+      failures: self.upstream_failures.clone(),
+      user_agent_override: Some(format!(
+        "WannaDanceSelfHostedCDN/{}.{}",
+        MY_VERSION_ID,
+        my_git_hash(),
+      )),
+      allow_304: self.opts.proxy_allow_304,
+      retries: self.opts.proxy_retries,
+    }
+  }
+
+  /// The audio offset to compensate song `id` with, `0.0` meaning none.
+  pub fn audio_offset(&self, id: SongId) -> f64 {
+    self
+      .audio_offsets
+      .get(&id)
+      .copied()
+      .unwrap_or(self.opts.audio_compensation)
+  }
+
+  /// Where the override video of song `id` is uploaded to, if overrides are
+  /// enabled.
+  pub fn video_override_path(&self, id: SongId) -> Option<String> {
+    let dir = self.opts.video_override_path_ud.as_ref()?;
+    Some(format!("{}/{}.mp4", dir, id))
+  }
+
+  /// The video uploaded to replace song `id`, if there is one.
+  pub fn video_override_file(&self, id: SongId) -> Option<String> {
+    self
+      .video_override_path(id)
+      .filter(|x| std::path::Path::new(x).exists())
+  }
+
+  /// All the override videos, by id. `None` if overrides are disabled.
+  pub async fn video_overrides(&self) -> Option<Result<Vec<VideoOverride>>> {
+    let dir = self.opts.video_override_path_ud.as_ref()?;
+    let list = async {
+      let mut overrides = vec![];
+      let mut cursor = match tokio::fs::read_dir(dir).await {
+        Ok(cursor) => cursor,
+        // Nothing was uploaded yet.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(overrides),
+        Err(e) => return Err(e.into()),
+      };
+      while let Some(entry) = cursor.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Uploads in progress are hidden, see `save_override_video`.
+        let Some(id) = name
+          .strip_suffix(".mp4")
+          .and_then(|x| x.parse::<SongId>().ok())
+        else {
+          continue;
+        };
+        let metadata = entry.metadata().await?;
+        overrides.push(VideoOverride {
+          id,
+          size: metadata.len(),
+          mtime: metadata
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0),
+        });
+      }
+      overrides.sort_by_key(|x| x.id);
+      Ok(overrides)
+    };
+    Some(list.await)
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VideoOverride {
+  pub id: SongId,
+  pub size: u64,
+  pub mtime: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct AudioOffsetOverride {
+  offset: f64,
+}
+
+/// Parses per-song audio offsets, written as `[42]\noffset = -0.5`.
+fn load_audio_offsets(content: &str) -> Result<HashMap<SongId, f64>> {
+  toml::from_str::<HashMap<String, AudioOffsetOverride>>(content)?
+    .into_iter()
+    .map(|(id, x)| match id.parse::<SongId>() {
+      Ok(id) => Ok((id, x.offset)),
+      Err(_) => Err(anyhow!("Bad song id {} in audio offset overrides", id)),
+    })
+    .collect()
+}
+
+/// Creates the directory `option` points to if it doesn't exist yet, so a
+/// new deployment fails at startup rather than on the first write.
+fn ensure_dir(option: &str, dir: &str) -> Result<()> {
+  if std::path::Path::new(dir).is_dir() {
+    return Ok(());
+  }
+  std::fs::create_dir_all(dir)
+    .map_err(|e| anyhow!("Failed to create {} {}: {}", option, dir, e))?;
+  log::warn!("Created {} {} as it didn't exist", option, dir);
+  Ok(())
 }
 
 pub fn my_git_hash() -> String {
@@ -98,3 +442,72 @@ pub fn my_git_hash() -> String {
     .map(|x| x[..8].to_string())
     .unwrap_or_else(|| "0".to_string())
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn audio_offsets_are_loaded() {
+    let offsets = load_audio_offsets("[42]\noffset = -0.5\n\n[43]\noffset = 0.0\n").unwrap();
+    assert_eq!(offsets, HashMap::from([(42, -0.5), (43, 0.0)]));
+    assert!(load_audio_offsets("[song]\noffset = 1.0\n").is_err());
+    assert!(load_audio_offsets("[42]\nofset = 1.0\n").is_err());
+  }
+
+  #[tokio::test]
+  async fn bad_upstream_proxies_fail_at_startup() {
+    let root = std::env::temp_dir().join(format!("wanna-cdn-test-{}", uuid::Uuid::new_v4()));
+    let app = |args: &[&str]| {
+      let opts = AppOpts::parse_from(
+        [
+          "wanna-cdn",
+          "--video-path-ud",
+          root.join("song").to_str().unwrap(),
+          "--cache-path-ud",
+          root.join("cache").to_str().unwrap(),
+        ]
+        .iter()
+        .chain(args),
+      );
+      AppServiceImpl::new(opts)
+    };
+    assert!(app(&["--upstream-proxy-url", "socks5h://127.0.0.1:7890"])
+      .await
+      .is_ok());
+    assert!(app(&["--upstream-proxy-url", "ftp://127.0.0.1:7890"])
+      .await
+      .is_err());
+    // The SNI proxy only tunnels through HTTP proxies.
+    let socks = ["--upstream-proxy-url", "socks5h://127.0.0.1:7890"];
+    assert!(app(&[&socks[..], &["--sni-use-upstream-proxy"]].concat())
+      .await
+      .is_err());
+    assert!(app(&["--sni-use-upstream-proxy"]).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn missing_dirs_are_created_at_startup() {
+    let root = std::env::temp_dir().join(format!("wanna-cdn-test-{}", uuid::Uuid::new_v4()));
+    let app = |cache_path: &std::path::Path| {
+      let opts = AppOpts::parse_from([
+        "wanna-cdn",
+        "--video-path-ud",
+        root.join("song").to_str().unwrap(),
+        "--cache-path-ud",
+        cache_path.to_str().unwrap(),
+        "--video-override-path-ud",
+        root.join("override").to_str().unwrap(),
+      ]);
+      AppServiceImpl::new(opts)
+    };
+    assert!(app(&root.join("cache")).await.is_ok());
+    assert!(root.join("song").is_dir());
+    assert!(root.join("cache").is_dir());
+    assert!(root.join("override").is_dir());
+
+    std::fs::write(root.join("file"), b"not a directory").unwrap();
+    let err = app(&root.join("file").join("cache")).await.unwrap_err();
+    assert!(err.to_string().contains("--cache-path-ud"), "{}", err);
+  }
+}
@@ -0,0 +1,193 @@
+use std::sync::Arc;
+
+use futures::TryStreamExt;
+use prometheus::{
+  Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+  Registry, TextEncoder,
+};
+use warp::hyper::Body;
+
+use crate::{AppService, Result};
+
+#[derive(Debug)]
+pub struct MetricsServiceImpl {
+  registry: Registry,
+  /// Labelled by `route`, see `ROUTE_AYA` and `ROUTE_WANNA_DANCE`.
+  pub cdn_cache_hits: IntCounterVec,
+  pub cdn_cache_misses: IntCounterVec,
+  pub cdn_served_bytes: IntCounterVec,
+  pub tokens_issued: IntCounterVec,
+  pub token_verification_failures: IntCounter,
+  pub receipts_created: IntCounter,
+  pub proxy_upstream_bytes: IntCounter,
+  pub proxy_upstream_duration: Histogram,
+  pub audio_compensations_running: IntGauge,
+  pub sni_rejected_connections: IntCounter,
+  /// Refreshed from the CDN service on every scrape.
+  active_downloads: IntGauge,
+  /// Refreshed from the receipt service on every scrape.
+  receipts: IntGaugeVec,
+  /// Refreshed from the SNI map on every scrape, labelled by `host` rule and
+  /// `direction`.
+  sni_forwarded_bytes: IntGaugeVec,
+  sni_active_connections: IntGaugeVec,
+}
+
+pub type MetricsService = Arc<MetricsServiceImpl>;
+
+/// Values of the `route` label, telling which world the request came from.
+pub const ROUTE_AYA: &str = "aya";
+pub const ROUTE_WANNA_DANCE: &str = "wanna_dance";
+
+impl MetricsServiceImpl {
+  pub fn new() -> Result<MetricsService> {
+    let registry = Registry::new();
+    let cdn_cache_hits = IntCounterVec::new(
+      Opts::new(
+        "cdn_cache_hits_total",
+        "Requests served from the local cache",
+      ),
+      &["route"],
+    )?;
+    let cdn_cache_misses = IntCounterVec::new(
+      Opts::new(
+        "cdn_cache_misses_total",
+        "Requests not found in the local cache",
+      ),
+      &["route"],
+    )?;
+    let cdn_served_bytes = IntCounterVec::new(
+      Opts::new(
+        "cdn_served_bytes_total",
+        "Bytes served from the local cache",
+      ),
+      &["route"],
+    )?;
+    let tokens_issued = IntCounterVec::new(
+      Opts::new("tokens_issued_total", "Tokens handed out for cached songs"),
+      &["route"],
+    )?;
+    let token_verification_failures = IntCounter::new(
+      "token_verification_failures_total",
+      "Requests rejected for a bad token",
+    )?;
+    let receipts_created = IntCounter::new("receipts_created_total", "Receipts created")?;
+    let proxy_upstream_bytes =
+      IntCounter::new("proxy_upstream_bytes_total", "Bytes proxied from upstream")?;
+    let proxy_upstream_duration = Histogram::with_opts(HistogramOpts::new(
+      "proxy_upstream_duration_seconds",
+      "Time until the upstream responded to a proxied request",
+    ))?;
+    let audio_compensations_running = IntGauge::new(
+      "audio_compensations_running",
+      "Songs whose audio is being compensated",
+    )?;
+    let sni_rejected_connections = IntCounter::new(
+      "sni_rejected_connections_total",
+      "SNI proxy connections closed for being over --sni-max-connections",
+    )?;
+    let active_downloads = IntGauge::new(
+      "cdn_active_downloads",
+      "Songs being downloaded from upstream",
+    )?;
+    let receipts = IntGaugeVec::new(
+      Opts::new("receipts", "Receipts waiting in each room"),
+      &["room"],
+    )?;
+    registry.register(Box::new(cdn_cache_hits.clone()))?;
+    registry.register(Box::new(cdn_cache_misses.clone()))?;
+    registry.register(Box::new(cdn_served_bytes.clone()))?;
+    registry.register(Box::new(tokens_issued.clone()))?;
+    registry.register(Box::new(token_verification_failures.clone()))?;
+    registry.register(Box::new(receipts_created.clone()))?;
+    registry.register(Box::new(proxy_upstream_bytes.clone()))?;
+    registry.register(Box::new(proxy_upstream_duration.clone()))?;
+    registry.register(Box::new(audio_compensations_running.clone()))?;
+    registry.register(Box::new(sni_rejected_connections.clone()))?;
+    let sni_forwarded_bytes = IntGaugeVec::new(
+      Opts::new(
+        "sni_forwarded_bytes",
+        "Bytes the SNI proxy forwarded for each host",
+      ),
+      &["host", "direction"],
+    )?;
+    let sni_active_connections = IntGaugeVec::new(
+      Opts::new(
+        "sni_active_connections",
+        "SNI proxy connections open for each host",
+      ),
+      &["host"],
+    )?;
+    registry.register(Box::new(active_downloads.clone()))?;
+    registry.register(Box::new(receipts.clone()))?;
+    registry.register(Box::new(sni_forwarded_bytes.clone()))?;
+    registry.register(Box::new(sni_active_connections.clone()))?;
+    Ok(Arc::new(MetricsServiceImpl {
+      registry,
+      cdn_cache_hits,
+      cdn_cache_misses,
+      cdn_served_bytes,
+      tokens_issued,
+      token_verification_failures,
+      receipts_created,
+      proxy_upstream_bytes,
+      proxy_upstream_duration,
+      audio_compensations_running,
+      sni_rejected_connections,
+      active_downloads,
+      receipts,
+      sni_forwarded_bytes,
+      sni_active_connections,
+    }))
+  }
+
+  /// Increments `gauge` until the returned guard is dropped.
+  pub fn track(gauge: &IntGauge) -> GaugeGuard {
+    gauge.inc();
+    GaugeGuard(gauge.clone())
+  }
+
+  /// Adds the bytes of `body` to `counter` as they are sent.
+  pub fn count_body(counter: IntCounter, body: Body) -> Body {
+    Body::wrap_stream(body.inspect_ok(move |x| counter.inc_by(x.len() as u64)))
+  }
+}
+
+pub struct GaugeGuard(IntGauge);
+
+impl Drop for GaugeGuard {
+  fn drop(&mut self) {
+    self.0.dec();
+  }
+}
+
+/// Renders all metrics in the Prometheus text exposition format.
+pub async fn render_metrics(app: &AppService) -> Result<String> {
+  let metrics = &app.metrics;
+  metrics
+    .active_downloads
+    .set(app.cdn.active_downloads().await as i64);
+  metrics.receipts.reset();
+  for (room, n) in app.receipt.count_per_room().await {
+    metrics
+      .receipts
+      .with_label_values(&[room.as_str()])
+      .set(n as i64);
+  }
+  for (host, stats) in app.sni.traffic() {
+    for (direction, bytes) in [("up", stats.up_bytes), ("down", stats.down_bytes)] {
+      metrics
+        .sni_forwarded_bytes
+        .with_label_values(&[host.as_str(), direction])
+        .set(bytes as i64);
+    }
+    metrics
+      .sni_active_connections
+      .with_label_values(&[host.as_str()])
+      .set(stats.active_connections as i64);
+  }
+
+  let mut buffer = Vec::new();
+  TextEncoder::new().encode(&metrics.registry.gather(), &mut buffer)?;
+  Ok(String::from_utf8(buffer)?)
+}
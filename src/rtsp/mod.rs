@@ -1,19 +1,26 @@
 use std::{
-  collections::HashMap,
+  collections::VecDeque,
   net::{IpAddr, SocketAddr},
   sync::Arc,
+  time::Duration,
 };
 
 use anyhow::bail;
 use log::{debug, error, info};
-use rtsp_types::{Empty, Message, Method, Response};
+use rtsp_types::{Empty, Message, Method, ParseError, Response, StatusCode};
 use tokio::{
-  io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+  io::{AsyncReadExt, AsyncWriteExt, BufWriter},
   net::{TcpListener, TcpStream},
   sync::Mutex,
 };
 
-use crate::AppService;
+use crate::{
+  types::{timedmap, timedmap::TimedMap},
+  AppService,
+};
+
+/// The token of clients still using `/typewriter/{letter}`.
+const LEGACY_TOKEN: &str = "114514";
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct ClientToken {
@@ -30,37 +37,54 @@ impl ClientToken {
   }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct TypewriterServiceImpl {
-  pub typewriters: Mutex<HashMap<ClientToken, Vec<String>>>,
+  /// Letters typed by each client, forgotten after `expire` without writes.
+  pub typewriters: Arc<TimedMap<ClientToken, VecDeque<String>>>,
+  /// Serializes the read-modify-write of a client's letters.
+  lock: Mutex<()>,
+  expire: Duration,
+  /// Older letters are dropped beyond this.
+  max_letters: usize,
 }
 
 pub type TypewriterService = Arc<TypewriterServiceImpl>;
 
 impl TypewriterServiceImpl {
+  pub fn new(expire: Duration, max_letters: usize) -> TypewriterService {
+    let typewriters = Arc::new(TimedMap::new());
+    let _canceller = timedmap::tokio_cleaner(typewriters.clone(), Duration::from_secs(60));
+    Arc::new(TypewriterServiceImpl {
+      typewriters,
+      lock: Default::default(),
+      expire,
+      max_letters,
+    })
+  }
+
   pub async fn write(&self, client: IpAddr, token: String, letter: String) -> anyhow::Result<()> {
     let token = ClientToken::new(client, token);
-    let mut map = self.typewriters.lock().await;
-    match map.get_mut(&token) {
-      Some(x) => x.push(letter),
-      None => {
-        map.insert(token, vec![letter]);
-      }
+    let _guard = self.lock.lock().await;
+    let mut letters = self.typewriters.get(&token).await.unwrap_or_default();
+    letters.push_back(letter);
+    while letters.len() > self.max_letters {
+      letters.pop_front();
     }
+    self.typewriters.insert(token, letters, self.expire).await;
     Ok(())
   }
 
   pub async fn read(&self, client: IpAddr, token: String) -> anyhow::Result<String> {
     let token = ClientToken::new(client, token);
-    let mut map = self.typewriters.lock().await;
-    match map.get_mut(&token) {
-      Some(x) => {
-        let content = x.join("");
-        x.clear();
-        Ok(content)
-      }
-      None => Ok("".to_string()),
-    }
+    let _guard = self.lock.lock().await;
+    Ok(
+      self
+        .typewriters
+        .remove(&token)
+        .await
+        .map(|letters| letters.into_iter().collect())
+        .unwrap_or_default(),
+    )
   }
 }
 
@@ -112,13 +136,17 @@ async fn handle_client(
   client: SocketAddr,
   ctx: AppService,
 ) -> anyhow::Result<()> {
-  let (rx, tx) = stream.split();
-  let mut reader = BufReader::new(rx);
+  let (mut reader, tx) = stream.split();
   let mut writer = BufWriter::new(tx);
-  let mut buf = String::new();
+  let mut buf = Vec::new();
+  let idle_timeout = Duration::from_secs(ctx.opts.rtsp_idle_timeout_seconds);
 
   loop {
-    match reader.read_line(&mut buf).await {
+    let Ok(read) = tokio::time::timeout(idle_timeout, reader.read_buf(&mut buf)).await else {
+      debug!("RTSP Client {} idle, disconnecting", client);
+      return Ok(());
+    };
+    match read {
       Ok(0) => {
         debug!("RTSP Client {} disconnected", client);
         return Ok(());
@@ -126,35 +154,53 @@ async fn handle_client(
       Err(e) => {
         bail!("read error: {:?}", e);
       }
-      // If the buffer contains a double CRLF, then we have a complete message
-      Ok(_) if buf.contains("\r\n\r\n") => {
-        let response = handle_rtsp_message(ctx.clone(), client, &buf).await?;
-        let mut reply = Vec::new();
-        response
-          .write(&mut reply)
-          .map_err(|e| anyhow::anyhow!("failed to serialize response: {:?}", e))?;
-        writer
-          .write_all(&reply)
-          .await
-          .map_err(|e| anyhow::anyhow!("failed to write response: {:?}", e))?;
-        buf.clear();
-      }
-      // Not enough data to parse a message, continue reading
       Ok(_) => (),
     }
+
+    // Clients may pipeline requests, answer all the complete ones.
+    while let Some(message) = next_message(&mut buf)? {
+      let (response, close) = handle_rtsp_message(ctx.clone(), client, message).await?;
+      let mut reply = Vec::new();
+      response
+        .write(&mut reply)
+        .map_err(|e| anyhow::anyhow!("failed to serialize response: {:?}", e))?;
+      writer
+        .write_all(&reply)
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to write response: {:?}", e))?;
+      if close {
+        writer.flush().await?;
+        debug!("RTSP Client {} tore down", client);
+        return Ok(());
+      }
+    }
+    writer.flush().await?;
+  }
+}
+
+/// Takes the first message out of `buf`, if it is complete. Bodies are part of
+/// the message, so they must be complete as well.
+fn next_message(buf: &mut Vec<u8>) -> anyhow::Result<Option<Message<Vec<u8>>>> {
+  match Message::parse(buf) {
+    Ok((message, consumed)) => {
+      buf.drain(..consumed);
+      Ok(Some(message))
+    }
+    Err(ParseError::Incomplete(_)) => Ok(None),
+    Err(e) => bail!(
+      "failed to parse message {:?}: {}",
+      String::from_utf8_lossy(buf),
+      e
+    ),
   }
 }
 
+/// Returns the response to send, and whether to close the connection after.
 async fn handle_rtsp_message(
   ctx: AppService,
   client: SocketAddr,
-  raw: &String,
-) -> anyhow::Result<Response<Empty>> {
-  let (message, consumed): (Message<Vec<u8>>, _) = Message::parse(raw.as_bytes())?;
-  if consumed != raw.len() {
-    bail!("failed to consume entire buffer {}", raw);
-  }
-
+  message: Message<Vec<u8>>,
+) -> anyhow::Result<(Response<Empty>, bool)> {
   match message {
     Message::Request(request) => {
       let method = request.method();
@@ -165,29 +211,273 @@ async fn handle_rtsp_message(
         .path_segments()
         .ok_or_else(|| anyhow::anyhow!("missing path segments"))?
         .collect::<Vec<&str>>();
-      let cseq = request
-        .header(&rtsp_types::headers::CSEQ)
-        .ok_or_else(|| anyhow::anyhow!("missing CSeq"))?;
-
-      match (method, path.as_slice()) {
-        (Method::Describe, ["typewriter", letter]) => {
-          info!("RTSP Client {} typewriter: {}", client, letter);
-          ctx
-            .typewriter
-            .write(client.ip(), "114514".to_string(), letter.to_string())
-            .await?;
+      let Some(cseq) = request.header(&rtsp_types::headers::CSEQ) else {
+        debug!("RTSP Client {} sent a request without CSeq", client);
+        return Ok((
+          Response::builder(request.version(), StatusCode::BadRequest).empty(),
+          false,
+        ));
+      };
+      let response = |status| {
+        Response::builder(request.version(), status).header(rtsp_types::headers::CSEQ, cseq.clone())
+      };
+
+      match method {
+        Method::Options => Ok((
+          response(StatusCode::Ok)
+            .header(rtsp_types::headers::PUBLIC, "OPTIONS, DESCRIBE")
+            .empty(),
+          false,
+        )),
+        Method::Describe => {
+          if let Some((token, letter)) = typewriter_path(method, &path) {
+            info!("RTSP Client {} typewriter {}: {}", client, token, letter);
+            ctx
+              .typewriter
+              .write(client.ip(), token.to_string(), letter.to_string())
+              .await?;
+          }
+          Ok((response(StatusCode::Ok).empty(), false))
         }
-        _ => (),
+        Method::Teardown => Ok((response(StatusCode::Ok).empty(), true)),
+        _ => Ok((response(StatusCode::MethodNotAllowed).empty(), false)),
       }
-
-      Ok(
-        rtsp_types::Response::builder(rtsp_types::Version::V2_0, rtsp_types::StatusCode::Ok)
-          .header(rtsp_types::headers::CSEQ, cseq.clone())
-          .empty(),
-      )
     }
 
     Message::Response(_) => bail!("client sent a response, funny"),
     Message::Data(_) => bail!("client sent some data, funny"),
   }
 }
+
+/// Returns the token and letter of `/typewriter/{token}/{letter}`, or of the
+/// legacy `/typewriter/{letter}`.
+fn typewriter_path<'a>(method: &Method, path: &[&'a str]) -> Option<(&'a str, &'a str)> {
+  match (method, path) {
+    (Method::Describe, ["typewriter", token, letter]) => Some((token, letter)),
+    (Method::Describe, ["typewriter", letter]) => Some((LEGACY_TOKEN, letter)),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use clap::Parser;
+  use tokio::io::{AsyncBufReadExt, BufReader};
+
+  use super::*;
+  use crate::{AppOpts, AppServiceImpl};
+
+  fn client() -> IpAddr {
+    "127.0.0.1".parse().unwrap()
+  }
+
+  #[tokio::test]
+  async fn letters_expire_without_writes() {
+    let typewriter = TypewriterServiceImpl::new(Duration::from_millis(100), 256);
+    let write = |letter: &str| typewriter.write(client(), "t".to_string(), letter.to_string());
+    write("B").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    // Writing keeps the letters around.
+    write("V").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert_eq!(
+      typewriter.read(client(), "t".to_string()).await.unwrap(),
+      "BV"
+    );
+
+    write("1").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(
+      typewriter.read(client(), "t".to_string()).await.unwrap(),
+      ""
+    );
+  }
+
+  #[tokio::test]
+  async fn oldest_letters_are_dropped() {
+    let typewriter = TypewriterServiceImpl::new(Duration::from_secs(60), 3);
+    for letter in ["a", "b", "c", "d"] {
+      typewriter
+        .write(client(), "t".to_string(), letter.to_string())
+        .await
+        .unwrap();
+    }
+    assert_eq!(
+      typewriter.read(client(), "t".to_string()).await.unwrap(),
+      "bcd"
+    );
+    // Other tokens have their own letters.
+    assert_eq!(
+      typewriter.read(client(), "u".to_string()).await.unwrap(),
+      ""
+    );
+  }
+
+  /// Starts a typewriter server, and returns its address.
+  async fn serve(args: &[&str]) -> (SocketAddr, AppService) {
+    let root = std::env::temp_dir().join(format!("wanna-cdn-test-{}", uuid::Uuid::new_v4()));
+    let opts = AppOpts::parse_from(
+      [
+        "wanna-cdn",
+        "--video-path-ud",
+        root.join("song").to_str().unwrap(),
+        "--cache-path-ud",
+        root.join("cache").to_str().unwrap(),
+      ]
+      .iter()
+      .chain(args),
+    );
+    let app = AppServiceImpl::new(opts).await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let ctx = app.clone();
+    tokio::spawn(async move {
+      while let Ok((stream, client)) = listener.accept().await {
+        tokio::spawn(handle_client(stream, client, ctx.clone()));
+      }
+    });
+    (addr, app)
+  }
+
+  /// Sends `request` and reads the response head.
+  async fn send(stream: &mut BufReader<TcpStream>, request: &str) -> String {
+    stream
+      .get_mut()
+      .write_all(request.as_bytes())
+      .await
+      .unwrap();
+    let mut response = String::new();
+    while !response.ends_with("\r\n\r\n") {
+      if stream.read_line(&mut response).await.unwrap() == 0 {
+        break;
+      }
+    }
+    response
+  }
+
+  #[tokio::test]
+  async fn rtsp_requests_are_answered() {
+    let (addr, app) = serve(&[]).await;
+    let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+
+    let response = send(
+      &mut stream,
+      "OPTIONS rtsp://localhost/ RTSP/1.0\r\nCSeq: 1\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("RTSP/1.0 200 "), "{}", response);
+    assert!(response.contains("Public: OPTIONS, DESCRIBE\r\n"));
+    assert!(response.contains("CSeq: 1\r\n"));
+
+    let response = send(
+      &mut stream,
+      "DESCRIBE rtsp://localhost/typewriter/t/x RTSP/1.0\r\nCSeq: 2\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("RTSP/1.0 200 "), "{}", response);
+    let client = "127.0.0.1".parse().unwrap();
+    assert_eq!(
+      app.typewriter.read(client, "t".to_string()).await.unwrap(),
+      "x"
+    );
+
+    let response = send(
+      &mut stream,
+      "SETUP rtsp://localhost/ RTSP/1.0\r\nCSeq: 3\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("RTSP/1.0 405 "), "{}", response);
+    assert!(response.contains("CSeq: 3\r\n"));
+
+    // The connection survives a request without CSeq.
+    let response = send(&mut stream, "OPTIONS rtsp://localhost/ RTSP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("RTSP/1.0 400 "), "{}", response);
+
+    let response = send(
+      &mut stream,
+      "TEARDOWN rtsp://localhost/ RTSP/1.0\r\nCSeq: 4\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("RTSP/1.0 200 "), "{}", response);
+    assert_eq!(send(&mut stream, "").await, "");
+  }
+
+  #[tokio::test]
+  async fn idle_connections_are_closed() {
+    let (addr, _) = serve(&["--rtsp-idle-timeout-seconds", "0"]).await;
+    let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut line = String::new();
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read_line(&mut line)).await;
+    assert_eq!(read.unwrap().unwrap(), 0);
+  }
+
+  fn describe(letter: &str, cseq: u32) -> String {
+    format!(
+      "DESCRIBE rtsp://localhost/typewriter/t/{} RTSP/1.0\r\nCSeq: {}\r\n\r\n",
+      letter, cseq
+    )
+  }
+
+  #[test]
+  fn pipelined_messages_are_parsed_one_by_one() {
+    let mut buf = (describe("a", 1) + &describe("b", 2)).into_bytes();
+    for cseq in ["1", "2"] {
+      let Some(Message::Request(request)) = next_message(&mut buf).unwrap() else {
+        panic!("expected a request");
+      };
+      assert_eq!(
+        request.header(&rtsp_types::headers::CSEQ).unwrap().as_str(),
+        cseq
+      );
+    }
+    assert!(buf.is_empty());
+    assert!(next_message(&mut buf).unwrap().is_none());
+  }
+
+  #[test]
+  fn split_messages_wait_for_the_rest() {
+    let raw = "ANNOUNCE rtsp://localhost/ RTSP/1.0\r\nCSeq: 1\r\nContent-Length: 4\r\n\r\nbody";
+    // The head is split, then the body is.
+    for split in [20, raw.len() - 2] {
+      let mut buf = raw[..split].as_bytes().to_vec();
+      assert!(next_message(&mut buf).unwrap().is_none());
+      buf.extend_from_slice(raw[split..].as_bytes());
+      let Some(Message::Request(request)) = next_message(&mut buf).unwrap() else {
+        panic!("expected a request");
+      };
+      assert_eq!(request.body(), b"body");
+      assert!(buf.is_empty());
+    }
+  }
+
+  #[tokio::test]
+  async fn pipelined_requests_are_all_answered() {
+    let (addr, app) = serve(&[]).await;
+    let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let response = send(&mut stream, &(describe("a", 1) + &describe("b", 2))).await;
+    assert!(response.contains("CSeq: 1\r\n"), "{}", response);
+    let response = send(&mut stream, "").await;
+    assert!(response.contains("CSeq: 2\r\n"), "{}", response);
+    let client = "127.0.0.1".parse().unwrap();
+    assert_eq!(
+      app.typewriter.read(client, "t".to_string()).await.unwrap(),
+      "ab"
+    );
+  }
+
+  #[test]
+  fn token_is_taken_from_path() {
+    assert_eq!(
+      typewriter_path(&Method::Describe, &["typewriter", "abc", "x"]),
+      Some(("abc", "x"))
+    );
+    assert_eq!(
+      typewriter_path(&Method::Describe, &["typewriter", "x"]),
+      Some((LEGACY_TOKEN, "x"))
+    );
+    assert_eq!(
+      typewriter_path(&Method::Options, &["typewriter", "x"]),
+      None
+    );
+  }
+}
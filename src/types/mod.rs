@@ -1,3 +1,4 @@
+pub mod ratelimit;
 pub mod timedmap;
 
 pub use aya_dance_types::{Category, CategoryId, Song, SongId, UuidString};
@@ -0,0 +1,93 @@
+use std::{
+  hash::Hash,
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
+
+use crate::types::{timedmap, timedmap::TimedMap};
+
+/// A fixed-window rate limiter keyed by `K`, e.g. client IPs.
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+  /// Counters are created under the map's write lock and are atomic, so
+  /// concurrent hits from the same key are counted correctly.
+  hits: Arc<TimedMap<K, Arc<AtomicU32>>>,
+  max_hits: u32,
+  window: Duration,
+}
+
+impl<K> RateLimiter<K>
+where
+  K: Eq + PartialEq + Hash + Clone + Send + Sync + 'static,
+{
+  pub fn new(max_hits: u32, window: Duration) -> Self {
+    let hits = Arc::new(TimedMap::new());
+    let _canceller = timedmap::tokio_cleaner(hits.clone(), window);
+    RateLimiter {
+      hits,
+      max_hits,
+      window,
+    }
+  }
+
+  /// Records a hit from `key`. Returns `Err(retry_after)` if `key` has
+  /// already used up its hits in the current window.
+  pub async fn hit(&self, key: K) -> Result<(), Duration> {
+    let hits = self
+      .hits
+      .get_value_or_insert_with(key, self.window, Default::default)
+      .await;
+    let (counter, expires) = (hits.value(), *hits.expires());
+    if counter.fetch_add(1, Ordering::Relaxed) >= self.max_hits {
+      return Err(expires.saturating_duration_since(Instant::now()));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::net::IpAddr;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn limit_per_key() {
+    let limiter = RateLimiter::new(60, Duration::from_secs(60));
+    let ip = "192.168.1.1".parse::<IpAddr>().unwrap();
+    let other = "192.168.1.2".parse::<IpAddr>().unwrap();
+
+    let mut results = vec![];
+    for _ in 0..100 {
+      results.push(limiter.hit(ip).await);
+    }
+    assert!(results[..60].iter().all(|r| r.is_ok()));
+    assert!(results[60..].iter().all(|r| r.is_err()));
+    assert!(results[60].unwrap_err() <= Duration::from_secs(60));
+
+    assert!(limiter.hit(other).await.is_ok());
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+  async fn concurrent_first_hits_are_all_counted() {
+    let limiter = Arc::new(RateLimiter::new(10, Duration::from_secs(60)));
+    let ip = "192.168.1.1".parse::<IpAddr>().unwrap();
+
+    let hits = (0..50)
+      .map(|_| {
+        let limiter = limiter.clone();
+        tokio::spawn(async move { limiter.hit(ip).await })
+      })
+      .collect::<Vec<_>>();
+    let mut allowed = 0;
+    for hit in hits {
+      if hit.await.unwrap().is_ok() {
+        allowed += 1;
+      }
+    }
+    assert_eq!(allowed, 10);
+  }
+}
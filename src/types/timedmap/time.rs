@@ -20,12 +20,20 @@ pub trait TimeSource:
   + Clone
 {
   fn now() -> Self;
+
+  /// Returns the time from `earlier` to `self`, or zero if `earlier` is
+  /// later.
+  fn saturating_duration_since(&self, earlier: Self) -> Duration;
 }
 
 impl TimeSource for Instant {
   fn now() -> Self {
     Instant::now()
   }
+
+  fn saturating_duration_since(&self, earlier: Self) -> Duration {
+    Instant::saturating_duration_since(self, earlier)
+  }
 }
 
 #[cfg(test)]
@@ -33,4 +41,8 @@ impl TimeSource for mock_instant::Instant {
   fn now() -> Self {
     mock_instant::Instant::now()
   }
+
+  fn saturating_duration_since(&self, earlier: Self) -> Duration {
+    mock_instant::Instant::saturating_duration_since(self, earlier)
+  }
 }
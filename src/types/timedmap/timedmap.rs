@@ -1,19 +1,36 @@
 use std::{
   borrow::Borrow,
   collections::HashMap,
+  fmt,
   hash::Hash,
+  path::Path,
+  sync::Arc,
   time::{Duration, Instant},
 };
 
 use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 use crate::types::timedmap::{time::TimeSource, tokio_cleaner::Cleanup, Value};
 
+/// Called with each key-value pair removed by [`Cleanup::cleanup`].
+pub type ExpiryHook<K, V> = Arc<dyn Fn(K, V) + Send + Sync>;
+
 /// Provides a hash map with expiring key-value pairs.
-#[derive(Debug)]
 pub struct TimedMap<K, V, TS = Instant> {
   inner: RwLock<HashMap<K, Value<V, TS>>>,
+  expiry_hook: Option<ExpiryHook<K, V>>,
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, TS: fmt::Debug> fmt::Debug for TimedMap<K, V, TS> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("TimedMap")
+      .field("inner", &self.inner)
+      .field("expiry_hook", &self.expiry_hook.is_some())
+      .finish()
+  }
 }
 
 impl<K, V> TimedMap<K, V> {
@@ -30,8 +47,17 @@ impl<K, V, TS> TimedMap<K, V, TS> {
   pub fn new_with_timesource() -> Self {
     Self {
       inner: RwLock::new(HashMap::new()),
+      expiry_hook: None,
     }
   }
+
+  /// Calls `f` with every expired key-value pair removed by the cleanup.
+  /// Entries removed explicitly, or found expired by a getter, don't trigger
+  /// it.
+  pub fn with_expiry_hook(mut self, f: impl Fn(K, V) + Send + Sync + 'static) -> Self {
+    self.expiry_hook = Some(Arc::new(f));
+    self
+  }
 }
 
 impl<K, V, TS> TimedMap<K, V, TS>
@@ -176,6 +202,76 @@ where
     Some(v)
   }
 
+  /// Retrieves the raw [`Value`] wrapper by the given key,
+  /// first inserting `f()` with the given lifetime if there
+  /// is no such key or its pair has expired.
+  ///
+  /// Looks up and inserts under a single write lock, so
+  /// concurrent callers for the same key get the same value.
+  pub async fn get_value_or_insert_with(
+    &self,
+    key: K,
+    lifetime: Duration,
+    f: impl FnOnce() -> V,
+  ) -> Value<V, TS> {
+    let mut m = self.inner.write().await;
+    if let Some(v) = m.get(&key).filter(|v| !v.is_expired()) {
+      return v.clone();
+    }
+    let v = Value::new(f(), lifetime);
+    m.insert(key, v.clone());
+    v
+  }
+
+  /// Removes all expired key-value pairs from the map
+  /// and returns them.
+  pub async fn drain_expired(&self) -> Vec<(K, V)> {
+    let now = TS::now();
+
+    {
+      let m = self.inner.read().await;
+      if !m.values().any(|val| val.is_expired_at(&now)) {
+        return vec![];
+      }
+    }
+
+    let mut m = self.inner.write().await;
+    let keys = m
+      .iter()
+      .filter(|(_, val)| val.is_expired_at(&now))
+      .map(|(key, _)| key)
+      .cloned()
+      .collect::<Vec<_>>();
+
+    // TODO: Maybe shrink the map down if it exceeds a predefined
+    // capacity, like
+    // if m.capacity() > SOME_CAP_VAL {
+    //     m.shrink_to_fit();
+    // }
+
+    keys
+      .into_iter()
+      .filter_map(|key| m.remove(&key).map(|val| (key, val.value())))
+      .collect()
+  }
+
+  /// Returns how long the value corresponding to the
+  /// given key has left to live.
+  ///
+  /// [`None`] is returned when there is no such key or
+  /// its lifetime has been passed.
+  pub async fn time_remaining<Q>(&self, key: &Q) -> Option<Duration>
+  where
+    K: Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+  {
+    let v = self.get_value_unchecked(key).await?;
+    if v.is_expired() {
+      return None;
+    }
+    Some(v.expires().saturating_duration_since(TS::now()))
+  }
+
   /// Retrieves the raw [`Value`] wrapper by the given key
   /// without checking expiry.
   pub async fn get_value_unchecked<Q>(&self, key: &Q) -> Option<Value<V, TS>>
@@ -196,33 +292,82 @@ where
   TS: TimeSource + Send + Sync,
 {
   async fn cleanup(&self) {
-    let now = TS::now();
+    let expired = self.drain_expired().await;
 
-    let mut keys = vec![];
-    {
-      let m = self.inner.read().await;
-      keys.extend(
-        m.iter()
-          .filter(|(_, val)| val.is_expired_at(&now))
-          .map(|(key, _)| key)
-          .cloned(),
-      );
+    // Called without the lock, so the hook may use the map.
+    if let Some(hook) = &self.expiry_hook {
+      for (key, val) in expired {
+        hook(key, val);
+      }
     }
+  }
+}
 
-    if keys.is_empty() {
-      return;
-    }
+/// A key-value pair as saved on disk. `Instant`s mean nothing to another
+/// process, so the expiry is saved as a unix timestamp.
+#[derive(Serialize, Deserialize)]
+struct SavedEntry<K, V> {
+  key: K,
+  value: V,
+  expires_at_millis: i64,
+}
 
-    let mut m = self.inner.write().await;
-    for key in keys {
-      m.remove(&key);
+impl<K, V> TimedMap<K, V>
+where
+  K: Eq + PartialEq + Hash + Clone + serde::Serialize + DeserializeOwned,
+  V: Clone + serde::Serialize + DeserializeOwned,
+{
+  /// Saves the non-expired key-value pairs to the given
+  /// file, as JSON.
+  ///
+  /// The file is replaced at once, so a crash while
+  /// saving doesn't leave a truncated file behind.
+  pub async fn save_to_file<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+    let now = Instant::now();
+    let wall_now = chrono::Utc::now().timestamp_millis();
+    let json = {
+      let m = self.inner.read().await;
+      let entries = m
+        .iter()
+        .filter(|(_, v)| !v.is_expired_at(&now))
+        .map(|(k, v)| SavedEntry {
+          key: k,
+          value: v.value_ref(),
+          expires_at_millis: wall_now
+            + v.expires().saturating_duration_since(now).as_millis() as i64,
+        })
+        .collect::<Vec<_>>();
+      serde_json::to_vec(&entries)?
+    };
+    let path = path.as_ref();
+    let tmp = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4().simple()));
+    tokio::fs::write(&tmp, json).await?;
+    if let Err(e) = tokio::fs::rename(&tmp, path).await {
+      let _ = tokio::fs::remove_file(&tmp).await;
+      return Err(e.into());
     }
+    Ok(())
+  }
 
-    // TODO: Maybe shrink the map down if it exceeds a predefined
-    // capacity, like
-    // if m.capacity() > SOME_CAP_VAL {
-    //     m.shrink_to_fit();
-    // }
+  /// Creates a new instance of [`TimedMap`] with the
+  /// key-value pairs saved by [`save_to_file`](#method.save_to_file)
+  /// which have not expired since.
+  pub async fn load_from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+    let json = tokio::fs::read(path).await?;
+    let entries: Vec<SavedEntry<K, V>> = serde_json::from_slice(&json)?;
+    let wall_now = chrono::Utc::now().timestamp_millis();
+    let m = entries
+      .into_iter()
+      .filter(|e| e.expires_at_millis > wall_now)
+      .map(|e| {
+        let lifetime = Duration::from_millis((e.expires_at_millis - wall_now) as u64);
+        (e.key, Value::new(e.value, lifetime))
+      })
+      .collect();
+    Ok(Self {
+      inner: RwLock::new(m),
+      expiry_hook: None,
+    })
   }
 }
 
@@ -230,6 +375,104 @@ impl<K, V> Default for TimedMap<K, V> {
   fn default() -> Self {
     Self {
       inner: Default::default(),
+      expiry_hook: None,
     }
   }
 }
+
+#[cfg(test)]
+mod test {
+  use std::sync::Mutex;
+
+  use mock_instant::{Instant, MockClock};
+
+  use super::*;
+
+  #[tokio::test]
+  async fn expiry_hook() {
+    let expired = Arc::new(Mutex::new(vec![]));
+    let m: TimedMap<_, _, Instant> = TimedMap::new_with_timesource().with_expiry_hook({
+      let expired = expired.clone();
+      move |k, v| expired.lock().unwrap().push((k, v))
+    });
+    m.insert("foo", 1, Duration::from_millis(100)).await;
+    m.insert("bar", 2, Duration::from_millis(200)).await;
+    m.insert("baz", 3, Duration::from_millis(100)).await;
+    m.remove("baz").await;
+
+    MockClock::advance(Duration::from_millis(150));
+    m.cleanup().await;
+    assert_eq!(*expired.lock().unwrap(), vec![("foo", 1)]);
+    assert_eq!(m.len().await, 1);
+
+    MockClock::advance(Duration::from_millis(100));
+    m.cleanup().await;
+    assert_eq!(*expired.lock().unwrap(), vec![("foo", 1), ("bar", 2)]);
+    assert!(m.is_empty().await);
+  }
+
+  #[tokio::test]
+  async fn drain_expired() {
+    let m: TimedMap<_, _, Instant> = TimedMap::new_with_timesource();
+    m.insert("foo", 1, Duration::from_millis(100)).await;
+    m.insert("bar", 2, Duration::from_millis(200)).await;
+    assert!(m.drain_expired().await.is_empty());
+
+    MockClock::advance(Duration::from_millis(150));
+    assert_eq!(m.drain_expired().await, vec![("foo", 1)]);
+    assert!(m.drain_expired().await.is_empty());
+    assert_eq!(m.get(&"bar").await, Some(2));
+  }
+
+  #[tokio::test]
+  async fn get_value_or_insert_with() {
+    let m: TimedMap<_, _, Instant> = TimedMap::new_with_timesource();
+    let get = |value| m.get_value_or_insert_with("foo", Duration::from_millis(100), move || value);
+    assert_eq!(get(1).await.value(), 1);
+    assert_eq!(get(2).await.value(), 1);
+
+    MockClock::advance(Duration::from_millis(150));
+    assert_eq!(get(3).await.value(), 3);
+    assert_eq!(m.get(&"foo").await, Some(3));
+  }
+
+  #[tokio::test]
+  async fn save_and_load() {
+    let path = std::env::temp_dir().join(format!("timedmap-{}.json", uuid::Uuid::new_v4()));
+    let m: TimedMap<String, u32> = TimedMap::new();
+    m.insert("foo".to_string(), 1, Duration::from_secs(60))
+      .await;
+    m.insert("bar".to_string(), 2, Duration::from_millis(1))
+      .await;
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    m.save_to_file(&path).await.unwrap();
+
+    let loaded: TimedMap<String, u32> = TimedMap::load_from_file(&path).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+      loaded.snapshot::<Vec<_>>().await,
+      vec![("foo".to_string(), 1)]
+    );
+    assert!(loaded.time_remaining("foo").await.unwrap() <= Duration::from_secs(60));
+  }
+
+  #[tokio::test]
+  async fn time_remaining() {
+    let m: TimedMap<_, _, Instant> = TimedMap::new_with_timesource();
+    m.insert("foo", 1, Duration::from_millis(100)).await;
+    assert_eq!(
+      m.time_remaining("foo").await,
+      Some(Duration::from_millis(100))
+    );
+    assert_eq!(m.time_remaining("bar").await, None);
+
+    MockClock::advance(Duration::from_millis(60));
+    assert_eq!(
+      m.time_remaining("foo").await,
+      Some(Duration::from_millis(40))
+    );
+
+    MockClock::advance(Duration::from_millis(41));
+    assert_eq!(m.time_remaining("foo").await, None);
+  }
+}